@@ -1,5 +1,6 @@
 use std::{borrow::Cow, fs::File, io::Read, path::Path};
 
+use flate2::read::GzDecoder;
 use nom::{
     branch::alt,
     bytes::complete::{escaped, tag, take_while, take_while1},
@@ -33,6 +34,28 @@ pub fn parse_str<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, &'a
     preceded(char('\"'), cut(terminated(parse, char('\"'))))(i)
 }
 
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Read a whole file. gzip compressed content is decompressed transparently
+/// based on its magic bytes regardless of the file extension.
+pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, std::io::Error> {
+    let mut content = Vec::new();
+    File::open(path)?.read_to_end(&mut content)?;
+
+    if content.starts_with(&GZIP_MAGIC) {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(content.as_slice()).read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    } else {
+        Ok(content)
+    }
+}
+
+pub fn read_to_string<P: AsRef<Path>>(path: P) -> Result<String, std::io::Error> {
+    String::from_utf8(read_file(path)?)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
 pub fn expand_include<P: AsRef<Path>>(
     input: &str,
     current_dir: P,
@@ -49,12 +72,10 @@ pub fn expand_include<P: AsRef<Path>>(
 
             match preceded(preceded(tag("Include"), sp), parse_str::<Error<_>>)(r) {
                 Ok((r, path)) => {
-                    let mut buf = String::new();
-
                     let mut current_path = current_dir.as_ref().to_owned();
                     current_path.push(path);
 
-                    File::open(&current_path)?.read_to_string(&mut buf)?;
+                    let mut buf = read_to_string(&current_path)?;
 
                     match expand_include(&buf, current_dir.as_ref())? {
                         Cow::Borrowed(_) => {}
@@ -2,6 +2,8 @@ use chumsky::prelude::*;
 use glam::{vec2, vec3a, vec4, Mat4, Vec2, Vec3A, Vec4};
 
 pub mod include;
+pub mod visit;
+pub mod write;
 
 #[derive(Debug)]
 pub enum Scene {
@@ -11,10 +13,29 @@ pub enum Scene {
     Rotate(AxisAngle),
     Scale(Vec3A),
     Translate(Vec3A),
+    ActiveTransform(ActiveTransform),
+    TransformTimes(f32, f32),
+    CoordinateSystem(String),
+    MakeNamedMedium(WorldObject),
+    MediumInterface(String, String),
     SceneObject(SceneObject),
     World(Vec<World>),
 }
 
+/// Which of the start and end transforms following transform directives apply to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ActiveTransform {
+    StartTime,
+    EndTime,
+    All,
+}
+
+impl Default for ActiveTransform {
+    fn default() -> Self {
+        Self::All
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct AxisAngle {
     pub axis: Vec3A,
@@ -38,7 +59,9 @@ pub enum World {
     Transform(Mat4),
     ConcatTransform(Mat4),
     Translate(Vec3A),
+    CoordinateSystem(String),
     CoordSysTransform(String),
+    ActiveTransform(ActiveTransform),
     Scale(Vec3A),
     Rotate(AxisAngle),
     Texture(Texture),
@@ -61,6 +84,7 @@ pub enum Value {
     Integer(Vec<i32>),
     Rgb(Vec3A),
     BlackBody(Vec<Vec2>),
+    Point2(Vec<Vec2>),
     Point(Vec<Vec3A>),
     Normal(Vec<Vec3A>),
     String(Vec<String>),
@@ -263,6 +287,25 @@ fn parse_translate() -> impl Parser<char, Vec3A, Error = Simple<char>> {
         .labelled("Translate")
 }
 
+fn parse_active_transform() -> impl Parser<char, ActiveTransform, Error = Simple<char>> {
+    just("ActiveTransform")
+        .then_ignore(sp())
+        .ignore_then(choice((
+            just("StartTime").to(ActiveTransform::StartTime),
+            just("EndTime").to(ActiveTransform::EndTime),
+            just("All").to(ActiveTransform::All),
+        )))
+        .labelled("ActiveTransform")
+}
+
+fn parse_transform_times() -> impl Parser<char, (f32, f32), Error = Simple<char>> {
+    just("TransformTimes")
+        .then_ignore(sp())
+        .ignore_then(float().then_ignore(sp()))
+        .then(float())
+        .labelled("TransformTimes")
+}
+
 fn bracket<T>(
     parser: impl Parser<char, T, Error = Simple<char>>,
 ) -> impl Parser<char, Vec<T>, Error = Simple<char>> {
@@ -279,6 +322,7 @@ enum ArgumentType {
     Rgb,
     BlackBody,
     Integer,
+    Point2,
     Point,
     Normal,
     String,
@@ -314,9 +358,12 @@ impl ArgumentType {
                 .map(|v| Value::Rgb(vec3a(v[0], v[1], v[2])))
                 .labelled("rgb")
                 .boxed(),
-            Self::BlackBody => bracket(float())
+            // pbrt-v3 pairs a temperature with a scale, pbrt-v4 takes a lone temperature
+            Self::BlackBody => float()
+                .map(|f| vec![f])
+                .or(bracket(float()))
                 .validate(|v, span, emit| {
-                    if v.len() % 2 != 0 {
+                    if v.len() != 1 && v.len() % 2 != 0 {
                         emit(Simple::custom(
                             span,
                             format!(
@@ -327,7 +374,13 @@ impl ArgumentType {
                     }
                     v
                 })
-                .map(|v| Value::BlackBody(v.chunks(2).map(|v| vec2(v[0], v[1])).collect()))
+                .map(|v| {
+                    if v.len() == 1 {
+                        Value::BlackBody(vec![vec2(v[0], 1.0)])
+                    } else {
+                        Value::BlackBody(v.chunks(2).map(|v| vec2(v[0], v[1])).collect())
+                    }
+                })
                 .labelled("blackbody")
                 .boxed(),
             Self::Integer => integer()
@@ -336,6 +389,22 @@ impl ArgumentType {
                 .map(Value::Integer)
                 .labelled("integer")
                 .boxed(),
+            Self::Point2 => bracket(float())
+                .validate(|v, span, emit| {
+                    if v.len() % 2 != 0 {
+                        emit(Simple::custom(
+                            span,
+                            format!(
+                                "length of point2 value must be multiple of 2. It was {}",
+                                v.len(),
+                            ),
+                        ));
+                    }
+                    v
+                })
+                .map(|v| Value::Point2(v.chunks(2).map(|p| vec2(p[0], p[1])).collect()))
+                .labelled("point2")
+                .boxed(),
             Self::Point => bracket(float())
                 .validate(|v, span, emit| {
                     if v.len() % 3 != 0 {
@@ -391,8 +460,9 @@ fn parse_argument_type_name() -> impl Parser<char, (ArgumentType, String), Error
         just("bool").to(ArgumentType::Bool),
         just("integer").to(ArgumentType::Integer),
         just("string").to(ArgumentType::String),
-        just("point").to(ArgumentType::Point),
-        just("normal").to(ArgumentType::Normal),
+        just("point2").to(ArgumentType::Point2),
+        just("point3").or(just("point")).to(ArgumentType::Point),
+        just("normal3").or(just("normal")).to(ArgumentType::Normal),
         just("texture").to(ArgumentType::Texture),
         just("blackbody").to(ArgumentType::BlackBody),
         just("rgb").or(just("color")).to(ArgumentType::Rgb),
@@ -438,7 +508,11 @@ fn parse_scene_object() -> impl Parser<char, SceneObject, Error = Simple<char>>
 }
 
 fn parse_world_statement() -> impl Parser<char, Vec<World>, Error = Simple<char>> {
-    parse_worlds().delimited_by(just("WorldBegin").then_ignore(sp()), just("WorldEnd"))
+    // pbrt-v4 dropped WorldEnd, the world block then extends to the end of file
+    parse_worlds().delimited_by(
+        just("WorldBegin").then_ignore(sp()),
+        just("WorldEnd").ignored().or(end()),
+    )
 }
 
 fn parse_scene() -> impl Parser<char, Scene, Error = Simple<char>> {
@@ -449,6 +523,11 @@ fn parse_scene() -> impl Parser<char, Scene, Error = Simple<char>> {
         parse_translate().map(Scene::Translate),
         parse_concat_transform().map(Scene::ConcatTransform),
         parse_transform().map(Scene::Transform),
+        parse_active_transform().map(Scene::ActiveTransform),
+        parse_transform_times().map(|(start, end)| Scene::TransformTimes(start, end)),
+        parse_coordinate_system().map(Scene::CoordinateSystem),
+        parse_make_named_medium().map(Scene::MakeNamedMedium),
+        parse_medium_interface().map(|(i, e)| Scene::MediumInterface(i, e)),
         parse_scene_object().map(Scene::SceneObject),
         parse_world_statement().map(Scene::World),
     ))
@@ -514,6 +593,21 @@ fn parse_world_object() -> impl Parser<char, WorldObject, Error = Simple<char>>
     .labelled("world object")
 }
 
+/// Media may also be declared before `WorldBegin`, e.g. for the camera medium.
+fn parse_make_named_medium() -> impl Parser<char, WorldObject, Error = Simple<char>> {
+    just("MakeNamedMedium")
+        .then_ignore(sp())
+        .ignore_then(string())
+        .then_ignore(sp())
+        .then(parse_argument().then_ignore(sp()).repeated())
+        .map(|(t, arguments)| WorldObject {
+            object_type: WorldObjectType::MakeNamedMedium,
+            t,
+            arguments,
+        })
+        .labelled("MakeNamedMedium")
+}
+
 fn parse_object_instance() -> impl Parser<char, String, Error = Simple<char>> {
     just("ObjectInstance")
         .then_ignore(sp())
@@ -521,6 +615,13 @@ fn parse_object_instance() -> impl Parser<char, String, Error = Simple<char>> {
         .labelled("object instance")
 }
 
+fn parse_coordinate_system() -> impl Parser<char, String, Error = Simple<char>> {
+    just("CoordinateSystem")
+        .then_ignore(sp())
+        .ignore_then(string())
+        .labelled("CoordinateSystem")
+}
+
 fn parse_coord_sys_transform() -> impl Parser<char, String, Error = Simple<char>> {
     just("CoordSysTransform")
         .then_ignore(sp())
@@ -549,7 +650,9 @@ fn parse_worlds() -> impl Parser<char, Vec<World>, Error = Simple<char>> {
             parse_translate().map(World::Translate),
             parse_scale().map(World::Scale),
             parse_rotate().map(World::Rotate),
+            parse_coordinate_system().map(World::CoordinateSystem),
             parse_coord_sys_transform().map(World::CoordSysTransform),
+            parse_active_transform().map(World::ActiveTransform),
             parse_medium_interface().map(|(i, e)| World::MediumInterface(i, e)),
             just("ReverseOrientation").to(World::ReverseOrientation),
             bf.clone()
@@ -659,6 +762,41 @@ mod test {
                 value: Value::Rgb(vec3a(0.7, 0.2, 0.2))
             }
         );
+
+        assert_eq!(
+            parse_argument()
+                .parse(r#""point2 uv" [ 0 0 1 0 ]"#)
+                .unwrap(),
+            Argument {
+                name: "uv".to_string(),
+                value: Value::Point2(vec![vec2(0.0, 0.0), vec2(1.0, 0.0)])
+            }
+        );
+
+        assert_eq!(
+            parse_argument().parse(r#""point3 P" [ 0 1 2 ]"#).unwrap(),
+            Argument {
+                name: "P".to_string(),
+                value: Value::Point(vec![vec3a(0.0, 1.0, 2.0)])
+            }
+        );
+
+        assert_eq!(
+            parse_argument()
+                .parse(r#""blackbody L" [ 6500 2 ]"#)
+                .unwrap(),
+            Argument {
+                name: "L".to_string(),
+                value: Value::BlackBody(vec![vec2(6500.0, 2.0)])
+            }
+        );
+        assert_eq!(
+            parse_argument().parse(r#""blackbody L" 6500"#).unwrap(),
+            Argument {
+                name: "L".to_string(),
+                value: Value::BlackBody(vec![vec2(6500.0, 1.0)])
+            }
+        );
     }
 
     #[test]
@@ -708,4 +846,84 @@ WorldEnd
 
         parse_pbrt().parse(src).unwrap();
     }
+
+    #[test]
+    fn test_motion() {
+        let src = r#"
+ActiveTransform StartTime
+Translate 0 0 0
+ActiveTransform EndTime
+Translate 0 0 1
+ActiveTransform All
+TransformTimes 0 1
+Camera "perspective" "float shutteropen" 0 "float shutterclose" 1
+
+WorldBegin
+AttributeBegin
+  ActiveTransform EndTime
+  Rotate 90 0 0 1
+  ActiveTransform All
+  Shape "sphere" "float radius" 1
+AttributeEnd
+        "#;
+
+        let scenes = parse_pbrt().parse(src).unwrap();
+        assert!(matches!(
+            scenes[0],
+            Scene::ActiveTransform(ActiveTransform::StartTime)
+        ));
+        assert!(
+            matches!(scenes[5], Scene::TransformTimes(start, end) if start == 0.0 && end == 1.0)
+        );
+    }
+
+    #[test]
+    fn test_coordinate_system() {
+        let src = r#"
+LookAt 0 0 5  0 0 0  0 1 0
+CoordinateSystem "eye"
+Camera "perspective"
+
+WorldBegin
+Translate 1 0 0
+CoordinateSystem "shifted"
+CoordSysTransform "camera"
+        "#;
+
+        let scenes = parse_pbrt().parse(src).unwrap();
+        assert!(matches!(&scenes[1], Scene::CoordinateSystem(name) if name == "eye"));
+        if let Scene::World(worlds) = &scenes[3] {
+            assert!(matches!(&worlds[1], World::CoordinateSystem(name) if name == "shifted"));
+            assert!(matches!(&worlds[2], World::CoordSysTransform(name) if name == "camera"));
+        } else {
+            panic!("expected world block");
+        }
+    }
+
+    #[test]
+    fn test_camera_medium() {
+        let src = r#"
+LookAt 0 0 5  0 0 0  0 1 0
+MakeNamedMedium "fog" "string type" "homogeneous" "rgb sigma_s" [.5 .5 .5]
+MediumInterface "" "fog"
+Camera "perspective"
+
+WorldBegin
+        "#;
+
+        let scenes = parse_pbrt().parse(src).unwrap();
+        if let Scene::MakeNamedMedium(obj) = &scenes[1] {
+            assert_eq!(obj.object_type, WorldObjectType::MakeNamedMedium);
+            assert_eq!(obj.t, "fog");
+            assert_eq!(obj.arguments.len(), 2);
+            assert!(matches!(&obj.arguments[0].value, Value::String(t) if t == &["homogeneous"]));
+        } else {
+            panic!("expected MakeNamedMedium");
+        }
+        assert!(matches!(
+            &scenes[2],
+            Scene::MediumInterface(interior, exterior) if interior.is_empty() && exterior == "fog"
+        ));
+        assert!(matches!(&scenes[3], Scene::SceneObject(_)));
+    }
 }
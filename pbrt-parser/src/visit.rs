@@ -0,0 +1,402 @@
+use crate::{Argument, Scene, SceneObject, Texture, World, WorldObject};
+
+pub trait Visit {
+    fn visit_scenes(&mut self, scenes: &[Scene]) {
+        walk_scenes(self, scenes)
+    }
+
+    fn visit_scene(&mut self, scene: &Scene) {
+        walk_scene(self, scene)
+    }
+
+    fn visit_scene_object(&mut self, obj: &SceneObject) {
+        walk_scene_object(self, obj)
+    }
+
+    fn visit_worlds(&mut self, worlds: &[World]) {
+        walk_worlds(self, worlds)
+    }
+
+    fn visit_world(&mut self, world: &World) {
+        walk_world(self, world)
+    }
+
+    fn visit_world_object(&mut self, obj: &WorldObject) {
+        walk_world_object(self, obj)
+    }
+
+    fn visit_texture(&mut self, texture: &Texture) {
+        walk_texture(self, texture)
+    }
+
+    fn visit_argument(&mut self, _argument: &Argument) {}
+}
+
+pub fn walk_scenes<V: Visit + ?Sized>(v: &mut V, scenes: &[Scene]) {
+    for scene in scenes {
+        v.visit_scene(scene);
+    }
+}
+
+pub fn walk_scene<V: Visit + ?Sized>(v: &mut V, scene: &Scene) {
+    match scene {
+        Scene::SceneObject(obj) => v.visit_scene_object(obj),
+        Scene::MakeNamedMedium(obj) => v.visit_world_object(obj),
+        Scene::World(worlds) => v.visit_worlds(worlds),
+        Scene::Transform(_)
+        | Scene::ConcatTransform(_)
+        | Scene::LookAt(_)
+        | Scene::Rotate(_)
+        | Scene::Scale(_)
+        | Scene::Translate(_)
+        | Scene::ActiveTransform(_)
+        | Scene::TransformTimes(_, _)
+        | Scene::CoordinateSystem(_)
+        | Scene::MediumInterface(_, _) => {}
+    }
+}
+
+pub fn walk_scene_object<V: Visit + ?Sized>(v: &mut V, obj: &SceneObject) {
+    for argument in &obj.arguments {
+        v.visit_argument(argument);
+    }
+}
+
+pub fn walk_worlds<V: Visit + ?Sized>(v: &mut V, worlds: &[World]) {
+    for world in worlds {
+        v.visit_world(world);
+    }
+}
+
+pub fn walk_world<V: Visit + ?Sized>(v: &mut V, world: &World) {
+    match world {
+        World::WorldObject(obj) => v.visit_world_object(obj),
+        World::Texture(texture) => v.visit_texture(texture),
+        World::Attribute(worlds)
+        | World::TransformBeginEnd(worlds)
+        | World::ObjectBeginEnd(_, worlds) => v.visit_worlds(worlds),
+        World::ObjectInstance(_)
+        | World::Transform(_)
+        | World::ConcatTransform(_)
+        | World::Translate(_)
+        | World::CoordinateSystem(_)
+        | World::CoordSysTransform(_)
+        | World::ActiveTransform(_)
+        | World::Scale(_)
+        | World::Rotate(_)
+        | World::NamedMaterial(_)
+        | World::MediumInterface(_, _)
+        | World::ReverseOrientation => {}
+    }
+}
+
+pub fn walk_world_object<V: Visit + ?Sized>(v: &mut V, obj: &WorldObject) {
+    for argument in &obj.arguments {
+        v.visit_argument(argument);
+    }
+}
+
+pub fn walk_texture<V: Visit + ?Sized>(v: &mut V, texture: &Texture) {
+    for argument in &texture.obj.arguments {
+        v.visit_argument(argument);
+    }
+}
+
+pub trait VisitMut {
+    fn visit_scenes_mut(&mut self, scenes: &mut Vec<Scene>) {
+        walk_scenes_mut(self, scenes)
+    }
+
+    fn visit_scene_mut(&mut self, scene: &mut Scene) {
+        walk_scene_mut(self, scene)
+    }
+
+    fn visit_scene_object_mut(&mut self, obj: &mut SceneObject) {
+        walk_scene_object_mut(self, obj)
+    }
+
+    fn visit_worlds_mut(&mut self, worlds: &mut Vec<World>) {
+        walk_worlds_mut(self, worlds)
+    }
+
+    fn visit_world_mut(&mut self, world: &mut World) {
+        walk_world_mut(self, world)
+    }
+
+    fn visit_world_object_mut(&mut self, obj: &mut WorldObject) {
+        walk_world_object_mut(self, obj)
+    }
+
+    fn visit_texture_mut(&mut self, texture: &mut Texture) {
+        walk_texture_mut(self, texture)
+    }
+
+    fn visit_argument_mut(&mut self, _argument: &mut Argument) {}
+}
+
+pub fn walk_scenes_mut<V: VisitMut + ?Sized>(v: &mut V, scenes: &mut Vec<Scene>) {
+    for scene in scenes {
+        v.visit_scene_mut(scene);
+    }
+}
+
+pub fn walk_scene_mut<V: VisitMut + ?Sized>(v: &mut V, scene: &mut Scene) {
+    match scene {
+        Scene::SceneObject(obj) => v.visit_scene_object_mut(obj),
+        Scene::MakeNamedMedium(obj) => v.visit_world_object_mut(obj),
+        Scene::World(worlds) => v.visit_worlds_mut(worlds),
+        Scene::Transform(_)
+        | Scene::ConcatTransform(_)
+        | Scene::LookAt(_)
+        | Scene::Rotate(_)
+        | Scene::Scale(_)
+        | Scene::Translate(_)
+        | Scene::ActiveTransform(_)
+        | Scene::TransformTimes(_, _)
+        | Scene::CoordinateSystem(_)
+        | Scene::MediumInterface(_, _) => {}
+    }
+}
+
+pub fn walk_scene_object_mut<V: VisitMut + ?Sized>(v: &mut V, obj: &mut SceneObject) {
+    for argument in &mut obj.arguments {
+        v.visit_argument_mut(argument);
+    }
+}
+
+pub fn walk_worlds_mut<V: VisitMut + ?Sized>(v: &mut V, worlds: &mut Vec<World>) {
+    for world in worlds {
+        v.visit_world_mut(world);
+    }
+}
+
+pub fn walk_world_mut<V: VisitMut + ?Sized>(v: &mut V, world: &mut World) {
+    match world {
+        World::WorldObject(obj) => v.visit_world_object_mut(obj),
+        World::Texture(texture) => v.visit_texture_mut(texture),
+        World::Attribute(worlds)
+        | World::TransformBeginEnd(worlds)
+        | World::ObjectBeginEnd(_, worlds) => v.visit_worlds_mut(worlds),
+        World::ObjectInstance(_)
+        | World::Transform(_)
+        | World::ConcatTransform(_)
+        | World::Translate(_)
+        | World::CoordinateSystem(_)
+        | World::CoordSysTransform(_)
+        | World::ActiveTransform(_)
+        | World::Scale(_)
+        | World::Rotate(_)
+        | World::NamedMaterial(_)
+        | World::MediumInterface(_, _)
+        | World::ReverseOrientation => {}
+    }
+}
+
+pub fn walk_world_object_mut<V: VisitMut + ?Sized>(v: &mut V, obj: &mut WorldObject) {
+    for argument in &mut obj.arguments {
+        v.visit_argument_mut(argument);
+    }
+}
+
+pub fn walk_texture_mut<V: VisitMut + ?Sized>(v: &mut V, texture: &mut Texture) {
+    for argument in &mut texture.obj.arguments {
+        v.visit_argument_mut(argument);
+    }
+}
+
+pub trait Fold {
+    fn fold_scenes(&mut self, scenes: Vec<Scene>) -> Vec<Scene> {
+        fold_scenes(self, scenes)
+    }
+
+    fn fold_scene(&mut self, scene: Scene) -> Scene {
+        fold_scene(self, scene)
+    }
+
+    fn fold_scene_object(&mut self, obj: SceneObject) -> SceneObject {
+        fold_scene_object(self, obj)
+    }
+
+    fn fold_worlds(&mut self, worlds: Vec<World>) -> Vec<World> {
+        fold_worlds(self, worlds)
+    }
+
+    fn fold_world(&mut self, world: World) -> World {
+        fold_world(self, world)
+    }
+
+    fn fold_world_object(&mut self, obj: WorldObject) -> WorldObject {
+        fold_world_object(self, obj)
+    }
+
+    fn fold_texture(&mut self, texture: Texture) -> Texture {
+        fold_texture(self, texture)
+    }
+
+    fn fold_argument(&mut self, argument: Argument) -> Argument {
+        argument
+    }
+}
+
+pub fn fold_scenes<F: Fold + ?Sized>(f: &mut F, scenes: Vec<Scene>) -> Vec<Scene> {
+    scenes.into_iter().map(|s| f.fold_scene(s)).collect()
+}
+
+pub fn fold_scene<F: Fold + ?Sized>(f: &mut F, scene: Scene) -> Scene {
+    match scene {
+        Scene::SceneObject(obj) => Scene::SceneObject(f.fold_scene_object(obj)),
+        Scene::MakeNamedMedium(obj) => Scene::MakeNamedMedium(f.fold_world_object(obj)),
+        Scene::World(worlds) => Scene::World(f.fold_worlds(worlds)),
+        scene => scene,
+    }
+}
+
+pub fn fold_scene_object<F: Fold + ?Sized>(f: &mut F, obj: SceneObject) -> SceneObject {
+    SceneObject {
+        arguments: obj
+            .arguments
+            .into_iter()
+            .map(|a| f.fold_argument(a))
+            .collect(),
+        ..obj
+    }
+}
+
+pub fn fold_worlds<F: Fold + ?Sized>(f: &mut F, worlds: Vec<World>) -> Vec<World> {
+    worlds.into_iter().map(|w| f.fold_world(w)).collect()
+}
+
+pub fn fold_world<F: Fold + ?Sized>(f: &mut F, world: World) -> World {
+    match world {
+        World::WorldObject(obj) => World::WorldObject(f.fold_world_object(obj)),
+        World::Texture(texture) => World::Texture(f.fold_texture(texture)),
+        World::Attribute(worlds) => World::Attribute(f.fold_worlds(worlds)),
+        World::TransformBeginEnd(worlds) => World::TransformBeginEnd(f.fold_worlds(worlds)),
+        World::ObjectBeginEnd(name, worlds) => World::ObjectBeginEnd(name, f.fold_worlds(worlds)),
+        world => world,
+    }
+}
+
+pub fn fold_world_object<F: Fold + ?Sized>(f: &mut F, obj: WorldObject) -> WorldObject {
+    WorldObject {
+        arguments: obj
+            .arguments
+            .into_iter()
+            .map(|a| f.fold_argument(a))
+            .collect(),
+        ..obj
+    }
+}
+
+pub fn fold_texture<F: Fold + ?Sized>(f: &mut F, texture: Texture) -> Texture {
+    Texture {
+        obj: crate::Object {
+            arguments: texture
+                .obj
+                .arguments
+                .into_iter()
+                .map(|a| f.fold_argument(a))
+                .collect(),
+            ..texture.obj
+        },
+        ..texture
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chumsky::Parser;
+
+    use super::*;
+    use crate::{parse_pbrt, Value};
+
+    const SRC: &str = r#"
+LookAt 3 4 1.5  0 0 0  0 0 1
+Camera "perspective" "float fov" 45
+
+WorldBegin
+Texture "checks" "spectrum" "imagemap" "string filename" "checks.png"
+
+AttributeBegin
+  Material "matte" "rgb Kd" [ .7 .2 .2 ]
+  Shape "plymesh" "string filename" "a.ply"
+AttributeEnd
+
+ObjectBegin "obj"
+  AttributeBegin
+    Shape "plymesh" "string filename" "b.ply"
+  AttributeEnd
+ObjectEnd
+WorldEnd
+        "#;
+
+    #[test]
+    fn test_visit() {
+        #[derive(Default)]
+        struct Filenames(Vec<String>);
+
+        impl Visit for Filenames {
+            fn visit_argument(&mut self, argument: &Argument) {
+                if let (true, Value::String(s)) = (argument.name == "filename", &argument.value) {
+                    self.0.extend(s.iter().cloned());
+                }
+            }
+        }
+
+        let scenes = parse_pbrt().parse(SRC).unwrap();
+        let mut filenames = Filenames::default();
+        filenames.visit_scenes(&scenes);
+
+        assert_eq!(filenames.0, vec!["checks.png", "a.ply", "b.ply"]);
+    }
+
+    #[test]
+    fn test_visit_mut() {
+        struct RenameMaterial;
+
+        impl VisitMut for RenameMaterial {
+            fn visit_world_object_mut(&mut self, obj: &mut WorldObject) {
+                if obj.t == "matte" {
+                    obj.t = "diffuse".to_string();
+                }
+                walk_world_object_mut(self, obj);
+            }
+        }
+
+        let mut scenes = parse_pbrt().parse(SRC).unwrap();
+        RenameMaterial.visit_scenes_mut(&mut scenes);
+
+        let mut types = Vec::new();
+        struct Types<'a>(&'a mut Vec<String>);
+        impl<'a> Visit for Types<'a> {
+            fn visit_world_object(&mut self, obj: &WorldObject) {
+                self.0.push(obj.t.clone());
+            }
+        }
+        Types(&mut types).visit_scenes(&scenes);
+
+        assert_eq!(types, vec!["diffuse", "plymesh", "plymesh"]);
+    }
+
+    #[test]
+    fn test_fold() {
+        struct DropObjects;
+
+        impl Fold for DropObjects {
+            fn fold_worlds(&mut self, worlds: Vec<World>) -> Vec<World> {
+                worlds
+                    .into_iter()
+                    .filter(|w| !matches!(w, World::ObjectBeginEnd(_, _)))
+                    .map(|w| self.fold_world(w))
+                    .collect()
+            }
+        }
+
+        let scenes = DropObjects.fold_scenes(parse_pbrt().parse(SRC).unwrap());
+
+        match scenes.last().unwrap() {
+            Scene::World(worlds) => assert_eq!(worlds.len(), 2),
+            _ => panic!("last statement must be World"),
+        }
+    }
+}
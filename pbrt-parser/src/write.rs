@@ -0,0 +1,392 @@
+use std::fmt::{self, Write};
+
+use crate::{
+    ActiveTransform, Argument, Object, Scene, SceneObjectType, Value, World, WorldObjectType,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Version {
+    V3,
+    V4,
+}
+
+/// Write scene description back to pbrt text.
+/// `Version::V4` drops `WorldEnd` and uses pbrt-v4 names for parameter types.
+pub fn write_pbrt<W: Write>(w: &mut W, scenes: &[Scene], version: Version) -> fmt::Result {
+    let mut writer = Writer {
+        w,
+        version,
+        depth: 0,
+    };
+
+    for scene in scenes {
+        writer.scene(scene)?;
+    }
+
+    Ok(())
+}
+
+pub fn to_pbrt_string(scenes: &[Scene], version: Version) -> String {
+    let mut s = String::new();
+    write_pbrt(&mut s, scenes, version).unwrap();
+    s
+}
+
+struct Writer<'a, W> {
+    w: &'a mut W,
+    version: Version,
+    depth: usize,
+}
+
+impl<'a, W: Write> Writer<'a, W> {
+    fn indent(&mut self) -> fmt::Result {
+        for _ in 0..self.depth {
+            self.w.write_str("    ")?;
+        }
+        Ok(())
+    }
+
+    fn floats(&mut self, values: impl IntoIterator<Item = f32>) -> fmt::Result {
+        self.w.write_str("[")?;
+        for v in values {
+            write!(self.w, " {}", v)?;
+        }
+        self.w.write_str(" ]")
+    }
+
+    fn string(&mut self, s: &str) -> fmt::Result {
+        self.w.write_char('"')?;
+        for c in s.chars() {
+            match c {
+                '"' => self.w.write_str("\\\"")?,
+                '\\' => self.w.write_str("\\\\")?,
+                '\n' => self.w.write_str("\\n")?,
+                c => self.w.write_char(c)?,
+            }
+        }
+        self.w.write_char('"')
+    }
+
+    fn strings(&mut self, values: &[String]) -> fmt::Result {
+        self.w.write_str("[")?;
+        for v in values {
+            self.w.write_char(' ')?;
+            self.string(v)?;
+        }
+        self.w.write_str(" ]")
+    }
+
+    fn argument(&mut self, argument: &Argument) -> fmt::Result {
+        let v4 = self.version == Version::V4;
+        let type_name = match &argument.value {
+            Value::Float(_) => "float",
+            Value::Bool(_) => "bool",
+            Value::Integer(_) => "integer",
+            Value::Rgb(_) => "rgb",
+            Value::BlackBody(_) => "blackbody",
+            Value::Point2(_) => "point2",
+            Value::Point(_) if v4 => "point3",
+            Value::Point(_) => "point",
+            Value::Normal(_) => "normal",
+            Value::String(_) => "string",
+            Value::Texture(_) => "texture",
+            Value::Spectrum(_) => "spectrum",
+        };
+
+        write!(self.w, "\"{} {}\" ", type_name, argument.name)?;
+
+        match &argument.value {
+            Value::Float(v) => self.floats(v.iter().copied()),
+            Value::Integer(v) => {
+                self.w.write_str("[")?;
+                for i in v {
+                    write!(self.w, " {}", i)?;
+                }
+                self.w.write_str(" ]")
+            }
+            Value::Bool(v) => {
+                self.w.write_str("[")?;
+                for b in v {
+                    write!(self.w, " \"{}\"", b)?;
+                }
+                self.w.write_str(" ]")
+            }
+            Value::Rgb(v) => self.floats([v.x, v.y, v.z]),
+            // pbrt-v4 blackbody takes only a temperature, a scale is kept in pbrt-v3 form
+            // rather than lost, the upgrade moves it into the light's "scale" beforehand
+            Value::BlackBody(v) if v4 && v.len() == 1 && v[0].y == 1.0 => self.floats([v[0].x]),
+            Value::BlackBody(v) => self.floats(v.iter().flat_map(|v| [v.x, v.y])),
+            Value::Point2(v) => self.floats(v.iter().flat_map(|v| [v.x, v.y])),
+            Value::Point(v) | Value::Normal(v) => {
+                self.floats(v.iter().flat_map(|v| [v.x, v.y, v.z]))
+            }
+            Value::String(v) | Value::Texture(v) => self.strings(v),
+            Value::Spectrum(s) => self.string(s),
+        }
+    }
+
+    fn object<T>(&mut self, directive: &str, obj: &Object<T>) -> fmt::Result {
+        self.w.write_str(directive)?;
+        self.w.write_char(' ')?;
+        self.string(&obj.t)?;
+        self.arguments(&obj.arguments)
+    }
+
+    fn arguments(&mut self, arguments: &[Argument]) -> fmt::Result {
+        self.depth += 1;
+        for argument in arguments {
+            self.w.write_char('\n')?;
+            self.indent()?;
+            self.argument(argument)?;
+        }
+        self.depth -= 1;
+        self.w.write_char('\n')
+    }
+
+    fn active_transform(&mut self, active: ActiveTransform) -> fmt::Result {
+        let name = match active {
+            ActiveTransform::StartTime => "StartTime",
+            ActiveTransform::EndTime => "EndTime",
+            ActiveTransform::All => "All",
+        };
+        writeln!(self.w, "ActiveTransform {}", name)
+    }
+
+    fn scene(&mut self, scene: &Scene) -> fmt::Result {
+        match scene {
+            Scene::Transform(m) => {
+                self.w.write_str("Transform ")?;
+                self.floats(m.to_cols_array())?;
+                self.w.write_char('\n')
+            }
+            Scene::ConcatTransform(m) => {
+                self.w.write_str("ConcatTransform ")?;
+                self.floats(m.to_cols_array())?;
+                self.w.write_char('\n')
+            }
+            Scene::LookAt(look_at) => writeln!(
+                self.w,
+                "LookAt {} {} {}\n       {} {} {}\n       {} {} {}",
+                look_at.eye.x,
+                look_at.eye.y,
+                look_at.eye.z,
+                look_at.look_at.x,
+                look_at.look_at.y,
+                look_at.look_at.z,
+                look_at.up.x,
+                look_at.up.y,
+                look_at.up.z
+            ),
+            Scene::Rotate(r) => writeln!(
+                self.w,
+                "Rotate {} {} {} {}",
+                r.angle, r.axis.x, r.axis.y, r.axis.z
+            ),
+            Scene::Scale(v) => writeln!(self.w, "Scale {} {} {}", v.x, v.y, v.z),
+            Scene::Translate(v) => writeln!(self.w, "Translate {} {} {}", v.x, v.y, v.z),
+            Scene::ActiveTransform(active) => self.active_transform(*active),
+            Scene::CoordinateSystem(name) => {
+                self.w.write_str("CoordinateSystem ")?;
+                self.string(name)?;
+                self.w.write_char('\n')
+            }
+            Scene::MakeNamedMedium(obj) => self.object("MakeNamedMedium", obj),
+            Scene::MediumInterface(interior, exterior) => {
+                self.w.write_str("MediumInterface ")?;
+                self.string(interior)?;
+                self.w.write_char(' ')?;
+                self.string(exterior)?;
+                self.w.write_char('\n')
+            }
+            Scene::TransformTimes(start, end) => {
+                writeln!(self.w, "TransformTimes {} {}", start, end)
+            }
+            Scene::SceneObject(obj) => {
+                let directive = match obj.object_type {
+                    SceneObjectType::Camera => "Camera",
+                    SceneObjectType::Sampler => "Sampler",
+                    SceneObjectType::Integrator => "Integrator",
+                    SceneObjectType::PixelFilter => "PixelFilter",
+                    SceneObjectType::Film => "Film",
+                };
+                self.object(directive, obj)
+            }
+            Scene::World(worlds) => {
+                self.w.write_str("\nWorldBegin\n\n")?;
+                for world in worlds {
+                    self.world(world)?;
+                }
+                if self.version == Version::V3 {
+                    self.w.write_str("\nWorldEnd\n")?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn block(&mut self, begin: &str, worlds: &[World], end: &str) -> fmt::Result {
+        self.w.write_str(begin)?;
+        self.w.write_char('\n')?;
+        self.depth += 1;
+        for world in worlds {
+            self.world(world)?;
+        }
+        self.depth -= 1;
+        self.indent()?;
+        self.w.write_str(end)?;
+        self.w.write_char('\n')
+    }
+
+    fn world(&mut self, world: &World) -> fmt::Result {
+        self.indent()?;
+        match world {
+            World::WorldObject(obj) => {
+                let directive = match obj.object_type {
+                    WorldObjectType::LightSource => "LightSource",
+                    WorldObjectType::AreaLightSource => "AreaLightSource",
+                    WorldObjectType::Material => "Material",
+                    WorldObjectType::MakeNamedMaterial => "MakeNamedMaterial",
+                    WorldObjectType::MakeNamedMedium => "MakeNamedMedium",
+                    WorldObjectType::Shape => "Shape",
+                };
+                self.object(directive, obj)
+            }
+            World::Attribute(worlds) => self.block("AttributeBegin", worlds, "AttributeEnd"),
+            World::TransformBeginEnd(worlds) => {
+                // TransformBegin/End is deprecated in pbrt-v4
+                if self.version == Version::V4 {
+                    self.block("AttributeBegin", worlds, "AttributeEnd")
+                } else {
+                    self.block("TransformBegin", worlds, "TransformEnd")
+                }
+            }
+            World::ObjectBeginEnd(name, worlds) => {
+                self.w.write_str("ObjectBegin ")?;
+                self.string(name)?;
+                self.w.write_char('\n')?;
+                self.depth += 1;
+                for world in worlds {
+                    self.world(world)?;
+                }
+                self.depth -= 1;
+                self.indent()?;
+                self.w.write_str("ObjectEnd\n")
+            }
+            World::ObjectInstance(name) => {
+                self.w.write_str("ObjectInstance ")?;
+                self.string(name)?;
+                self.w.write_char('\n')
+            }
+            World::Transform(m) => {
+                self.w.write_str("Transform ")?;
+                self.floats(m.to_cols_array())?;
+                self.w.write_char('\n')
+            }
+            World::ConcatTransform(m) => {
+                self.w.write_str("ConcatTransform ")?;
+                self.floats(m.to_cols_array())?;
+                self.w.write_char('\n')
+            }
+            World::Translate(v) => writeln!(self.w, "Translate {} {} {}", v.x, v.y, v.z),
+            World::Scale(v) => writeln!(self.w, "Scale {} {} {}", v.x, v.y, v.z),
+            World::Rotate(r) => writeln!(
+                self.w,
+                "Rotate {} {} {} {}",
+                r.angle, r.axis.x, r.axis.y, r.axis.z
+            ),
+            World::CoordinateSystem(name) => {
+                self.w.write_str("CoordinateSystem ")?;
+                self.string(name)?;
+                self.w.write_char('\n')
+            }
+            World::CoordSysTransform(name) => {
+                self.w.write_str("CoordSysTransform ")?;
+                self.string(name)?;
+                self.w.write_char('\n')
+            }
+            World::ActiveTransform(active) => self.active_transform(*active),
+            World::Texture(texture) => {
+                self.w.write_str("Texture ")?;
+                self.string(&texture.name)?;
+                self.w.write_char(' ')?;
+                self.string(&texture.value_type)?;
+                self.w.write_char(' ')?;
+                self.string(&texture.obj.t)?;
+                self.arguments(&texture.obj.arguments)
+            }
+            World::NamedMaterial(name) => {
+                self.w.write_str("NamedMaterial ")?;
+                self.string(name)?;
+                self.w.write_char('\n')
+            }
+            World::MediumInterface(interior, exterior) => {
+                self.w.write_str("MediumInterface ")?;
+                self.string(interior)?;
+                self.w.write_char(' ')?;
+                self.string(exterior)?;
+                self.w.write_char('\n')
+            }
+            World::ReverseOrientation => self.w.write_str("ReverseOrientation\n"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chumsky::Parser;
+
+    use super::*;
+    use crate::parse_pbrt;
+
+    const SRC: &str = r#"
+LookAt 3 4 1.5  0 0 0  0 0 1
+TransformTimes 0 1
+MakeNamedMedium "fog" "string type" "homogeneous" "rgb sigma_s" [.1 .1 .1]
+MediumInterface "" "fog"
+Camera "perspective" "float fov" 45
+Film "image" "string filename" "out \"1\".png" "integer xresolution" [400]
+
+WorldBegin
+LightSource "infinite" "rgb L" [.4 .45 .5]
+Texture "checks" "spectrum" "checkerboard" "float uscale" 4 "rgb tex1" [1 0 0]
+
+AttributeBegin
+  Material "matte" "texture Kd" "checks" "bool remaproughness" "false"
+  Translate 0 0 -1e-3
+  CoordinateSystem "lifted"
+  Shape "trianglemesh" "integer indices" [0 1 2] "point P" [0 0 0 1 0 0 0 1 0]
+      "point2 uv" [0 0 1 0 0 1]
+AttributeEnd
+
+ObjectBegin "obj"
+  ActiveTransform EndTime
+  Translate 0 0 1
+  ActiveTransform All
+  Shape "sphere" "float radius" 1
+ObjectEnd
+CoordSysTransform "lifted"
+ObjectInstance "obj"
+WorldEnd
+        "#;
+
+    #[test]
+    fn test_round_trip() {
+        let scenes = parse_pbrt().parse(SRC).unwrap();
+
+        for version in [Version::V3, Version::V4] {
+            let written = to_pbrt_string(&scenes, version);
+            let reparsed = parse_pbrt().parse(written.as_str()).unwrap();
+
+            assert_eq!(written, to_pbrt_string(&reparsed, version));
+        }
+    }
+
+    #[test]
+    fn test_world_end() {
+        let scenes = parse_pbrt().parse(SRC).unwrap();
+
+        assert!(to_pbrt_string(&scenes, Version::V3).contains("WorldEnd"));
+        assert!(!to_pbrt_string(&scenes, Version::V4).contains("WorldEnd"));
+        assert!(to_pbrt_string(&scenes, Version::V4).contains("\"point3 P\""));
+    }
+}
@@ -0,0 +1,894 @@
+//! Bidirectional path tracing after pbrt-v3's `BDPTIntegrator`, on surfaces only.
+
+use core::f32::consts::FRAC_1_PI;
+#[allow(unused_imports)]
+use spirv_std::num_traits::Float;
+use spirv_std::{
+    arch::IndexUnchecked,
+    glam::{Mat4, Vec2, Vec3A},
+    ray_tracing::{AccelerationStructure, RayFlags},
+    RuntimeArray,
+};
+
+use crate::{
+    area_light::{AreaLight, EnumAreaLight},
+    light::{EnumLight, InfiniteLight, Light},
+    material::{EnumMaterial, Material},
+    math::{random_cosine_direction, random_in_unit_sphere},
+    rand::Rng,
+    reflection::{onb::Onb, Bsdf, BxdfKind},
+    surface_sample::{EnumSurfaceSample, SurfaceSample},
+    texture::EnumTexture,
+    IndexData, InputImage, Ray, RayPayload, Uniform, Vertex,
+};
+
+/// Longest subpath, the camera one holds `max_depth + 2` vertices.
+pub const MAX_VERTICES: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+    InfiniteLight,
+}
+
+#[derive(Clone, Copy)]
+pub struct PathVertex {
+    pub kind: VertexKind,
+    /// Direction towards the environment for `InfiniteLight`
+    pub position: Vec3A,
+    /// Zero for the camera and point lights
+    pub normal: Vec3A,
+    /// Direction towards the previous vertex of the subpath
+    pub wo: Vec3A,
+    pub uv: Vec2,
+    /// `IndexData` of a surface, light of a point light, area light of an emitter
+    pub index: u32,
+    pub beta: Vec3A,
+    /// Radiance of an `InfiniteLight` found by the miss shader
+    pub le: Vec3A,
+    /// Area density of sampling the vertex from the previous one
+    pub pdf_fwd: f32,
+    /// Area density of sampling the vertex from the next one
+    pub pdf_rev: f32,
+    /// Sampled from a specular bxdf
+    pub delta: bool,
+}
+
+impl Default for PathVertex {
+    fn default() -> Self {
+        Self {
+            kind: VertexKind::Surface,
+            position: Vec3A::ZERO,
+            normal: Vec3A::ZERO,
+            wo: Vec3A::ZERO,
+            uv: Vec2::ZERO,
+            index: 0,
+            beta: Vec3A::ZERO,
+            le: Vec3A::ZERO,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+        }
+    }
+}
+
+impl PathVertex {
+    fn is_delta_light(&self) -> bool {
+        self.kind == VertexKind::Light && self.normal == Vec3A::ZERO
+    }
+}
+
+pub type Subpath = [PathVertex; MAX_VERTICES];
+
+fn remap0(f: f32) -> f32 {
+    if f != 0.0 {
+        f
+    } else {
+        1.0
+    }
+}
+
+/// Unit direction from `from` towards `to`.
+fn direction(from: &PathVertex, to: &PathVertex) -> Vec3A {
+    if to.kind == VertexKind::InfiniteLight {
+        to.position
+    } else {
+        (to.position - from.position).normalize()
+    }
+}
+
+/// Converts a solid angle density at `from` to an area density at `to`.
+fn convert_density(pdf: f32, from: &PathVertex, to: &PathVertex) -> f32 {
+    if to.kind == VertexKind::InfiniteLight {
+        return pdf;
+    }
+
+    let w = to.position - from.position;
+    let distance_squared = w.length_squared();
+    if distance_squared == 0.0 {
+        return 0.0;
+    }
+
+    let mut pdf = pdf / distance_squared;
+    if to.normal != Vec3A::ZERO {
+        pdf *= to.normal.dot(w / distance_squared.sqrt()).abs();
+    }
+    pdf
+}
+
+pub struct Bdpt<'a> {
+    pub uniform: &'a Uniform,
+    pub world_to_camera: Mat4,
+    pub infinite_light: InfiniteLight<'a>,
+    pub lights: &'a [EnumLight],
+    pub area_lights: &'a [EnumAreaLight],
+    pub emit_objects: &'a [EnumSurfaceSample],
+    pub materials: &'a [EnumMaterial],
+    pub textures: &'a [EnumTexture],
+    pub images: &'a RuntimeArray<InputImage>,
+    pub index_data: &'a [IndexData],
+    pub indices: &'a [u32],
+    pub vertices: &'a [Vertex],
+    pub emit_distribution: &'a [f32],
+}
+
+impl<'a> Bdpt<'a> {
+    /// Lights are picked uniformly, the analytic ones, the emitters and the environment.
+    fn light_count(&self) -> u32 {
+        self.uniform.lights_len
+            + self.uniform.emit_object_len
+            + if self.infinite_light.is_samplable() {
+                1
+            } else {
+                0
+            }
+    }
+
+    fn index(&self, v: &PathVertex) -> &IndexData {
+        unsafe { self.index_data.index_unchecked(v.index as usize) }
+    }
+
+    fn area_light(&self, v: &PathVertex) -> &EnumAreaLight {
+        let index = if v.kind == VertexKind::Light {
+            v.index
+        } else {
+            self.index(v).area_light_index
+        };
+        unsafe { self.area_lights.index_unchecked(index as usize) }
+    }
+
+    fn compute_bsdf(&self, v: &PathVertex, bsdf: &mut Bsdf) {
+        bsdf.clear(v.normal, Onb::from_w(v.normal));
+        unsafe {
+            self.materials
+                .index_unchecked(self.index(v).material_index as usize)
+        }
+        .compute_bsdf(bsdf, v.uv, self.textures, self.images);
+    }
+
+    /// A connection can only be made through a vertex with a non-specular bxdf.
+    fn is_connectible(&self, v: &PathVertex, bsdf: &mut Bsdf) -> bool {
+        match v.kind {
+            VertexKind::Camera => self.uniform.camera.is_connectible(),
+            VertexKind::Light => true,
+            VertexKind::Surface => {
+                self.compute_bsdf(v, bsdf);
+                bsdf.contains(BxdfKind::DIFFUSE)
+            }
+            VertexKind::InfiniteLight => false,
+        }
+    }
+
+    /// Radiance emitted from `v` towards `to`.
+    fn le(&self, v: &PathVertex, to: &PathVertex) -> Vec3A {
+        match v.kind {
+            VertexKind::InfiniteLight => v.le,
+            VertexKind::Surface => self.area_light(v).emit(direction(v, to), v.normal),
+            _ => Vec3A::ZERO,
+        }
+    }
+
+    /// Area density of sampling `next` from `v` reached from `prev`,
+    /// which is ignored for the camera and lights.
+    fn pdf(&self, v: &PathVertex, prev: &PathVertex, next: &PathVertex, bsdf: &mut Bsdf) -> f32 {
+        match v.kind {
+            VertexKind::Light => self.pdf_light(v, next),
+            VertexKind::Camera => {
+                let w = self
+                    .world_to_camera
+                    .transform_vector3a(direction(v, next))
+                    .normalize();
+                let (_, _, pdf_dir) = self.uniform.camera.importance(Ray {
+                    origin: self.world_to_camera.transform_point3a(v.position),
+                    direction: w,
+                });
+                convert_density(pdf_dir, v, next)
+            }
+            VertexKind::Surface => {
+                self.compute_bsdf(v, bsdf);
+                let pdf = bsdf.pdf(direction(v, prev), direction(v, next));
+                convert_density(pdf, v, next)
+            }
+            VertexKind::InfiniteLight => 0.0,
+        }
+    }
+
+    /// Area density of the light at `v` emitting towards `to`.
+    /// Point lights emit uniformly and emitters by cosine.
+    fn pdf_light(&self, v: &PathVertex, to: &PathVertex) -> f32 {
+        if v.kind == VertexKind::InfiniteLight {
+            return 0.0;
+        }
+
+        let w = direction(v, to);
+        let pdf_dir = if v.normal == Vec3A::ZERO {
+            0.25 * FRAC_1_PI
+        } else {
+            v.normal.dot(w).max(0.0) * FRAC_1_PI
+        };
+        convert_density(pdf_dir, v, to)
+    }
+
+    /// Density of picking the light at `v` and sampling the vertex on it.
+    fn pdf_light_origin(&self, v: &PathVertex) -> f32 {
+        let pmf = 1.0 / self.light_count() as f32;
+        match v.kind {
+            VertexKind::InfiniteLight => {
+                if self.infinite_light.is_samplable() {
+                    pmf * self.infinite_light.pdf(v.position)
+                } else {
+                    0.0
+                }
+            }
+            VertexKind::Surface => {
+                if self.area_light(v).is_null() {
+                    0.0
+                } else {
+                    pmf / self.index(v).emit_area
+                }
+            }
+            VertexKind::Light => pmf,
+            VertexKind::Camera => 0.0,
+        }
+    }
+
+    /// Whether nothing is between the points, neither of which may be at infinity.
+    #[inline(always)]
+    fn unoccluded(
+        tlas: &AccelerationStructure,
+        from: Vec3A,
+        to: Vec3A,
+        payload: &mut RayPayload,
+    ) -> bool {
+        *payload = RayPayload::default();
+        unsafe {
+            tlas.trace_ray(
+                RayFlags::empty(),
+                0xff,
+                0,
+                0,
+                0,
+                from,
+                0.001,
+                (to - from).normalize(),
+                from.distance(to) * 0.999,
+                payload,
+            );
+        }
+        payload.is_miss != 0
+    }
+
+    /// Extends `path` from its `start`th vertex by sampling bsdfs, pbrt's `RandomWalk`.
+    /// Returns the number of vertices in the path.
+    #[inline(always)]
+    #[allow(clippy::too_many_arguments)]
+    fn random_walk(
+        &self,
+        tlas: &AccelerationStructure,
+        path: &mut Subpath,
+        start: usize,
+        max_vertices: usize,
+        mut ray: Ray,
+        mut beta: Vec3A,
+        mut pdf_fwd: f32,
+        is_camera_path: bool,
+        bsdf: &mut Bsdf,
+        payload: &mut RayPayload,
+        rng: &mut impl Rng,
+    ) -> usize {
+        let mut bounces = start;
+
+        while bounces < max_vertices {
+            *payload = RayPayload::default();
+            unsafe {
+                tlas.trace_ray(
+                    RayFlags::OPAQUE,
+                    0xff,
+                    0,
+                    0,
+                    0,
+                    ray.origin,
+                    0.001,
+                    ray.direction,
+                    100000.0,
+                    payload,
+                );
+            }
+
+            let prev = *unsafe { path.index_unchecked(bounces - 1) };
+
+            if payload.is_miss != 0 {
+                // Only radiance arrives from the environment
+                if is_camera_path {
+                    *unsafe { path.index_unchecked_mut(bounces) } = PathVertex {
+                        kind: VertexKind::InfiniteLight,
+                        position: ray.direction,
+                        wo: -ray.direction,
+                        beta,
+                        le: payload.position,
+                        pdf_fwd,
+                        ..Default::default()
+                    };
+                    bounces += 1;
+                }
+                break;
+            }
+
+            let mut vertex = PathVertex {
+                kind: VertexKind::Surface,
+                position: payload.position,
+                normal: payload.normal.normalize(),
+                wo: -ray.direction,
+                uv: payload.uv,
+                index: payload.index,
+                beta,
+                ..Default::default()
+            };
+            vertex.pdf_fwd = convert_density(pdf_fwd, &prev, &vertex);
+            *unsafe { path.index_unchecked_mut(bounces) } = vertex;
+            bounces += 1;
+
+            if bounces >= max_vertices {
+                break;
+            }
+
+            self.compute_bsdf(&vertex, bsdf);
+            let sampled_f = bsdf.sample_f(vertex.wo, rng);
+            if sampled_f.pdf < 1e-5 || sampled_f.f == Vec3A::ZERO {
+                break;
+            }
+
+            beta *= sampled_f.f * sampled_f.wi.dot(vertex.normal).abs() / sampled_f.pdf;
+            pdf_fwd = sampled_f.pdf;
+            let mut pdf_rev = bsdf.pdf(sampled_f.wi, vertex.wo);
+            if sampled_f.is_specular {
+                unsafe { path.index_unchecked_mut(bounces - 1) }.delta = true;
+                pdf_fwd = 0.0;
+                pdf_rev = 0.0;
+            }
+
+            unsafe { path.index_unchecked_mut(bounces - 2) }.pdf_rev =
+                convert_density(pdf_rev, &vertex, &prev);
+
+            ray = Ray {
+                origin: vertex.position,
+                direction: sampled_f.wi,
+            };
+        }
+
+        bounces
+    }
+
+    /// Samples the camera subpath through `ray` generated by `get_ray`.
+    #[inline(always)]
+    #[allow(clippy::too_many_arguments)]
+    pub fn generate_camera_subpath(
+        &self,
+        tlas: &AccelerationStructure,
+        path: &mut Subpath,
+        max_vertices: usize,
+        ray: Ray,
+        camera_weight: f32,
+        bsdf: &mut Bsdf,
+        payload: &mut RayPayload,
+        rng: &mut impl Rng,
+    ) -> usize {
+        let beta = camera_weight * Vec3A::ONE;
+        let camera = PathVertex {
+            kind: VertexKind::Camera,
+            position: ray.origin,
+            beta,
+            // Nothing can connect to the lens of other cameras
+            delta: !self.uniform.camera.is_connectible(),
+            ..Default::default()
+        };
+        *unsafe { path.index_unchecked_mut(0) } = camera;
+
+        let (_, _, pdf_dir) = self.uniform.camera.importance(Ray {
+            origin: self.world_to_camera.transform_point3a(ray.origin),
+            direction: self
+                .world_to_camera
+                .transform_vector3a(ray.direction)
+                .normalize(),
+        });
+
+        self.random_walk(
+            tlas,
+            path,
+            1,
+            max_vertices,
+            ray,
+            beta,
+            pdf_dir,
+            true,
+            bsdf,
+            payload,
+            rng,
+        )
+    }
+
+    /// Starts a subpath on a light picked uniformly, nothing for distant lights and the environment.
+    #[inline(always)]
+    #[allow(clippy::too_many_arguments)]
+    pub fn generate_light_subpath(
+        &self,
+        tlas: &AccelerationStructure,
+        path: &mut Subpath,
+        max_vertices: usize,
+        bsdf: &mut Bsdf,
+        payload: &mut RayPayload,
+        rng: &mut impl Rng,
+    ) -> usize {
+        let light_count = self.light_count();
+        if light_count == 0 || max_vertices == 0 {
+            return 0;
+        }
+        let pmf = 1.0 / light_count as f32;
+        let light_index = rng.next_u32() % light_count;
+
+        let (vertex, direction, pdf_dir) = if light_index < self.uniform.unbounded_lights_len {
+            return 0;
+        } else if light_index < self.uniform.lights_len {
+            let light = unsafe { self.lights.index_unchecked(light_index as usize) };
+            let position = light.ray_target(Vec3A::ZERO).0;
+            let w = random_in_unit_sphere(rng).normalize();
+            let pdf_dir = 0.25 * FRAC_1_PI;
+
+            // The intensity is the radiance received at a unit distance
+            let le = light.color(position + w, self.textures, self.images);
+            let vertex = PathVertex {
+                kind: VertexKind::Light,
+                position,
+                index: light_index,
+                beta: le,
+                pdf_fwd: pmf,
+                ..Default::default()
+            };
+
+            (vertex, w, pdf_dir)
+        } else if light_index < self.uniform.lights_len + self.uniform.emit_object_len {
+            let emit_object = unsafe {
+                self.emit_objects
+                    .index_unchecked((light_index - self.uniform.lights_len) as usize)
+            };
+            let (position, normal, pdf_pos) =
+                emit_object.sample_area(self.indices, self.vertices, self.emit_distribution, rng);
+            let local = random_cosine_direction(rng);
+            let w = Onb::from_w(normal).local_to_world(local);
+            let pdf_dir = local.z * FRAC_1_PI;
+
+            let area_light_index = emit_object.area_light_index();
+            let le = unsafe { self.area_lights.index_unchecked(area_light_index as usize) }
+                .emit(w, normal);
+            let vertex = PathVertex {
+                kind: VertexKind::Light,
+                position,
+                normal,
+                index: area_light_index,
+                beta: le,
+                pdf_fwd: pmf * pdf_pos,
+                ..Default::default()
+            };
+
+            (vertex, w, pdf_dir)
+        } else {
+            return 0;
+        };
+
+        if vertex.pdf_fwd == 0.0 || pdf_dir == 0.0 || vertex.beta == Vec3A::ZERO {
+            return 0;
+        }
+        *unsafe { path.index_unchecked_mut(0) } = vertex;
+
+        let cos = if vertex.normal == Vec3A::ZERO {
+            1.0
+        } else {
+            vertex.normal.dot(direction).abs()
+        };
+        let beta = vertex.beta * cos / (vertex.pdf_fwd * pdf_dir);
+
+        self.random_walk(
+            tlas,
+            path,
+            1,
+            max_vertices,
+            Ray {
+                origin: vertex.position,
+                direction,
+            },
+            beta,
+            pdf_dir,
+            false,
+            bsdf,
+            payload,
+            rng,
+        )
+    }
+
+    /// Contribution of the strategy with `s` light and `t` camera vertices, weighted by MIS.
+    /// A strategy with one camera vertex resamples it on the lens and returns the film position.
+    #[inline(always)]
+    #[allow(clippy::too_many_arguments)]
+    pub fn connect(
+        &self,
+        tlas: &AccelerationStructure,
+        light_path: &Subpath,
+        camera_path: &Subpath,
+        s: usize,
+        t: usize,
+        bsdf: &mut Bsdf,
+        payload: &mut RayPayload,
+        rng: &mut impl Rng,
+    ) -> (Vec3A, Vec2) {
+        let pt = *unsafe { camera_path.index_unchecked(t - 1) };
+        if t > 1 && s != 0 && pt.kind == VertexKind::InfiniteLight {
+            return (Vec3A::ZERO, Vec2::ZERO);
+        }
+
+        let mut sampled = PathVertex::default();
+        let mut st = Vec2::ZERO;
+
+        let l = if s == 0 {
+            // The camera subpath found a light by itself
+            let pt_minus = unsafe { camera_path.index_unchecked(t - 2) };
+            if pt.kind == VertexKind::Surface && self.area_light(&pt).is_null() {
+                Vec3A::ZERO
+            } else {
+                pt.beta * self.le(&pt, pt_minus)
+            }
+        } else if t == 1 {
+            let qs = unsafe { light_path.index_unchecked(s - 1) };
+            if !self.uniform.camera.is_connectible()
+                || qs.kind != VertexKind::Surface
+                || !self.is_connectible(qs, bsdf)
+            {
+                return (Vec3A::ZERO, Vec2::ZERO);
+            }
+
+            let lens = self.uniform.camera.sample_lens(rng);
+            let lens_world = self.uniform.camera_to_world.transform_point3a(lens);
+            let distance_squared = qs.position.distance_squared(lens_world);
+            let w = self
+                .world_to_camera
+                .transform_vector3a(qs.position - lens_world)
+                .normalize();
+            let (film, we, _) = self.uniform.camera.importance(Ray {
+                origin: lens,
+                direction: w,
+            });
+            if we == 0.0 {
+                return (Vec3A::ZERO, Vec2::ZERO);
+            }
+            st = film;
+
+            // pbrt's `Sample_Wi`, the lens point is sampled by area
+            let pdf = distance_squared / (w.z * self.uniform.camera.lens_area());
+            sampled = PathVertex {
+                kind: VertexKind::Camera,
+                position: lens_world,
+                beta: Vec3A::splat(we / pdf),
+                ..Default::default()
+            };
+
+            let wi = direction(qs, &sampled);
+            let l = qs.beta * bsdf.f(qs.wo, wi) * sampled.beta * wi.dot(qs.normal).abs();
+            if l == Vec3A::ZERO || !Self::unoccluded(tlas, qs.position, lens_world, payload) {
+                return (Vec3A::ZERO, Vec2::ZERO);
+            }
+            l
+        } else if s == 1 {
+            if !self.is_connectible(&pt, bsdf) {
+                return (Vec3A::ZERO, Vec2::ZERO);
+            }
+            let light_count = self.light_count();
+            if light_count == 0 {
+                return (Vec3A::ZERO, Vec2::ZERO);
+            }
+            let pmf = 1.0 / light_count as f32;
+            let light_index = rng.next_u32() % light_count;
+
+            if light_index < self.uniform.lights_len {
+                let light = unsafe { self.lights.index_unchecked(light_index as usize) };
+                let (target, t_max) = light.ray_target(pt.position);
+                let wi = (target - pt.position).normalize();
+                let li = light.color(pt.position, self.textures, self.images);
+
+                let l = pt.beta * bsdf.f(pt.wo, wi) * wi.dot(pt.normal).abs() * li / pmf;
+                if l == Vec3A::ZERO {
+                    return (Vec3A::ZERO, Vec2::ZERO);
+                }
+
+                *payload = RayPayload::default();
+                unsafe {
+                    tlas.trace_ray(
+                        RayFlags::empty(),
+                        0xff,
+                        0,
+                        0,
+                        0,
+                        pt.position,
+                        0.001,
+                        wi,
+                        t_max,
+                        payload,
+                    );
+                }
+                if payload.is_miss == 0 {
+                    return (Vec3A::ZERO, Vec2::ZERO);
+                }
+
+                // No other strategy reaches a distant light
+                if light_index < self.uniform.unbounded_lights_len {
+                    return (l, Vec2::ZERO);
+                }
+
+                sampled = PathVertex {
+                    kind: VertexKind::Light,
+                    position: target,
+                    index: light_index,
+                    beta: li / pmf,
+                    pdf_fwd: pmf,
+                    ..Default::default()
+                };
+                l
+            } else if light_index < self.uniform.lights_len + self.uniform.emit_object_len {
+                let emit_object = unsafe {
+                    self.emit_objects
+                        .index_unchecked((light_index - self.uniform.lights_len) as usize)
+                };
+                let (position, normal, pdf_pos) = emit_object.sample_area(
+                    self.indices,
+                    self.vertices,
+                    self.emit_distribution,
+                    rng,
+                );
+                let distance_squared = position.distance_squared(pt.position);
+                let wi = (position - pt.position).normalize();
+                let cos = normal.dot(wi).abs();
+                if pdf_pos == 0.0 || cos == 0.0 {
+                    return (Vec3A::ZERO, Vec2::ZERO);
+                }
+                let pdf = pdf_pos * distance_squared / cos;
+
+                let area_light_index = emit_object.area_light_index();
+                let le = unsafe { self.area_lights.index_unchecked(area_light_index as usize) }
+                    .emit(-wi, normal);
+                sampled = PathVertex {
+                    kind: VertexKind::Light,
+                    position,
+                    normal,
+                    index: area_light_index,
+                    beta: le / (pdf * pmf),
+                    pdf_fwd: pmf * pdf_pos,
+                    ..Default::default()
+                };
+
+                let l = pt.beta * bsdf.f(pt.wo, wi) * wi.dot(pt.normal).abs() * sampled.beta;
+                if l == Vec3A::ZERO || !Self::unoccluded(tlas, pt.position, position, payload) {
+                    return (Vec3A::ZERO, Vec2::ZERO);
+                }
+                l
+            } else {
+                let (wi, pdf) = self.infinite_light.sample(rng);
+                if pdf == 0.0 {
+                    return (Vec3A::ZERO, Vec2::ZERO);
+                }
+                let f = bsdf.f(pt.wo, wi) * wi.dot(pt.normal).abs();
+                if f == Vec3A::ZERO {
+                    return (Vec3A::ZERO, Vec2::ZERO);
+                }
+
+                *payload = RayPayload::default();
+                unsafe {
+                    tlas.trace_ray(
+                        RayFlags::empty(),
+                        0xff,
+                        0,
+                        0,
+                        0,
+                        pt.position,
+                        0.001,
+                        wi,
+                        100000.0,
+                        payload,
+                    );
+                }
+                if payload.is_miss == 0 {
+                    return (Vec3A::ZERO, Vec2::ZERO);
+                }
+
+                sampled = PathVertex {
+                    kind: VertexKind::InfiniteLight,
+                    position: wi,
+                    beta: payload.position / (pdf * pmf),
+                    le: payload.position,
+                    pdf_fwd: pdf * pmf,
+                    ..Default::default()
+                };
+                pt.beta * f * sampled.beta
+            }
+        } else {
+            let qs = unsafe { light_path.index_unchecked(s - 1) };
+            if !self.is_connectible(qs, bsdf) {
+                return (Vec3A::ZERO, Vec2::ZERO);
+            }
+            let fs = qs.beta * bsdf.f(qs.wo, direction(qs, &pt));
+            if fs == Vec3A::ZERO || !self.is_connectible(&pt, bsdf) {
+                return (Vec3A::ZERO, Vec2::ZERO);
+            }
+            let l = fs * bsdf.f(pt.wo, direction(&pt, qs)) * pt.beta;
+            if l == Vec3A::ZERO {
+                return (Vec3A::ZERO, Vec2::ZERO);
+            }
+
+            // Geometry term
+            let w = qs.position - pt.position;
+            let distance_squared = w.length_squared();
+            let w = w / distance_squared.sqrt();
+            let g = qs.normal.dot(w).abs() * pt.normal.dot(w).abs() / distance_squared;
+
+            if g == 0.0 || !Self::unoccluded(tlas, pt.position, qs.position, payload) {
+                return (Vec3A::ZERO, Vec2::ZERO);
+            }
+            l * g
+        };
+
+        if l == Vec3A::ZERO {
+            return (Vec3A::ZERO, Vec2::ZERO);
+        }
+
+        (
+            l * self.mis_weight(light_path, camera_path, &sampled, s, t, bsdf),
+            st,
+        )
+    }
+
+    /// Balance heuristic weight of the strategy against all the others making the same path,
+    /// pbrt's `MISWeight` with the connection vertices overridden in local copies.
+    fn mis_weight(
+        &self,
+        light_path: &Subpath,
+        camera_path: &Subpath,
+        sampled: &PathVertex,
+        s: usize,
+        t: usize,
+        bsdf: &mut Bsdf,
+    ) -> f32 {
+        if s + t == 2 {
+            return 1.0;
+        }
+
+        let mut pt = if t == 1 {
+            *sampled
+        } else {
+            *unsafe { camera_path.index_unchecked(t - 1) }
+        };
+        let mut qs = if s == 1 {
+            *sampled
+        } else if s > 1 {
+            *unsafe { light_path.index_unchecked(s - 1) }
+        } else {
+            PathVertex::default()
+        };
+        let mut pt_minus = if t > 1 {
+            *unsafe { camera_path.index_unchecked(t - 2) }
+        } else {
+            PathVertex::default()
+        };
+        let mut qs_minus = if s > 1 {
+            *unsafe { light_path.index_unchecked(s - 2) }
+        } else {
+            PathVertex::default()
+        };
+
+        // The connection vertices are sampled by the connection itself
+        pt.delta = false;
+        qs.delta = false;
+
+        pt.pdf_rev = if s > 0 {
+            self.pdf(&qs, &qs_minus, &pt, bsdf)
+        } else {
+            self.pdf_light_origin(&pt)
+        };
+        if t > 1 {
+            pt_minus.pdf_rev = if s > 0 {
+                self.pdf(&pt, &qs, &pt_minus, bsdf)
+            } else {
+                self.pdf_light(&pt, &pt_minus)
+            };
+        }
+        if s > 0 {
+            qs.pdf_rev = self.pdf(&pt, &pt_minus, &qs, bsdf);
+        }
+        if s > 1 {
+            qs_minus.pdf_rev = self.pdf(&qs, &pt, &qs_minus, bsdf);
+        }
+
+        let camera_vertex = |i: usize| {
+            if i + 1 == t {
+                pt
+            } else if i + 2 == t {
+                pt_minus
+            } else {
+                *unsafe { camera_path.index_unchecked(i) }
+            }
+        };
+        let light_vertex = |i: usize| {
+            if i + 1 == s {
+                qs
+            } else if i + 2 == s {
+                qs_minus
+            } else {
+                *unsafe { light_path.index_unchecked(i) }
+            }
+        };
+
+        let mut sum_ri = 0.0;
+
+        // Light subpaths never start at the environment, it is only sampled by connecting to it
+        let env_sampled = s == 1 && qs.kind == VertexKind::InfiniteLight;
+        let env_found = s == 0 && pt.kind == VertexKind::InfiniteLight;
+        if !env_sampled {
+            let mut ri = 1.0;
+            let mut i = t - 1;
+            while i > 0 {
+                let v = camera_vertex(i);
+                ri *= remap0(v.pdf_rev) / remap0(v.pdf_fwd);
+                if !v.delta && !camera_vertex(i - 1).delta {
+                    sum_ri += ri;
+                }
+                if env_found {
+                    if !self.infinite_light.is_samplable() {
+                        sum_ri = 0.0;
+                    }
+                    break;
+                }
+                i -= 1;
+            }
+        }
+
+        let mut ri = 1.0;
+        let mut i = s;
+        while i > 0 {
+            i -= 1;
+            let v = light_vertex(i);
+            ri *= remap0(v.pdf_rev) / remap0(v.pdf_fwd);
+            let delta_light = if i > 0 {
+                light_vertex(i - 1).delta
+            } else {
+                v.is_delta_light()
+            };
+            if !v.delta && !delta_light {
+                sum_ri += ri;
+            }
+        }
+
+        1.0 / (1.0 + sum_ri)
+    }
+}
+
+/// Fixed point scale of the splatted radiance, accumulated with integer atomics.
+pub const SPLAT_SCALE: f32 = 4096.0;
@@ -1,90 +1,761 @@
-use spirv_std::glam::{vec3a, Mat4, Vec2, Vec3A};
+use core::f32::consts::PI;
+use spirv_std::arch::IndexUnchecked;
+use spirv_std::glam::{vec2, vec3a, Mat4, Vec2, Vec3A, Vec3Swizzles, Vec4};
 #[allow(unused_imports)]
 use spirv_std::num_traits::Float;
 
-use crate::math::random_in_unit_disk;
-use crate::rand::DefaultRng;
+use crate::math::{concentric_sample_disk, face_forward, refract};
+use crate::rand::Rng;
 use crate::Ray;
 
-#[derive(Copy, Clone)]
-pub struct Camera {
-    origin: Vec3A,
-    lower_left_corner: Vec3A,
-    horizontal: Vec3A,
-    vertical: Vec3A,
-    u: Vec3A,
-    v: Vec3A,
-    // w: Vec3,
-    lens_radius: f32,
-}
-
-impl Camera {
-    #[allow(clippy::too_many_arguments)]
-    pub fn new(
-        look_from: Vec3A,
-        look_at: Vec3A,
-        vup: Vec3A,
-        vfov: f32,
-        aspect_ratio: f32,
-        aperture: f32,
-        focus_dist: f32,
-    ) -> Self {
-        let theta = vfov;
-        let h = (theta / 2.0).tan();
-        let viewport_height = 2.0 * h;
-        let viewport_width = aspect_ratio * viewport_height;
-
-        let w = (look_from - look_at).normalize();
-        let u = vup.cross(w).normalize();
-        let v = w.cross(u);
-
-        let origin = look_from;
-        let horizontal = focus_dist * viewport_width * u;
-        let vertical = focus_dist * viewport_height * v;
-        let lower_left_corner = origin - horizontal / 2.0 - vertical / 2.0 - focus_dist * w;
+#[derive(Clone, Copy, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[repr(C)]
+pub struct EnumCameraData {
+    /// NDC to camera space
+    projection: Mat4,
+    /// x: lens radius, y: focal distance
+    v0: Vec4,
+    lens_system: LensSystem,
+}
+
+#[derive(Clone, Copy)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[repr(u32)]
+enum CameraType {
+    Perspective,
+    Orthographic,
+    Environment,
+    Realistic,
+}
+
+#[derive(Clone, Copy)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[repr(C)]
+pub struct EnumCamera {
+    t: CameraType,
+    data: EnumCameraData,
+}
+
+impl Default for EnumCamera {
+    fn default() -> Self {
+        Self::new_perspective(Mat4::IDENTITY, 0.0, 1e6)
+    }
+}
 
+impl EnumCamera {
+    pub fn new_perspective(projection: Mat4, lens_radius: f32, focal_distance: f32) -> Self {
         Self {
-            origin,
-            lower_left_corner,
-            horizontal,
-            vertical,
-            u,
-            v,
-            // w,
-            lens_radius: aperture / 2.0,
+            t: CameraType::Perspective,
+            data: EnumCameraData::new(projection, lens_radius, focal_distance),
+        }
+    }
+
+    pub fn new_orthographic(projection: Mat4, lens_radius: f32, focal_distance: f32) -> Self {
+        Self {
+            t: CameraType::Orthographic,
+            data: EnumCameraData::new(projection, lens_radius, focal_distance),
+        }
+    }
+
+    pub fn new_environment() -> Self {
+        Self {
+            t: CameraType::Environment,
+            data: EnumCameraData::new(Mat4::IDENTITY, 0.0, 1e6),
+        }
+    }
+
+    /// `film_to_camera` maps NDC to the physical film position in meters.
+    pub fn new_realistic(film_to_camera: Mat4, lens_system: LensSystem) -> Self {
+        let mut data = EnumCameraData::new(film_to_camera, 0.0, 1e6);
+        data.lens_system = lens_system;
+
+        Self {
+            t: CameraType::Realistic,
+            data,
+        }
+    }
+
+    /// Returns the world space ray and its weight,
+    /// zero when the ray is blocked by the lens system.
+    pub fn get_ray(&self, st: Vec2, camera_to_world: Mat4, rng: &mut impl Rng) -> (Ray, f32) {
+        let (ray, weight) = match self.t {
+            CameraType::Perspective => (PerspectiveCamera { data: &self.data }.get_ray(st), 1.0),
+            CameraType::Orthographic => (OrthographicCamera { data: &self.data }.get_ray(st), 1.0),
+            CameraType::Environment => (EnvironmentCamera.get_ray(st), 1.0),
+            CameraType::Realistic => RealisticCamera { data: &self.data }.get_ray(st, rng),
+        };
+        let ray = self.data.thin_lens(ray, rng);
+
+        (
+            Ray {
+                origin: camera_to_world.transform_point3a(ray.origin),
+                direction: camera_to_world
+                    .transform_vector3a(ray.direction)
+                    .normalize(),
+            },
+            weight,
+        )
+    }
+}
+
+impl EnumCamera {
+    /// Only a perspective camera can be reached by connecting to it from the scene.
+    pub fn is_connectible(&self) -> bool {
+        matches!(self.t, CameraType::Perspective)
+    }
+
+    /// Area of the lens, one for a pinhole.
+    pub fn lens_area(&self) -> f32 {
+        self.data.lens_area()
+    }
+
+    /// Uniform camera space point on the lens.
+    pub fn sample_lens(&self, rng: &mut impl Rng) -> Vec3A {
+        if self.data.lens_radius() <= 0.0 {
+            return Vec3A::ZERO;
+        }
+
+        let lens = self.data.lens_radius() * concentric_sample_disk(rng);
+        vec3a(lens.x, lens.y, 0.0)
+    }
+
+    /// pbrt's `We` of a camera space ray leaving the lens. Returns the film position it reaches,
+    /// the importance and the solid angle pdf of generating the ray's direction.
+    pub fn importance(&self, ray: Ray) -> (Vec2, f32, f32) {
+        match self.t {
+            CameraType::Perspective => PerspectiveCamera { data: &self.data }.importance(ray),
+            _ => (Vec2::ZERO, 0.0, 0.0),
+        }
+    }
+}
+
+impl EnumCameraData {
+    fn new(projection: Mat4, lens_radius: f32, focal_distance: f32) -> Self {
+        Self {
+            projection,
+            v0: Vec4::new(lens_radius, focal_distance, 0.0, 0.0),
+            lens_system: LensSystem::default(),
+        }
+    }
+
+    fn lens_radius(&self) -> f32 {
+        self.v0.x
+    }
+
+    fn focal_distance(&self) -> f32 {
+        self.v0.y
+    }
+
+    fn lens_area(&self) -> f32 {
+        if self.lens_radius() > 0.0 {
+            PI * self.lens_radius() * self.lens_radius()
+        } else {
+            1.0
         }
     }
 
-    pub fn get_ray(&self, s: f32, t: f32, rng: &mut DefaultRng) -> Ray {
-        let rd = self.lens_radius * random_in_unit_disk(rng);
-        let offset = self.u * rd.x + self.v * rd.y;
+    /// Move the camera space ray origin on the lens,
+    /// keeping the point on the plane of focus in place.
+    fn thin_lens(&self, ray: Ray, rng: &mut impl Rng) -> Ray {
+        if self.lens_radius() <= 0.0 {
+            return ray;
+        }
+
+        let lens = self.lens_radius() * concentric_sample_disk(rng);
+        let ft = self.focal_distance() / ray.direction.z;
+        let focus = ray.origin + ft * ray.direction;
+        let origin = ray.origin + vec3a(lens.x, lens.y, 0.0);
 
         Ray {
-            origin: self.origin + offset,
-            direction: (self.lower_left_corner + s * self.horizontal + t * self.vertical
-                - self.origin
-                - offset),
+            origin,
+            direction: (focus - origin).normalize(),
         }
     }
 }
 
-#[derive(Clone, Copy, Default)]
-#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
-pub struct PerspectiveCamera {
-    pub projection: Mat4,
+/// Rays are in the camera space.
+struct PerspectiveCamera<'a> {
+    data: &'a EnumCameraData,
 }
 
-impl PerspectiveCamera {
-    pub fn get_ray(&self, st: Vec2, camera_to_world: Mat4) -> Ray {
-        let origin = camera_to_world.transform_point3a(vec3a(0.0, 0.0, 0.0));
+impl<'a> PerspectiveCamera<'a> {
+    fn get_ray(&self, st: Vec2) -> Ray {
         let target =
-            self.projection
+            self.data
+                .projection
                 .transform_point3a(vec3a(st.x * 2.0 - 1.0, st.y * 2.0 - 1.0, 1.0));
-        let target = camera_to_world.transform_point3a(target);
+
+        Ray {
+            origin: Vec3A::ZERO,
+            direction: target.normalize(),
+        }
+    }
+
+    fn importance(&self, ray: Ray) -> (Vec2, f32, f32) {
+        let cos_theta = ray.direction.z;
+        if cos_theta <= 0.0 {
+            return (Vec2::ZERO, 0.0, 0.0);
+        }
+
+        // Project the point the ray focuses on onto the screen window at z = 1
+        let focal_distance = if self.data.lens_radius() > 0.0 {
+            self.data.focal_distance()
+        } else {
+            1.0
+        };
+        let focus = ray.origin + ray.direction * (focal_distance / cos_theta);
+        let screen = focus / focus.z;
+
+        let min = self
+            .data
+            .projection
+            .transform_point3a(vec3a(-1.0, -1.0, 1.0));
+        let max = self.data.projection.transform_point3a(vec3a(1.0, 1.0, 1.0));
+        let st = vec2(
+            (screen.x - min.x) / (max.x - min.x),
+            (screen.y - min.y) / (max.y - min.y),
+        );
+        if st.x < 0.0 || st.x > 1.0 || st.y < 0.0 || st.y > 1.0 {
+            return (Vec2::ZERO, 0.0, 0.0);
+        }
+
+        let lens_area = self.data.lens_area();
+        let area = ((max.x - min.x) * (max.y - min.y)).abs();
+        let cos2_theta = cos_theta * cos_theta;
+
+        (
+            st,
+            1.0 / (area * lens_area * cos2_theta * cos2_theta),
+            1.0 / (area * cos2_theta * cos_theta),
+        )
+    }
+}
+
+struct OrthographicCamera<'a> {
+    data: &'a EnumCameraData,
+}
+
+impl<'a> OrthographicCamera<'a> {
+    fn get_ray(&self, st: Vec2) -> Ray {
+        // Every ray starts on the screen window and looks down +z
+        let origin =
+            self.data
+                .projection
+                .transform_point3a(vec3a(st.x * 2.0 - 1.0, st.y * 2.0 - 1.0, 0.0));
 
         Ray {
             origin,
-            direction: (target - origin).normalize(),
+            direction: vec3a(0.0, 0.0, 1.0),
+        }
+    }
+}
+
+/// Lat-long panorama, the inverse of `sphere_uv` used by the infinite light,
+/// so a render of an empty scene reproduces the environment map.
+struct EnvironmentCamera;
+
+impl EnvironmentCamera {
+    fn get_ray(&self, st: Vec2) -> Ray {
+        let phi = 2.0 * PI * st.x;
+        // st.y runs from the bottom row of the film, the map's top row is theta = 0
+        let theta = PI * (1.0 - st.y);
+
+        Ray {
+            origin: Vec3A::ZERO,
+            direction: vec3a(
+                theta.sin() * phi.cos(),
+                theta.sin() * phi.sin(),
+                theta.cos(),
+            ),
+        }
+    }
+}
+
+/// Traces the film sample through the lens system toward a point on the exit pupil.
+struct RealisticCamera<'a> {
+    data: &'a EnumCameraData,
+}
+
+impl<'a> RealisticCamera<'a> {
+    fn get_ray(&self, st: Vec2, rng: &mut impl Rng) -> (Ray, f32) {
+        let lens_system = &self.data.lens_system;
+        let film =
+            self.data
+                .projection
+                .transform_point3a(vec3a(st.x * 2.0 - 1.0, st.y * 2.0 - 1.0, 0.0));
+        let (rear, bounds_area) = lens_system.sample_exit_pupil(film.xy(), rng);
+        let film_ray = Ray {
+            origin: film,
+            direction: rear - film,
+        };
+
+        let mut ray = Ray::default();
+        if !lens_system.trace_lenses_from_film(film_ray, &mut ray) {
+            return (
+                Ray {
+                    origin: Vec3A::ZERO,
+                    direction: vec3a(0.0, 0.0, 1.0),
+                },
+                0.0,
+            );
+        }
+
+        let cos_theta = film_ray.direction.normalize().z;
+        let cos2_theta = cos_theta * cos_theta;
+        let weight = cos2_theta * cos2_theta * bounds_area / lens_system.exit_pupil_area(0);
+
+        (ray, weight)
+    }
+}
+
+pub const MAX_LENS_ELEMENTS: usize = 16;
+pub const N_EXIT_PUPIL_BOUNDS: usize = 32;
+
+/// Spherical lens interfaces of pbrt's realistic camera in camera space,
+/// the film is on z = 0 and the elements are in front of it along +z.
+#[derive(Clone, Copy, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[repr(C)]
+pub struct LensSystem {
+    /// From the front element to the rear one,
+    /// x: curvature radius, y: thickness, z: index of refraction, w: aperture radius.
+    /// The aperture stop has zero curvature radius.
+    elements: [Vec4; MAX_LENS_ELEMENTS],
+    /// Bounds of the exit pupil on the rear element plane
+    /// for film radius intervals up to half the diagonal, (min x, min y, max x, max y)
+    exit_pupil_bounds: [Vec4; N_EXIT_PUPIL_BOUNDS],
+    /// x: number of elements, y: film diagonal
+    v0: Vec4,
+}
+
+impl LensSystem {
+    fn len(&self) -> usize {
+        self.v0.x as usize
+    }
+
+    fn film_diagonal(&self) -> f32 {
+        self.v0.y
+    }
+
+    fn element(&self, i: usize) -> Vec4 {
+        *unsafe { self.elements.index_unchecked(i) }
+    }
+
+    fn rear_z(&self) -> f32 {
+        self.element(self.len() - 1).y
+    }
+
+    fn exit_pupil_area(&self, i: usize) -> f32 {
+        let bounds = *unsafe { self.exit_pupil_bounds.index_unchecked(i) };
+        (bounds.z - bounds.x) * (bounds.w - bounds.y)
+    }
+
+    /// Returns a point on the rear element plane and the area of the sampled bounds.
+    fn sample_exit_pupil(&self, film: Vec2, rng: &mut impl Rng) -> (Vec3A, f32) {
+        let r_film = film.length();
+        let r_index = ((r_film / (self.film_diagonal() / 2.0) * N_EXIT_PUPIL_BOUNDS as f32)
+            as usize)
+            .min(N_EXIT_PUPIL_BOUNDS - 1);
+        let bounds = *unsafe { self.exit_pupil_bounds.index_unchecked(r_index) };
+        let lens = vec2(
+            bounds.x + rng.next_f32() * (bounds.z - bounds.x),
+            bounds.y + rng.next_f32() * (bounds.w - bounds.y),
+        );
+
+        // The bounds are computed along +x, rotate them to the film position
+        let (sin_theta, cos_theta) = if r_film != 0.0 {
+            (film.y / r_film, film.x / r_film)
+        } else {
+            (0.0, 1.0)
+        };
+
+        (
+            vec3a(
+                cos_theta * lens.x - sin_theta * lens.y,
+                sin_theta * lens.x + cos_theta * lens.y,
+                self.rear_z(),
+            ),
+            self.exit_pupil_area(r_index),
+        )
+    }
+
+    /// Returns false when the ray is blocked by an element or the aperture stop.
+    fn trace_lenses_from_film(&self, ray: Ray, out: &mut Ray) -> bool {
+        // The lens space looks down -z like pbrt
+        let mut ray = flip_z(ray);
+        let mut element_z = 0.0;
+
+        let mut i = self.len();
+        while i > 0 {
+            i -= 1;
+            let element = self.element(i);
+            element_z -= element.y;
+
+            let is_stop = element.x == 0.0;
+            let mut t = 0.0;
+            let mut n = Vec3A::ZERO;
+            if is_stop {
+                if ray.direction.z >= 0.0 {
+                    return false;
+                }
+                t = (element_z - ray.origin.z) / ray.direction.z;
+            } else if !intersect_spherical_element(
+                element.x,
+                element_z + element.x,
+                ray,
+                &mut t,
+                &mut n,
+            ) {
+                return false;
+            }
+
+            let hit = ray.origin + t * ray.direction;
+            if hit.x * hit.x + hit.y * hit.y > element.w * element.w {
+                return false;
+            }
+            ray.origin = hit;
+
+            if !is_stop {
+                let eta_i = element.z;
+                let eta_t = if i > 0 && self.element(i - 1).z != 0.0 {
+                    self.element(i - 1).z
+                } else {
+                    1.0
+                };
+                let (refracted, wt) = refract(-ray.direction.normalize(), n, eta_i / eta_t);
+                if !refracted {
+                    return false;
+                }
+                ray.direction = wt;
+            }
+        }
+
+        *out = flip_z(ray);
+        true
+    }
+}
+
+fn flip_z(ray: Ray) -> Ray {
+    Ray {
+        origin: ray.origin * vec3a(1.0, 1.0, -1.0),
+        direction: ray.direction * vec3a(1.0, 1.0, -1.0),
+    }
+}
+
+fn intersect_spherical_element(
+    radius: f32,
+    z_center: f32,
+    ray: Ray,
+    t: &mut f32,
+    n: &mut Vec3A,
+) -> bool {
+    let o = ray.origin - vec3a(0.0, 0.0, z_center);
+    let a = ray.direction.length_squared();
+    let b = 2.0 * ray.direction.dot(o);
+    let c = o.length_squared() - radius * radius;
+
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return false;
+    }
+    let root = discriminant.sqrt();
+    let q = if b < 0.0 {
+        -0.5 * (b - root)
+    } else {
+        -0.5 * (b + root)
+    };
+    let t0 = (q / a).min(c / q);
+    let t1 = (q / a).max(c / q);
+
+    // Pick the intersection on the side of the sphere facing the ray
+    let use_closer_t = (ray.direction.z > 0.0) ^ (radius < 0.0);
+    *t = if use_closer_t { t0 } else { t1 };
+    if *t < 0.0 {
+        return false;
+    }
+
+    *n = face_forward((o + *t * ray.direction).normalize(), -ray.direction);
+    true
+}
+
+#[cfg(not(target_arch = "spirv"))]
+impl LensSystem {
+    /// `elements` are from the front to the rear in meters,
+    /// the exit pupil bounds are filled by [`Self::compute_exit_pupil_bounds`].
+    pub fn new(elements: &[Vec4], film_diagonal: f32) -> Self {
+        assert!(elements.len() <= MAX_LENS_ELEMENTS);
+
+        let mut lens_system = Self {
+            v0: Vec4::new(elements.len() as f32, film_diagonal, 0.0, 0.0),
+            ..Default::default()
+        };
+        lens_system.elements[..elements.len()].copy_from_slice(elements);
+        lens_system
+    }
+
+    fn front_z(&self) -> f32 {
+        self.elements[..self.len()].iter().map(|e| e.y).sum()
+    }
+
+    fn trace_lenses_from_scene(&self, ray: Ray, out: &mut Ray) -> bool {
+        let mut ray = flip_z(ray);
+        let mut element_z = -self.front_z();
+
+        for i in 0..self.len() {
+            let element = self.elements[i];
+            let is_stop = element.x == 0.0;
+            let mut t = 0.0;
+            let mut n = Vec3A::ZERO;
+            if is_stop {
+                t = (element_z - ray.origin.z) / ray.direction.z;
+            } else if !intersect_spherical_element(
+                element.x,
+                element_z + element.x,
+                ray,
+                &mut t,
+                &mut n,
+            ) {
+                return false;
+            }
+
+            let hit = ray.origin + t * ray.direction;
+            if hit.x * hit.x + hit.y * hit.y > element.w * element.w {
+                return false;
+            }
+            ray.origin = hit;
+
+            if !is_stop {
+                let eta_i = if i == 0 || self.elements[i - 1].z == 0.0 {
+                    1.0
+                } else {
+                    self.elements[i - 1].z
+                };
+                let eta_t = if element.z != 0.0 { element.z } else { 1.0 };
+                let (refracted, wt) = refract(-ray.direction.normalize(), n, eta_i / eta_t);
+                if !refracted {
+                    return false;
+                }
+                ray.direction = wt;
+            }
+            element_z += element.y;
+        }
+
+        *out = flip_z(ray);
+        true
+    }
+
+    /// Principal and focal planes of the lens system seen from the scene side and the film side.
+    fn thick_lens_approximation(&self) -> Option<([f32; 2], [f32; 2])> {
+        fn cardinal_points(r_in: Ray, r_out: Ray) -> (f32, f32) {
+            let tf = -r_out.origin.x / r_out.direction.x;
+            let fz = -(r_out.origin + tf * r_out.direction).z;
+            let tp = (r_in.origin.x - r_out.origin.x) / r_out.direction.x;
+            let pz = -(r_out.origin + tp * r_out.direction).z;
+            (pz, fz)
+        }
+
+        // A ray parallel to the axis, close enough for the paraxial approximation
+        let x = 0.001 * self.film_diagonal();
+
+        let r_scene = Ray {
+            origin: vec3a(x, 0.0, self.front_z() + 1.0),
+            direction: vec3a(0.0, 0.0, -1.0),
+        };
+        let mut r_film = Ray::default();
+        if !self.trace_lenses_from_scene(r_scene, &mut r_film) {
+            return None;
+        }
+        let (pz0, fz0) = cardinal_points(r_scene, r_film);
+
+        let r_film = Ray {
+            origin: vec3a(x, 0.0, self.rear_z() - 1.0),
+            direction: vec3a(0.0, 0.0, 1.0),
+        };
+        let mut r_scene = Ray::default();
+        if !self.trace_lenses_from_film(r_film, &mut r_scene) {
+            return None;
+        }
+        let (pz1, fz1) = cardinal_points(r_film, r_scene);
+
+        Some(([pz0, pz1], [fz0, fz1]))
+    }
+
+    /// Moves the film so that the plane at `focus_distance` is in focus.
+    /// Returns false when the lens system can't focus there.
+    pub fn focus(&mut self, focus_distance: f32) -> bool {
+        let (pz, fz) = match self.thick_lens_approximation() {
+            Some(cardinal_points) => cardinal_points,
+            None => return false,
+        };
+
+        let f = fz[0] - pz[0];
+        let z = -focus_distance;
+        let c = (pz[1] - z - pz[0]) * (pz[1] - z - 4.0 * f - pz[0]);
+        if c <= 0.0 {
+            return false;
+        }
+        let delta = 0.5 * (pz[1] - z + pz[0] - c.sqrt());
+
+        let rear = self.len() - 1;
+        self.elements[rear].y += delta;
+        true
+    }
+
+    pub fn compute_exit_pupil_bounds(&mut self) {
+        for i in 0..N_EXIT_PUPIL_BOUNDS {
+            let r0 = i as f32 / N_EXIT_PUPIL_BOUNDS as f32 * self.film_diagonal() / 2.0;
+            let r1 = (i + 1) as f32 / N_EXIT_PUPIL_BOUNDS as f32 * self.film_diagonal() / 2.0;
+            self.exit_pupil_bounds[i] = self.bound_exit_pupil(r0, r1);
+        }
+    }
+
+    /// Bounds of the rear element points reached by rays leaving the film
+    /// between `r0` and `r1` on the x axis that make it through the lens system.
+    fn bound_exit_pupil(&self, r0: f32, r1: f32) -> Vec4 {
+        const N_SAMPLES: u32 = 256 * 256;
+
+        fn radical_inverse_2(i: u32) -> f32 {
+            (i.reverse_bits() as f64 / (1u64 << 32) as f64) as f32
         }
+
+        fn radical_inverse_3(mut i: u32) -> f32 {
+            let mut inv_base_n = 1.0;
+            let mut reversed = 0.0;
+            while i > 0 {
+                inv_base_n /= 3.0;
+                reversed += (i % 3) as f64 * inv_base_n;
+                i /= 3;
+            }
+            reversed as f32
+        }
+
+        let rear_radius = self.elements[self.len() - 1].w;
+        let proj_rear_min = Vec2::splat(-1.5 * rear_radius);
+        let proj_rear_max = Vec2::splat(1.5 * rear_radius);
+
+        let mut min = Vec2::splat(f32::INFINITY);
+        let mut max = Vec2::splat(f32::NEG_INFINITY);
+        for i in 0..N_SAMPLES {
+            let film = vec3a(
+                r0 + (i as f32 + 0.5) / N_SAMPLES as f32 * (r1 - r0),
+                0.0,
+                0.0,
+            );
+            let u = vec2(radical_inverse_2(i), radical_inverse_3(i));
+            let rear = proj_rear_min + u * (proj_rear_max - proj_rear_min);
+
+            let inside = rear.cmpge(min).all() && rear.cmple(max).all();
+            if inside
+                || self.trace_lenses_from_film(
+                    Ray {
+                        origin: film,
+                        direction: vec3a(rear.x, rear.y, self.rear_z()) - film,
+                    },
+                    &mut Ray::default(),
+                )
+            {
+                min = min.min(rear);
+                max = max.max(rear);
+            }
+        }
+
+        if min.x > max.x {
+            return Vec4::new(
+                proj_rear_min.x,
+                proj_rear_min.y,
+                proj_rear_max.x,
+                proj_rear_max.y,
+            );
+        }
+
+        let delta = 2.0 * (proj_rear_max - proj_rear_min).length() / (N_SAMPLES as f32).sqrt();
+        Vec4::new(min.x - delta, min.y - delta, max.x + delta, max.y + delta)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// pbrt's dgauss.50mm.dat, a 50 mm double Gauss lens, in meters
+    fn double_gauss() -> LensSystem {
+        let elements = [
+            [29.475, 3.76, 1.67, 25.2],
+            [84.83, 0.12, 1.0, 25.2],
+            [19.275, 4.025, 1.67, 23.0],
+            [40.77, 3.275, 1.699, 23.0],
+            [12.75, 5.705, 1.0, 18.0],
+            [0.0, 4.5, 0.0, 17.1],
+            [-14.495, 1.18, 1.603, 17.0],
+            [40.77, 6.065, 1.658, 20.0],
+            [-20.385, 0.19, 1.0, 20.0],
+            [437.065, 3.22, 1.717, 20.0],
+            [-39.73, 5.0, 1.0, 20.0],
+        ]
+        .map(|[radius, thickness, eta, diameter]| {
+            Vec4::new(
+                radius * 0.001,
+                thickness * 0.001,
+                eta,
+                diameter * 0.001 / 2.0,
+            )
+        });
+
+        LensSystem::new(&elements, 0.035)
+    }
+
+    /// Distance from the axis where a ray from the axis at `distance` reaches the film.
+    fn film_offset(lens_system: &LensSystem, distance: f32) -> f32 {
+        let origin = vec3a(0.0, 0.0, distance);
+        let front = vec3a(0.001, 0.0, lens_system.front_z());
+        let mut ray = Ray::default();
+        assert!(lens_system.trace_lenses_from_scene(
+            Ray {
+                origin,
+                direction: front - origin,
+            },
+            &mut ray,
+        ));
+
+        let t = -ray.origin.z / ray.direction.z;
+        (ray.origin + t * ray.direction).x
+    }
+
+    #[test]
+    fn test_focal_length() {
+        let (pz, fz) = double_gauss().thick_lens_approximation().unwrap();
+        let f = fz[0] - pz[0];
+        assert!((f - 0.05).abs() < 0.001, "focal length {}", f);
+    }
+
+    #[test]
+    fn test_focus() {
+        for distance in [0.5, 1.0, 10.0] {
+            let mut lens_system = double_gauss();
+            let unfocused = film_offset(&lens_system, distance).abs();
+            assert!(lens_system.focus(distance));
+            let focused = film_offset(&lens_system, distance).abs();
+            assert!(
+                focused < 1e-5 && focused < 0.1 * unfocused,
+                "{} {}",
+                focused,
+                unfocused
+            );
+        }
+    }
+
+    #[test]
+    fn test_focus_closer_moves_film_back() {
+        let mut far = double_gauss();
+        let mut near = double_gauss();
+        assert!(far.focus(10.0));
+        assert!(near.focus(0.5));
+        assert!(near.rear_z() > far.rear_z());
+    }
+
+    #[test]
+    fn test_focus_within_focal_length() {
+        assert!(!double_gauss().focus(0.01));
     }
 }
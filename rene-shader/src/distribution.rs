@@ -0,0 +1,254 @@
+#[allow(unused_imports)]
+use spirv_std::num_traits::Float;
+use spirv_std::{
+    arch::IndexUnchecked,
+    glam::{vec2, Vec2},
+};
+
+/// Piecewise-constant distribution over [0, 1),
+/// `n + 1` entries of the normalized CDF from `offset` in `data`.
+pub struct Distribution1D<'a> {
+    data: &'a [f32],
+    offset: usize,
+    n: usize,
+}
+
+impl<'a> Distribution1D<'a> {
+    pub fn new(data: &'a [f32], offset: u32, n: u32) -> Self {
+        Self {
+            data,
+            offset: offset as usize,
+            n: n as usize,
+        }
+    }
+
+    fn cdf(&self, i: usize) -> f32 {
+        *unsafe { self.data.index_unchecked(self.offset + i) }
+    }
+
+    /// Index of the segment containing u, the last CDF entry not greater than u.
+    fn find_interval(&self, u: f32) -> usize {
+        let mut first = 0;
+        let mut len = self.n + 1;
+        while len > 0 {
+            let half = len >> 1;
+            let middle = first + half;
+            if self.cdf(middle) <= u {
+                first = middle + 1;
+                len -= half + 1;
+            } else {
+                len = half;
+            }
+        }
+
+        if first == 0 {
+            0
+        } else {
+            (first - 1).min(self.n - 1)
+        }
+    }
+
+    /// Returns the sampled value, its pdf and the index of the sampled segment.
+    fn sample_continuous(&self, u: f32) -> (f32, f32, usize) {
+        let offset = self.find_interval(u);
+
+        let width = self.cdf(offset + 1) - self.cdf(offset);
+        let mut du = u - self.cdf(offset);
+        if width > 0.0 {
+            du /= width;
+        }
+
+        (
+            (offset as f32 + du) / self.n as f32,
+            width * self.n as f32,
+            offset,
+        )
+    }
+
+    /// Picks a segment with probability proportional to its value.
+    pub fn sample_discrete(&self, u: f32) -> usize {
+        self.find_interval(u)
+    }
+
+    /// Probability of `sample_discrete` picking segment `i`.
+    pub fn pmf(&self, i: usize) -> f32 {
+        self.cdf(i + 1) - self.cdf(i)
+    }
+
+    fn pdf(&self, x: f32) -> f32 {
+        let offset = ((x * self.n as f32) as usize).min(self.n - 1);
+        (self.cdf(offset + 1) - self.cdf(offset)) * self.n as f32
+    }
+}
+
+/// Piecewise-constant distribution over [0, 1)^2 laid out by [`build_distribution_2d`],
+/// the conditional CDFs of each row followed by the marginal CDF of the rows.
+pub struct Distribution2D<'a> {
+    data: &'a [f32],
+    width: usize,
+    height: usize,
+}
+
+impl<'a> Distribution2D<'a> {
+    pub fn new(data: &'a [f32], width: u32, height: u32) -> Self {
+        Self {
+            data,
+            width: width as usize,
+            height: height as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    fn conditional(&self, v: usize) -> Distribution1D<'a> {
+        Distribution1D {
+            data: self.data,
+            offset: v * (self.width + 1),
+            n: self.width,
+        }
+    }
+
+    fn marginal(&self) -> Distribution1D<'a> {
+        Distribution1D {
+            data: self.data,
+            offset: self.height * (self.width + 1),
+            n: self.height,
+        }
+    }
+
+    pub fn sample_continuous(&self, u: Vec2) -> (Vec2, f32) {
+        let (d1, pdf1, v) = self.marginal().sample_continuous(u.y);
+        let (d0, pdf0, _) = self.conditional(v).sample_continuous(u.x);
+
+        (vec2(d0, d1), pdf0 * pdf1)
+    }
+
+    pub fn pdf(&self, p: Vec2) -> f32 {
+        let v = ((p.y * self.height as f32) as usize).min(self.height - 1);
+        self.conditional(v).pdf(p.x) * self.marginal().pdf(p.y)
+    }
+}
+
+/// Normalized CDF of `func`, uniform if it integrates to zero. Returns it and the integral.
+#[cfg(not(target_arch = "spirv"))]
+fn build_cdf(func: &[f32]) -> (Vec<f32>, f32) {
+    let n = func.len();
+    let mut cdf = Vec::with_capacity(n + 1);
+    cdf.push(0.0);
+    for (i, f) in func.iter().enumerate() {
+        cdf.push(cdf[i] + f.abs() / n as f32);
+    }
+
+    let integral = cdf[n];
+    if integral == 0.0 {
+        cdf.iter_mut()
+            .enumerate()
+            .for_each(|(i, c)| *c = i as f32 / n as f32);
+    } else {
+        cdf.iter_mut().for_each(|c| *c /= integral);
+    }
+
+    (cdf, integral)
+}
+
+/// Normalized CDF of `func` for [`Distribution1D`].
+#[cfg(not(target_arch = "spirv"))]
+pub fn build_distribution_1d(func: &[f32]) -> Vec<f32> {
+    build_cdf(func).0
+}
+
+/// Flattens the distribution of `func`, whose rows of `width` are along v.
+#[cfg(not(target_arch = "spirv"))]
+pub fn build_distribution_2d(func: &[f32], width: usize, height: usize) -> Vec<f32> {
+    let mut data = Vec::with_capacity(height * (width + 1) + height + 1);
+    let mut marginal = Vec::with_capacity(height);
+
+    for row in func.chunks_exact(width).take(height) {
+        let (cdf, integral) = build_cdf(row);
+        data.extend(cdf);
+        marginal.push(integral);
+    }
+    data.extend(build_cdf(&marginal).0);
+
+    data
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_cdf_normalized() {
+        let (cdf, integral) = build_cdf(&[1.0, 3.0, 0.0, 4.0]);
+
+        assert_close(integral, 2.0);
+        for (c, expected) in cdf.iter().zip([0.0, 0.125, 0.5, 0.5, 1.0]) {
+            assert_close(*c, expected);
+        }
+    }
+
+    #[test]
+    fn test_zero_cdf_uniform() {
+        let (cdf, integral) = build_cdf(&[0.0; 4]);
+
+        assert_eq!(integral, 0.0);
+        for (c, expected) in cdf.iter().zip([0.0, 0.25, 0.5, 0.75, 1.0]) {
+            assert_close(*c, expected);
+        }
+    }
+
+    #[test]
+    fn test_discrete_proportional() {
+        let func = [2.0, 0.0, 6.0, 2.0];
+        let mut data = vec![0.5; 3];
+        data.extend(build_distribution_1d(&func));
+        let distribution = Distribution1D::new(&data, 3, func.len() as u32);
+
+        for (i, f) in func.iter().enumerate() {
+            assert_close(distribution.pmf(i), f / 10.0);
+        }
+        for (u, expected) in [
+            (0.0, 0),
+            (0.19, 0),
+            (0.2, 2),
+            (0.79, 2),
+            (0.8, 3),
+            (0.99, 3),
+        ] {
+            assert_eq!(distribution.sample_discrete(u), expected);
+        }
+    }
+
+    #[test]
+    fn test_distribution_2d_normalized() {
+        let (width, height) = (3, 2);
+        let data = build_distribution_2d(&[1.0, 2.0, 1.0, 0.0, 0.0, 4.0], width, height);
+        assert_eq!(data.len(), height * (width + 1) + height + 1);
+
+        for v in 0..height {
+            let row = &data[v * (width + 1)..(v + 1) * (width + 1)];
+            assert_close(row[0], 0.0);
+            assert_close(row[width], 1.0);
+        }
+        let marginal = &data[height * (width + 1)..];
+        for (c, expected) in marginal.iter().zip([0.0, 0.5, 1.0]) {
+            assert_close(*c, expected);
+        }
+
+        let distribution = Distribution2D::new(&data, width as u32, height as u32);
+        let integral = (0..width * height)
+            .map(|i| {
+                let p = vec2((i % width) as f32 + 0.5, (i / width) as f32 + 0.5)
+                    / vec2(width as f32, height as f32);
+                distribution.pdf(p) / (width * height) as f32
+            })
+            .sum::<f32>();
+        assert_close(integral, 1.0);
+    }
+}
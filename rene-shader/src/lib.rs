@@ -5,39 +5,50 @@
     register_attr(spirv)
 )]
 
-use crate::rand::DefaultRng;
+use crate::rand::{DefaultRng, Rng};
 use area_light::{AreaLight, EnumAreaLight};
-use camera::PerspectiveCamera;
+use bdpt::{Bdpt, PathVertex, VertexKind, MAX_VERTICES, SPLAT_SCALE};
+use camera::EnumCamera;
 use core::f32::consts::{FRAC_1_PI, PI};
-use light::{EnumLight, Light};
+use distribution::Distribution2D;
+use light::{EnumLight, InfiniteLight, Light};
+use light_bvh::{LightBvh, LightBvhNode};
 use material::{EnumMaterial, Material};
-use math::sphere_uv;
-use medium::{EnumMedium, Medium};
+use math::{face_forward, luminance, random_cosine_direction, random_in_hemisphere, sphere_uv};
+use medium::{average, EnumMedium, Medium};
+use mlt::{MltChain, PrimarySampleRng};
 use reflection::{onb::Onb, Bsdf, BxdfKind};
 #[cfg(not(target_arch = "spirv"))]
 use spirv_std::macros::spirv;
+use sppm::{PhotonMap, SppmPixel};
 use surface_sample::{EnumSurfaceSample, SurfaceSample};
 use texture::EnumTexture;
 
 #[allow(unused_imports)]
 use spirv_std::num_traits::Float;
 use spirv_std::{
-    arch::{report_intersection, IndexUnchecked},
+    arch::{atomic_exchange, atomic_i_add, report_intersection, IndexUnchecked},
     glam::{uvec2, vec2, vec3a, Mat4, UVec3, Vec2, Vec3A, Vec4, Vec4Swizzles},
     image::{Image, SampledImage},
+    memory::{Scope, Semantics},
     ray_tracing::{AccelerationStructure, RayFlags},
     RuntimeArray,
 };
 
 pub mod area_light;
 mod asm;
+pub mod bdpt;
 pub mod camera;
+pub mod distribution;
 pub mod light;
+pub mod light_bvh;
 pub mod material;
 pub mod math;
 pub mod medium;
+pub mod mlt;
 pub mod rand;
 pub mod reflection;
+pub mod sppm;
 pub mod surface_sample;
 pub mod texture;
 
@@ -95,14 +106,47 @@ pub struct Uniform {
     pub background_matrix: Mat4,
     pub background_color: Vec4,
     pub background_texture: u32,
-    pub camera: PerspectiveCamera,
+    /// Inverse of `background_matrix`
+    pub background_to_world: Mat4,
+    /// Size of the environment map's distribution, zero if it isn't importance sampled
+    pub background_distribution_width: u32,
+    pub background_distribution_height: u32,
+    pub camera: EnumCamera,
     pub lights_len: u32,
+    /// Lights evaluated at every vertex, the rest are picked from the light BVH
+    pub unbounded_lights_len: u32,
+    /// Root of the analytic lights' tree in the light BVH, the emitters' one is at zero
+    pub light_bvh_root: u32,
     pub emit_object_len: u32,
     pub emit_primitives: u32,
+    pub camera_medium_index: u32,
+    /// Number of bounces before a path ends
+    pub max_depth: u32,
+    /// Russian roulette starts once the path throughput falls below it
+    pub rr_threshold: f32,
+    /// Ambient occlusion only counts occluders closer than it
+    pub ao_max_distance: f32,
+    /// Ambient occlusion samples directions by cosine instead of uniformly
+    pub ao_cos_sample: u32,
+    /// Direct lighting estimates every light instead of picking one
+    pub sample_all_lights: u32,
+    /// Photons traced by each SPPM iteration
+    pub sppm_photons_per_iteration: u32,
+    /// Initial SPPM search radius, which also sizes the cells of the photon grid
+    pub sppm_radius: f32,
+    /// Film size, for launches that don't cover the film
+    pub film_width: u32,
+    pub film_height: u32,
+    /// Share of MLT mutations drawing a new path instead of perturbing the current one
+    pub mlt_large_step_probability: f32,
+    /// Deviation of the primary samples' perturbation in MLT small steps
+    pub mlt_sigma: f32,
 }
 
 pub struct PushConstants {
     seed: u32,
+    /// Step of integrators launched several times per sample, see `bdpt`, `sppm` and `mlt`
+    pass: u32,
 }
 
 #[derive(Copy, Clone)]
@@ -115,6 +159,10 @@ pub struct IndexData {
     pub primitive_count: u32,
     pub interior_medium_index: u32,
     pub exterior_medium_index: u32,
+    /// Path to the instance's leaf in the emitters' light BVH
+    pub emit_bit_trail: u32,
+    /// Total world space surface area of the instance
+    pub emit_area: f32,
 }
 
 #[spirv(miss)]
@@ -156,12 +204,25 @@ pub fn main_ray_generation_path(
     #[spirv(storage_buffer, descriptor_set = 0, binding = 9)] index_data: &[IndexData],
     #[spirv(storage_buffer, descriptor_set = 0, binding = 10)] indices: &[u32],
     #[spirv(storage_buffer, descriptor_set = 0, binding = 11)] vertices: &[Vertex],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 13)] background_distribution: &[f32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 14)] emit_distribution: &[f32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 15)] light_bvh: &[LightBvhNode],
     #[spirv(ray_payload)] payload: &mut RayPayload,
     #[spirv(ray_payload)] payload_pdf: &mut RayPayloadPDF,
 ) {
     let tlas_main = unsafe { tlases.index(0) };
     let tlas_emit = unsafe { tlases.index(1) };
 
+    let infinite_light = InfiniteLight {
+        world_to_light: uniform.background_matrix,
+        light_to_world: uniform.background_to_world,
+        distribution: Distribution2D::new(
+            background_distribution,
+            uniform.background_distribution_width,
+            uniform.background_distribution_height,
+        ),
+    };
+
     let add_image = |i: u32, v: Vec3A| {
         let pos = uvec2(launch_id.x, launch_size.y - 1 - launch_id.y).extend(i);
         let prev: Vec4 = image.read(pos);
@@ -184,12 +245,24 @@ pub fn main_ray_generation_path(
 
     let mut bsdf = Bsdf::default();
 
-    let mut color = vec3a(1.0, 1.0, 1.0);
+    let (mut ray, camera_weight) =
+        uniform
+            .camera
+            .get_ray(vec2(u, v), uniform.camera_to_world, &mut rng);
+
+    let mut color = camera_weight * vec3a(1.0, 1.0, 1.0);
+    // Squared relative indices of refraction of the transmissions so far, pbrt's `etaScale`
+    let mut eta_scale = 1.0;
 
-    let mut ray = uniform.camera.get_ray(vec2(u, v), uniform.camera_to_world);
+    // The last scattered direction, weighs the lights found by it against sampling them
+    let mut last_pdf = 0.0;
+    let mut last_specular = true;
+    let mut last_position = Vec3A::ZERO;
+    let mut last_normal = Vec3A::ZERO;
+    let mut last_infinite_light_mis = false;
 
     let mut i = 0;
-    while i < 50 {
+    loop {
         *payload = RayPayload::default();
         unsafe {
             tlas_main.trace_ray(
@@ -207,7 +280,12 @@ pub fn main_ray_generation_path(
         }
 
         if payload.is_miss != 0 {
-            add_image(0, color * payload.position);
+            let weight = if last_infinite_light_mis {
+                power_heuristic(1.0, last_pdf, 1.0, infinite_light.pdf(ray.direction))
+            } else {
+                1.0
+            };
+            add_image(0, color * payload.position * weight);
             break;
         } else {
             let wo = -ray.direction.normalize();
@@ -223,7 +301,19 @@ pub fn main_ray_generation_path(
             material.compute_bsdf(&mut bsdf, uv, textures, images);
 
             if !area_light.is_null() {
-                add_image(0, color * area_light.emit(wo, normal));
+                let weight = if last_specular {
+                    1.0
+                } else {
+                    let light_pdf = emit_pdf(
+                        tlas_emit,
+                        last_position,
+                        last_normal,
+                        ray.direction,
+                        payload_pdf,
+                    );
+                    power_heuristic(1.0, last_pdf, 1.0, light_pdf)
+                };
+                add_image(0, color * area_light.emit(wo, normal) * weight);
             }
 
             if i == 0 {
@@ -231,132 +321,252 @@ pub fn main_ray_generation_path(
                 add_image(2, material.albedo(uv, textures, images));
             }
 
+            if i >= uniform.max_depth {
+                break;
+            }
+
+            let non_specular = bsdf.contains(BxdfKind::DIFFUSE);
+
             let mut l = 0;
-            while l < uniform.lights_len {
-                let (target, t_max) =
-                    unsafe { lights.index_unchecked(l as usize) }.ray_target(position);
-                let wi = (target - position).normalize();
-                let light_ray = Ray {
-                    origin: position,
-                    direction: wi,
-                };
+            while l <= uniform.unbounded_lights_len {
+                let (light_index, light_weight) =
+                    pick_light(uniform, light_bvh, l, position, normal, &mut rng);
+                if light_weight > 0.0 {
+                    let (target, t_max) = unsafe { lights.index_unchecked(light_index as usize) }
+                        .ray_target(position);
+                    let wi = (target - position).normalize();
+                    let light_ray = Ray {
+                        origin: position,
+                        direction: wi,
+                    };
 
-                *payload = RayPayload::default();
-                unsafe {
-                    tlas_main.trace_ray(
-                        RayFlags::empty(),
-                        cull_mask,
-                        0,
-                        0,
-                        0,
-                        light_ray.origin,
-                        tmin,
-                        light_ray.direction,
-                        t_max,
-                        payload,
-                    );
-                }
+                    *payload = RayPayload::default();
+                    unsafe {
+                        tlas_main.trace_ray(
+                            RayFlags::empty(),
+                            cull_mask,
+                            0,
+                            0,
+                            0,
+                            light_ray.origin,
+                            tmin,
+                            light_ray.direction,
+                            t_max,
+                            payload,
+                        );
+                    }
 
-                if payload.is_miss != 0 {
-                    let f = bsdf.f(wo, wi);
+                    if payload.is_miss != 0 {
+                        let f = bsdf.f(wo, wi);
 
-                    add_image(
-                        0,
-                        color
-                            * f
-                            * wi.dot(normal).abs()
-                            * unsafe { lights.index_unchecked(l as usize) }.color(position),
-                    );
+                        add_image(
+                            0,
+                            color
+                                * f
+                                * wi.dot(normal).abs()
+                                * unsafe { lights.index_unchecked(light_index as usize) }
+                                    .color(position, textures, images)
+                                * light_weight,
+                        );
+                    }
                 }
                 l += 1;
             }
 
-            if uniform.emit_object_len > 0 && bsdf.contains(BxdfKind::DIFFUSE) {
-                // Use frame wide RNG to reduce warp divergence
-                let (wi, pdf, f) = if frame_wide_rng.next_f32() > 0.5 {
-                    let emit_object = unsafe {
-                        emit_objects.index_unchecked(
-                            (frame_wide_rng.next_u32() % uniform.emit_object_len) as usize,
-                        )
-                    };
-
-                    let wi = (emit_object.sample(indices, vertices, &mut frame_wide_rng)
-                        - position)
-                        .normalize();
-
-                    (wi, bsdf.pdf(wi, normal), bsdf.f(wo, wi))
-                } else {
-                    let sampled_f = bsdf.sample_f(wo, &mut rng);
-
-                    (sampled_f.wi, sampled_f.pdf, sampled_f.f)
-                };
+            let sample_infinite_light = infinite_light.is_samplable() && non_specular;
+            if sample_infinite_light {
+                let (wi, light_pdf) = infinite_light.sample(&mut rng);
 
-                ray = Ray {
-                    origin: position,
-                    direction: wi,
-                };
+                if light_pdf > 0.0 {
+                    *payload = RayPayload::default();
+                    unsafe {
+                        tlas_main.trace_ray(
+                            RayFlags::empty(),
+                            cull_mask,
+                            0,
+                            0,
+                            0,
+                            position,
+                            tmin,
+                            wi,
+                            tmax,
+                            payload,
+                        );
+                    }
 
-                *payload_pdf = RayPayloadPDF::default();
+                    if payload.is_miss != 0 {
+                        let weight = power_heuristic(1.0, light_pdf, 1.0, bsdf.pdf(wo, wi));
 
-                unsafe {
-                    tlas_emit.trace_ray(
-                        RayFlags::OPAQUE,
-                        cull_mask,
-                        2,
-                        0,
-                        1,
-                        ray.origin,
-                        tmin,
-                        ray.direction,
-                        tmax,
-                        payload_pdf,
-                    );
+                        add_image(
+                            0,
+                            color
+                                * bsdf.f(wo, wi)
+                                * wi.dot(normal).abs()
+                                * payload.position
+                                * weight
+                                / light_pdf,
+                        );
+                    }
                 }
+            }
+            last_infinite_light_mis = sample_infinite_light;
+
+            if uniform.emit_object_len > 0 && non_specular {
+                let (emit_index, emit_pmf) =
+                    LightBvh::new(light_bvh, 0).sample(position, normal, rng.next_f32());
+
+                if emit_pmf > 0.0 {
+                    let emit_object = unsafe { emit_objects.index_unchecked(emit_index as usize) };
+                    let target = emit_object.sample(
+                        position,
+                        indices,
+                        vertices,
+                        emit_distribution,
+                        &mut rng,
+                    );
+                    let wi = (target - position).normalize();
 
-                color *= f * normal.dot(wi).abs();
-
-                let pdf = 0.5 * pdf + 0.5 * payload_pdf.pdf / uniform.emit_object_len as f32;
+                    *payload = RayPayload::default();
+                    unsafe {
+                        tlas_main.trace_ray(
+                            RayFlags::OPAQUE,
+                            cull_mask,
+                            0,
+                            0,
+                            0,
+                            position,
+                            tmin,
+                            wi,
+                            tmax,
+                            payload,
+                        );
+                    }
 
-                if pdf < 1e-5 {
-                    break;
-                }
+                    // Visible unless something is in front of the sampled point
+                    if payload.is_miss == 0 && payload.t > 0.999 * target.distance(position) {
+                        let hit = unsafe { index_data.index_unchecked(payload.index as usize) };
+                        let emit =
+                            unsafe { area_lights.index_unchecked(hit.area_light_index as usize) }
+                                .emit(-wi, payload.normal.normalize());
 
-                color /= pdf;
-            } else {
-                let sampled_f = bsdf.sample_f(wo, &mut rng);
+                        let light_pdf = emit_pdf(tlas_emit, position, normal, wi, payload_pdf);
+                        if emit != Vec3A::ZERO && light_pdf > 0.0 {
+                            let weight = power_heuristic(1.0, light_pdf, 1.0, bsdf.pdf(wo, wi));
 
-                if sampled_f.pdf < 1e-5 {
-                    break;
+                            add_image(
+                                0,
+                                color * bsdf.f(wo, wi) * wi.dot(normal).abs() * emit * weight
+                                    / light_pdf,
+                            );
+                        }
+                    }
                 }
+            }
 
-                color *= sampled_f.f * normal.dot(sampled_f.wi).abs() / sampled_f.pdf;
-                ray = Ray {
-                    origin: position,
-                    direction: sampled_f.wi,
-                };
+            let sampled_f = bsdf.sample_f(wo, &mut rng);
+
+            if sampled_f.pdf < 1e-5 {
+                break;
             }
+            last_pdf = sampled_f.pdf;
+            last_specular = sampled_f.is_specular;
+            last_position = position;
+            last_normal = normal;
+
+            color *= sampled_f.f * normal.dot(sampled_f.wi).abs() / sampled_f.pdf;
+            eta_scale *= sampled_f.eta * sampled_f.eta;
+            ray = Ray {
+                origin: position,
+                direction: sampled_f.wi,
+            };
         }
 
         if color == Vec3A::ZERO {
             break;
         }
 
-        // russian roulette
-        if i > 12 {
-            let rr_coin = frame_wide_rng.next_f32();
-            let continue_p = color.max_element();
-
-            if rr_coin > continue_p {
-                break;
-            } else {
-                color /= continue_p;
-            }
+        if russian_roulette(uniform, i, eta_scale, &mut color, &mut frame_wide_rng) {
+            break;
         }
         i += 1;
     }
 }
 
+/// Pdf of sampling the emitter found by `emit_pdf` once the light BVH has picked it.
+#[inline(always)]
+fn unpicked_emit_pdf(
+    light_bvh: &[LightBvhNode],
+    origin: Vec3A,
+    normal: Vec3A,
+    bit_trail: u32,
+    pdf: f32,
+) -> f32 {
+    let pmf = LightBvh::new(light_bvh, 0).pmf(origin, normal, bit_trail);
+
+    if pmf > 0.0 {
+        pdf / pmf
+    } else {
+        0.0
+    }
+}
+
+/// Randomly ends a path whose throughput fell below `rr_threshold`, returns whether it ended.
+/// Like pbrt-v4, the throughput is judged with the radiance scaling of the refractions
+/// so far undone by `eta_scale`. A surviving path's `color` is scaled up to keep the estimate unbiased.
+#[inline(always)]
+fn russian_roulette(
+    uniform: &Uniform,
+    depth: u32,
+    eta_scale: f32,
+    color: &mut Vec3A,
+    rng: &mut DefaultRng,
+) -> bool {
+    let max_element = (*color * eta_scale).max_element();
+
+    if depth > 1 && max_element < uniform.rr_threshold {
+        let q = (1.0 - max_element).max(0.0);
+
+        if rng.next_f32() < q {
+            return true;
+        }
+        *color /= 1.0 - q;
+    }
+
+    false
+}
+
+/// Solid angle pdf of sampling the emitter seen from `origin` along `direction`,
+/// picking it from the light BVH with `normal` at `origin`.
+#[inline(always)]
+fn emit_pdf(
+    tlas_emit: &AccelerationStructure,
+    origin: Vec3A,
+    normal: Vec3A,
+    direction: Vec3A,
+    payload_pdf: &mut RayPayloadPDF,
+) -> f32 {
+    *payload_pdf = RayPayloadPDF::new(normal);
+    unsafe {
+        tlas_emit.trace_ray(
+            RayFlags::OPAQUE,
+            0xff,
+            2,
+            0,
+            1,
+            origin,
+            0.001,
+            direction,
+            100000.0,
+            payload_pdf,
+        );
+    }
+    payload_pdf.pdf
+}
+
+/// Transmittance to the end of `ray` by ratio tracking through each medium on the way,
+/// over the channels' average probability of the whole path, `r_u` up to the ray.
 #[inline(always)]
+#[allow(clippy::too_many_arguments)]
 fn tr(
     tlas_main: &AccelerationStructure,
     mut ray: Ray,
@@ -364,9 +574,13 @@ fn tr(
     mediums: &[EnumMedium],
     materials: &[EnumMaterial],
     index_data: &[IndexData],
+    channel: u32,
+    r_u: Vec3A,
     payload: &mut RayPayload,
+    rng: &mut DefaultRng,
 ) -> Vec3A {
     let mut tr = vec3a(1.0, 1.0, 1.0);
+    let mut r = r_u;
 
     loop {
         *payload = RayPayload::default();
@@ -389,13 +603,18 @@ fn tr(
         let index = unsafe { index_data.index_unchecked(payload.index as usize) };
 
         if payload.is_miss != 0 {
-            break tr;
+            break tr / average(r);
         } else if !unsafe { materials.index_unchecked(index.material_index as usize) }.is_none() {
             break Vec3A::ZERO;
         } else {
             let medium = unsafe { mediums.index_unchecked(medium_index as usize) };
             if !medium.is_vaccum() {
-                tr *= medium.tr(ray, payload.t);
+                let transmittance = medium.tr(ray, payload.t, channel, rng);
+                tr *= transmittance.tr;
+                r *= transmittance.r;
+                if tr == Vec3A::ZERO {
+                    break Vec3A::ZERO;
+                }
             }
 
             medium_index = if ray.direction.dot(payload.normal) > 0.0 {
@@ -408,6 +627,7 @@ fn tr(
     }
 }
 
+/// Emission of the first area light along `ray` times the transmittance to it, as `tr`.
 #[inline(always)]
 #[allow(clippy::too_many_arguments)]
 fn tr_emit<'a>(
@@ -418,9 +638,13 @@ fn tr_emit<'a>(
     materials: &[EnumMaterial],
     area_lights: &[EnumAreaLight],
     index_data: &[IndexData],
+    channel: u32,
+    r_u: Vec3A,
     payload: &mut RayPayload,
+    rng: &mut DefaultRng,
 ) -> Vec3A {
     let mut tr = vec3a(1.0, 1.0, 1.0);
+    let mut r = r_u;
 
     loop {
         *payload = RayPayload::default();
@@ -446,7 +670,7 @@ fn tr_emit<'a>(
             break Vec3A::ZERO;
         } else if !unsafe { area_lights.index_unchecked(index.area_light_index as usize) }.is_null()
         {
-            break tr
+            break tr / average(r)
                 * unsafe { area_lights.index_unchecked(index.area_light_index as usize) }
                     .emit(-ray.direction.normalize(), payload.normal);
         } else if !unsafe { materials.index_unchecked(index.material_index as usize) }.is_none() {
@@ -454,7 +678,12 @@ fn tr_emit<'a>(
         } else {
             let medium = unsafe { mediums.index_unchecked(medium_index as usize) };
             if !medium.is_vaccum() {
-                tr *= medium.tr(ray, payload.t);
+                let transmittance = medium.tr(ray, payload.t, channel, rng);
+                tr *= transmittance.tr;
+                r *= transmittance.r;
+                if tr == Vec3A::ZERO {
+                    break Vec3A::ZERO;
+                }
             }
 
             medium_index = if ray.direction.dot(payload.normal) > 0.0 {
@@ -467,7 +696,34 @@ fn tr_emit<'a>(
     }
 }
 
-#[allow(dead_code)]
+/// Light evaluated in the `l`th of `unbounded_lights_len + 1` iterations at `p`,
+/// the unbounded lights in turn and then one of the others picked from the light BVH.
+/// Returns its index and the weight of its contribution, zero to skip it.
+#[inline(always)]
+fn pick_light(
+    uniform: &Uniform,
+    light_bvh: &[LightBvhNode],
+    l: u32,
+    p: Vec3A,
+    n: Vec3A,
+    rng: &mut DefaultRng,
+) -> (u32, f32) {
+    if l < uniform.unbounded_lights_len {
+        return (l, 1.0);
+    }
+    if uniform.lights_len == uniform.unbounded_lights_len {
+        return (0, 0.0);
+    }
+
+    let (index, pmf) =
+        LightBvh::new(light_bvh, uniform.light_bvh_root).sample(p, n, rng.next_f32());
+    if pmf > 0.0 {
+        (index, 1.0 / pmf)
+    } else {
+        (0, 0.0)
+    }
+}
+
 fn power_heuristic(nf: f32, fpdf: f32, ng: f32, gpdf: f32) -> f32 {
     let f = nf * fpdf;
     let g = ng * gpdf;
@@ -493,13 +749,25 @@ pub fn main_ray_generation_volpath(
     #[spirv(storage_buffer, descriptor_set = 0, binding = 10)] indices: &[u32],
     #[spirv(storage_buffer, descriptor_set = 0, binding = 11)] vertices: &[Vertex],
     #[spirv(storage_buffer, descriptor_set = 0, binding = 12)] mediums: &[EnumMedium],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 13)] background_distribution: &[f32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 14)] emit_distribution: &[f32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 15)] light_bvh: &[LightBvhNode],
     #[spirv(ray_payload)] payload: &mut RayPayload,
     #[spirv(ray_payload)] payload_pdf: &mut RayPayloadPDF,
 ) {
-    const MAX_DEPTH: u32 = 80;
     let tlas_main = unsafe { tlases.index(0) };
     let tlas_emit = unsafe { tlases.index(1) };
 
+    let infinite_light = InfiniteLight {
+        world_to_light: uniform.background_matrix,
+        light_to_world: uniform.background_to_world,
+        distribution: Distribution2D::new(
+            background_distribution,
+            uniform.background_distribution_width,
+            uniform.background_distribution_height,
+        ),
+    };
+
     let add_image = |i: u32, v: Vec3A| {
         let pos = uvec2(launch_id.x, launch_size.y - 1 - launch_id.y).extend(i);
         let prev: Vec4 = image.read(pos);
@@ -522,14 +790,31 @@ pub fn main_ray_generation_volpath(
 
     let mut bsdf = Bsdf::default();
 
-    let mut color = vec3a(1.0, 1.0, 1.0);
+    let (mut ray, camera_weight) =
+        uniform
+            .camera
+            .get_ray(vec2(u, v), uniform.camera_to_world, &mut rng);
+
+    let mut color = camera_weight * vec3a(1.0, 1.0, 1.0);
+
+    let mut medium_index = uniform.camera_medium_index;
+
+    // Media sample distances by a hero channel, `r_u` holds each channel's probability
+    // of the path relative to it and the throughput is kept over their average
+    let channel = rng.next_u32() % 3;
+    let mut r_u = vec3a(1.0, 1.0, 1.0);
+    // Squared relative indices of refraction of the transmissions so far, pbrt's `etaScale`
+    let mut eta_scale = 1.0;
 
-    let mut ray = uniform.camera.get_ray(vec2(u, v), uniform.camera_to_world);
+    // Pdf of the last scattered direction, weighs the infinite light found by it
+    let mut last_pdf = 0.0;
+    let mut last_infinite_light_mis = false;
 
-    let mut medium_index = 0u32;
+    // Scattering events so far, passing through a medium boundary isn't one
+    let mut depth = 0;
 
     let mut i = 0;
-    while i < MAX_DEPTH {
+    loop {
         *payload = RayPayload::default();
         unsafe {
             tlas_main.trace_ray(
@@ -547,7 +832,12 @@ pub fn main_ray_generation_volpath(
         }
 
         if payload.is_miss != 0 {
-            add_image(0, color * payload.position);
+            let weight = if last_infinite_light_mis {
+                power_heuristic(1.0, last_pdf, 1.0, infinite_light.pdf(ray.direction))
+            } else {
+                1.0
+            };
+            add_image(0, color * payload.position * weight);
             break;
         } else {
             let wo = -ray.direction.normalize();
@@ -560,52 +850,77 @@ pub fn main_ray_generation_volpath(
                 unsafe { area_lights.index_unchecked(index.area_light_index as usize) };
             let medium = unsafe { mediums.index_unchecked(medium_index as usize) };
 
-            let sampled_medium = medium.sample(ray, payload.t, &mut rng);
+            let sampled_medium = medium.sample(ray, payload.t, channel, &mut rng);
 
-            color *= sampled_medium.tr;
+            // Rescaling both keeps the throughput over the channels' average probability
+            color *= sampled_medium.weight;
+            r_u *= sampled_medium.weight;
+            let r_average = average(r_u);
+            color /= r_average;
+            r_u /= r_average;
 
             if sampled_medium.sampled {
+                if depth >= uniform.max_depth {
+                    break;
+                }
+                depth += 1;
+
                 ray.origin = sampled_medium.position;
 
                 let mut l = 0;
-                while l < uniform.lights_len {
-                    let (target, _t_max) =
-                        unsafe { lights.index_unchecked(l as usize) }.ray_target(ray.origin);
-                    let wi = (target - ray.origin).normalize();
-                    let light_ray = Ray {
-                        origin: ray.origin,
-                        direction: wi,
-                    };
+                while l <= uniform.unbounded_lights_len {
+                    let (light_index, light_weight) =
+                        pick_light(uniform, light_bvh, l, ray.origin, Vec3A::ZERO, &mut rng);
+                    if light_weight > 0.0 {
+                        let (target, _t_max) =
+                            unsafe { lights.index_unchecked(light_index as usize) }
+                                .ray_target(ray.origin);
+                        let wi = (target - ray.origin).normalize();
+                        let light_ray = Ray {
+                            origin: ray.origin,
+                            direction: wi,
+                        };
 
-                    let tr = tr(
-                        tlas_main,
-                        light_ray,
-                        medium_index,
-                        mediums,
-                        materials,
-                        index_data,
-                        payload,
-                    );
-                    add_image(
-                        0,
-                        color
-                            * tr
-                            * medium.phase(wo, wi)
-                            * unsafe { lights.index_unchecked(l as usize) }.color(ray.origin),
-                    );
+                        let tr = tr(
+                            tlas_main,
+                            light_ray,
+                            medium_index,
+                            mediums,
+                            materials,
+                            index_data,
+                            channel,
+                            r_u,
+                            payload,
+                            &mut rng,
+                        );
+                        add_image(
+                            0,
+                            color
+                                * tr
+                                * medium.phase(wo, wi)
+                                * unsafe { lights.index_unchecked(light_index as usize) }
+                                    .color(ray.origin, textures, images)
+                                * light_weight,
+                        );
+                    }
                     l += 1;
                 }
 
                 if uniform.emit_object_len > 0 {
-                    let emit_object = unsafe {
-                        emit_objects
-                            .index_unchecked((rng.next_u32() % uniform.emit_object_len) as usize)
-                    };
+                    let (emit_index, emit_pmf) =
+                        LightBvh::new(light_bvh, 0).sample(ray.origin, Vec3A::ZERO, rng.next_f32());
+                    let emit_object = unsafe { emit_objects.index_unchecked(emit_index as usize) };
 
-                    let wi =
-                        (emit_object.sample(indices, vertices, &mut rng) - ray.origin).normalize();
+                    let wi = (emit_object.sample(
+                        ray.origin,
+                        indices,
+                        vertices,
+                        emit_distribution,
+                        &mut rng,
+                    ) - ray.origin)
+                        .normalize();
 
-                    *payload_pdf = RayPayloadPDF::default();
+                    *payload_pdf = RayPayloadPDF::new(Vec3A::ZERO);
 
                     let light_ray = Ray {
                         origin: ray.origin,
@@ -635,9 +950,12 @@ pub fn main_ray_generation_volpath(
                         materials,
                         area_lights,
                         index_data,
+                        channel,
+                        r_u,
                         payload,
+                        &mut rng,
                     );
-                    let pdf = payload_pdf.pdf / uniform.emit_object_len as f32;
+                    let pdf = payload_pdf.pdf;
 
                     /*
                     let weight = power_heuristic(
@@ -648,12 +966,47 @@ pub fn main_ray_generation_volpath(
                     );
                     */
 
-                    if pdf > 1e-5 {
+                    if emit_pmf > 0.0 && pdf > 1e-5 {
                         add_image(0, color * tr * medium.phase(wo, wi) / pdf);
                     }
                 }
 
+                if infinite_light.is_samplable() {
+                    let (wi, light_pdf) = infinite_light.sample(&mut rng);
+
+                    if light_pdf > 0.0 {
+                        let light_ray = Ray {
+                            origin: ray.origin,
+                            direction: wi,
+                        };
+                        let tr = tr(
+                            tlas_main,
+                            light_ray,
+                            medium_index,
+                            mediums,
+                            materials,
+                            index_data,
+                            channel,
+                            r_u,
+                            payload,
+                            &mut rng,
+                        );
+
+                        // A visible light leaves the payload of the final miss
+                        if tr != Vec3A::ZERO {
+                            let phase = medium.phase(wo, wi);
+                            let weight = power_heuristic(1.0, light_pdf, 1.0, phase);
+                            add_image(
+                                0,
+                                color * tr * phase * payload.position * weight / light_pdf,
+                            );
+                        }
+                    }
+                }
+
                 ray.direction = medium.sample_p(wo, &mut rng);
+                last_pdf = medium.phase(wo, ray.direction);
+                last_infinite_light_mis = infinite_light.is_samplable();
             } else {
                 bsdf.clear(normal, Onb::from_w(normal));
                 material.compute_bsdf(&mut bsdf, uv, textures, images);
@@ -668,137 +1021,1446 @@ pub fn main_ray_generation_volpath(
                 }
 
                 if !material.is_none() {
+                    if depth >= uniform.max_depth {
+                        break;
+                    }
+                    depth += 1;
+
                     let mut l = 0;
-                    while l < uniform.lights_len {
-                        let (target, _t_max) =
-                            unsafe { lights.index_unchecked(l as usize) }.ray_target(position);
-                        let wi = (target - position).normalize();
-                        let light_ray = Ray {
-                            origin: position,
-                            direction: wi,
-                        };
+                    while l <= uniform.unbounded_lights_len {
+                        let (light_index, light_weight) =
+                            pick_light(uniform, light_bvh, l, position, normal, &mut rng);
+                        if light_weight > 0.0 {
+                            let (target, _t_max) =
+                                unsafe { lights.index_unchecked(light_index as usize) }
+                                    .ray_target(position);
+                            let wi = (target - position).normalize();
+                            let light_ray = Ray {
+                                origin: position,
+                                direction: wi,
+                            };
 
-                        let f = bsdf.f(wo, wi);
-                        let tr = tr(
-                            tlas_main,
-                            light_ray,
-                            medium_index,
-                            mediums,
-                            materials,
-                            index_data,
-                            payload,
-                        );
+                            let f = bsdf.f(wo, wi);
+                            let tr = tr(
+                                tlas_main,
+                                light_ray,
+                                medium_index,
+                                mediums,
+                                materials,
+                                index_data,
+                                channel,
+                                r_u,
+                                payload,
+                                &mut rng,
+                            );
 
-                        add_image(
-                            0,
-                            color
-                                * tr
-                                * f
-                                * wi.dot(normal).abs()
-                                * unsafe { lights.index_unchecked(l as usize) }.color(position),
-                        );
+                            add_image(
+                                0,
+                                color
+                                    * tr
+                                    * f
+                                    * wi.dot(normal).abs()
+                                    * unsafe { lights.index_unchecked(light_index as usize) }
+                                        .color(position, textures, images)
+                                    * light_weight,
+                            );
+                        }
                         l += 1;
                     }
 
-                    if uniform.emit_object_len > 0 && bsdf.contains(BxdfKind::DIFFUSE) {
-                        // Use frame wide RNG to reduce warp divergence
-                        let (wi, pdf, f) = if frame_wide_rng.next_f32() > 0.5 {
-                            let emit_object = unsafe {
-                                emit_objects.index_unchecked(
-                                    (frame_wide_rng.next_u32() % uniform.emit_object_len) as usize,
-                                )
+                    let sample_infinite_light =
+                        infinite_light.is_samplable() && bsdf.contains(BxdfKind::DIFFUSE);
+                    if sample_infinite_light {
+                        let (wi, light_pdf) = infinite_light.sample(&mut rng);
+
+                        if light_pdf > 0.0 {
+                            let light_ray = Ray {
+                                origin: position,
+                                direction: wi,
+                            };
+                            let tr = tr(
+                                tlas_main,
+                                light_ray,
+                                medium_index,
+                                mediums,
+                                materials,
+                                index_data,
+                                channel,
+                                r_u,
+                                payload,
+                                &mut rng,
+                            );
+
+                            // A visible light leaves the payload of the final miss
+                            if tr != Vec3A::ZERO {
+                                let bsdf_pdf = if uniform.emit_object_len > 0 {
+                                    0.5 * bsdf.pdf(wo, wi)
+                                } else {
+                                    bsdf.pdf(wo, wi)
+                                };
+                                let weight = power_heuristic(1.0, light_pdf, 1.0, bsdf_pdf);
+
+                                add_image(
+                                    0,
+                                    color
+                                        * tr
+                                        * bsdf.f(wo, wi)
+                                        * wi.dot(normal).abs()
+                                        * payload.position
+                                        * weight
+                                        / light_pdf,
+                                );
+                            }
+                        }
+                    }
+                    last_infinite_light_mis = sample_infinite_light;
+
+                    if uniform.emit_object_len > 0 && bsdf.contains(BxdfKind::DIFFUSE) {
+                        // Use frame wide RNG to reduce warp divergence
+                        let (wi, pdf, f) = if frame_wide_rng.next_f32() > 0.5 {
+                            let (emit_index, emit_pmf) = LightBvh::new(light_bvh, 0).sample(
+                                position,
+                                normal,
+                                frame_wide_rng.next_f32(),
+                            );
+                            let emit_object =
+                                unsafe { emit_objects.index_unchecked(emit_index as usize) };
+
+                            let wi = (emit_object.sample(
+                                position,
+                                indices,
+                                vertices,
+                                emit_distribution,
+                                &mut frame_wide_rng,
+                            ) - position)
+                                .normalize();
+
+                            let f = if emit_pmf > 0.0 {
+                                bsdf.f(wo, wi)
+                            } else {
+                                Vec3A::ZERO
                             };
 
-                            let wi = (emit_object.sample(indices, vertices, &mut frame_wide_rng)
-                                - position)
-                                .normalize();
+                            (wi, bsdf.pdf(wo, wi), f)
+                        } else {
+                            let sampled_f = bsdf.sample_f(wo, &mut rng);
+                            eta_scale *= sampled_f.eta * sampled_f.eta;
+
+                            (sampled_f.wi, sampled_f.pdf, sampled_f.f)
+                        };
+
+                        ray = Ray {
+                            origin: position,
+                            direction: wi,
+                        };
+
+                        *payload_pdf = RayPayloadPDF::new(normal);
+
+                        unsafe {
+                            tlas_emit.trace_ray(
+                                RayFlags::OPAQUE,
+                                cull_mask,
+                                2,
+                                0,
+                                1,
+                                ray.origin,
+                                tmin,
+                                ray.direction,
+                                tmax,
+                                payload_pdf,
+                            );
+                        }
+
+                        color *= f * normal.dot(wi).abs();
+
+                        let pdf = 0.5 * pdf + 0.5 * payload_pdf.pdf;
+                        last_pdf = pdf;
+
+                        if pdf < 1e-5 {
+                            break;
+                        }
+
+                        color /= pdf;
+                    } else {
+                        let sampled_f = bsdf.sample_f(wo, &mut rng);
+
+                        if sampled_f.pdf < 1e-5 {
+                            break;
+                        }
+                        last_pdf = sampled_f.pdf;
+
+                        color *= sampled_f.f * normal.dot(sampled_f.wi).abs() / sampled_f.pdf;
+                        eta_scale *= sampled_f.eta * sampled_f.eta;
+                        ray = Ray {
+                            origin: position,
+                            direction: sampled_f.wi,
+                        };
+                    }
+                } else {
+                    ray = Ray {
+                        origin: payload.position,
+                        direction: ray.direction,
+                    };
+                }
+
+                medium_index = if wo.dot(normal) < 0.0 {
+                    index.exterior_medium_index
+                } else {
+                    index.interior_medium_index
+                };
+            }
+        }
+
+        if color == Vec3A::ZERO {
+            break;
+        }
+
+        if russian_roulette(uniform, depth, eta_scale, &mut color, &mut frame_wide_rng) {
+            break;
+        }
+
+        i += 1;
+    }
+}
+
+#[spirv(ray_generation)]
+#[allow(clippy::too_many_arguments)]
+pub fn main_ray_generation_ao(
+    #[spirv(launch_id)] launch_id: UVec3,
+    #[spirv(launch_size)] launch_size: UVec3,
+    #[spirv(push_constant)] constants: &PushConstants,
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] uniform: &Uniform,
+    #[spirv(descriptor_set = 0, binding = 1)] tlases: &RuntimeArray<AccelerationStructure>,
+    #[spirv(descriptor_set = 0, binding = 2)] image: &Image!(2D, format=rgba32f, sampled=false, arrayed=true),
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] materials: &[EnumMaterial],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 7)] textures: &[EnumTexture],
+    #[spirv(descriptor_set = 0, binding = 8)] images: &RuntimeArray<InputImage>,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 9)] index_data: &[IndexData],
+    #[spirv(ray_payload)] payload: &mut RayPayload,
+) {
+    let tlas_main = unsafe { tlases.index(0) };
+
+    let add_image = |i: u32, v: Vec3A| {
+        let pos = uvec2(launch_id.x, launch_size.y - 1 - launch_id.y).extend(i);
+        let prev: Vec4 = image.read(pos);
+
+        unsafe {
+            image.write(pos, prev + v.extend(0.0));
+        }
+    };
+
+    let rand_seed = (launch_id.y * launch_size.x + launch_id.x) ^ constants.seed;
+    let mut rng = DefaultRng::new(rand_seed);
+
+    let u = (launch_id.x as f32 + rng.next_f32()) / (launch_size.x - 1) as f32;
+    let v = (launch_id.y as f32 + rng.next_f32()) / (launch_size.y - 1) as f32;
+
+    let cull_mask = 0xff;
+    let tmin = 0.001;
+    let tmax = 100000.0;
+
+    let (ray, camera_weight) =
+        uniform
+            .camera
+            .get_ray(vec2(u, v), uniform.camera_to_world, &mut rng);
+
+    *payload = RayPayload::default();
+    unsafe {
+        tlas_main.trace_ray(
+            RayFlags::OPAQUE,
+            cull_mask,
+            0,
+            0,
+            0,
+            ray.origin,
+            tmin,
+            ray.direction,
+            tmax,
+            payload,
+        );
+    }
+
+    if payload.is_miss != 0 {
+        return;
+    }
+
+    let normal = face_forward(payload.normal.normalize(), -ray.direction);
+    let position = payload.position;
+    let index = unsafe { index_data.index_unchecked(payload.index as usize) };
+    let material = unsafe { materials.index_unchecked(index.material_index as usize) };
+
+    add_image(1, normal);
+    add_image(2, material.albedo(payload.uv, textures, images));
+
+    let (wi, pdf) = if uniform.ao_cos_sample != 0 {
+        let wi = Onb::from_w(normal).local_to_world(random_cosine_direction(&mut rng));
+        (wi, wi.dot(normal) * FRAC_1_PI)
+    } else {
+        (random_in_hemisphere(normal, &mut rng), 0.5 * FRAC_1_PI)
+    };
+
+    if pdf <= 0.0 {
+        return;
+    }
+
+    *payload = RayPayload::default();
+    unsafe {
+        tlas_main.trace_ray(
+            RayFlags::empty(),
+            cull_mask,
+            0,
+            0,
+            0,
+            position,
+            tmin,
+            wi,
+            uniform.ao_max_distance.min(tmax),
+            payload,
+        );
+    }
+
+    // An unoccluded hemisphere integrates to one
+    if payload.is_miss != 0 {
+        add_image(
+            0,
+            camera_weight * vec3a(1.0, 1.0, 1.0) * wi.dot(normal) * FRAC_1_PI / pdf,
+        );
+    }
+}
+
+/// Lighting of the first non-specular surface only, specular ones are followed up to `max_depth`.
+#[spirv(ray_generation)]
+#[allow(clippy::too_many_arguments)]
+pub fn main_ray_generation_direct(
+    #[spirv(launch_id)] launch_id: UVec3,
+    #[spirv(launch_size)] launch_size: UVec3,
+    #[spirv(push_constant)] constants: &PushConstants,
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] uniform: &Uniform,
+    #[spirv(descriptor_set = 0, binding = 1)] tlases: &RuntimeArray<AccelerationStructure>,
+    #[spirv(descriptor_set = 0, binding = 2)] image: &Image!(2D, format=rgba32f, sampled=false, arrayed=true),
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] lights: &[EnumLight],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] area_lights: &[EnumAreaLight],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] emit_objects: &[EnumSurfaceSample],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] materials: &[EnumMaterial],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 7)] textures: &[EnumTexture],
+    #[spirv(descriptor_set = 0, binding = 8)] images: &RuntimeArray<InputImage>,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 9)] index_data: &[IndexData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 10)] indices: &[u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 11)] vertices: &[Vertex],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 13)] background_distribution: &[f32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 14)] emit_distribution: &[f32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 15)] light_bvh: &[LightBvhNode],
+    #[spirv(ray_payload)] payload: &mut RayPayload,
+    #[spirv(ray_payload)] payload_pdf: &mut RayPayloadPDF,
+) {
+    let tlas_main = unsafe { tlases.index(0) };
+    let tlas_emit = unsafe { tlases.index(1) };
+
+    let infinite_light = InfiniteLight {
+        world_to_light: uniform.background_matrix,
+        light_to_world: uniform.background_to_world,
+        distribution: Distribution2D::new(
+            background_distribution,
+            uniform.background_distribution_width,
+            uniform.background_distribution_height,
+        ),
+    };
+
+    let add_image = |i: u32, v: Vec3A| {
+        let pos = uvec2(launch_id.x, launch_size.y - 1 - launch_id.y).extend(i);
+        let prev: Vec4 = image.read(pos);
+
+        unsafe {
+            image.write(pos, prev + v.extend(0.0));
+        }
+    };
+
+    let rand_seed = (launch_id.y * launch_size.x + launch_id.x) ^ constants.seed;
+    let mut rng = DefaultRng::new(rand_seed);
+
+    let u = (launch_id.x as f32 + rng.next_f32()) / (launch_size.x - 1) as f32;
+    let v = (launch_id.y as f32 + rng.next_f32()) / (launch_size.y - 1) as f32;
+
+    let cull_mask = 0xff;
+    let tmin = 0.001;
+    let tmax = 100000.0;
+
+    let mut bsdf = Bsdf::default();
+
+    let (mut ray, camera_weight) =
+        uniform
+            .camera
+            .get_ray(vec2(u, v), uniform.camera_to_world, &mut rng);
+
+    let mut color = camera_weight * vec3a(1.0, 1.0, 1.0);
+
+    let sample_all_lights = uniform.sample_all_lights != 0;
+
+    // The last scattered direction, weighs the lights found by it against sampling them
+    let mut last_pdf = 0.0;
+    let mut last_specular = true;
+    let mut last_position = Vec3A::ZERO;
+    let mut last_normal = Vec3A::ZERO;
+    let mut last_infinite_light_mis = false;
+
+    let mut i = 0;
+    loop {
+        *payload = RayPayload::default();
+        unsafe {
+            tlas_main.trace_ray(
+                RayFlags::OPAQUE,
+                cull_mask,
+                0,
+                0,
+                0,
+                ray.origin,
+                tmin,
+                ray.direction,
+                tmax,
+                payload,
+            );
+        }
+
+        if payload.is_miss != 0 {
+            let weight = if last_infinite_light_mis {
+                power_heuristic(1.0, last_pdf, 1.0, infinite_light.pdf(ray.direction))
+            } else {
+                1.0
+            };
+            add_image(0, color * payload.position * weight);
+            break;
+        }
+
+        let wo = -ray.direction.normalize();
+        let normal = payload.normal.normalize();
+        let position = payload.position;
+        let uv = payload.uv;
+        let index = unsafe { index_data.index_unchecked(payload.index as usize) };
+        let material = unsafe { materials.index_unchecked(index.material_index as usize) };
+        let area_light = unsafe { area_lights.index_unchecked(index.area_light_index as usize) };
+
+        if !area_light.is_null() {
+            let weight = if last_specular {
+                1.0
+            } else {
+                let light_pdf = emit_pdf(
+                    tlas_emit,
+                    last_position,
+                    last_normal,
+                    ray.direction,
+                    payload_pdf,
+                );
+                let light_pdf = if sample_all_lights {
+                    unpicked_emit_pdf(
+                        light_bvh,
+                        last_position,
+                        last_normal,
+                        index.emit_bit_trail,
+                        light_pdf,
+                    )
+                } else {
+                    light_pdf
+                };
+                power_heuristic(1.0, last_pdf, 1.0, light_pdf)
+            };
+            add_image(0, color * area_light.emit(wo, normal) * weight);
+        }
+
+        // The light found by a non-specular bounce was all it was traced for
+        if !last_specular {
+            break;
+        }
+
+        bsdf.clear(normal, Onb::from_w(normal));
+        material.compute_bsdf(&mut bsdf, uv, textures, images);
+
+        if i == 0 {
+            add_image(1, normal);
+            add_image(2, material.albedo(uv, textures, images));
+        }
+
+        if i >= uniform.max_depth {
+            break;
+        }
+
+        let non_specular = bsdf.contains(BxdfKind::DIFFUSE);
+
+        // Every light, or the unbounded ones and one picked from the light BVH
+        let lights_count = if sample_all_lights {
+            uniform.lights_len
+        } else {
+            uniform.unbounded_lights_len + 1
+        };
+        let mut l = 0;
+        while l < lights_count {
+            let (light_index, light_weight) = if sample_all_lights {
+                (l, 1.0)
+            } else {
+                pick_light(uniform, light_bvh, l, position, normal, &mut rng)
+            };
+            if light_weight > 0.0 {
+                let (target, t_max) =
+                    unsafe { lights.index_unchecked(light_index as usize) }.ray_target(position);
+                let wi = (target - position).normalize();
+
+                *payload = RayPayload::default();
+                unsafe {
+                    tlas_main.trace_ray(
+                        RayFlags::empty(),
+                        cull_mask,
+                        0,
+                        0,
+                        0,
+                        position,
+                        tmin,
+                        wi,
+                        t_max,
+                        payload,
+                    );
+                }
+
+                if payload.is_miss != 0 {
+                    add_image(
+                        0,
+                        color
+                            * bsdf.f(wo, wi)
+                            * wi.dot(normal).abs()
+                            * unsafe { lights.index_unchecked(light_index as usize) }
+                                .color(position, textures, images)
+                            * light_weight,
+                    );
+                }
+            }
+            l += 1;
+        }
+
+        let sample_infinite_light = infinite_light.is_samplable() && non_specular;
+        if sample_infinite_light {
+            let (wi, light_pdf) = infinite_light.sample(&mut rng);
+
+            if light_pdf > 0.0 {
+                *payload = RayPayload::default();
+                unsafe {
+                    tlas_main.trace_ray(
+                        RayFlags::empty(),
+                        cull_mask,
+                        0,
+                        0,
+                        0,
+                        position,
+                        tmin,
+                        wi,
+                        tmax,
+                        payload,
+                    );
+                }
+
+                if payload.is_miss != 0 {
+                    let weight = power_heuristic(1.0, light_pdf, 1.0, bsdf.pdf(wo, wi));
+
+                    add_image(
+                        0,
+                        color * bsdf.f(wo, wi) * wi.dot(normal).abs() * payload.position * weight
+                            / light_pdf,
+                    );
+                }
+            }
+        }
+        last_infinite_light_mis = sample_infinite_light;
+
+        if non_specular {
+            let emitters_count = if sample_all_lights {
+                uniform.emit_object_len
+            } else {
+                uniform.emit_object_len.min(1)
+            };
+            let mut e = 0;
+            while e < emitters_count {
+                let (emit_index, emit_pmf) = if sample_all_lights {
+                    (e, 1.0)
+                } else {
+                    LightBvh::new(light_bvh, 0).sample(position, normal, rng.next_f32())
+                };
+
+                if emit_pmf > 0.0 {
+                    let emit_object = unsafe { emit_objects.index_unchecked(emit_index as usize) };
+                    let target = emit_object.sample(
+                        position,
+                        indices,
+                        vertices,
+                        emit_distribution,
+                        &mut rng,
+                    );
+                    let wi = (target - position).normalize();
+
+                    *payload = RayPayload::default();
+                    unsafe {
+                        tlas_main.trace_ray(
+                            RayFlags::OPAQUE,
+                            cull_mask,
+                            0,
+                            0,
+                            0,
+                            position,
+                            tmin,
+                            wi,
+                            tmax,
+                            payload,
+                        );
+                    }
+
+                    // Visible unless something is in front of the sampled point
+                    if payload.is_miss == 0 && payload.t > 0.999 * target.distance(position) {
+                        let hit = unsafe { index_data.index_unchecked(payload.index as usize) };
+                        let emit =
+                            unsafe { area_lights.index_unchecked(hit.area_light_index as usize) }
+                                .emit(-wi, payload.normal.normalize());
+
+                        let light_pdf = emit_pdf(tlas_emit, position, normal, wi, payload_pdf);
+                        let light_pdf = if sample_all_lights {
+                            unpicked_emit_pdf(
+                                light_bvh,
+                                position,
+                                normal,
+                                hit.emit_bit_trail,
+                                light_pdf,
+                            )
+                        } else {
+                            light_pdf
+                        };
+                        if emit != Vec3A::ZERO && light_pdf > 0.0 {
+                            let weight = power_heuristic(1.0, light_pdf, 1.0, bsdf.pdf(wo, wi));
+
+                            add_image(
+                                0,
+                                color * bsdf.f(wo, wi) * wi.dot(normal).abs() * emit * weight
+                                    / light_pdf,
+                            );
+                        }
+                    }
+                }
+                e += 1;
+            }
+        }
+
+        let sampled_f = bsdf.sample_f(wo, &mut rng);
+
+        if sampled_f.pdf < 1e-5 {
+            break;
+        }
+        last_pdf = sampled_f.pdf;
+        last_specular = sampled_f.is_specular;
+        last_position = position;
+        last_normal = normal;
+
+        color *= sampled_f.f * normal.dot(sampled_f.wi).abs() / sampled_f.pdf;
+        ray = Ray {
+            origin: position,
+            direction: sampled_f.wi,
+        };
+
+        if color == Vec3A::ZERO {
+            break;
+        }
+        i += 1;
+    }
+}
+
+/// Light subpaths connected to the camera land on any pixel,
+/// so they are accumulated in `splat` and moved into the image by the pixel's own invocation.
+#[spirv(ray_generation)]
+#[allow(clippy::too_many_arguments)]
+pub fn main_ray_generation_bdpt(
+    #[spirv(launch_id)] launch_id: UVec3,
+    #[spirv(launch_size)] launch_size: UVec3,
+    #[spirv(push_constant)] constants: &PushConstants,
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] uniform: &Uniform,
+    #[spirv(descriptor_set = 0, binding = 1)] tlases: &RuntimeArray<AccelerationStructure>,
+    #[spirv(descriptor_set = 0, binding = 2)] image: &Image!(2D, format=rgba32f, sampled=false, arrayed=true),
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] lights: &[EnumLight],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] area_lights: &[EnumAreaLight],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] emit_objects: &[EnumSurfaceSample],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] materials: &[EnumMaterial],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 7)] textures: &[EnumTexture],
+    #[spirv(descriptor_set = 0, binding = 8)] images: &RuntimeArray<InputImage>,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 9)] index_data: &[IndexData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 10)] indices: &[u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 11)] vertices: &[Vertex],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 13)] background_distribution: &[f32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 14)] emit_distribution: &[f32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 16)] splat: &mut [u32],
+    #[spirv(ray_payload)] payload: &mut RayPayload,
+) {
+    let tlas_main = unsafe { tlases.index(0) };
+
+    let add_image = |i: u32, v: Vec3A| {
+        let pos = uvec2(launch_id.x, launch_size.y - 1 - launch_id.y).extend(i);
+        let prev: Vec4 = image.read(pos);
+
+        unsafe {
+            image.write(pos, prev + v.extend(0.0));
+        }
+    };
+
+    // Fixed point radiance splatted to this pixel since the last flush
+    let pixel = 3 * (launch_id.y * launch_size.x + launch_id.x) as usize;
+    let mut take_splat = |i: usize| {
+        let value = unsafe {
+            atomic_exchange::<u32, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+                splat.index_unchecked_mut(i),
+                0,
+            )
+        };
+        value as f32 / SPLAT_SCALE
+    };
+    let splatted = vec3a(
+        take_splat(pixel),
+        take_splat(pixel + 1),
+        take_splat(pixel + 2),
+    );
+    add_image(0, splatted);
+
+    // A last launch only moves the remaining splats into the image
+    if constants.pass != 0 {
+        return;
+    }
+
+    let rand_seed = (launch_id.y * launch_size.x + launch_id.x) ^ constants.seed;
+    let mut rng = DefaultRng::new(rand_seed);
+
+    let mut add_splat = |st: Vec2, v: Vec3A, rng: &mut DefaultRng| {
+        let x = ((st.x * (launch_size.x - 1) as f32) as u32).min(launch_size.x - 1);
+        let y = ((st.y * (launch_size.y - 1) as f32) as u32).min(launch_size.y - 1);
+        let index = 3 * (y * launch_size.x + x) as usize;
+
+        // Rounding stochastically keeps the sum unbiased
+        let mut c = 0;
+        while c < 3 {
+            let value = match c {
+                0 => v.x,
+                1 => v.y,
+                _ => v.z,
+            };
+            unsafe {
+                atomic_i_add::<u32, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+                    splat.index_unchecked_mut(index + c),
+                    (value * SPLAT_SCALE + rng.next_f32()) as u32,
+                );
+            }
+            c += 1;
+        }
+    };
+
+    let u = (launch_id.x as f32 + rng.next_f32()) / (launch_size.x - 1) as f32;
+    let v = (launch_id.y as f32 + rng.next_f32()) / (launch_size.y - 1) as f32;
+
+    let (ray, camera_weight) =
+        uniform
+            .camera
+            .get_ray(vec2(u, v), uniform.camera_to_world, &mut rng);
+
+    let bdpt = Bdpt {
+        uniform,
+        world_to_camera: uniform.camera_to_world.inverse(),
+        infinite_light: InfiniteLight {
+            world_to_light: uniform.background_matrix,
+            light_to_world: uniform.background_to_world,
+            distribution: Distribution2D::new(
+                background_distribution,
+                uniform.background_distribution_width,
+                uniform.background_distribution_height,
+            ),
+        },
+        lights,
+        area_lights,
+        emit_objects,
+        materials,
+        textures,
+        images,
+        index_data,
+        indices,
+        vertices,
+        emit_distribution,
+    };
+
+    let max_depth = uniform.max_depth.min(MAX_VERTICES as u32 - 2) as usize;
+    let mut bsdf = Bsdf::default();
+    let mut camera_path = [PathVertex::default(); MAX_VERTICES];
+    let mut light_path = [PathVertex::default(); MAX_VERTICES];
+
+    let n_camera = bdpt.generate_camera_subpath(
+        tlas_main,
+        &mut camera_path,
+        max_depth + 2,
+        ray,
+        camera_weight,
+        &mut bsdf,
+        payload,
+        &mut rng,
+    );
+    let n_light = bdpt.generate_light_subpath(
+        tlas_main,
+        &mut light_path,
+        max_depth + 1,
+        &mut bsdf,
+        payload,
+        &mut rng,
+    );
+
+    if n_camera > 1 {
+        let first_hit = unsafe { camera_path.index_unchecked(1) };
+        if first_hit.kind == VertexKind::Surface {
+            let index = unsafe { index_data.index_unchecked(first_hit.index as usize) };
+            add_image(1, first_hit.normal);
+            add_image(
+                2,
+                unsafe { materials.index_unchecked(index.material_index as usize) }.albedo(
+                    first_hit.uv,
+                    textures,
+                    images,
+                ),
+            );
+        }
+    }
+
+    // A single light sample doesn't need a light subpath, so s = 1 is tried without one
+    let mut color = Vec3A::ZERO;
+    let mut t = 1;
+    while t <= n_camera {
+        let mut s = 0;
+        while s <= n_light.max(1) {
+            // The camera alone and a light connected to the lens make no useful path
+            if s + t >= 2 && !(s == 1 && t == 1) && s + t - 2 <= max_depth {
+                let (l, st) = bdpt.connect(
+                    tlas_main,
+                    &light_path,
+                    &camera_path,
+                    s,
+                    t,
+                    &mut bsdf,
+                    payload,
+                    &mut rng,
+                );
+
+                if t == 1 {
+                    if l != Vec3A::ZERO {
+                        add_splat(st, l, &mut rng);
+                    }
+                } else {
+                    color += l;
+                }
+            }
+            s += 1;
+        }
+        t += 1;
+    }
+
+    add_image(0, color);
+}
+
+/// The pass in the push constants picks which step of an SPPM iteration to run.
+/// Direct lighting is estimated at the visible points, the photons carry the rest.
+#[spirv(ray_generation)]
+#[allow(clippy::too_many_arguments)]
+pub fn main_ray_generation_sppm(
+    #[spirv(launch_id)] launch_id: UVec3,
+    #[spirv(launch_size)] launch_size: UVec3,
+    #[spirv(push_constant)] constants: &PushConstants,
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] uniform: &Uniform,
+    #[spirv(descriptor_set = 0, binding = 1)] tlases: &RuntimeArray<AccelerationStructure>,
+    #[spirv(descriptor_set = 0, binding = 2)] image: &Image!(2D, format=rgba32f, sampled=false, arrayed=true),
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] lights: &[EnumLight],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] area_lights: &[EnumAreaLight],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] emit_objects: &[EnumSurfaceSample],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] materials: &[EnumMaterial],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 7)] textures: &[EnumTexture],
+    #[spirv(descriptor_set = 0, binding = 8)] images: &RuntimeArray<InputImage>,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 9)] index_data: &[IndexData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 10)] indices: &[u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 11)] vertices: &[Vertex],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 13)] background_distribution: &[f32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 14)] emit_distribution: &[f32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 15)] light_bvh: &[LightBvhNode],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 17)] sppm_pixels: &mut [SppmPixel],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 18)] sppm_grid: &mut [u32],
+    #[spirv(ray_payload)] payload: &mut RayPayload,
+    #[spirv(ray_payload)] payload_pdf: &mut RayPayloadPDF,
+) {
+    let tlas_main = unsafe { tlases.index(0) };
+    let tlas_emit = unsafe { tlases.index(1) };
+
+    let infinite_light = InfiniteLight {
+        world_to_light: uniform.background_matrix,
+        light_to_world: uniform.background_to_world,
+        distribution: Distribution2D::new(
+            background_distribution,
+            uniform.background_distribution_width,
+            uniform.background_distribution_height,
+        ),
+    };
+
+    // The grid has a bucket per pixel and the photon launch is layers of the film's size
+    let buckets = launch_size.x * launch_size.y;
+    let pixel_index = launch_id.y * launch_size.x + launch_id.x;
+
+    let mut bsdf = Bsdf::default();
+
+    if constants.pass == sppm::PASS_PHOTONS {
+        let photon_index = launch_id.z * buckets + pixel_index;
+        if photon_index >= uniform.sppm_photons_per_iteration {
+            return;
+        }
+        let mut rng = DefaultRng::new(photon_index ^ constants.seed);
+
+        let bdpt = Bdpt {
+            uniform,
+            world_to_camera: uniform.camera_to_world.inverse(),
+            infinite_light,
+            lights,
+            area_lights,
+            emit_objects,
+            materials,
+            textures,
+            images,
+            index_data,
+            indices,
+            vertices,
+            emit_distribution,
+        };
+        let photon_map = PhotonMap {
+            uniform,
+            materials,
+            textures,
+            images,
+            index_data,
+        };
+
+        // Photons leave the lights as BDPT's light subpaths do
+        let max_depth = uniform.max_depth.min(MAX_VERTICES as u32 - 1) as usize;
+        let mut path = [PathVertex::default(); MAX_VERTICES];
+        let n = bdpt.generate_light_subpath(
+            tlas_main,
+            &mut path,
+            max_depth + 1,
+            &mut bsdf,
+            payload,
+            &mut rng,
+        );
+
+        // The first hit is lit directly, which the camera pass estimates
+        let mut i = 2;
+        while i < n {
+            let vertex = unsafe { path.index_unchecked(i) };
+            photon_map.add_photon(
+                sppm_pixels,
+                sppm_grid,
+                buckets,
+                vertex.position,
+                vertex.wo,
+                vertex.beta,
+                &mut bsdf,
+                &mut rng,
+            );
+            i += 1;
+        }
+        return;
+    }
+
+    let add_image = |i: u32, v: Vec3A| {
+        let pos = uvec2(launch_id.x, launch_size.y - 1 - launch_id.y).extend(i);
+        let prev: Vec4 = image.read(pos);
 
-                            (wi, bsdf.pdf(wi, normal), bsdf.f(wo, wi))
-                        } else {
-                            let sampled_f = bsdf.sample_f(wo, &mut rng);
+        unsafe {
+            image.write(pos, prev + v.extend(0.0));
+        }
+    };
 
-                            (sampled_f.wi, sampled_f.pdf, sampled_f.f)
-                        };
+    if constants.pass == sppm::PASS_UPDATE {
+        unsafe { sppm_pixels.index_unchecked_mut(pixel_index as usize) }
+            .update(uniform.sppm_photons_per_iteration);
+        *unsafe { sppm_grid.index_unchecked_mut(pixel_index as usize) } = sppm::NONE;
+        return;
+    }
 
-                        ray = Ray {
-                            origin: position,
-                            direction: wi,
-                        };
+    if constants.pass == sppm::PASS_ESTIMATE {
+        add_image(
+            0,
+            unsafe { sppm_pixels.index_unchecked(pixel_index as usize) }
+                .estimate(uniform.sppm_photons_per_iteration),
+        );
+        return;
+    }
 
-                        *payload_pdf = RayPayloadPDF::default();
+    let rand_seed = pixel_index ^ constants.seed;
+    let mut rng = DefaultRng::new(rand_seed);
 
-                        unsafe {
-                            tlas_emit.trace_ray(
-                                RayFlags::OPAQUE,
-                                cull_mask,
-                                2,
-                                0,
-                                1,
-                                ray.origin,
-                                tmin,
-                                ray.direction,
-                                tmax,
-                                payload_pdf,
-                            );
-                        }
+    let u = (launch_id.x as f32 + rng.next_f32()) / (launch_size.x - 1) as f32;
+    let v = (launch_id.y as f32 + rng.next_f32()) / (launch_size.y - 1) as f32;
 
-                        color *= f * normal.dot(wi).abs();
+    let cull_mask = 0xff;
+    let tmin = 0.001;
+    let tmax = 100000.0;
 
-                        let pdf =
-                            0.5 * pdf + 0.5 * payload_pdf.pdf / uniform.emit_object_len as f32;
+    let (mut ray, camera_weight) =
+        uniform
+            .camera
+            .get_ray(vec2(u, v), uniform.camera_to_world, &mut rng);
 
-                        if pdf < 1e-5 {
-                            break;
-                        }
+    let mut color = camera_weight * vec3a(1.0, 1.0, 1.0);
 
-                        color /= pdf;
-                    } else {
-                        let sampled_f = bsdf.sample_f(wo, &mut rng);
+    // The last scattered direction, weighs the lights found by it against sampling them
+    let mut last_pdf = 0.0;
+    let mut last_specular = true;
+    let mut last_position = Vec3A::ZERO;
+    let mut last_normal = Vec3A::ZERO;
+    let mut last_infinite_light_mis = false;
+    let mut visible_point = false;
 
-                        if sampled_f.pdf < 1e-5 {
-                            break;
-                        }
+    let mut i = 0;
+    loop {
+        *payload = RayPayload::default();
+        unsafe {
+            tlas_main.trace_ray(
+                RayFlags::OPAQUE,
+                cull_mask,
+                0,
+                0,
+                0,
+                ray.origin,
+                tmin,
+                ray.direction,
+                tmax,
+                payload,
+            );
+        }
 
-                        color *= sampled_f.f * normal.dot(sampled_f.wi).abs() / sampled_f.pdf;
-                        ray = Ray {
-                            origin: position,
-                            direction: sampled_f.wi,
-                        };
+        if payload.is_miss != 0 {
+            let weight = if last_infinite_light_mis {
+                power_heuristic(1.0, last_pdf, 1.0, infinite_light.pdf(ray.direction))
+            } else {
+                1.0
+            };
+            add_image(0, color * payload.position * weight);
+            break;
+        }
+
+        let wo = -ray.direction.normalize();
+        let normal = payload.normal.normalize();
+        let position = payload.position;
+        let uv = payload.uv;
+        let instance_index = payload.index;
+        let index = unsafe { index_data.index_unchecked(payload.index as usize) };
+        let material = unsafe { materials.index_unchecked(index.material_index as usize) };
+        let area_light = unsafe { area_lights.index_unchecked(index.area_light_index as usize) };
+
+        if !area_light.is_null() {
+            let weight = if last_specular {
+                1.0
+            } else {
+                let light_pdf = emit_pdf(
+                    tlas_emit,
+                    last_position,
+                    last_normal,
+                    ray.direction,
+                    payload_pdf,
+                );
+                power_heuristic(1.0, last_pdf, 1.0, light_pdf)
+            };
+            add_image(0, color * area_light.emit(wo, normal) * weight);
+        }
+
+        // Past the visible point only the light found by its bsdf sample was left to add
+        if !last_specular || visible_point {
+            break;
+        }
+
+        bsdf.clear(normal, Onb::from_w(normal));
+        material.compute_bsdf(&mut bsdf, uv, textures, images);
+
+        if i == 0 {
+            add_image(1, normal);
+            add_image(2, material.albedo(uv, textures, images));
+        }
+
+        if i >= uniform.max_depth {
+            break;
+        }
+
+        let non_specular = bsdf.contains(BxdfKind::DIFFUSE);
+
+        if non_specular {
+            // The unbounded lights and one picked from the light BVH
+            let mut l = 0;
+            while l < uniform.unbounded_lights_len + 1 {
+                let (light_index, light_weight) =
+                    pick_light(uniform, light_bvh, l, position, normal, &mut rng);
+                if light_weight > 0.0 {
+                    let (target, t_max) = unsafe { lights.index_unchecked(light_index as usize) }
+                        .ray_target(position);
+                    let wi = (target - position).normalize();
+
+                    *payload = RayPayload::default();
+                    unsafe {
+                        tlas_main.trace_ray(
+                            RayFlags::empty(),
+                            cull_mask,
+                            0,
+                            0,
+                            0,
+                            position,
+                            tmin,
+                            wi,
+                            t_max,
+                            payload,
+                        );
+                    }
+
+                    if payload.is_miss != 0 {
+                        add_image(
+                            0,
+                            color
+                                * bsdf.f(wo, wi)
+                                * wi.dot(normal).abs()
+                                * unsafe { lights.index_unchecked(light_index as usize) }
+                                    .color(position, textures, images)
+                                * light_weight,
+                        );
                     }
-                } else {
-                    ray = Ray {
-                        origin: payload.position,
-                        direction: ray.direction,
-                    };
                 }
+                l += 1;
+            }
+        }
 
-                medium_index = if wo.dot(normal) < 0.0 {
-                    index.exterior_medium_index
-                } else {
-                    index.interior_medium_index
-                };
+        let sample_infinite_light = infinite_light.is_samplable() && non_specular;
+        if sample_infinite_light {
+            let (wi, light_pdf) = infinite_light.sample(&mut rng);
+
+            if light_pdf > 0.0 {
+                *payload = RayPayload::default();
+                unsafe {
+                    tlas_main.trace_ray(
+                        RayFlags::empty(),
+                        cull_mask,
+                        0,
+                        0,
+                        0,
+                        position,
+                        tmin,
+                        wi,
+                        tmax,
+                        payload,
+                    );
+                }
+
+                if payload.is_miss != 0 {
+                    let weight = power_heuristic(1.0, light_pdf, 1.0, bsdf.pdf(wo, wi));
+
+                    add_image(
+                        0,
+                        color * bsdf.f(wo, wi) * wi.dot(normal).abs() * payload.position * weight
+                            / light_pdf,
+                    );
+                }
+            }
+        }
+        last_infinite_light_mis = sample_infinite_light;
+
+        if non_specular && uniform.emit_object_len > 0 {
+            let (emit_index, emit_pmf) =
+                LightBvh::new(light_bvh, 0).sample(position, normal, rng.next_f32());
+
+            if emit_pmf > 0.0 {
+                let emit_object = unsafe { emit_objects.index_unchecked(emit_index as usize) };
+                let target =
+                    emit_object.sample(position, indices, vertices, emit_distribution, &mut rng);
+                let wi = (target - position).normalize();
+
+                *payload = RayPayload::default();
+                unsafe {
+                    tlas_main.trace_ray(
+                        RayFlags::OPAQUE,
+                        cull_mask,
+                        0,
+                        0,
+                        0,
+                        position,
+                        tmin,
+                        wi,
+                        tmax,
+                        payload,
+                    );
+                }
+
+                // Visible unless something is in front of the sampled point
+                if payload.is_miss == 0 && payload.t > 0.999 * target.distance(position) {
+                    let hit = unsafe { index_data.index_unchecked(payload.index as usize) };
+                    let emit =
+                        unsafe { area_lights.index_unchecked(hit.area_light_index as usize) }
+                            .emit(-wi, payload.normal.normalize());
+
+                    let light_pdf = emit_pdf(tlas_emit, position, normal, wi, payload_pdf);
+                    if emit != Vec3A::ZERO && light_pdf > 0.0 {
+                        let weight = power_heuristic(1.0, light_pdf, 1.0, bsdf.pdf(wo, wi));
+
+                        add_image(
+                            0,
+                            color * bsdf.f(wo, wi) * wi.dot(normal).abs() * emit * weight
+                                / light_pdf,
+                        );
+                    }
+                }
             }
         }
 
+        // Photons are gathered at the first surface that isn't a perfect mirror or glass
+        if non_specular {
+            let pixel = unsafe { sppm_pixels.index_unchecked_mut(pixel_index as usize) };
+            pixel.position = position;
+            pixel.normal = normal;
+            pixel.wo = wo;
+            pixel.uv = uv;
+            pixel.index = instance_index;
+            pixel.beta = color;
+            sppm::insert(uniform, sppm_pixels, sppm_grid, buckets, pixel_index);
+            visible_point = true;
+        }
+
+        let sampled_f = bsdf.sample_f(wo, &mut rng);
+
+        if sampled_f.pdf < 1e-5 {
+            break;
+        }
+        last_pdf = sampled_f.pdf;
+        last_specular = sampled_f.is_specular;
+        last_position = position;
+        last_normal = normal;
+
+        color *= sampled_f.f * normal.dot(sampled_f.wi).abs() / sampled_f.pdf;
+        ray = Ray {
+            origin: position,
+            direction: sampled_f.wi,
+        };
+
         if color == Vec3A::ZERO {
             break;
         }
+        i += 1;
+    }
+}
+
+/// The pass in the push constants picks the MLT step, the flush covers the film
+/// and the others launch a thread per chain.
+#[spirv(ray_generation)]
+#[allow(clippy::too_many_arguments)]
+pub fn main_ray_generation_mlt(
+    #[spirv(launch_id)] launch_id: UVec3,
+    #[spirv(launch_size)] launch_size: UVec3,
+    #[spirv(push_constant)] constants: &PushConstants,
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] uniform: &Uniform,
+    #[spirv(descriptor_set = 0, binding = 1)] tlases: &RuntimeArray<AccelerationStructure>,
+    #[spirv(descriptor_set = 0, binding = 2)] image: &Image!(2D, format=rgba32f, sampled=false, arrayed=true),
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] lights: &[EnumLight],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] area_lights: &[EnumAreaLight],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] emit_objects: &[EnumSurfaceSample],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] materials: &[EnumMaterial],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 7)] textures: &[EnumTexture],
+    #[spirv(descriptor_set = 0, binding = 8)] images: &RuntimeArray<InputImage>,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 9)] index_data: &[IndexData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 10)] indices: &[u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 11)] vertices: &[Vertex],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 13)] background_distribution: &[f32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 14)] emit_distribution: &[f32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 16)] splat: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 19)] mlt_chains: &mut [MltChain],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 20)] mlt_bootstrap: &mut [u32],
+    #[spirv(ray_payload)] payload: &mut RayPayload,
+) {
+    let tlas_main = unsafe { tlases.index(0) };
+
+    let bdpt = Bdpt {
+        uniform,
+        world_to_camera: uniform.camera_to_world.inverse(),
+        infinite_light: InfiniteLight {
+            world_to_light: uniform.background_matrix,
+            light_to_world: uniform.background_to_world,
+            distribution: Distribution2D::new(
+                background_distribution,
+                uniform.background_distribution_width,
+                uniform.background_distribution_height,
+            ),
+        },
+        lights,
+        area_lights,
+        emit_objects,
+        materials,
+        textures,
+        images,
+        index_data,
+        indices,
+        vertices,
+        emit_distribution,
+    };
+
+    let mut bsdf = Bsdf::default();
+    let depths = uniform.max_depth + 1;
+    let chain_index = launch_id.x;
+
+    if constants.pass == mlt::PASS_BOOTSTRAP {
+        let mut depth = 0;
+        while depth < depths {
+            let sample = chain_index * depths + depth;
+            let mut rng = DefaultRng::new(sample ^ constants.seed);
+            let mut sampler = PrimarySampleRng::random(&mut rng);
+
+            let (l, _) = mlt::radiance(&bdpt, tlas_main, depth, &mut sampler, &mut bsdf, payload);
+            unsafe {
+                *mlt_bootstrap
+                    .index_unchecked_mut(mlt::BOOTSTRAP_HEADER + (mlt::CHAINS + sample) as usize) =
+                    luminance(l).to_bits();
+            }
+            depth += 1;
+        }
+        return;
+    }
 
-        /*
-        // russian roulette
-        if i > 12 {
-            let rr_coin = frame_wide_rng.next_f32();
-            let continue_p = color.max_element();
+    if constants.pass == mlt::PASS_INIT {
+        // The bootstrap path is traced again from its seed
+        let sample =
+            *unsafe { mlt_bootstrap.index_unchecked(mlt::BOOTSTRAP_HEADER + chain_index as usize) };
+        let seed = *unsafe { mlt_bootstrap.index_unchecked(2) };
+        let mut rng = DefaultRng::new(sample ^ seed);
+        let mut sampler = PrimarySampleRng::random(&mut rng);
+        let depth = sample % depths;
+
+        let (l, st) = mlt::radiance(&bdpt, tlas_main, depth, &mut sampler, &mut bsdf, payload);
+        *unsafe { mlt_chains.index_unchecked_mut(chain_index as usize) } = MltChain {
+            x: sampler.x,
+            l,
+            st,
+            depth,
+        };
+        return;
+    }
 
-            if rr_coin > continue_p {
-                break;
-            } else {
-                color /= continue_p;
+    if constants.pass == mlt::PASS_FLUSH {
+        let add_image = |i: u32, v: Vec3A| {
+            let pos = uvec2(launch_id.x, launch_size.y - 1 - launch_id.y).extend(i);
+            let prev: Vec4 = image.read(pos);
+
+            unsafe {
+                image.write(pos, prev + v.extend(0.0));
+            }
+        };
+
+        // The splats sum every launch, the host averages the image over them
+        let scale = f32::from_bits(*unsafe { mlt_bootstrap.index_unchecked(0) }) / SPLAT_SCALE;
+        let launches = *unsafe { mlt_bootstrap.index_unchecked(1) } as f32;
+        let pixel = 3 * (launch_id.y * launch_size.x + launch_id.x) as usize;
+        let splatted = |i: usize| *unsafe { splat.index_unchecked(i) } as f32 * scale;
+        add_image(
+            0,
+            vec3a(splatted(pixel), splatted(pixel + 1), splatted(pixel + 2)),
+        );
+
+        // The auxiliary images see the pixel's center once
+        let u = (launch_id.x as f32 + 0.5) / (launch_size.x - 1) as f32;
+        let v = (launch_id.y as f32 + 0.5) / (launch_size.y - 1) as f32;
+        let mut rng = DefaultRng::new((launch_id.y * launch_size.x + launch_id.x) ^ constants.seed);
+        let (ray, camera_weight) =
+            uniform
+                .camera
+                .get_ray(vec2(u, v), uniform.camera_to_world, &mut rng);
+
+        let mut camera_path = [PathVertex::default(); MAX_VERTICES];
+        let n_camera = bdpt.generate_camera_subpath(
+            tlas_main,
+            &mut camera_path,
+            2,
+            ray,
+            camera_weight,
+            &mut bsdf,
+            payload,
+            &mut rng,
+        );
+        if n_camera > 1 {
+            let first_hit = unsafe { camera_path.index_unchecked(1) };
+            if first_hit.kind == VertexKind::Surface {
+                let index = unsafe { index_data.index_unchecked(first_hit.index as usize) };
+                add_image(1, first_hit.normal * launches);
+                add_image(
+                    2,
+                    unsafe { materials.index_unchecked(index.material_index as usize) }.albedo(
+                        first_hit.uv,
+                        textures,
+                        images,
+                    ) * launches,
+                );
             }
         }
-        */
+        return;
+    }
 
-        i += 1;
+    let mut rng = DefaultRng::new(chain_index ^ constants.seed);
+
+    let mut add_splat = |st: Vec2, v: Vec3A, rng: &mut DefaultRng| {
+        let x = ((st.x * (uniform.film_width - 1) as f32) as u32).min(uniform.film_width - 1);
+        let y = ((st.y * (uniform.film_height - 1) as f32) as u32).min(uniform.film_height - 1);
+        let index = 3 * (y * uniform.film_width + x) as usize;
+
+        // Rounding stochastically keeps the sum unbiased
+        let mut c = 0;
+        while c < 3 {
+            let value = match c {
+                0 => v.x,
+                1 => v.y,
+                _ => v.z,
+            };
+            unsafe {
+                atomic_i_add::<u32, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+                    splat.index_unchecked_mut(index + c),
+                    (value * SPLAT_SCALE + rng.next_f32()) as u32,
+                );
+            }
+            c += 1;
+        }
+    };
+
+    let chain = unsafe { mlt_chains.index_unchecked_mut(chain_index as usize) };
+
+    let mut x = chain.x;
+    let large_step = rng.next_f32() < uniform.mlt_large_step_probability;
+    mlt::mutate(&mut x, large_step, uniform.mlt_sigma, &mut rng);
+    let mut sampler = PrimarySampleRng::new(x, rng.next_u32());
+
+    let (l, st) = mlt::radiance(
+        &bdpt,
+        tlas_main,
+        chain.depth,
+        &mut sampler,
+        &mut bsdf,
+        payload,
+    );
+
+    // Both paths are splatted by their odds, normalized by the luminance the chain samples
+    let y_proposed = luminance(l);
+    let y_current = luminance(chain.l);
+    let accept = if y_current > 0.0 {
+        (y_proposed / y_current).min(1.0)
+    } else {
+        1.0
+    };
+
+    if accept > 0.0 && y_proposed > 0.0 {
+        add_splat(st, l * (accept / y_proposed), &mut rng);
+    }
+    if accept < 1.0 && y_current > 0.0 {
+        add_splat(chain.st, chain.l * ((1.0 - accept) / y_current), &mut rng);
+    }
+
+    if rng.next_f32() < accept {
+        chain.x = sampler.x;
+        chain.l = l;
+        chain.st = st;
     }
 }
 
@@ -954,11 +2616,19 @@ pub fn triangle_closest_hit(
 #[derive(Default)]
 pub struct RayPayloadPDF {
     pdf: f32,
+    /// Normal at the ray's origin for the light BVH, zero in media
+    normal: Vec3A,
+}
+
+impl RayPayloadPDF {
+    pub fn new(normal: Vec3A) -> Self {
+        Self { pdf: 0.0, normal }
+    }
 }
 
 #[spirv(miss)]
 pub fn main_miss_pdf(#[spirv(incoming_ray_payload)] out: &mut RayPayloadPDF) {
-    *out = RayPayloadPDF { pdf: 0.0 };
+    out.pdf = 0.0;
 }
 
 #[spirv(closest_hit)]
@@ -972,6 +2642,7 @@ pub fn triangle_closest_hit_pdf(
     #[spirv(storage_buffer, descriptor_set = 0, binding = 9)] index_data: &[IndexData],
     #[spirv(storage_buffer, descriptor_set = 0, binding = 10)] indices: &[u32],
     #[spirv(storage_buffer, descriptor_set = 0, binding = 11)] vertices: &[Vertex],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 15)] light_bvh: &[LightBvhNode],
     #[spirv(primitive_id)] primitive_id: u32,
     #[spirv(instance_custom_index)] instance_custom_index: u32,
     #[spirv(incoming_ray_payload)] out: &mut RayPayloadPDF,
@@ -1003,21 +2674,6 @@ pub fn triangle_closest_hit_pdf(
 
     let nrm = (v1.position - v0.position).cross(v2.position - v0.position);
 
-    let p0 = v0.position.x * object_to_world.x
-        + v0.position.y * object_to_world.y
-        + v0.position.z * object_to_world.z
-        + object_to_world.w;
-
-    let p1 = v1.position.x * object_to_world.x
-        + v1.position.y * object_to_world.y
-        + v1.position.z * object_to_world.z
-        + object_to_world.w;
-
-    let p2 = v2.position.x * object_to_world.x
-        + v2.position.y * object_to_world.y
-        + v2.position.z * object_to_world.z
-        + object_to_world.w;
-
     let hit_pos = pos.x * object_to_world.x
         + pos.y * object_to_world.y
         + pos.z * object_to_world.z
@@ -1030,37 +2686,41 @@ pub fn triangle_closest_hit_pdf(
     )
     .normalize();
 
-    let ab = p1 - p0;
-    let ac = p2 - p0;
-
-    let area = 0.5 * ab.cross(ac).length();
     let distance_squared = (world_ray_origin - hit_pos).length_squared();
     // let cosine = (-world_ray_direction).normalize().dot(normal).abs();
     // Same Value
     let cosine = world_ray_direction.normalize().dot(normal).abs();
 
-    *out = RayPayloadPDF {
-        pdf: distance_squared / (cosine * area) / index_data.primitive_count as f32,
-    };
+    let pmf =
+        LightBvh::new(light_bvh, 0).pmf(world_ray_origin, out.normal, index_data.emit_bit_trail);
+    out.pdf = pmf * distance_squared / (cosine * index_data.emit_area);
 }
 
 #[spirv(closest_hit)]
 pub fn sphere_closest_hit_pdf(
     #[spirv(object_to_world)] object_to_world: Affine3,
     #[spirv(world_ray_origin)] world_ray_origin: Vec3A,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 9)] index_data: &[IndexData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 15)] light_bvh: &[LightBvhNode],
+    #[spirv(instance_custom_index)] instance_custom_index: u32,
     #[spirv(incoming_ray_payload)] out: &mut RayPayloadPDF,
 ) {
-    // TODO
-    let radius =
-        (object_to_world.x.x.abs() + object_to_world.y.y.abs() + object_to_world.z.z.abs()) / 3.0;
-    let center = object_to_world.w;
+    let index_data = unsafe { index_data.index_unchecked(instance_custom_index as usize) };
 
-    let cos_theta_max = (1.0 - radius * radius / (center - world_ray_origin).length_squared())
-        .max(0.0)
-        .sqrt();
-    let solid_angle = 2.0 * PI * (1.0 - cos_theta_max);
+    // Spheres are unit spheres scaled uniformly, any column's length is the radius
+    let radius = object_to_world.x.length();
+    let center = object_to_world.w;
+    let distance_squared = (center - world_ray_origin).length_squared();
 
-    *out = RayPayloadPDF {
-        pdf: 1.0 / solid_angle,
+    // Cone sampled from outside, uniform from inside like `Sphere::sample`
+    let solid_angle = if distance_squared <= radius * radius {
+        4.0 * PI
+    } else {
+        let cos_theta_max = (1.0 - radius * radius / distance_squared).max(0.0).sqrt();
+        2.0 * PI * (1.0 - cos_theta_max)
     };
+
+    let pmf =
+        LightBvh::new(light_bvh, 0).pmf(world_ray_origin, out.normal, index_data.emit_bit_trail);
+    out.pdf = pmf / solid_angle;
 }
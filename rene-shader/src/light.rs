@@ -1,8 +1,31 @@
-use spirv_std::glam::{Vec3A, Vec4, Vec4Swizzles};
+use core::f32::consts::{FRAC_1_PI, PI};
+#[allow(unused_imports)]
+use spirv_std::num_traits::Float;
+use spirv_std::{
+    arch::IndexUnchecked,
+    glam::{uvec4, vec2, vec3a, Mat4, UVec4, Vec2, Vec3A, Vec4, Vec4Swizzles},
+    RuntimeArray,
+};
+
+use crate::{
+    asm::f32_clamp,
+    distribution::Distribution2D,
+    math::{equal_area_sphere_to_square, sphere_uv},
+    rand::Rng,
+    texture::EnumTexture,
+    InputImage,
+};
+#[cfg(not(target_arch = "spirv"))]
+use crate::{light_bvh::LightBounds, math::luminance};
 
 pub trait Light {
     fn ray_target(&self, position: Vec3A) -> (Vec3A, f32);
-    fn color(&self, position: Vec3A) -> Vec3A;
+    fn color(
+        &self,
+        position: Vec3A,
+        textures: &[EnumTexture],
+        images: &RuntimeArray<InputImage>,
+    ) -> Vec3A;
 }
 
 #[derive(Clone, Copy, Default)]
@@ -11,6 +34,10 @@ pub trait Light {
 pub struct EnumLightData {
     v0: Vec4,
     v1: Vec4,
+    v2: Vec4,
+    /// World to light space
+    matrix: Mat4,
+    u0: UVec4,
 }
 
 #[derive(Clone, Copy)]
@@ -18,6 +45,10 @@ pub struct EnumLightData {
 #[repr(u32)]
 enum LightType {
     Distant,
+    Point,
+    Spot,
+    Goniometric,
+    Projection,
 }
 
 #[derive(Clone, Copy)]
@@ -34,6 +65,105 @@ impl EnumLight {
             data: Distant::new_data(from, to, color),
         }
     }
+
+    pub fn new_point(from: Vec3A, intensity: Vec3A) -> Self {
+        Self {
+            t: LightType::Point,
+            data: Point::new_data(from, intensity),
+        }
+    }
+
+    /// Angles are in radians, measured from the spot axis.
+    pub fn new_spot(
+        from: Vec3A,
+        to: Vec3A,
+        intensity: Vec3A,
+        cone_angle: f32,
+        cone_delta_angle: f32,
+    ) -> Self {
+        Self {
+            t: LightType::Spot,
+            data: Spot::new_data(from, to, intensity, cone_angle, cone_delta_angle),
+        }
+    }
+
+    /// `texture` holds the intensity distribution, lat-long or pbrt-v4's equal-area square.
+    pub fn new_goniometric(
+        light_to_world: Mat4,
+        intensity: Vec3A,
+        texture: u32,
+        equal_area: bool,
+    ) -> Self {
+        Self {
+            t: LightType::Goniometric,
+            data: Goniometric::new_data(light_to_world, intensity, texture, equal_area),
+        }
+    }
+
+    /// `fov` is in radians and spans the shorter axis of the image of `aspect_ratio`.
+    pub fn new_projection(
+        light_to_world: Mat4,
+        intensity: Vec3A,
+        texture: u32,
+        fov: f32,
+        aspect_ratio: f32,
+    ) -> Self {
+        Self {
+            t: LightType::Projection,
+            data: Projection::new_data(light_to_world, intensity, texture, fov, aspect_ratio),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "spirv"))]
+impl EnumLight {
+    /// Bounds for the light BVH, `None` for lights without a position.
+    pub fn bounds(&self) -> Option<LightBounds> {
+        let from = Vec3A::from(self.data.v0.xyz());
+        let intensity = luminance(self.data.v1.xyz().into());
+
+        match self.t {
+            LightType::Distant => None,
+            LightType::Point | LightType::Goniometric => {
+                Some(LightBounds::new_point(from, 4.0 * PI * intensity))
+            }
+            LightType::Spot => {
+                let spot = Spot { data: &self.data };
+                let cos_total_width = spot.cos_total_width();
+                let cos_falloff_start = spot.cos_falloff_start();
+                Some(LightBounds {
+                    phi: 2.0
+                        * PI
+                        * intensity
+                        * ((1.0 - cos_falloff_start) + 0.5 * (cos_falloff_start - cos_total_width)),
+                    w: self.data.v2.xyz().into(),
+                    cos_theta_o: cos_falloff_start,
+                    cos_theta_e: (cos_total_width.acos() - cos_falloff_start.acos()).cos(),
+                    ..LightBounds::new_point(from, 0.0)
+                })
+            }
+            LightType::Projection => {
+                let projection = Projection { data: &self.data };
+                let bounds = self.data.v2;
+                let corner = vec2(
+                    bounds.x.abs().max(bounds.z.abs()),
+                    bounds.y.abs().max(bounds.w.abs()),
+                ) * projection.tan_half_fov();
+                let cos_total_width = 1.0 / (1.0 + corner.length_squared()).sqrt();
+                Some(LightBounds {
+                    phi: 2.0 * PI * intensity * (1.0 - cos_total_width),
+                    w: self
+                        .data
+                        .matrix
+                        .inverse()
+                        .transform_vector3a(Vec3A::Z)
+                        .normalize(),
+                    cos_theta_o: cos_total_width,
+                    ..LightBounds::new_point(from, 0.0)
+                })
+            }
+        }
+    }
 }
 
 struct Distant<'a> {
@@ -45,6 +175,7 @@ impl<'a> Distant<'a> {
         EnumLightData {
             v0: (from - to).normalize().extend(0.0),
             v1: color.extend(0.0),
+            ..Default::default()
         }
     }
 }
@@ -54,21 +185,325 @@ impl<'a> Light for Distant<'a> {
         (position + Vec3A::from(self.data.v0.xyz()), 1e5)
     }
 
-    fn color(&self, _position: Vec3A) -> Vec3A {
+    fn color(
+        &self,
+        _position: Vec3A,
+        _textures: &[EnumTexture],
+        _images: &RuntimeArray<InputImage>,
+    ) -> Vec3A {
         self.data.v1.xyz().into()
     }
 }
 
+struct Point<'a> {
+    data: &'a EnumLightData,
+}
+
+impl<'a> Point<'a> {
+    pub fn new_data(from: Vec3A, intensity: Vec3A) -> EnumLightData {
+        EnumLightData {
+            v0: from.extend(0.0),
+            v1: intensity.extend(0.0),
+            ..Default::default()
+        }
+    }
+}
+
+impl<'a> Light for Point<'a> {
+    fn ray_target(&self, position: Vec3A) -> (Vec3A, f32) {
+        let from = Vec3A::from(self.data.v0.xyz());
+        (from, from.distance(position))
+    }
+
+    fn color(
+        &self,
+        position: Vec3A,
+        _textures: &[EnumTexture],
+        _images: &RuntimeArray<InputImage>,
+    ) -> Vec3A {
+        Vec3A::from(self.data.v1.xyz()) / Vec3A::from(self.data.v0.xyz()).distance_squared(position)
+    }
+}
+
+struct Spot<'a> {
+    data: &'a EnumLightData,
+}
+
+impl<'a> Spot<'a> {
+    pub fn new_data(
+        from: Vec3A,
+        to: Vec3A,
+        intensity: Vec3A,
+        cone_angle: f32,
+        cone_delta_angle: f32,
+    ) -> EnumLightData {
+        EnumLightData {
+            v0: from.extend(cone_angle.cos()),
+            v1: intensity.extend((cone_angle - cone_delta_angle).cos()),
+            v2: (to - from).normalize().extend(0.0),
+            ..Default::default()
+        }
+    }
+
+    fn cos_total_width(&self) -> f32 {
+        self.data.v0.w
+    }
+
+    fn cos_falloff_start(&self) -> f32 {
+        self.data.v1.w
+    }
+
+    /// pbrt-v3's quartic falloff from the edge of the cone to the start of the falloff
+    fn falloff(&self, w: Vec3A) -> f32 {
+        let cos_theta = w.dot(Vec3A::from(self.data.v2.xyz()));
+        if cos_theta < self.cos_total_width() {
+            return 0.0;
+        }
+        if cos_theta >= self.cos_falloff_start() {
+            return 1.0;
+        }
+
+        let delta = (cos_theta - self.cos_total_width())
+            / (self.cos_falloff_start() - self.cos_total_width());
+        (delta * delta) * (delta * delta)
+    }
+}
+
+impl<'a> Light for Spot<'a> {
+    fn ray_target(&self, position: Vec3A) -> (Vec3A, f32) {
+        let from = Vec3A::from(self.data.v0.xyz());
+        (from, from.distance(position))
+    }
+
+    fn color(
+        &self,
+        position: Vec3A,
+        _textures: &[EnumTexture],
+        _images: &RuntimeArray<InputImage>,
+    ) -> Vec3A {
+        let from = Vec3A::from(self.data.v0.xyz());
+        let w = (position - from).normalize();
+        Vec3A::from(self.data.v1.xyz()) * self.falloff(w) / from.distance_squared(position)
+    }
+}
+
+/// Looks up pbrt's image coordinates, whose origin is the first row of the image.
+fn lookup(
+    texture: u32,
+    st: Vec2,
+    textures: &[EnumTexture],
+    images: &RuntimeArray<InputImage>,
+) -> Vec3A {
+    unsafe { textures.index_unchecked(texture as usize) }.color(
+        textures,
+        images,
+        vec2(st.x, 1.0 - st.y),
+    )
+}
+
+struct Goniometric<'a> {
+    data: &'a EnumLightData,
+}
+
+impl<'a> Goniometric<'a> {
+    pub fn new_data(
+        light_to_world: Mat4,
+        intensity: Vec3A,
+        texture: u32,
+        equal_area: bool,
+    ) -> EnumLightData {
+        EnumLightData {
+            v0: light_to_world.transform_point3a(Vec3A::ZERO).extend(0.0),
+            v1: intensity.extend(0.0),
+            matrix: light_to_world.inverse(),
+            u0: uvec4(texture, equal_area as u32, 0, 0),
+            ..Default::default()
+        }
+    }
+
+    fn st(&self, w: Vec3A) -> Vec2 {
+        // The distribution's pole is +y of the light space
+        let w = self.data.matrix.transform_vector3a(w).normalize();
+        let w = vec3a(w.x, w.z, w.y);
+
+        if self.data.u0.y != 0 {
+            equal_area_sphere_to_square(w)
+        } else {
+            let theta = f32_clamp(w.z, -1.0, 1.0).acos();
+            let phi = w.y.atan2(w.x);
+            let phi = if phi < 0.0 { phi + 2.0 * PI } else { phi };
+            vec2(phi * 0.5 * FRAC_1_PI, theta * FRAC_1_PI)
+        }
+    }
+}
+
+impl<'a> Light for Goniometric<'a> {
+    fn ray_target(&self, position: Vec3A) -> (Vec3A, f32) {
+        let from = Vec3A::from(self.data.v0.xyz());
+        (from, from.distance(position))
+    }
+
+    fn color(
+        &self,
+        position: Vec3A,
+        textures: &[EnumTexture],
+        images: &RuntimeArray<InputImage>,
+    ) -> Vec3A {
+        let from = Vec3A::from(self.data.v0.xyz());
+        let scale = lookup(self.data.u0.x, self.st(position - from), textures, images);
+        Vec3A::from(self.data.v1.xyz()) * scale / from.distance_squared(position)
+    }
+}
+
+/// Projects the image through a frustum looking down +z of the light space.
+struct Projection<'a> {
+    data: &'a EnumLightData,
+}
+
+impl<'a> Projection<'a> {
+    pub fn new_data(
+        light_to_world: Mat4,
+        intensity: Vec3A,
+        texture: u32,
+        fov: f32,
+        aspect_ratio: f32,
+    ) -> EnumLightData {
+        let screen_bounds = if aspect_ratio > 1.0 {
+            Vec4::new(-aspect_ratio, -1.0, aspect_ratio, 1.0)
+        } else {
+            Vec4::new(-1.0, -1.0 / aspect_ratio, 1.0, 1.0 / aspect_ratio)
+        };
+
+        EnumLightData {
+            v0: light_to_world
+                .transform_point3a(Vec3A::ZERO)
+                .extend((fov * 0.5).tan()),
+            v1: intensity.extend(0.0),
+            v2: screen_bounds,
+            matrix: light_to_world.inverse(),
+            u0: uvec4(texture, 0, 0, 0),
+        }
+    }
+
+    fn tan_half_fov(&self) -> f32 {
+        self.data.v0.w
+    }
+
+    fn projection(
+        &self,
+        w: Vec3A,
+        textures: &[EnumTexture],
+        images: &RuntimeArray<InputImage>,
+    ) -> Vec3A {
+        const HITHER: f32 = 1e-3;
+
+        let w = self.data.matrix.transform_vector3a(w).normalize();
+        if w.z < HITHER {
+            return Vec3A::ZERO;
+        }
+
+        let p = vec2(w.x, w.y) / (w.z * self.tan_half_fov());
+        let bounds = self.data.v2;
+        if p.x < bounds.x || p.y < bounds.y || p.x > bounds.z || p.y > bounds.w {
+            return Vec3A::ZERO;
+        }
+
+        let st = (p - bounds.xy()) / (bounds.zw() - bounds.xy());
+        lookup(self.data.u0.x, st, textures, images)
+    }
+}
+
+impl<'a> Light for Projection<'a> {
+    fn ray_target(&self, position: Vec3A) -> (Vec3A, f32) {
+        let from = Vec3A::from(self.data.v0.xyz());
+        (from, from.distance(position))
+    }
+
+    fn color(
+        &self,
+        position: Vec3A,
+        textures: &[EnumTexture],
+        images: &RuntimeArray<InputImage>,
+    ) -> Vec3A {
+        let from = Vec3A::from(self.data.v0.xyz());
+        let scale = self.projection(position - from, textures, images);
+        Vec3A::from(self.data.v1.xyz()) * scale / from.distance_squared(position)
+    }
+}
+
 impl Light for EnumLight {
     fn ray_target(&self, position: Vec3A) -> (Vec3A, f32) {
         match self.t {
             LightType::Distant => Distant { data: &self.data }.ray_target(position),
+            LightType::Point => Point { data: &self.data }.ray_target(position),
+            LightType::Spot => Spot { data: &self.data }.ray_target(position),
+            LightType::Goniometric => Goniometric { data: &self.data }.ray_target(position),
+            LightType::Projection => Projection { data: &self.data }.ray_target(position),
         }
     }
 
-    fn color(&self, position: Vec3A) -> Vec3A {
+    fn color(
+        &self,
+        position: Vec3A,
+        textures: &[EnumTexture],
+        images: &RuntimeArray<InputImage>,
+    ) -> Vec3A {
         match self.t {
-            LightType::Distant => Distant { data: &self.data }.color(position),
+            LightType::Distant => Distant { data: &self.data }.color(position, textures, images),
+            LightType::Point => Point { data: &self.data }.color(position, textures, images),
+            LightType::Spot => Spot { data: &self.data }.color(position, textures, images),
+            LightType::Goniometric => {
+                Goniometric { data: &self.data }.color(position, textures, images)
+            }
+            LightType::Projection => {
+                Projection { data: &self.data }.color(position, textures, images)
+            }
         }
     }
 }
+
+/// Importance sampling of the infinite light's environment map by its luminance.
+/// The radiance itself comes from the miss shader.
+pub struct InfiniteLight<'a> {
+    pub world_to_light: Mat4,
+    pub light_to_world: Mat4,
+    pub distribution: Distribution2D<'a>,
+}
+
+impl<'a> InfiniteLight<'a> {
+    pub fn is_samplable(&self) -> bool {
+        !self.distribution.is_empty()
+    }
+
+    /// Returns a world space direction and its solid angle pdf.
+    pub fn sample(&self, rng: &mut impl Rng) -> (Vec3A, f32) {
+        let (st, map_pdf) = self
+            .distribution
+            .sample_continuous(vec2(rng.next_f32(), rng.next_f32()));
+
+        // Inverse of `sphere_uv`, the map's rows are along theta
+        let theta = st.y * PI;
+        let phi = st.x * 2.0 * PI;
+        let sin_theta = theta.sin();
+        let w = vec3a(sin_theta * phi.cos(), sin_theta * phi.sin(), theta.cos());
+
+        let pdf = if map_pdf == 0.0 || sin_theta == 0.0 {
+            0.0
+        } else {
+            map_pdf / (2.0 * PI * PI * sin_theta)
+        };
+
+        (self.light_to_world.transform_vector3a(w).normalize(), pdf)
+    }
+
+    pub fn pdf(&self, w: Vec3A) -> f32 {
+        let w = self.world_to_light.transform_vector3a(w).normalize();
+        let sin_theta = (1.0 - w.z * w.z).max(0.0).sqrt();
+        if sin_theta == 0.0 {
+            return 0.0;
+        }
+
+        let uv = sphere_uv(w);
+        self.distribution.pdf(vec2(uv.x, 1.0 - uv.y)) / (2.0 * PI * PI * sin_theta)
+    }
+}
@@ -0,0 +1,567 @@
+#[allow(unused_imports)]
+use spirv_std::num_traits::Float;
+use spirv_std::{
+    arch::IndexUnchecked,
+    glam::{UVec4, Vec3A, Vec4, Vec4Swizzles},
+};
+
+#[cfg(not(target_arch = "spirv"))]
+use spirv_std::glam::{uvec4, Quat};
+
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON;
+
+/// Node of a light bounding volume hierarchy like pbrt-v4's `BVHLightSampler`.
+/// The first child follows its parent, leaves hold a single light.
+#[derive(Clone, Copy, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[repr(C)]
+pub struct LightBvhNode {
+    /// xyz: minimum of the bounds, w: power of the lights
+    p_min: Vec4,
+    /// xyz: maximum of the bounds, w: cosine of the normal cone's spread
+    p_max: Vec4,
+    /// xyz: axis of the normal cone, w: cosine of the emission's spread beyond the normals
+    w: Vec4,
+    /// x: second child or light index, y: is leaf, z: is two sided
+    u: UVec4,
+}
+
+fn safe_sqrt(x: f32) -> f32 {
+    x.max(0.0).sqrt()
+}
+
+/// Cosine of the angle a minus b, clamped at zero.
+fn cos_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if cos_a > cos_b {
+        1.0
+    } else {
+        cos_a * cos_b + sin_a * sin_b
+    }
+}
+
+/// Sine of the angle a minus b, clamped at zero.
+fn sin_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if cos_a > cos_b {
+        0.0
+    } else {
+        sin_a * cos_b - cos_a * sin_b
+    }
+}
+
+impl LightBvhNode {
+    fn is_leaf(&self) -> bool {
+        self.u.y != 0
+    }
+
+    fn child_or_light_index(&self) -> u32 {
+        self.u.x
+    }
+
+    /// Conservative estimate of the lights' contribution at `p`.
+    /// `n` is the surface normal at `p`, zero in media.
+    fn importance(&self, p: Vec3A, n: Vec3A) -> f32 {
+        let phi = self.p_min.w;
+        if phi == 0.0 {
+            return 0.0;
+        }
+
+        let p_min = Vec3A::from(self.p_min.xyz());
+        let p_max = Vec3A::from(self.p_max.xyz());
+        let p_center = 0.5 * (p_min + p_max);
+
+        let d2 = p
+            .distance_squared(p_center)
+            .max(0.5 * p_min.distance(p_max));
+
+        let to_p = p - p_center;
+        let wi = if to_p == Vec3A::ZERO {
+            Vec3A::Z
+        } else {
+            to_p.normalize()
+        };
+
+        let mut cos_theta_w = Vec3A::from(self.w.xyz()).dot(wi);
+        if self.u.z != 0 {
+            cos_theta_w = cos_theta_w.abs();
+        }
+        let sin_theta_w = safe_sqrt(1.0 - cos_theta_w * cos_theta_w);
+
+        // Directions subtended by the bounding sphere of the bounds
+        let radius_squared = p_center.distance_squared(p_max);
+        let distance_squared = p.distance_squared(p_center);
+        let cos_theta_b = if distance_squared < radius_squared {
+            -1.0
+        } else {
+            safe_sqrt(1.0 - radius_squared / distance_squared)
+        };
+        let sin_theta_b = safe_sqrt(1.0 - cos_theta_b * cos_theta_b);
+
+        let cos_theta_o = self.p_max.w;
+        let sin_theta_o = safe_sqrt(1.0 - cos_theta_o * cos_theta_o);
+
+        // Smallest angle between the emitted directions and p
+        let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, cos_theta_o);
+        let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, cos_theta_o);
+        let cos_theta_p = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta_p <= self.w.w {
+            return 0.0;
+        }
+
+        let mut importance = phi * cos_theta_p / d2;
+
+        if n != Vec3A::ZERO {
+            let cos_theta_i = wi.dot(n).abs();
+            let sin_theta_i = safe_sqrt(1.0 - cos_theta_i * cos_theta_i);
+            importance *= cos_sub_clamped(sin_theta_i, cos_theta_i, sin_theta_b, cos_theta_b);
+        }
+
+        importance.max(0.0)
+    }
+}
+
+/// Tree of [`LightBvhNode`] whose root is at `root` in `nodes`,
+/// child indices are relative to it.
+pub struct LightBvh<'a> {
+    nodes: &'a [LightBvhNode],
+    root: usize,
+}
+
+impl<'a> LightBvh<'a> {
+    pub fn new(nodes: &'a [LightBvhNode], root: u32) -> Self {
+        Self {
+            nodes,
+            root: root as usize,
+        }
+    }
+
+    fn node(&self, index: usize) -> &LightBvhNode {
+        unsafe { self.nodes.index_unchecked(self.root + index) }
+    }
+
+    /// Picks a light by its importance at `p` with the normal `n`.
+    /// Returns the light index and its probability, zero if no light is picked.
+    pub fn sample(&self, p: Vec3A, n: Vec3A, u: f32) -> (u32, f32) {
+        let mut u = u;
+        let mut index = 0;
+        let mut pmf = 1.0;
+
+        loop {
+            let node = self.node(index);
+            if node.is_leaf() {
+                if index > 0 || node.importance(p, n) > 0.0 {
+                    return (node.child_or_light_index(), pmf);
+                }
+                return (0, 0.0);
+            }
+
+            let second = node.child_or_light_index() as usize;
+            let c0 = self.node(index + 1).importance(p, n);
+            let c1 = self.node(second).importance(p, n);
+            if c0 == 0.0 && c1 == 0.0 {
+                return (0, 0.0);
+            }
+
+            let p0 = c0 / (c0 + c1);
+            if u < p0 {
+                index += 1;
+                pmf *= p0;
+                u = (u / p0).min(ONE_MINUS_EPSILON);
+            } else {
+                index = second;
+                pmf *= 1.0 - p0;
+                u = ((u - p0) / (1.0 - p0)).min(ONE_MINUS_EPSILON);
+            }
+        }
+    }
+
+    /// Probability of [`LightBvh::sample`] picking the light at the end of `bit_trail`,
+    /// whose bit at each depth is set when the path takes the second child.
+    pub fn pmf(&self, p: Vec3A, n: Vec3A, bit_trail: u32) -> f32 {
+        let mut bit_trail = bit_trail;
+        let mut index = 0;
+        let mut pmf = 1.0;
+
+        loop {
+            let node = self.node(index);
+            if node.is_leaf() {
+                if index > 0 || node.importance(p, n) > 0.0 {
+                    return pmf;
+                }
+                return 0.0;
+            }
+
+            let second = node.child_or_light_index() as usize;
+            let c0 = self.node(index + 1).importance(p, n);
+            let c1 = self.node(second).importance(p, n);
+            if c0 == 0.0 && c1 == 0.0 {
+                return 0.0;
+            }
+
+            if bit_trail & 1 == 0 {
+                index += 1;
+                pmf *= c0 / (c0 + c1);
+            } else {
+                index = second;
+                pmf *= c1 / (c0 + c1);
+            }
+            bit_trail >>= 1;
+        }
+    }
+}
+
+/// Spatial and directional bounds of the emission of a light.
+#[cfg(not(target_arch = "spirv"))]
+#[derive(Clone, Copy, Debug)]
+pub struct LightBounds {
+    pub p_min: Vec3A,
+    pub p_max: Vec3A,
+    pub phi: f32,
+    /// Axis of the cone bounding the normals of the emitter
+    pub w: Vec3A,
+    pub cos_theta_o: f32,
+    /// Spread of the emission around the normals
+    pub cos_theta_e: f32,
+    pub two_sided: bool,
+}
+
+#[cfg(not(target_arch = "spirv"))]
+impl LightBounds {
+    /// Bounds emitting to every direction from `p`, like point lights.
+    pub fn new_point(p: Vec3A, phi: f32) -> Self {
+        Self {
+            p_min: p,
+            p_max: p,
+            phi,
+            w: Vec3A::Z,
+            cos_theta_o: -1.0,
+            cos_theta_e: 0.0,
+            two_sided: false,
+        }
+    }
+
+    fn centroid(&self) -> Vec3A {
+        0.5 * (self.p_min + self.p_max)
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        if self.phi == 0.0 {
+            return *other;
+        }
+        if other.phi == 0.0 {
+            return *self;
+        }
+
+        let (w, cos_theta_o) = cone_union(self.w, self.cos_theta_o, other.w, other.cos_theta_o);
+        Self {
+            p_min: self.p_min.min(other.p_min),
+            p_max: self.p_max.max(other.p_max),
+            phi: self.phi + other.phi,
+            w,
+            cos_theta_o,
+            cos_theta_e: self.cos_theta_e.min(other.cos_theta_e),
+            two_sided: self.two_sided || other.two_sided,
+        }
+    }
+
+    /// pbrt's surface area orientation heuristic along `dim`, `extent` is that of the parent.
+    fn cost(&self, extent: Vec3A, dim: usize) -> f32 {
+        use core::f32::consts::{FRAC_PI_2, PI};
+
+        let theta_o = self.cos_theta_o.clamp(-1.0, 1.0).acos();
+        let theta_e = self.cos_theta_e.clamp(-1.0, 1.0).acos();
+        let theta_w = (theta_o + theta_e).min(PI);
+        let sin_theta_o = safe_sqrt(1.0 - self.cos_theta_o * self.cos_theta_o);
+        let m_omega = 2.0 * PI * (1.0 - self.cos_theta_o)
+            + FRAC_PI_2
+                * (2.0 * theta_w * sin_theta_o
+                    - (theta_o - 2.0 * theta_w).cos()
+                    - 2.0 * theta_o * sin_theta_o
+                    + self.cos_theta_o);
+
+        let d = self.p_max - self.p_min;
+        let surface_area = 2.0 * (d.x * d.y + d.x * d.z + d.y * d.z);
+        let kr = extent.max_element() / extent[dim];
+
+        self.phi * m_omega * kr * surface_area
+    }
+
+    fn leaf(&self, light_index: u32) -> LightBvhNode {
+        LightBvhNode {
+            p_min: self.p_min.extend(self.phi),
+            p_max: self.p_max.extend(self.cos_theta_o),
+            w: self.w.extend(self.cos_theta_e),
+            u: uvec4(light_index, 1, self.two_sided as u32, 0),
+        }
+    }
+
+    fn interior(&self, second_child: u32) -> LightBvhNode {
+        LightBvhNode {
+            u: uvec4(second_child, 0, self.two_sided as u32, 0),
+            ..self.leaf(0)
+        }
+    }
+}
+
+/// Smallest cone containing both, as axis and cosine of the spread.
+#[cfg(not(target_arch = "spirv"))]
+pub fn cone_union(w_a: Vec3A, cos_a: f32, w_b: Vec3A, cos_b: f32) -> (Vec3A, f32) {
+    use core::f32::consts::PI;
+
+    let theta_a = cos_a.clamp(-1.0, 1.0).acos();
+    let theta_b = cos_b.clamp(-1.0, 1.0).acos();
+    let theta_d = w_a.angle_between(w_b);
+    if (theta_d + theta_b).min(PI) <= theta_a {
+        return (w_a, cos_a);
+    }
+    if (theta_d + theta_a).min(PI) <= theta_b {
+        return (w_b, cos_b);
+    }
+
+    let theta_o = 0.5 * (theta_a + theta_d + theta_b);
+    if theta_o >= PI {
+        return (w_a, -1.0);
+    }
+
+    let w_r = w_a.cross(w_b);
+    if w_r.length_squared() == 0.0 {
+        return (w_a, -1.0);
+    }
+
+    let rotation = Quat::from_axis_angle(w_r.normalize().into(), theta_o - theta_a);
+    (rotation * w_a, theta_o.cos())
+}
+
+/// Flattens the hierarchy over `lights`, pairs of the index stored in the leaf and the bounds.
+/// Returns the nodes and the bit trail of each light.
+#[cfg(not(target_arch = "spirv"))]
+pub fn build_light_bvh(lights: &[(u32, LightBounds)]) -> (Vec<LightBvhNode>, Vec<u32>) {
+    let mut nodes = Vec::new();
+    let mut bit_trails = vec![0; lights.len()];
+    if lights.is_empty() {
+        return (nodes, bit_trails);
+    }
+
+    let mut order: Vec<usize> = (0..lights.len()).collect();
+    build_recursive(lights, &mut order, 0, 0, &mut nodes, &mut bit_trails);
+
+    (nodes, bit_trails)
+}
+
+#[cfg(not(target_arch = "spirv"))]
+fn build_recursive(
+    lights: &[(u32, LightBounds)],
+    order: &mut [usize],
+    depth: u32,
+    bit_trail: u32,
+    nodes: &mut Vec<LightBvhNode>,
+    bit_trails: &mut [u32],
+) -> LightBounds {
+    const BUCKETS: usize = 12;
+
+    if order.len() == 1 {
+        let (light_index, bounds) = lights[order[0]];
+        bit_trails[order[0]] = bit_trail;
+        nodes.push(bounds.leaf(light_index));
+        return bounds;
+    }
+
+    let bounds = order
+        .iter()
+        .skip(1)
+        .fold(lights[order[0]].1, |b, &i| b.union(&lights[i].1));
+    let (centroid_min, centroid_max) = order.iter().fold(
+        (Vec3A::splat(f32::INFINITY), Vec3A::splat(f32::NEG_INFINITY)),
+        |(min, max), &i| {
+            let c = lights[i].1.centroid();
+            (min.min(c), max.max(c))
+        },
+    );
+    let extent = bounds.p_max - bounds.p_min;
+
+    // Best bucket split over all dimensions by the cost of the children
+    let mut best: Option<(f32, usize, usize)> = None;
+    // Keep the bit trails within 32 bits with balanced splits near the limit
+    let log2_len = usize::BITS - (order.len() - 1).leading_zeros();
+    if depth + log2_len < 31 {
+        for dim in 0..3 {
+            if centroid_max[dim] == centroid_min[dim] || extent[dim] == 0.0 {
+                continue;
+            }
+
+            let bucket = |i: usize| {
+                let t = (lights[i].1.centroid()[dim] - centroid_min[dim])
+                    / (centroid_max[dim] - centroid_min[dim]);
+                ((t * BUCKETS as f32) as usize).min(BUCKETS - 1)
+            };
+
+            let mut buckets: [Option<LightBounds>; BUCKETS] = [None; BUCKETS];
+            for &i in order.iter() {
+                let b = &mut buckets[bucket(i)];
+                *b = Some(b.map_or(lights[i].1, |b| b.union(&lights[i].1)));
+            }
+
+            for split in 1..BUCKETS {
+                let union = |buckets: &[Option<LightBounds>]| {
+                    buckets
+                        .iter()
+                        .flatten()
+                        .fold(None, |acc: Option<LightBounds>, b| {
+                            Some(acc.map_or(*b, |acc| acc.union(b)))
+                        })
+                };
+                if let (Some(b0), Some(b1)) = (union(&buckets[..split]), union(&buckets[split..])) {
+                    let cost = b0.cost(extent, dim) + b1.cost(extent, dim);
+                    if !matches!(best, Some((c, _, _)) if c <= cost) {
+                        best = Some((cost, dim, split));
+                    }
+                }
+            }
+        }
+    }
+
+    let mid = match best {
+        Some((_, dim, split)) => {
+            let t = |i: usize| {
+                (lights[i].1.centroid()[dim] - centroid_min[dim])
+                    / (centroid_max[dim] - centroid_min[dim])
+            };
+            let (mut left, mut right): (Vec<usize>, Vec<usize>) = order
+                .iter()
+                .partition(|&&i| (((t(i) * BUCKETS as f32) as usize).min(BUCKETS - 1)) < split);
+            let mid = left.len();
+            left.append(&mut right);
+            order.copy_from_slice(&left);
+            mid
+        }
+        None => {
+            let d = centroid_max - centroid_min;
+            let dim = if d.x >= d.y && d.x >= d.z {
+                0
+            } else if d.y >= d.z {
+                1
+            } else {
+                2
+            };
+            order.sort_by(|&a, &b| {
+                lights[a].1.centroid()[dim].total_cmp(&lights[b].1.centroid()[dim])
+            });
+            order.len() / 2
+        }
+    };
+
+    let node_index = nodes.len();
+    nodes.push(LightBvhNode::default());
+
+    let (left, right) = order.split_at_mut(mid);
+    let b0 = build_recursive(lights, left, depth + 1, bit_trail, nodes, bit_trails);
+    let second_child = nodes.len() as u32;
+    let b1 = build_recursive(
+        lights,
+        right,
+        depth + 1,
+        bit_trail | (1 << depth),
+        nodes,
+        bit_trails,
+    );
+
+    let bounds = b0.union(&b1);
+    nodes[node_index] = bounds.interior(second_child);
+    bounds
+}
+
+#[cfg(test)]
+mod test {
+    use spirv_std::glam::vec3a;
+
+    use super::*;
+
+    /// Point lights and quads about the xy plane, one sided quads face +z or -z.
+    fn lights(two_sided: bool) -> Vec<(u32, LightBounds)> {
+        (0..37)
+            .map(|i| {
+                let f = i as f32;
+                let p = vec3a((f * 1.7).sin() * 5.0, (f * 2.3).cos() * 3.0, f * 0.25);
+                let bounds = if i % 3 == 0 {
+                    LightBounds::new_point(p, 1.0 + f)
+                } else {
+                    LightBounds {
+                        p_min: p - vec3a(0.5, 0.5, 0.0),
+                        p_max: p + vec3a(0.5, 0.5, 0.0),
+                        phi: 0.5 * f,
+                        w: if i % 2 == 0 { Vec3A::Z } else { -Vec3A::Z },
+                        cos_theta_o: 1.0,
+                        cos_theta_e: 0.0,
+                        two_sided,
+                    }
+                };
+                (i * 10, bounds)
+            })
+            .collect()
+    }
+
+    /// Shading points and their normals, zero in media.
+    fn points() -> [(Vec3A, Vec3A); 3] {
+        [
+            (vec3a(0.0, 0.0, 4.1), Vec3A::ZERO),
+            (vec3a(1.0, -2.0, -3.0), Vec3A::Z),
+            (vec3a(20.0, 5.0, 2.1), -Vec3A::X),
+        ]
+    }
+
+    #[test]
+    fn test_pmf_sums_to_one() {
+        let lights = lights(true);
+        let (nodes, bit_trails) = build_light_bvh(&lights);
+        let bvh = LightBvh::new(&nodes, 0);
+
+        for (p, n) in points() {
+            let sum: f32 = bit_trails.iter().map(|&b| bvh.pmf(p, n, b)).sum();
+            assert!((sum - 1.0).abs() < 1e-4, "sum of pmfs {} at {}", sum, p);
+        }
+    }
+
+    #[test]
+    fn test_pmf_sum_bounded() {
+        // Subtrees facing away from p lose their share like in pbrt-v4
+        let lights = lights(false);
+        let (nodes, bit_trails) = build_light_bvh(&lights);
+        let bvh = LightBvh::new(&nodes, 0);
+
+        for (p, n) in points() {
+            let sum: f32 = bit_trails.iter().map(|&b| bvh.pmf(p, n, b)).sum();
+            assert!(
+                sum > 0.0 && sum < 1.0 + 1e-4,
+                "sum of pmfs {} at {}",
+                sum,
+                p
+            );
+        }
+    }
+
+    #[test]
+    fn test_sample_matches_pmf() {
+        let lights = lights(false);
+        let (nodes, bit_trails) = build_light_bvh(&lights);
+        let bvh = LightBvh::new(&nodes, 0);
+        let (p, n) = (vec3a(2.0, 1.0, 3.1), Vec3A::Y);
+
+        for i in 0..64 {
+            let (light_index, pmf) = bvh.sample(p, n, (i as f32 + 0.5) / 64.0);
+            if pmf == 0.0 {
+                continue;
+            }
+            let light = lights.iter().position(|l| l.0 == light_index).unwrap();
+            assert!((bvh.pmf(p, n, bit_trails[light]) - pmf).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_single_light() {
+        let lights = [(7, LightBounds::new_point(Vec3A::ZERO, 2.0))];
+        let (nodes, bit_trails) = build_light_bvh(&lights);
+        let bvh = LightBvh::new(&nodes, 0);
+
+        assert_eq!(bvh.sample(Vec3A::X, Vec3A::ZERO, 0.3), (7, 1.0));
+        assert_eq!(bvh.pmf(Vec3A::X, Vec3A::ZERO, bit_trails[0]), 1.0);
+    }
+}
@@ -3,9 +3,14 @@ use spirv_std::glam::{vec2, vec3a, Vec2, Vec3A};
 #[allow(unused_imports)]
 use spirv_std::num_traits::Float;
 
-use crate::rand::DefaultRng;
+use crate::rand::Rng;
 
-pub fn random_in_unit_sphere(rng: &mut DefaultRng) -> Vec3A {
+/// Y of CIE XYZ for linear sRGB.
+pub fn luminance(color: Vec3A) -> f32 {
+    0.212671 * color.x + 0.715160 * color.y + 0.072169 * color.z
+}
+
+pub fn random_in_unit_sphere(rng: &mut impl Rng) -> Vec3A {
     loop {
         let v = vec3a(
             rng.next_f32_range(-1.0, 1.0),
@@ -19,8 +24,7 @@ pub fn random_in_unit_sphere(rng: &mut DefaultRng) -> Vec3A {
     }
 }
 
-#[allow(dead_code)]
-pub fn random_in_hemisphere(normal: Vec3A, rng: &mut DefaultRng) -> Vec3A {
+pub fn random_in_hemisphere(normal: Vec3A, rng: &mut impl Rng) -> Vec3A {
     let v = random_in_unit_sphere(rng).normalize();
     if normal.dot(v) > 0.0 {
         v
@@ -29,20 +33,24 @@ pub fn random_in_hemisphere(normal: Vec3A, rng: &mut DefaultRng) -> Vec3A {
     }
 }
 
-pub fn random_in_unit_disk(rng: &mut DefaultRng) -> Vec3A {
-    loop {
-        let p = vec3a(
-            rng.next_f32_range(-1.0, 1.0),
-            rng.next_f32_range(-1.0, 1.0),
-            0.0,
-        );
-        if p.length_squared() < 1.0 {
-            break p;
-        }
+/// Shirley's concentric mapping, keeps strata intact unlike rejection sampling.
+pub fn concentric_sample_disk(rng: &mut impl Rng) -> Vec2 {
+    let u_offset = 2.0 * vec2(rng.next_f32(), rng.next_f32()) - vec2(1.0, 1.0);
+
+    if u_offset == Vec2::ZERO {
+        return Vec2::ZERO;
     }
+
+    let (theta, r) = if u_offset.x.abs() > u_offset.y.abs() {
+        (PI / 4.0 * (u_offset.y / u_offset.x), u_offset.x)
+    } else {
+        (PI / 2.0 - PI / 4.0 * (u_offset.x / u_offset.y), u_offset.y)
+    };
+
+    r * vec2(theta.cos(), theta.sin())
 }
 
-pub fn random_cosine_direction(rng: &mut DefaultRng) -> Vec3A {
+pub fn random_cosine_direction(rng: &mut impl Rng) -> Vec3A {
     let r1: f32 = rng.next_f32();
     let r2: f32 = rng.next_f32();
     let z = (1.0 - r2).sqrt();
@@ -55,7 +63,7 @@ pub fn random_cosine_direction(rng: &mut DefaultRng) -> Vec3A {
     vec3a(x, y, z)
 }
 
-pub fn random_to_sphere(radius: f32, distance_squared: f32, rng: &mut DefaultRng) -> Vec3A {
+pub fn random_to_sphere(radius: f32, distance_squared: f32, rng: &mut impl Rng) -> Vec3A {
     let r1 = rng.next_f32();
     let r2 = rng.next_f32();
     let z = 1.0 + r2 * ((1.0 - radius * radius / distance_squared).sqrt() - 1.0);
@@ -75,6 +83,31 @@ pub fn sphere_uv(point: Vec3A) -> Vec2 {
     vec2(phi * 0.5 * FRAC_1_PI, (theta - PI) * -FRAC_1_PI)
 }
 
+/// pbrt-v4's equal-area octahedral mapping of the unit sphere to the unit square.
+pub fn equal_area_sphere_to_square(d: Vec3A) -> Vec2 {
+    let x = d.x.abs();
+    let y = d.y.abs();
+    let z = d.z.abs();
+    let r = (1.0 - z).max(0.0).sqrt();
+    let a = x.max(y);
+    let b = x.min(y);
+    let b = if a == 0.0 { 0.0 } else { b / a };
+    let phi = b.atan() * 2.0 * FRAC_1_PI;
+    let phi = if x < y { 1.0 - phi } else { phi };
+
+    let v = phi * r;
+    let u = r - v;
+    let (u, v) = if d.z < 0.0 {
+        (1.0 - v, 1.0 - u)
+    } else {
+        (u, v)
+    };
+    let u = if d.x < 0.0 { -u } else { u };
+    let v = if d.y < 0.0 { -v } else { v };
+
+    vec2(0.5 * (u + 1.0), 0.5 * (v + 1.0))
+}
+
 pub trait IsNearZero {
     fn is_near_zero(&self) -> bool;
 }
@@ -95,3 +128,28 @@ pub fn coordinate_system(v1: Vec3A) -> (Vec3A, Vec3A) {
 
     (v2, v1.cross(v2))
 }
+
+pub fn refract(wi: Vec3A, n: Vec3A, etai_over_etat: f32) -> (bool, Vec3A) {
+    let cos_theta_i = n.dot(wi);
+    let sin2theta_i = (1.0 - cos_theta_i * cos_theta_i).max(0.0);
+    let sin2theta_t = etai_over_etat * etai_over_etat * sin2theta_i;
+
+    if sin2theta_t >= 1.0 {
+        return (false, Vec3A::ZERO);
+    }
+
+    let cos_theta_t = (1.0 - sin2theta_t).sqrt();
+
+    (
+        true,
+        etai_over_etat * -wi + (etai_over_etat * cos_theta_i - cos_theta_t) * n,
+    )
+}
+
+pub fn face_forward(v: Vec3A, v2: Vec3A) -> Vec3A {
+    if v.dot(v2) < 0.0 {
+        -v
+    } else {
+        v
+    }
+}
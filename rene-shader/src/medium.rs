@@ -1,13 +1,10 @@
 use core::f32::consts::PI;
 
+use spirv_std::glam::{vec3a, Vec3A, Vec4, Vec4Swizzles};
 #[allow(unused_imports)]
 use spirv_std::num_traits::Float;
-use spirv_std::{
-    arch::IndexUnchecked,
-    glam::{vec3a, Vec3A, Vec4, Vec4Swizzles},
-};
 
-use crate::{math::coordinate_system, rand::DefaultRng, Ray};
+use crate::{math::coordinate_system, rand::Rng, Ray};
 
 fn spherical_direction(
     sin_theta: f32,
@@ -20,10 +17,25 @@ fn spherical_direction(
     sin_theta * phi.cos() * x + sin_theta * phi.sin() * y + cos_theta * z
 }
 
+/// Component `c` of an RGB value, the hero channel of the spectral MIS.
+pub fn channel(v: Vec3A, c: u32) -> f32 {
+    match c {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+pub fn average(v: Vec3A) -> f32 {
+    (v.x + v.y + v.z) / 3.0
+}
+
 pub struct SampledMedium {
     pub sampled: bool,
     pub position: Vec3A,
-    pub tr: Vec3A,
+    /// Scales both the throughput and the channels' probabilities relative to the hero channel,
+    /// their ratio is the path's spectral MIS estimate
+    pub weight: Vec3A,
 }
 
 impl Default for SampledMedium {
@@ -31,16 +43,149 @@ impl Default for SampledMedium {
         SampledMedium {
             sampled: false,
             position: Vec3A::ZERO,
-            tr: vec3a(1.0, 1.0, 1.0),
+            weight: vec3a(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+/// Ratio tracking estimate of the transmittance along a ray.
+pub struct Transmittance {
+    pub tr: Vec3A,
+    /// Probabilities of the tracked collisions relative to the hero channel
+    pub r: Vec3A,
+}
+
+/// Tentative collisions along a ray against the majorant of its medium,
+/// pbrt-v4's `SampleT_maj` over a single majorant segment.
+/// Distances follow the hero channel's majorant.
+struct MajorantTracker {
+    origin: Vec3A,
+    direction: Vec3A,
+    t: f32,
+    t_max: f32,
+    sigma_maj: Vec3A,
+    channel: u32,
+}
+
+impl MajorantTracker {
+    fn new(ray: Ray, t_max: f32, sigma_maj: Vec3A, channel: u32) -> Self {
+        let length = ray.direction.length();
+        Self {
+            origin: ray.origin,
+            direction: ray.direction / length,
+            t: 0.0,
+            t_max: t_max * length,
+            sigma_maj,
+            channel,
         }
     }
+
+    /// Returns whether a collision comes before the end of the segment, its position
+    /// and the majorant transmittance since the previous one, or up to the end.
+    fn next(&mut self, rng: &mut impl Rng) -> (bool, Vec3A, Vec3A) {
+        let sigma = channel(self.sigma_maj, self.channel);
+        let t = if sigma > 0.0 {
+            self.t - (1.0 - rng.next_f32()).ln() / sigma
+        } else {
+            self.t_max
+        };
+
+        let collided = t < self.t_max;
+        let t = t.min(self.t_max);
+        let t_maj = (-self.sigma_maj * (t - self.t)).exp();
+        self.t = t;
+
+        (collided, self.origin + t * self.direction, t_maj)
+    }
 }
 
+/// Media give their coefficients against a majorant, the null-scattering estimators
+/// of pbrt-v4's volpath sample the collisions with spectral MIS over the RGB channels.
 pub trait Medium {
-    fn tr(&self, ray: Ray, t_max: f32) -> Vec3A;
-    fn sample(&self, ray: Ray, t_max: f32, rng: &mut DefaultRng) -> SampledMedium;
-    fn sample_p(&self, wo: Vec3A, rng: &mut DefaultRng) -> Vec3A;
+    /// Bound of the extinction over the whole medium
+    fn sigma_maj(&self) -> Vec3A;
+    fn sigma_a(&self, p: Vec3A) -> Vec3A;
+    fn sigma_s(&self, p: Vec3A) -> Vec3A;
+    fn sample_p(&self, wo: Vec3A, rng: &mut impl Rng) -> Vec3A;
     fn phase(&self, wo: Vec3A, wi: Vec3A) -> f32;
+
+    /// Delta tracking up to `t_max`: the hero channel `c` picks between absorption,
+    /// real and null scattering at each tentative collision.
+    /// A path absorbed on the way has a zero weight.
+    fn sample(&self, ray: Ray, t_max: f32, c: u32, rng: &mut impl Rng) -> SampledMedium {
+        let sigma_maj = self.sigma_maj();
+        let mut tracker = MajorantTracker::new(ray, t_max, sigma_maj, c);
+        let mut sampled = SampledMedium::default();
+
+        loop {
+            let (collided, position, t_maj) = tracker.next(rng);
+            if !collided {
+                sampled.weight *= t_maj / channel(t_maj, c);
+                break sampled;
+            }
+
+            let sigma_a = self.sigma_a(position);
+            let sigma_s = self.sigma_s(position);
+            let u = rng.next_f32() * channel(sigma_maj, c);
+
+            if u < channel(sigma_a, c) {
+                sampled.weight = Vec3A::ZERO;
+                break sampled;
+            } else if u < channel(sigma_a + sigma_s, c) {
+                let pdf = channel(t_maj, c) * channel(sigma_s, c);
+                sampled.sampled = true;
+                sampled.position = position;
+                sampled.weight *= t_maj * sigma_s / pdf;
+                break sampled;
+            } else {
+                let sigma_n = (sigma_maj - sigma_a - sigma_s).max(Vec3A::ZERO);
+                let pdf = channel(t_maj, c) * channel(sigma_n, c);
+                if pdf == 0.0 {
+                    sampled.weight = Vec3A::ZERO;
+                    break sampled;
+                }
+                sampled.weight *= t_maj * sigma_n / pdf;
+            }
+        }
+    }
+
+    /// Ratio tracking up to `t_max` with the hero channel `c` sampling the collisions,
+    /// with Russian roulette once the estimate gets small.
+    fn tr(&self, ray: Ray, t_max: f32, c: u32, rng: &mut impl Rng) -> Transmittance {
+        let sigma_maj = self.sigma_maj();
+        let mut tracker = MajorantTracker::new(ray, t_max, sigma_maj, c);
+        let mut tr = vec3a(1.0, 1.0, 1.0);
+        let mut r = vec3a(1.0, 1.0, 1.0);
+
+        loop {
+            let (collided, position, t_maj) = tracker.next(rng);
+            if !collided {
+                let pdf = channel(t_maj, c);
+                tr *= t_maj / pdf;
+                r *= t_maj / pdf;
+                break;
+            }
+
+            let sigma_n =
+                (sigma_maj - self.sigma_a(position) - self.sigma_s(position)).max(Vec3A::ZERO);
+            let pdf = channel(t_maj, c) * channel(sigma_maj, c);
+            tr *= t_maj * sigma_n / pdf;
+            r *= t_maj * sigma_maj / pdf;
+
+            if (tr / average(r)).max_element() < 0.05 {
+                if rng.next_f32() < 0.75 {
+                    tr = Vec3A::ZERO;
+                } else {
+                    tr /= 0.25;
+                }
+            }
+            if tr == Vec3A::ZERO {
+                break;
+            }
+        }
+
+        Transmittance { tr, r }
+    }
 }
 
 #[repr(u32)]
@@ -93,43 +238,22 @@ impl<'a> Homogeneous<'a> {
         self.data.v1.xyz().into()
     }
 
-    fn sigma_t(&self) -> Vec3A {
-        self.sigma_a() + self.sigma_s()
-    }
-
     fn g(&self) -> f32 {
         self.data.v0.w
     }
 }
 
 impl<'a> Medium for Homogeneous<'a> {
-    fn tr(&self, ray: Ray, t_max: f32) -> Vec3A {
-        (-self.sigma_t() * ray.direction.length() * t_max).exp()
+    fn sigma_maj(&self) -> Vec3A {
+        self.sigma_a() + self.sigma_s()
     }
 
-    fn sample(&self, ray: Ray, t_max: f32, rng: &mut DefaultRng) -> SampledMedium {
-        let channel = rng.next_u32() % 3;
-        let sigma_t = self.sigma_t();
-        let dist = -(1.0 - rng.next_f32()).ln()
-            / *unsafe { [sigma_t.x, sigma_t.y, sigma_t.z].index_unchecked(channel as usize) };
-        let t = dist / ray.direction.length();
-        let sampled = t < t_max;
-        let t = t.min(t_max);
-
-        let tr = (-sigma_t * t * ray.direction.length()).exp();
-        let density = if sampled { sigma_t * tr } else { tr };
-        let pdf = (density.x + density.y + density.z) / 3.0;
-        let pdf = if pdf == 0.0 { 1.0 } else { pdf };
+    fn sigma_a(&self, _p: Vec3A) -> Vec3A {
+        self.sigma_a()
+    }
 
-        SampledMedium {
-            sampled,
-            position: ray.origin + t * ray.direction,
-            tr: if sampled {
-                tr * self.sigma_s() / pdf
-            } else {
-                tr / pdf
-            },
-        }
+    fn sigma_s(&self, _p: Vec3A) -> Vec3A {
+        self.sigma_s()
     }
 
     fn phase(&self, wo: Vec3A, wi: Vec3A) -> f32 {
@@ -139,7 +263,7 @@ impl<'a> Medium for Homogeneous<'a> {
         1.0 / (4.0 * PI) * (1.0 - g * g) / (denom * denom.sqrt())
     }
 
-    fn sample_p(&self, wo: Vec3A, rng: &mut DefaultRng) -> Vec3A {
+    fn sample_p(&self, wo: Vec3A, rng: &mut impl Rng) -> Vec3A {
         let u0 = rng.next_f32();
         let u1 = rng.next_f32();
         let g = self.g();
@@ -178,17 +302,24 @@ impl EnumMedium {
 }
 
 impl Medium for EnumMedium {
-    fn tr(&self, ray: Ray, t_max: f32) -> Vec3A {
+    fn sigma_maj(&self) -> Vec3A {
         match self.t {
-            MediumType::Vaccum => vec3a(1.0, 1.0, 1.0),
-            MediumType::Homogeneous => Homogeneous { data: &self.data }.tr(ray, t_max),
+            MediumType::Vaccum => Vec3A::ZERO,
+            MediumType::Homogeneous => Homogeneous { data: &self.data }.sigma_maj(),
         }
     }
 
-    fn sample(&self, ray: Ray, t_max: f32, rng: &mut DefaultRng) -> SampledMedium {
+    fn sigma_a(&self, p: Vec3A) -> Vec3A {
         match self.t {
-            MediumType::Vaccum => Default::default(),
-            MediumType::Homogeneous => Homogeneous { data: &self.data }.sample(ray, t_max, rng),
+            MediumType::Vaccum => Vec3A::ZERO,
+            MediumType::Homogeneous => Medium::sigma_a(&Homogeneous { data: &self.data }, p),
+        }
+    }
+
+    fn sigma_s(&self, p: Vec3A) -> Vec3A {
+        match self.t {
+            MediumType::Vaccum => Vec3A::ZERO,
+            MediumType::Homogeneous => Medium::sigma_s(&Homogeneous { data: &self.data }, p),
         }
     }
 
@@ -199,7 +330,7 @@ impl Medium for EnumMedium {
         }
     }
 
-    fn sample_p(&self, wo: Vec3A, rng: &mut DefaultRng) -> Vec3A {
+    fn sample_p(&self, wo: Vec3A, rng: &mut impl Rng) -> Vec3A {
         match self.t {
             MediumType::Vaccum => Vec3A::ZERO,
             MediumType::Homogeneous => Homogeneous { data: &self.data }.sample_p(wo, rng),
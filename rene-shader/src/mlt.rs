@@ -0,0 +1,198 @@
+//! Primary sample space Metropolis light transport after pbrt-v3's `MLTIntegrator`,
+//! on top of the bidirectional path tracer.
+//!
+//! A bootstrap launch estimates the image's brightness from random paths of every depth,
+//! the host picks a starting path per chain among them, and each later launch mutates
+//! every chain once and splats both the proposed and the current path by their share.
+
+use core::f32::consts::PI;
+#[allow(unused_imports)]
+use spirv_std::num_traits::Float;
+use spirv_std::{
+    arch::IndexUnchecked,
+    glam::{vec2, Vec2, Vec3A},
+    ray_tracing::AccelerationStructure,
+};
+
+use crate::{
+    bdpt::{Bdpt, PathVertex, MAX_VERTICES},
+    rand::{DefaultRng, Rng},
+    reflection::Bsdf,
+    RayPayload,
+};
+
+/// Markov chains run side by side, a launch is one mutation of each.
+pub const CHAINS: u32 = 1 << 16;
+
+/// Evaluates `max_depth + 1` random paths per chain, one of each depth.
+pub const PASS_BOOTSTRAP: u32 = 0;
+/// Starts every chain from the bootstrap path the host picked for it.
+pub const PASS_INIT: u32 = 1;
+/// Proposes a mutation of every chain and splats both paths.
+pub const PASS_MUTATE: u32 = 2;
+/// Moves the splats into the image after the last launch, this one covers the film.
+pub const PASS_FLUSH: u32 = 3;
+
+/// The host's words at the start of `mlt_bootstrap`: the scale of the splats
+/// as `f32` bits, the launch count the image is averaged over and the bootstrap seed.
+/// Each chain's bootstrap path follows, then the bootstrap luminances.
+pub const BOOTSTRAP_HEADER: usize = 3;
+
+const CAMERA_STREAM: usize = 0;
+const LIGHT_STREAM: usize = 1;
+const CONNECTION_STREAM: usize = 2;
+const STREAMS: usize = 3;
+/// Primary samples kept per stream, later draws aren't mutated but drawn anew.
+const STREAM_SAMPLES: usize = 32;
+pub const PRIMARY_SAMPLES: usize = STREAMS * STREAM_SAMPLES;
+
+const ONE_MINUS_EPSILON: f32 = 0.99999994;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MltChain {
+    /// Primary samples of the current path, a block per stream
+    pub x: [f32; PRIMARY_SAMPLES],
+    /// Radiance of the current path
+    pub l: Vec3A,
+    /// Film position of the current path
+    pub st: Vec2,
+    pub depth: u32,
+}
+
+/// Replays primary samples in place of random numbers, so that the paths
+/// traced by the bidirectional machinery are a function of them.
+pub struct PrimarySampleRng {
+    pub x: [f32; PRIMARY_SAMPLES],
+    stream: usize,
+    taken: [usize; STREAMS],
+    fallback: DefaultRng,
+}
+
+impl PrimarySampleRng {
+    pub fn new(x: [f32; PRIMARY_SAMPLES], seed: u32) -> Self {
+        Self {
+            x,
+            stream: CAMERA_STREAM,
+            taken: [0; STREAMS],
+            fallback: DefaultRng::new(seed),
+        }
+    }
+
+    /// Uniform primary samples, a large step.
+    pub fn random(rng: &mut DefaultRng) -> Self {
+        let mut x = [0.0; PRIMARY_SAMPLES];
+        mutate(&mut x, true, 0.0, rng);
+        Self::new(x, rng.next_u32())
+    }
+
+    fn start_stream(&mut self, stream: usize) {
+        self.stream = stream;
+    }
+}
+
+impl Rng for PrimarySampleRng {
+    fn next_u32(&mut self) -> u32 {
+        // Integers are only used reduced to small ranges, 24 bits are plenty
+        (self.next_f32() * 16777216.0) as u32
+    }
+
+    fn next_f32(&mut self) -> f32 {
+        let taken = unsafe { self.taken.index_unchecked_mut(self.stream) };
+        if *taken < STREAM_SAMPLES {
+            let i = self.stream * STREAM_SAMPLES + *taken;
+            *taken += 1;
+            *unsafe { self.x.index_unchecked(i) }
+        } else {
+            self.fallback.next_f32()
+        }
+    }
+}
+
+/// pbrt's `MLTSampler` mutation of every primary sample at once: a large step draws
+/// them anew, a small step moves each by a normal of deviation `sigma` wrapping around.
+pub fn mutate(x: &mut [f32; PRIMARY_SAMPLES], large_step: bool, sigma: f32, rng: &mut DefaultRng) {
+    let mut i = 0;
+    while i < PRIMARY_SAMPLES {
+        let v = unsafe { x.index_unchecked_mut(i) };
+        if large_step {
+            *v = rng.next_f32();
+        } else {
+            // Box-Muller transform
+            let normal =
+                (-2.0 * (1.0 - rng.next_f32()).ln()).sqrt() * (2.0 * PI * rng.next_f32()).cos();
+            let moved = *v + sigma * normal;
+            *v = (moved - moved.floor()).min(ONE_MINUS_EPSILON);
+        }
+        i += 1;
+    }
+}
+
+/// pbrt's `MLTIntegrator::L`, radiance of a path of `depth` bounces made by the
+/// bidirectional strategy the primary samples pick, and its film position.
+/// The radiance is scaled by the strategy count, as only one is taken.
+pub fn radiance(
+    bdpt: &Bdpt,
+    tlas: &AccelerationStructure,
+    depth: u32,
+    sampler: &mut PrimarySampleRng,
+    bsdf: &mut Bsdf,
+    payload: &mut RayPayload,
+) -> (Vec3A, Vec2) {
+    let uniform = bdpt.uniform;
+
+    sampler.start_stream(CAMERA_STREAM);
+    let (strategies, s, t) = if depth == 0 {
+        (1, 0, 2)
+    } else {
+        let strategies = depth as usize + 2;
+        let s = ((sampler.next_f32() * strategies as f32) as usize).min(strategies - 1);
+        (strategies, s, strategies - s)
+    };
+
+    // Film coordinates as the other integrators lay pixels on them
+    let st = vec2(
+        sampler.next_f32() * uniform.film_width as f32 / (uniform.film_width - 1) as f32,
+        sampler.next_f32() * uniform.film_height as f32 / (uniform.film_height - 1) as f32,
+    );
+    let (ray, camera_weight) = uniform.camera.get_ray(st, uniform.camera_to_world, sampler);
+
+    let mut camera_path = [PathVertex::default(); MAX_VERTICES];
+    let n_camera = bdpt.generate_camera_subpath(
+        tlas,
+        &mut camera_path,
+        t,
+        ray,
+        camera_weight,
+        bsdf,
+        payload,
+        sampler,
+    );
+    if n_camera != t {
+        return (Vec3A::ZERO, st);
+    }
+
+    // A single light vertex is sampled by the connection itself
+    sampler.start_stream(LIGHT_STREAM);
+    let mut light_path = [PathVertex::default(); MAX_VERTICES];
+    if s > 1 {
+        let n_light = bdpt.generate_light_subpath(tlas, &mut light_path, s, bsdf, payload, sampler);
+        if n_light != s {
+            return (Vec3A::ZERO, st);
+        }
+    }
+
+    sampler.start_stream(CONNECTION_STREAM);
+    let (l, splat_st) = bdpt.connect(
+        tlas,
+        &light_path,
+        &camera_path,
+        s,
+        t,
+        bsdf,
+        payload,
+        sampler,
+    );
+
+    (l * strategies as f32, if t == 1 { splat_st } else { st })
+}
@@ -1,5 +1,17 @@
 use crate::asm::u32_to_f32;
 
+/// Source of the uniform numbers consumed by sampling routines.
+pub trait Rng {
+    fn next_u32(&mut self) -> u32;
+
+    /// Uniform in [0, 1)
+    fn next_f32(&mut self) -> f32;
+
+    fn next_f32_range(&mut self, min: f32, max: f32) -> f32 {
+        min + (max - min) * self.next_f32()
+    }
+}
+
 #[repr(transparent)]
 pub struct PCG32si {
     state: u32,
@@ -28,14 +40,16 @@ impl PCG32si {
         rng.pcg_oneseq_32_step_r();
         rng
     }
+}
 
-    pub fn next_u32(&mut self) -> u32 {
+impl Rng for PCG32si {
+    fn next_u32(&mut self) -> u32 {
         let old_state = self.state;
         self.pcg_oneseq_32_step_r();
         Self::pcg_output_rxs_m_xs_32_32(old_state)
     }
 
-    pub fn next_f32(&mut self) -> f32 {
+    fn next_f32(&mut self) -> f32 {
         const FLOAT_SIZE: u32 = core::mem::size_of::<f32>() as u32 * 8;
         const PRECISION: u32 = 23 + 1;
         const SCALE: f32 = 1.0 / (1 << PRECISION) as f32;
@@ -45,10 +59,6 @@ impl PCG32si {
         let value = value >> SHIFT;
         SCALE * u32_to_f32(value)
     }
-
-    pub fn next_f32_range(&mut self, min: f32, max: f32) -> f32 {
-        min + (max - min) * self.next_f32()
-    }
 }
 
 pub type DefaultRng = PCG32si;
@@ -7,13 +7,17 @@ use spirv_std::{
     glam::{vec3a, Vec3A, Vec4},
 };
 
-use crate::rand::DefaultRng;
+use crate::rand::Rng;
 
 #[derive(Default)]
 pub struct SampledF {
     pub wi: Vec3A,
     pub f: Vec3A,
     pub pdf: f32,
+    /// Sampled from a delta distribution, which light sampling can't reach
+    pub is_specular: bool,
+    /// Relative index of refraction `eta_t / eta_i` of a transmission, one for a reflection
+    pub eta: f32,
 }
 
 mod bxdf;
@@ -86,7 +90,7 @@ pub trait Bxdf {
 
     fn f(&self, wo: Vec3A, wi: Vec3A) -> Vec3A;
 
-    fn sample_f(&self, wo: Vec3A, rng: &mut DefaultRng) -> SampledF;
+    fn sample_f(&self, wo: Vec3A, rng: &mut impl Rng) -> SampledF;
 
     fn pdf(&self, wo: Vec3A, wi: Vec3A) -> f32;
 }
@@ -148,7 +152,7 @@ impl Bxdf for EnumBxdf {
         }
     }
 
-    fn sample_f(&self, wo: Vec3A, rng: &mut DefaultRng) -> SampledF {
+    fn sample_f(&self, wo: Vec3A, rng: &mut impl Rng) -> SampledF {
         match self.t() {
             BxdfType::LambertianReflection => {
                 LambertianReflection { data: &self.data }.sample_f(wo, rng)
@@ -310,7 +314,7 @@ impl Bsdf {
         f
     }
 
-    pub fn sample_f(&self, wo_world: Vec3A, rng: &mut DefaultRng) -> SampledF {
+    pub fn sample_f(&self, wo_world: Vec3A, rng: &mut impl Rng) -> SampledF {
         if self.len == 0 {
             SampledF::default()
         } else {
@@ -318,9 +322,15 @@ impl Bsdf {
             let bxdf = unsafe { self.bxdfs.index_unchecked(index) };
             let wo = self.onb.world_to_local(wo_world);
             let mut sampled_f = bxdf.sample_f(wo, rng);
-
-            sampled_f.pdf /= self.len as f32;
             sampled_f.wi = self.onb.local_to_world(sampled_f.wi);
+
+            // Same f and pdf as evaluating the direction, for consistent MIS weights
+            if bxdf.kind().contains(BxdfKind::DIFFUSE) && sampled_f.pdf > 0.0 {
+                sampled_f.f = self.f(wo_world, sampled_f.wi);
+                sampled_f.pdf = self.pdf(wo_world, sampled_f.wi);
+            } else {
+                sampled_f.pdf /= self.len as f32;
+            }
             sampled_f
         }
     }
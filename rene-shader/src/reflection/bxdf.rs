@@ -1,10 +1,13 @@
 use core::f32::consts::{FRAC_1_PI, PI};
-use spirv_std::glam::{vec2, vec3a, Vec2, Vec3A, Vec4Swizzles};
+use spirv_std::glam::{vec3a, Vec3A, Vec4Swizzles};
 #[allow(unused_imports)]
 use spirv_std::num_traits::Float;
 
 use crate::{
-    asm::f32_clamp, math::random_cosine_direction, rand::DefaultRng, reflection::fresnel::Fresnel,
+    asm::f32_clamp,
+    math::{concentric_sample_disk, face_forward, random_cosine_direction, refract},
+    rand::Rng,
+    reflection::fresnel::Fresnel,
 };
 
 use super::{
@@ -45,24 +48,7 @@ pub struct SpecularTransmission<'a> {
 }
 
 #[allow(dead_code)]
-fn concentric_sample_disk(rng: &mut DefaultRng) -> Vec2 {
-    let u_offset = 2.0 * vec2(rng.next_f32(), rng.next_f32()) - vec2(1.0, 1.0);
-
-    if u_offset == Vec2::ZERO {
-        return Vec2::ZERO;
-    }
-
-    let (theta, r) = if u_offset.x.abs() > u_offset.y.abs() {
-        (PI / 4.0 * (u_offset.y / u_offset.x), u_offset.x)
-    } else {
-        (PI / 2.0 - PI / 4.0 * (u_offset.x / u_offset.y), u_offset.y)
-    };
-
-    r * vec2(theta.cos(), theta.sin())
-}
-
-#[allow(dead_code)]
-fn cosine_sample_hemisphere(rng: &mut DefaultRng) -> Vec3A {
+fn cosine_sample_hemisphere(rng: &mut impl Rng) -> Vec3A {
     let d = concentric_sample_disk(rng);
     let z = (1.0 - d.x * d.x - d.y * d.y).max(0.0).sqrt();
 
@@ -88,7 +74,7 @@ impl<'a> Bxdf for LambertianReflection<'a> {
         self.albedo() * FRAC_1_PI
     }
 
-    fn sample_f(&self, wo: Vec3A, rng: &mut DefaultRng) -> SampledF {
+    fn sample_f(&self, wo: Vec3A, rng: &mut impl Rng) -> SampledF {
         let mut wi = random_cosine_direction(rng);
 
         if wo.z < 0.0 {
@@ -101,6 +87,8 @@ impl<'a> Bxdf for LambertianReflection<'a> {
             wi,
             f: self.f(wo, wi),
             pdf,
+            is_specular: false,
+            eta: 1.0,
         }
     }
 
@@ -118,23 +106,6 @@ fn reflect(wo: Vec3A, n: Vec3A) -> Vec3A {
     -wo + 2.0 * wo.dot(n) * n
 }
 
-fn refract(wi: Vec3A, n: Vec3A, etai_over_etat: f32) -> (bool, Vec3A) {
-    let cos_theta_i = n.dot(wi);
-    let sin2theta_i = (1.0 - cos_theta_i * cos_theta_i).max(0.0);
-    let sin2theta_t = etai_over_etat * etai_over_etat * sin2theta_i;
-
-    if sin2theta_t >= 1.0 {
-        return (false, Vec3A::ZERO);
-    }
-
-    let cos_theta_t = (1.0 - sin2theta_t).sqrt();
-
-    (
-        true,
-        etai_over_etat * -wi + (etai_over_etat * cos_theta_i - cos_theta_t) * n,
-    )
-}
-
 pub fn fr_dielectric(cos_theta_i: f32, eta_i: f32, eta_t: f32) -> f32 {
     let cos_theta_i = f32_clamp(cos_theta_i, -1.0, 1.0);
     let entering = cos_theta_i > 0.0;
@@ -190,7 +161,7 @@ impl<'a> Bxdf for FresnelSpecular<'a> {
         Vec3A::ZERO
     }
 
-    fn sample_f(&self, wo: Vec3A, rng: &mut DefaultRng) -> SampledF {
+    fn sample_f(&self, wo: Vec3A, rng: &mut impl Rng) -> SampledF {
         let cos_theta = Onb::local_cos_theta(wo);
         let f = fr_dielectric(cos_theta, 1.0, self.ir());
 
@@ -201,6 +172,8 @@ impl<'a> Bxdf for FresnelSpecular<'a> {
                 wi,
                 f: f * vec3a(1.0, 1.0, 1.0) / Onb::local_abs_cos_theta(wi),
                 pdf: f,
+                is_specular: true,
+                eta: 1.0,
             }
         } else {
             let (eta_i, eta_t) = if Onb::local_cos_theta(wo) > 0.0 {
@@ -221,6 +194,8 @@ impl<'a> Bxdf for FresnelSpecular<'a> {
                 wi,
                 f: vec3a(1.0, 1.0, 1.0) * (1.0 - f) / Onb::local_abs_cos_theta(wi),
                 pdf: if !b { 0.0 } else { 1.0 - f },
+                is_specular: true,
+                eta: eta_t / eta_i,
             }
         }
     }
@@ -289,7 +264,7 @@ impl<'a> Bxdf for FresnelBlend<'a> {
         diffuse + specular
     }
 
-    fn sample_f(&self, wo: Vec3A, rng: &mut DefaultRng) -> SampledF {
+    fn sample_f(&self, wo: Vec3A, rng: &mut impl Rng) -> SampledF {
         let wi = if rng.next_f32() < 0.5 {
             let mut wi = random_cosine_direction(rng);
 
@@ -313,6 +288,8 @@ impl<'a> Bxdf for FresnelBlend<'a> {
             wi,
             f: self.f(wo, wi),
             pdf: self.pdf(wo, wi),
+            is_specular: false,
+            eta: 1.0,
         }
     }
 
@@ -345,14 +322,6 @@ impl<'a> MicrofacetReflection<'a> {
     }
 }
 
-fn face_forward(v: Vec3A, v2: Vec3A) -> Vec3A {
-    if v.dot(v2) < 0.0 {
-        -v
-    } else {
-        v
-    }
-}
-
 impl<'a> Bxdf for MicrofacetReflection<'a> {
     fn kind(&self) -> BxdfKind {
         BxdfKind::REFLECTION | BxdfKind::DIFFUSE
@@ -382,7 +351,7 @@ impl<'a> Bxdf for MicrofacetReflection<'a> {
             / (4.0 * cos_theta_i * cos_theta_o)
     }
 
-    fn sample_f(&self, wo: Vec3A, rng: &mut DefaultRng) -> SampledF {
+    fn sample_f(&self, wo: Vec3A, rng: &mut impl Rng) -> SampledF {
         if wo.z == 0.0 {
             return SampledF::default();
         }
@@ -402,6 +371,8 @@ impl<'a> Bxdf for MicrofacetReflection<'a> {
             wi,
             f: self.f(wo, wi),
             pdf,
+            is_specular: false,
+            eta: 1.0,
         }
     }
 
@@ -434,12 +405,18 @@ impl<'a> Bxdf for SpecularReflection<'a> {
         Vec3A::ZERO
     }
 
-    fn sample_f(&self, wo: Vec3A, _rng: &mut DefaultRng) -> SampledF {
+    fn sample_f(&self, wo: Vec3A, _rng: &mut impl Rng) -> SampledF {
         let wi = vec3a(-wo.x, -wo.y, wo.z);
         let f = self.data.fresnel.evaluate(Onb::local_cos_theta(wi)) * self.r()
             / Onb::local_abs_cos_theta(wi);
 
-        SampledF { wi, f, pdf: 1.0 }
+        SampledF {
+            wi,
+            f,
+            pdf: 1.0,
+            is_specular: true,
+            eta: 1.0,
+        }
     }
 
     fn pdf(&self, _wo: Vec3A, _wi: Vec3A) -> f32 {
@@ -478,7 +455,7 @@ impl<'a> Bxdf for SpecularTransmission<'a> {
         Vec3A::ZERO
     }
 
-    fn sample_f(&self, wo: Vec3A, _rng: &mut DefaultRng) -> SampledF {
+    fn sample_f(&self, wo: Vec3A, _rng: &mut impl Rng) -> SampledF {
         let entering = Onb::local_cos_theta(wo) > 0.0;
 
         let (eta_i, eta_t) = if entering {
@@ -508,6 +485,8 @@ impl<'a> Bxdf for SpecularTransmission<'a> {
             wi,
             f: ft / Onb::local_abs_cos_theta(wi),
             pdf: 1.0,
+            is_specular: true,
+            eta: eta_t / eta_i,
         }
     }
 
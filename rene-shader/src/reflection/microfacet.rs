@@ -3,14 +3,14 @@ use spirv_std::glam::{vec2, vec3a, Vec2, Vec3A};
 #[allow(unused_imports)]
 use spirv_std::num_traits::Float;
 
-use crate::rand::DefaultRng;
+use crate::rand::Rng;
 
 use super::onb::Onb;
 
 pub trait MicrofacetDistribution {
     fn d(&self, wh: Vec3A) -> f32;
     fn lambda(&self, w: Vec3A) -> f32;
-    fn sample_wh(&self, wo: Vec3A, rng: &mut DefaultRng) -> Vec3A;
+    fn sample_wh(&self, wo: Vec3A, rng: &mut impl Rng) -> Vec3A;
     fn pdf(&self, wo: Vec3A, wh: Vec3A) -> f32;
 
     fn g(&self, wo: Vec3A, wi: Vec3A) -> f32 {
@@ -74,7 +74,7 @@ impl<'a> TrowbridgeReitz<'a> {
     }
 }
 
-fn trowbridge_reitz_sample11(cos_theta: f32, rng: &mut DefaultRng) -> Vec2 {
+fn trowbridge_reitz_sample11(cos_theta: f32, rng: &mut impl Rng) -> Vec2 {
     let u1 = rng.next_f32();
     let mut u2 = rng.next_f32();
 
@@ -121,7 +121,7 @@ fn trowbridge_reitz_sample11(cos_theta: f32, rng: &mut DefaultRng) -> Vec2 {
     vec2(slope_x, slope_y)
 }
 
-fn trowbridge_reitz_sample(wi: Vec3A, alpha_x: f32, alpha_y: f32, rng: &mut DefaultRng) -> Vec3A {
+fn trowbridge_reitz_sample(wi: Vec3A, alpha_x: f32, alpha_y: f32, rng: &mut impl Rng) -> Vec3A {
     let wi_stretched = vec3a(alpha_x * wi.x, alpha_y * wi.y, wi.z).normalize();
 
     let slope = trowbridge_reitz_sample11(Onb::local_cos_theta(wi_stretched), rng);
@@ -173,7 +173,7 @@ impl<'a> MicrofacetDistribution for TrowbridgeReitz<'a> {
         (1.0 - 1.259 * a + 0.396 * a * a) / (3.535 * a + 2.181 * a * a)
     }
 
-    fn sample_wh(&self, wo: Vec3A, rng: &mut DefaultRng) -> Vec3A {
+    fn sample_wh(&self, wo: Vec3A, rng: &mut impl Rng) -> Vec3A {
         let flip = wo.z < 0.0;
         let wh = trowbridge_reitz_sample(
             if flip { -wo } else { wo },
@@ -229,7 +229,7 @@ impl MicrofacetDistribution for EnumMicrofacetDistribution {
         }
     }
 
-    fn sample_wh(&self, wo: Vec3A, rng: &mut DefaultRng) -> Vec3A {
+    fn sample_wh(&self, wo: Vec3A, rng: &mut impl Rng) -> Vec3A {
         match self.t {
             MicrofacetDistributionType::TrowbridgeReitz => {
                 TrowbridgeReitz { data: &self.data }.sample_wh(wo, rng)
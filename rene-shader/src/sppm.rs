@@ -0,0 +1,255 @@
+//! Stochastic progressive photon mapping after pbrt-v3's `SPPMIntegrator`, on surfaces only.
+//!
+//! Each iteration is three launches over the film: the camera pass finds a visible point
+//! per pixel and hashes it into a grid, the photon pass gathers photons into the visible
+//! points around them and the update pass shrinks the search radii.
+
+use core::f32::consts::PI;
+#[allow(unused_imports)]
+use spirv_std::num_traits::Float;
+use spirv_std::{
+    arch::{atomic_exchange, atomic_i_add, IndexUnchecked},
+    glam::{ivec3, IVec3, Vec2, Vec3A},
+    memory::{Scope, Semantics},
+    RuntimeArray,
+};
+
+use crate::{
+    material::{EnumMaterial, Material},
+    rand::{DefaultRng, Rng},
+    reflection::{onb::Onb, Bsdf},
+    texture::EnumTexture,
+    IndexData, InputImage, Uniform,
+};
+
+/// Finds the visible points, also the only pass adding to the image each iteration.
+pub const PASS_CAMERA: u32 = 0;
+/// Traces `sppm_photons_per_iteration` photons, the launch is deep enough to hold them.
+pub const PASS_PHOTONS: u32 = 1;
+/// Folds the gathered photons into each pixel and empties the grid.
+pub const PASS_UPDATE: u32 = 2;
+/// Adds the photon estimate to the image after the last iteration.
+pub const PASS_ESTIMATE: u32 = 3;
+
+/// Ends the lists of the photon grid and marks a pixel without a visible point.
+pub const NONE: u32 = u32::MAX;
+
+/// Scale of the fixed point flux gathered by the visible points.
+/// The sums are 64 bits wide, a single photon adds at most `u32::MAX / FLUX_SCALE`.
+pub const FLUX_SCALE: f32 = 4096.0;
+
+/// Largest `f32` below 2^32, the most a photon adds to the fixed point flux.
+const MAX_FLUX_ADD: f32 = 4294967040.0;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+pub struct SppmPixel {
+    /// Visible point found by this iteration's camera pass
+    pub position: Vec3A,
+    pub normal: Vec3A,
+    pub wo: Vec3A,
+    /// Throughput of the camera path up to the visible point
+    pub beta: Vec3A,
+    /// Flux gathered by the previous iterations, pbrt's `tau`
+    pub tau: Vec3A,
+    pub uv: Vec2,
+    /// Instance of the visible point, `NONE` if the camera path found no diffuse surface
+    pub index: u32,
+    /// Next visible point in the same bucket of the photon grid
+    pub next: u32,
+    pub radius: f32,
+    /// Photons gathered so far, pbrt's `N`
+    pub n: f32,
+    /// Photons gathered this iteration, pbrt's `M`
+    pub m: u32,
+    /// Fixed point flux gathered this iteration, over the photon count and the search area.
+    /// Low words, the carries go to `phi_high`
+    pub phi: [u32; 3],
+    pub phi_high: [u32; 3],
+}
+
+impl SppmPixel {
+    pub fn new(radius: f32) -> Self {
+        Self {
+            index: NONE,
+            next: NONE,
+            radius,
+            ..Default::default()
+        }
+    }
+
+    /// pbrt's progressive update with `gamma` of 2/3, the new radius keeps a share of the photons.
+    pub fn update(&mut self, photons_per_iteration: u32) {
+        if self.m > 0 {
+            let area = photons_per_iteration as f32 * PI * self.radius * self.radius;
+            let phi = Vec3A::new(
+                self.phi_high[0] as f32 * 4294967296.0 + self.phi[0] as f32,
+                self.phi_high[1] as f32 * 4294967296.0 + self.phi[1] as f32,
+                self.phi_high[2] as f32 * 4294967296.0 + self.phi[2] as f32,
+            ) * (area / FLUX_SCALE);
+
+            let m = self.m as f32;
+            let n = self.n + 2.0 / 3.0 * m;
+            let radius = self.radius * (n / (self.n + m)).sqrt();
+
+            self.tau =
+                (self.tau + self.beta * phi) * (radius * radius) / (self.radius * self.radius);
+            self.n = n;
+            self.radius = radius;
+            self.m = 0;
+            self.phi = [0; 3];
+            self.phi_high = [0; 3];
+        }
+        self.index = NONE;
+    }
+
+    /// Radiance of the photons gathered over every iteration,
+    /// scaled by the iteration count the image is averaged with.
+    pub fn estimate(&self, photons_per_iteration: u32) -> Vec3A {
+        self.tau / (photons_per_iteration as f32 * PI * self.radius * self.radius)
+    }
+}
+
+/// Cell of the photon grid holding `position`, as wide as the initial search radius
+/// so that the cells around a photon hold every visible point it can reach.
+fn grid_cell(position: Vec3A, cell_size: f32) -> IVec3 {
+    let p = (position / cell_size).floor();
+    ivec3(p.x as i32, p.y as i32, p.z as i32)
+}
+
+/// Bucket of `cell` in the hashed grid, pbrt's `hash`.
+fn grid_hash(cell: IVec3, buckets: u32) -> u32 {
+    ((cell.x as u32).wrapping_mul(73856093)
+        ^ (cell.y as u32).wrapping_mul(19349663)
+        ^ (cell.z as u32).wrapping_mul(83492791))
+        % buckets
+}
+
+/// Adds the visible point of `pixel_index` to its grid cell's list, `grid` has a bucket per pixel.
+pub fn insert(
+    uniform: &Uniform,
+    pixels: &mut [SppmPixel],
+    grid: &mut [u32],
+    buckets: u32,
+    pixel_index: u32,
+) {
+    let pixel = unsafe { pixels.index_unchecked_mut(pixel_index as usize) };
+    let bucket = grid_hash(grid_cell(pixel.position, uniform.sppm_radius), buckets);
+
+    pixel.next = unsafe {
+        atomic_exchange::<u32, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+            grid.index_unchecked_mut(bucket as usize),
+            pixel_index,
+        )
+    };
+}
+
+pub struct PhotonMap<'a> {
+    pub uniform: &'a Uniform,
+    pub materials: &'a [EnumMaterial],
+    pub textures: &'a [EnumTexture],
+    pub images: &'a RuntimeArray<InputImage>,
+    pub index_data: &'a [IndexData],
+}
+
+impl<'a> PhotonMap<'a> {
+    /// Gathers a photon with throughput `beta` arriving from `wi` at `position`
+    /// into every visible point whose search radius holds it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_photon(
+        &self,
+        pixels: &mut [SppmPixel],
+        grid: &[u32],
+        buckets: u32,
+        position: Vec3A,
+        wi: Vec3A,
+        beta: Vec3A,
+        bsdf: &mut Bsdf,
+        rng: &mut DefaultRng,
+    ) {
+        let center = grid_cell(position, self.uniform.sppm_radius);
+
+        // Neighbouring cells may share a bucket, which must be visited once
+        let mut visited = [NONE; 27];
+        let mut c = 0;
+        while c < 27 {
+            let cell = center + ivec3(c % 3 - 1, c / 3 % 3 - 1, c / 9 - 1);
+            let bucket = grid_hash(cell, buckets);
+
+            let mut seen = false;
+            let mut i = 0;
+            while i < c {
+                seen |= *unsafe { visited.index_unchecked(i as usize) } == bucket;
+                i += 1;
+            }
+            *unsafe { visited.index_unchecked_mut(c as usize) } = bucket;
+
+            if !seen {
+                let mut pixel_index = *unsafe { grid.index_unchecked(bucket as usize) };
+                while pixel_index != NONE {
+                    let pixel = unsafe { pixels.index_unchecked_mut(pixel_index as usize) };
+
+                    if pixel.position.distance_squared(position) <= pixel.radius * pixel.radius {
+                        self.gather(pixel, wi, beta, bsdf, rng);
+                    }
+                    pixel_index = pixel.next;
+                }
+            }
+            c += 1;
+        }
+    }
+
+    #[inline(always)]
+    fn gather(
+        &self,
+        pixel: &mut SppmPixel,
+        wi: Vec3A,
+        beta: Vec3A,
+        bsdf: &mut Bsdf,
+        rng: &mut DefaultRng,
+    ) {
+        let index = unsafe { self.index_data.index_unchecked(pixel.index as usize) };
+        bsdf.clear(pixel.normal, Onb::from_w(pixel.normal));
+        unsafe {
+            self.materials
+                .index_unchecked(index.material_index as usize)
+        }
+        .compute_bsdf(bsdf, pixel.uv, self.textures, self.images);
+
+        let area =
+            self.uniform.sppm_photons_per_iteration as f32 * PI * pixel.radius * pixel.radius;
+        let phi = beta * bsdf.f(pixel.wo, wi) / area;
+
+        // Rounding stochastically keeps the sum unbiased, a 64 bit sum out of
+        // 32 bit atomics as the add which wraps the low word carries into the high one
+        let mut c = 0;
+        while c < 3 {
+            let value = match c {
+                0 => phi.x,
+                1 => phi.y,
+                _ => phi.z,
+            };
+            let add = (value * FLUX_SCALE + rng.next_f32()).clamp(0.0, MAX_FLUX_ADD) as u32;
+            unsafe {
+                let low = atomic_i_add::<u32, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+                    pixel.phi.index_unchecked_mut(c),
+                    add,
+                );
+                if low.wrapping_add(add) < low {
+                    atomic_i_add::<u32, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+                        pixel.phi_high.index_unchecked_mut(c),
+                        1,
+                    );
+                }
+            }
+            c += 1;
+        }
+        unsafe {
+            atomic_i_add::<u32, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+                &mut pixel.m,
+                1,
+            );
+        }
+    }
+}
@@ -1,13 +1,39 @@
+use core::f32::consts::FRAC_1_PI;
+#[allow(unused_imports)]
+use spirv_std::num_traits::Float;
 use spirv_std::{
     arch::IndexUnchecked,
-    glam::{uvec2, Affine3A, UVec2, Vec3A},
+    glam::{uvec4, Affine3A, UVec4, Vec3A},
 };
 
-use crate::{math::random_in_unit_sphere, rand::DefaultRng, Vertex};
+use crate::{
+    distribution::Distribution1D,
+    math::{random_in_unit_sphere, random_to_sphere},
+    rand::Rng,
+    reflection::onb::Onb,
+    Vertex,
+};
 
 pub trait SurfaceSample {
     fn primitive_count(&self) -> u32;
-    fn sample(&self, indices: &[u32], vertices: &[Vertex], rng: &mut DefaultRng) -> Vec3A;
+    /// Samples a point on the surface seen from `p`.
+    /// `distribution` holds the area CDFs of the triangle meshes.
+    fn sample(
+        &self,
+        p: Vec3A,
+        indices: &[u32],
+        vertices: &[Vertex],
+        distribution: &[f32],
+        rng: &mut impl Rng,
+    ) -> Vec3A;
+    /// Samples a point uniformly by area, returns it with its normal and area pdf.
+    fn sample_area(
+        &self,
+        indices: &[u32],
+        vertices: &[Vertex],
+        distribution: &[f32],
+        rng: &mut impl Rng,
+    ) -> (Vec3A, Vec3A, f32);
 }
 
 #[derive(Clone, Copy)]
@@ -28,7 +54,7 @@ pub struct EnumSurfaceSample {
 #[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
 #[repr(C)]
 struct EnumSurfaceSampleData {
-    u0: UVec2,
+    u0: UVec4,
     matrix: Affine3A,
 }
 
@@ -44,10 +70,12 @@ impl<'a> Triangle<'a> {
     pub fn new_data(
         index_offset: u32,
         primitive_count: u32,
+        cdf_offset: u32,
+        area_light_index: u32,
         matrix: Affine3A,
     ) -> EnumSurfaceSampleData {
         EnumSurfaceSampleData {
-            u0: uvec2(index_offset, primitive_count),
+            u0: uvec4(index_offset, primitive_count, cdf_offset, area_light_index),
             matrix,
         }
     }
@@ -55,15 +83,24 @@ impl<'a> Triangle<'a> {
     fn index_offset(&self) -> u32 {
         self.data.u0.x
     }
+
+    fn cdf_offset(&self) -> u32 {
+        self.data.u0.z
+    }
 }
 
 impl<'a> Sphere<'a> {
-    pub fn new_data(matrix: Affine3A) -> EnumSurfaceSampleData {
+    pub fn new_data(area_light_index: u32, matrix: Affine3A) -> EnumSurfaceSampleData {
         EnumSurfaceSampleData {
-            u0: UVec2::ZERO,
+            u0: uvec4(0, 0, 0, area_light_index),
             matrix,
         }
     }
+
+    /// Same as `sphere_closest_hit_pdf`
+    fn radius(&self) -> f32 {
+        self.data.matrix.matrix3.x_axis.length()
+    }
 }
 
 impl<'a> SurfaceSample for Triangle<'a> {
@@ -71,8 +108,27 @@ impl<'a> SurfaceSample for Triangle<'a> {
         self.data.u0.y
     }
 
-    fn sample(&self, indices: &[u32], vertices: &[Vertex], rng: &mut DefaultRng) -> Vec3A {
-        let p = rng.next_u32() % self.primitive_count();
+    fn sample(
+        &self,
+        _p: Vec3A,
+        indices: &[u32],
+        vertices: &[Vertex],
+        distribution: &[f32],
+        rng: &mut impl Rng,
+    ) -> Vec3A {
+        self.sample_area(indices, vertices, distribution, rng).0
+    }
+
+    fn sample_area(
+        &self,
+        indices: &[u32],
+        vertices: &[Vertex],
+        distribution: &[f32],
+        rng: &mut impl Rng,
+    ) -> (Vec3A, Vec3A, f32) {
+        // Proportional to the area, so the point is uniform over the whole mesh
+        let cdf = Distribution1D::new(distribution, self.cdf_offset(), self.primitive_count());
+        let p = cdf.sample_discrete(rng.next_f32()) as u32;
 
         let v0 = unsafe {
             vertices.index_unchecked(
@@ -101,7 +157,32 @@ impl<'a> SurfaceSample for Triangle<'a> {
 
         let pos = v0.position * (1.0 - r - s) + v1.position * r + v2.position * s;
 
-        self.data.matrix.transform_point3a(pos)
+        // Same normal as `triangle_closest_hit`
+        let nrm =
+            if v0.normal == Vec3A::ZERO && v1.normal == Vec3A::ZERO && v2.normal == Vec3A::ZERO {
+                (v1.position - v0.position).cross(v2.position - v0.position)
+            } else {
+                v0.normal * (1.0 - r - s) + v1.normal * r + v2.normal * s
+            };
+        let normal = self
+            .data
+            .matrix
+            .matrix3
+            .inverse()
+            .transpose()
+            .mul_vec3a(nrm)
+            .normalize();
+
+        let p0 = self.data.matrix.transform_point3a(v0.position);
+        let p1 = self.data.matrix.transform_point3a(v1.position);
+        let p2 = self.data.matrix.transform_point3a(v2.position);
+        let area = 0.5 * (p1 - p0).cross(p2 - p0).length();
+
+        (
+            self.data.matrix.transform_point3a(pos),
+            normal,
+            cdf.pmf(p as usize) / area,
+        )
     }
 }
 
@@ -110,26 +191,89 @@ impl<'a> SurfaceSample for Sphere<'a> {
         1
     }
 
-    fn sample(&self, _indices: &[u32], _vertices: &[Vertex], rng: &mut DefaultRng) -> Vec3A {
-        let v = random_in_unit_sphere(rng).normalize();
-        self.data.matrix.transform_point3a(v)
+    /// Uniform in the cone of directions from `p` to the sphere, the visible side only.
+    fn sample(
+        &self,
+        p: Vec3A,
+        _indices: &[u32],
+        _vertices: &[Vertex],
+        _distribution: &[f32],
+        rng: &mut impl Rng,
+    ) -> Vec3A {
+        let radius = self.radius();
+        let center = self.data.matrix.translation;
+        let distance_squared = p.distance_squared(center);
+
+        // Inside, every direction sees the sphere
+        if distance_squared <= radius * radius {
+            let w = random_in_unit_sphere(rng).normalize();
+            let b = (p - center).dot(w);
+            let t = -b + (b * b - distance_squared + radius * radius).max(0.0).sqrt();
+            return p + t * w;
+        }
+
+        let onb = Onb::from_w((center - p) / distance_squared.sqrt());
+        let local = random_to_sphere(radius, distance_squared, rng);
+        let w = onb.local_to_world(local);
+
+        // Nearest intersection along w, clamped at the silhouette
+        let cos_theta = Onb::local_cos_theta(local);
+        let t = distance_squared.sqrt() * cos_theta
+            - (radius * radius - distance_squared * (1.0 - cos_theta * cos_theta))
+                .max(0.0)
+                .sqrt();
+        p + t * w
+    }
+
+    fn sample_area(
+        &self,
+        _indices: &[u32],
+        _vertices: &[Vertex],
+        _distribution: &[f32],
+        rng: &mut impl Rng,
+    ) -> (Vec3A, Vec3A, f32) {
+        let radius = self.radius();
+        let normal = random_in_unit_sphere(rng).normalize();
+
+        (
+            self.data.matrix.translation + radius * normal,
+            normal,
+            0.25 * FRAC_1_PI / (radius * radius),
+        )
     }
 }
 
 impl EnumSurfaceSample {
-    pub fn new_triangle(index_offset: u32, primitive_count: u32, matrix: Affine3A) -> Self {
+    /// `cdf_offset` locates the area CDF of the primitives in the emitter distribution.
+    pub fn new_triangle(
+        index_offset: u32,
+        primitive_count: u32,
+        cdf_offset: u32,
+        area_light_index: u32,
+        matrix: Affine3A,
+    ) -> Self {
         EnumSurfaceSample {
             t: SurfaceType::Triangle,
-            data: Triangle::new_data(index_offset, primitive_count, matrix),
+            data: Triangle::new_data(
+                index_offset,
+                primitive_count,
+                cdf_offset,
+                area_light_index,
+                matrix,
+            ),
         }
     }
 
-    pub fn new_sphere(matrix: Affine3A) -> Self {
+    pub fn new_sphere(area_light_index: u32, matrix: Affine3A) -> Self {
         EnumSurfaceSample {
             t: SurfaceType::Sphere,
-            data: Sphere::new_data(matrix),
+            data: Sphere::new_data(area_light_index, matrix),
         }
     }
+
+    pub fn area_light_index(&self) -> u32 {
+        self.data.u0.w
+    }
 }
 
 impl SurfaceSample for EnumSurfaceSample {
@@ -140,10 +284,38 @@ impl SurfaceSample for EnumSurfaceSample {
         }
     }
 
-    fn sample(&self, indices: &[u32], vertices: &[Vertex], rng: &mut DefaultRng) -> Vec3A {
+    fn sample(
+        &self,
+        p: Vec3A,
+        indices: &[u32],
+        vertices: &[Vertex],
+        distribution: &[f32],
+        rng: &mut impl Rng,
+    ) -> Vec3A {
+        match self.t {
+            SurfaceType::Triangle => {
+                Triangle { data: &self.data }.sample(p, indices, vertices, distribution, rng)
+            }
+            SurfaceType::Sphere => {
+                Sphere { data: &self.data }.sample(p, indices, vertices, distribution, rng)
+            }
+        }
+    }
+
+    fn sample_area(
+        &self,
+        indices: &[u32],
+        vertices: &[Vertex],
+        distribution: &[f32],
+        rng: &mut impl Rng,
+    ) -> (Vec3A, Vec3A, f32) {
         match self.t {
-            SurfaceType::Triangle => Triangle { data: &self.data }.sample(indices, vertices, rng),
-            SurfaceType::Sphere => Sphere { data: &self.data }.sample(indices, vertices, rng),
+            SurfaceType::Triangle => {
+                Triangle { data: &self.data }.sample_area(indices, vertices, distribution, rng)
+            }
+            SurfaceType::Sphere => {
+                Sphere { data: &self.data }.sample_area(indices, vertices, distribution, rng)
+            }
         }
     }
 }
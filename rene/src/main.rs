@@ -3,9 +3,9 @@ use std::{
     collections::HashSet,
     ffi::{c_void, CStr, CString},
     fs::File,
-    io::{Read, Write},
+    io::Write,
     os::raw::c_char,
-    path::PathBuf,
+    path::{Path, PathBuf},
     ptr::{self, null},
     time::Instant,
 };
@@ -17,26 +17,33 @@ use ash::{
     vk::{self},
 };
 
-use clap::{ArgEnum, Parser};
-use glam::{Vec2, Vec3A};
+use clap::{ArgEnum, Parser, Subcommand};
+use glam::{Affine3A, Vec2, Vec3A};
 use gpu_allocator::{
     vulkan::{Allocation, AllocationCreateDesc, Allocator, AllocatorCreateDesc},
     MemoryLocation,
 };
-use pbrt_parser::include::expand_include;
+use pbrt_parser::{
+    include::{expand_include, read_to_string},
+    write::{to_pbrt_string, Version},
+};
 use rand::prelude::*;
 use rene_shader::{
     area_light::EnumAreaLight,
     light::EnumLight,
+    light_bvh::LightBvhNode,
     material::EnumMaterial,
     medium::EnumMedium,
+    mlt::{self, MltChain},
+    sppm::{self, SppmPixel},
     surface_sample::{EnumSurfaceSample, SurfaceSample},
     texture::EnumTexture,
     IndexData, Uniform, Vertex,
 };
-use scene::Scene;
+use scene::{Scene, TlasInstance};
 
 mod scene;
+mod upgrade;
 
 #[derive(Debug, Clone, Copy)]
 pub enum ShaderOffset {
@@ -51,9 +58,22 @@ enum Denoiser {
     Oidn,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    #[clap(about = "Convert pbrt-v3 scene to pbrt-v4 and print it to stdout")]
+    Upgrade {
+        #[clap(help = "pbrt file")]
+        pbrt_path: PathBuf,
+    },
+}
+
 #[derive(Parser)]
+#[clap(subcommand_negates_reqs = true)]
 struct Opts {
-    #[clap(help = "pbrt file")]
+    #[clap(subcommand)]
+    command: Option<Command>,
+    // Required unless a subcommand is given
+    #[clap(help = "pbrt file", required = true)]
     pbrt_path: Option<PathBuf>,
     #[clap(help = "AOV normal", long = "aov-normal")]
     aov_normal: Option<PathBuf>,
@@ -68,6 +88,91 @@ struct Opts {
     denoiser: Denoiser,
     #[clap(help = "Dump SPIR-V module", long = "dump-module")]
     dump_module_path: Option<PathBuf>,
+    #[clap(help = "Treat scene warnings as errors", long = "strict")]
+    strict: bool,
+}
+
+/// Read a pbrt file with its includes expanded and parse it.
+/// Parse errors are reported to stderr.
+fn load_pbrt(pbrt_path: &Path) -> Option<Vec<pbrt_parser::Scene>> {
+    use ariadne::{Color, Fmt, Label, Report, ReportKind, Source};
+    use chumsky::Parser;
+
+    let mut pbrt_file = read_to_string(pbrt_path).unwrap();
+
+    let pbrt_dir = pbrt_path.parent().unwrap_or_else(|| Path::new(""));
+    match expand_include(pbrt_file.as_str(), pbrt_dir).unwrap() {
+        Cow::Borrowed(_) => {}
+        Cow::Owned(s) => pbrt_file = s,
+    }
+
+    let (scenes, errs) = pbrt_parser::parse_pbrt().parse_recovery(pbrt_file.as_str());
+    let path = pbrt_path.to_string_lossy();
+
+    errs.into_iter().for_each(|e| {
+        let msg = format!(
+            "{}{}, expected {}",
+            if e.found().is_some() {
+                "Unexpected token"
+            } else {
+                "Unexpected end of input"
+            },
+            if let Some(label) = e.label() {
+                format!(" while parsing {}", label)
+            } else {
+                String::new()
+            },
+            if e.expected().len() == 0 {
+                "something else".to_string()
+            } else {
+                e.expected()
+                    .map(|expected| match expected {
+                        Some(expected) => expected.to_string(),
+                        None => "end of input".to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            },
+        );
+
+        let report = Report::build(ReportKind::Error, &path, e.span().start)
+            .with_code(3)
+            .with_message(msg)
+            .with_label(
+                Label::new((&path, e.span()))
+                    .with_message(format!(
+                        "Unexpected {}",
+                        e.found()
+                            .map(|c| format!("token {}", c.fg(Color::Red)))
+                            .unwrap_or_else(|| "end of input".to_string())
+                    ))
+                    .with_color(Color::Red),
+            );
+
+        let report = match e.reason() {
+            chumsky::error::SimpleReason::Unclosed { span, delimiter } => report.with_label(
+                Label::new((&path, span.clone()))
+                    .with_message(format!(
+                        "Unclosed delimiter {}",
+                        delimiter.fg(Color::Yellow)
+                    ))
+                    .with_color(Color::Yellow),
+            ),
+            chumsky::error::SimpleReason::Unexpected => report,
+            chumsky::error::SimpleReason::Custom(msg) => report.with_label(
+                Label::new((&path, e.span()))
+                    .with_message(format!("{}", msg.fg(Color::Yellow)))
+                    .with_color(Color::Yellow),
+            ),
+        };
+
+        report
+            .finish()
+            .eprint((&path, Source::from(&pbrt_file)))
+            .unwrap();
+    });
+
+    scenes
 }
 
 fn main() {
@@ -81,7 +186,6 @@ fn main() {
     const N_SAMPLES_ITER: u32 = 100;
 
     let opts: Opts = Opts::parse();
-    let mut pbrt_file = String::new();
 
     #[cfg(not(feature = "optix-denoiser"))]
     if opts.denoiser == Denoiser::Optix {
@@ -105,98 +209,30 @@ fn main() {
         return;
     }
 
+    if let Some(Command::Upgrade { pbrt_path }) = opts.command {
+        if let Some(scenes) = load_pbrt(&pbrt_path) {
+            let (scenes, warnings) = upgrade::upgrade(scenes);
+            // stdout is reserved for the converted scene
+            for warning in warnings {
+                eprintln!("warning: {}", warning);
+            }
+            print!("{}", to_pbrt_string(&scenes, Version::V4));
+        }
+        return;
+    }
+
     let before_parse = Instant::now();
+    // clap requires the path without a subcommand
     let mut pbrt_path = opts.pbrt_path.unwrap();
 
-    File::open(&pbrt_path)
-        .unwrap()
-        .read_to_string(&mut pbrt_file)
-        .unwrap();
+    let parsed_scene = match load_pbrt(&pbrt_path) {
+        Some(scenes) => scenes,
+        None => return,
+    };
 
     pbrt_path.pop();
 
-    match expand_include(pbrt_file.as_str(), &pbrt_path).unwrap() {
-        Cow::Borrowed(_) => {}
-        Cow::Owned(s) => pbrt_file = s,
-    }
-
-    let parsed_scene = {
-        use ariadne::{Color, Fmt, Label, Report, ReportKind, Source};
-        use chumsky::Parser;
-        let (scenes, errs) = pbrt_parser::parse_pbrt().parse_recovery(pbrt_file.as_str());
-        let path = pbrt_path.to_string_lossy();
-
-        errs.into_iter().for_each(|e| {
-            let msg = format!(
-                "{}{}, expected {}",
-                if e.found().is_some() {
-                    "Unexpected token"
-                } else {
-                    "Unexpected end of input"
-                },
-                if let Some(label) = e.label() {
-                    format!(" while parsing {}", label)
-                } else {
-                    String::new()
-                },
-                if e.expected().len() == 0 {
-                    "something else".to_string()
-                } else {
-                    e.expected()
-                        .map(|expected| match expected {
-                            Some(expected) => expected.to_string(),
-                            None => "end of input".to_string(),
-                        })
-                        .collect::<Vec<_>>()
-                        .join(", ")
-                },
-            );
-
-            let report = Report::build(ReportKind::Error, &path, e.span().start)
-                .with_code(3)
-                .with_message(msg)
-                .with_label(
-                    Label::new((&path, e.span()))
-                        .with_message(format!(
-                            "Unexpected {}",
-                            e.found()
-                                .map(|c| format!("token {}", c.fg(Color::Red)))
-                                .unwrap_or_else(|| "end of input".to_string())
-                        ))
-                        .with_color(Color::Red),
-                );
-
-            let report = match e.reason() {
-                chumsky::error::SimpleReason::Unclosed { span, delimiter } => report.with_label(
-                    Label::new((&path, span.clone()))
-                        .with_message(format!(
-                            "Unclosed delimiter {}",
-                            delimiter.fg(Color::Yellow)
-                        ))
-                        .with_color(Color::Yellow),
-                ),
-                chumsky::error::SimpleReason::Unexpected => report,
-                chumsky::error::SimpleReason::Custom(msg) => report.with_label(
-                    Label::new((&path, e.span()))
-                        .with_message(format!("{}", msg.fg(Color::Yellow)))
-                        .with_color(Color::Yellow),
-                ),
-            };
-
-            report
-                .finish()
-                .print((&path, Source::from(&pbrt_file)))
-                .unwrap();
-        });
-
-        if let Some(scenes) = scenes {
-            scenes
-        } else {
-            return;
-        }
-    };
-
-    let scene = match scene::Scene::create(parsed_scene, &pbrt_path) {
+    let scene = match scene::Scene::create(parsed_scene, &pbrt_path, opts.strict) {
         Ok(scene) => scene,
         Err(e) => {
             println!("{}", e);
@@ -206,6 +242,17 @@ fn main() {
 
     log::info!("Scene parsed ({} ms)", before_parse.elapsed().as_millis());
 
+    // SPPM takes a sample per iteration, MLT mutates each chain once per sample
+    let n_samples = match &scene.integrator {
+        scene::intermediate_scene::Integrator::Sppm(parameters) => parameters.iterations,
+        scene::intermediate_scene::Integrator::Mlt(parameters) => {
+            let mutations = parameters.mutations_per_pixel as u64
+                * (scene.film.xresolution * scene.film.yresolution) as u64;
+            ((mutations + mlt::CHAINS as u64 - 1) / mlt::CHAINS as u64) as u32
+        }
+        _ => N_SAMPLES,
+    };
+
     let validation_layers: Vec<CString> = if ENABLE_VALIDATION_LAYER {
         vec![CString::new("VK_LAYER_KHRONOS_validation").unwrap()]
     } else {
@@ -510,7 +557,7 @@ fn main() {
     }
 
     let before_scene_buffer = Instant::now();
-    let scene_buffers = SceneBuffers::new(
+    let mut scene_buffers = SceneBuffers::new(
         &mut allocator,
         &scene,
         &device,
@@ -636,6 +683,65 @@ fn main() {
                             .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
                             .binding(12)
                             .build(),
+                        // background distribution
+                        vk::DescriptorSetLayoutBinding::builder()
+                            .descriptor_count(1)
+                            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                            .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
+                            .binding(13)
+                            .build(),
+                        // emitter distribution
+                        vk::DescriptorSetLayoutBinding::builder()
+                            .descriptor_count(1)
+                            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                            .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
+                            .binding(14)
+                            .build(),
+                        // light bvh
+                        vk::DescriptorSetLayoutBinding::builder()
+                            .descriptor_count(1)
+                            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                            .stage_flags(
+                                vk::ShaderStageFlags::CLOSEST_HIT_KHR
+                                    | vk::ShaderStageFlags::RAYGEN_KHR,
+                            )
+                            .binding(15)
+                            .build(),
+                        // light paths splatted to the film
+                        vk::DescriptorSetLayoutBinding::builder()
+                            .descriptor_count(1)
+                            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                            .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
+                            .binding(16)
+                            .build(),
+                        // visible points and photon statistics of SPPM
+                        vk::DescriptorSetLayoutBinding::builder()
+                            .descriptor_count(1)
+                            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                            .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
+                            .binding(17)
+                            .build(),
+                        // hashed grid of the SPPM visible points
+                        vk::DescriptorSetLayoutBinding::builder()
+                            .descriptor_count(1)
+                            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                            .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
+                            .binding(18)
+                            .build(),
+                        // Markov chains of MLT
+                        vk::DescriptorSetLayoutBinding::builder()
+                            .descriptor_count(1)
+                            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                            .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
+                            .binding(19)
+                            .build(),
+                        // MLT bootstrap, read back by the host
+                        vk::DescriptorSetLayoutBinding::builder()
+                            .descriptor_count(1)
+                            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                            .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
+                            .binding(20)
+                            .build(),
                     ])
                     .build(),
                 None,
@@ -645,7 +751,7 @@ fn main() {
 
         let push_constant_range = vk::PushConstantRange::builder()
             .offset(0)
-            .size(4)
+            .size(8)
             .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
             .build();
 
@@ -678,6 +784,41 @@ fn main() {
                 .any_hit_shader(vk::SHADER_UNUSED_KHR)
                 .intersection_shader(vk::SHADER_UNUSED_KHR)
                 .build(),
+            vk::RayTracingShaderGroupCreateInfoKHR::builder()
+                .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
+                .general_shader(9)
+                .closest_hit_shader(vk::SHADER_UNUSED_KHR)
+                .any_hit_shader(vk::SHADER_UNUSED_KHR)
+                .intersection_shader(vk::SHADER_UNUSED_KHR)
+                .build(),
+            vk::RayTracingShaderGroupCreateInfoKHR::builder()
+                .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
+                .general_shader(10)
+                .closest_hit_shader(vk::SHADER_UNUSED_KHR)
+                .any_hit_shader(vk::SHADER_UNUSED_KHR)
+                .intersection_shader(vk::SHADER_UNUSED_KHR)
+                .build(),
+            vk::RayTracingShaderGroupCreateInfoKHR::builder()
+                .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
+                .general_shader(11)
+                .closest_hit_shader(vk::SHADER_UNUSED_KHR)
+                .any_hit_shader(vk::SHADER_UNUSED_KHR)
+                .intersection_shader(vk::SHADER_UNUSED_KHR)
+                .build(),
+            vk::RayTracingShaderGroupCreateInfoKHR::builder()
+                .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
+                .general_shader(12)
+                .closest_hit_shader(vk::SHADER_UNUSED_KHR)
+                .any_hit_shader(vk::SHADER_UNUSED_KHR)
+                .intersection_shader(vk::SHADER_UNUSED_KHR)
+                .build(),
+            vk::RayTracingShaderGroupCreateInfoKHR::builder()
+                .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
+                .general_shader(13)
+                .closest_hit_shader(vk::SHADER_UNUSED_KHR)
+                .any_hit_shader(vk::SHADER_UNUSED_KHR)
+                .intersection_shader(vk::SHADER_UNUSED_KHR)
+                .build(),
             // group1 = [ miss ]
             vk::RayTracingShaderGroupCreateInfoKHR::builder()
                 .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
@@ -776,6 +917,31 @@ fn main() {
                     std::ffi::CStr::from_bytes_with_nul(b"main_ray_generation_volpath\0").unwrap(),
                 )
                 .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::RAYGEN_KHR)
+                .module(shader_module)
+                .name(std::ffi::CStr::from_bytes_with_nul(b"main_ray_generation_ao\0").unwrap())
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::RAYGEN_KHR)
+                .module(shader_module)
+                .name(std::ffi::CStr::from_bytes_with_nul(b"main_ray_generation_direct\0").unwrap())
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::RAYGEN_KHR)
+                .module(shader_module)
+                .name(std::ffi::CStr::from_bytes_with_nul(b"main_ray_generation_bdpt\0").unwrap())
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::RAYGEN_KHR)
+                .module(shader_module)
+                .name(std::ffi::CStr::from_bytes_with_nul(b"main_ray_generation_sppm\0").unwrap())
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::RAYGEN_KHR)
+                .module(shader_module)
+                .name(std::ffi::CStr::from_bytes_with_nul(b"main_ray_generation_mlt\0").unwrap())
+                .build(),
         ];
 
         let pipeline = unsafe {
@@ -858,6 +1024,38 @@ fn main() {
             ty: vk::DescriptorType::STORAGE_BUFFER,
             descriptor_count: 1,
         },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+        },
     ];
 
     let descriptor_pool_info = vk::DescriptorPoolCreateInfo::builder()
@@ -1083,6 +1281,126 @@ fn main() {
             .build()
     };
 
+    let background_distribution_buffer_info = [vk::DescriptorBufferInfo::builder()
+        .buffer(scene_buffers.background_distribution.buffer)
+        .range(vk::WHOLE_SIZE)
+        .build()];
+
+    let background_distribution_write = {
+        vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set)
+            .dst_binding(13)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(&background_distribution_buffer_info)
+            .build()
+    };
+
+    let emit_distribution_buffer_info = [vk::DescriptorBufferInfo::builder()
+        .buffer(scene_buffers.emit_distribution.buffer)
+        .range(vk::WHOLE_SIZE)
+        .build()];
+
+    let emit_distribution_write = {
+        vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set)
+            .dst_binding(14)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(&emit_distribution_buffer_info)
+            .build()
+    };
+
+    let light_bvh_buffer_info = [vk::DescriptorBufferInfo::builder()
+        .buffer(scene_buffers.light_bvh.buffer)
+        .range(vk::WHOLE_SIZE)
+        .build()];
+
+    let light_bvh_write = {
+        vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set)
+            .dst_binding(15)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(&light_bvh_buffer_info)
+            .build()
+    };
+
+    let splat_buffer_info = [vk::DescriptorBufferInfo::builder()
+        .buffer(scene_buffers.splat.buffer)
+        .range(vk::WHOLE_SIZE)
+        .build()];
+
+    let splat_write = {
+        vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set)
+            .dst_binding(16)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(&splat_buffer_info)
+            .build()
+    };
+
+    let sppm_pixels_buffer_info = [vk::DescriptorBufferInfo::builder()
+        .buffer(scene_buffers.sppm_pixels.buffer)
+        .range(vk::WHOLE_SIZE)
+        .build()];
+
+    let sppm_pixels_write = {
+        vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set)
+            .dst_binding(17)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(&sppm_pixels_buffer_info)
+            .build()
+    };
+
+    let sppm_grid_buffer_info = [vk::DescriptorBufferInfo::builder()
+        .buffer(scene_buffers.sppm_grid.buffer)
+        .range(vk::WHOLE_SIZE)
+        .build()];
+
+    let sppm_grid_write = {
+        vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set)
+            .dst_binding(18)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(&sppm_grid_buffer_info)
+            .build()
+    };
+
+    let mlt_chains_buffer_info = [vk::DescriptorBufferInfo::builder()
+        .buffer(scene_buffers.mlt_chains.buffer)
+        .range(vk::WHOLE_SIZE)
+        .build()];
+
+    let mlt_chains_write = {
+        vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set)
+            .dst_binding(19)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(&mlt_chains_buffer_info)
+            .build()
+    };
+
+    let mlt_bootstrap_buffer_info = [vk::DescriptorBufferInfo::builder()
+        .buffer(scene_buffers.mlt_bootstrap.buffer)
+        .range(vk::WHOLE_SIZE)
+        .build()];
+
+    let mlt_bootstrap_write = {
+        vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set)
+            .dst_binding(20)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(&mlt_bootstrap_buffer_info)
+            .build()
+    };
+
     unsafe {
         device.update_descriptor_sets(
             &[
@@ -1099,6 +1417,14 @@ fn main() {
                 indices_write,
                 vertices_write,
                 mediums_write,
+                background_distribution_write,
+                emit_distribution_write,
+                light_bvh_write,
+                splat_write,
+                sppm_pixels_write,
+                sppm_grid_write,
+                mlt_chains_write,
+                mlt_bootstrap_write,
             ],
             &[],
         );
@@ -1160,9 +1486,9 @@ fn main() {
             rt_pipeline_properties.shader_group_base_alignment,
         ) as u64;
 
-        // |[ raygen shader ]|[ miss shader ]|[ miss shader (PDF) ]|[ hit shader (triangle) ]|[ hit shader (sphere) ]|[ hit shader (triangle) (PDF) ]|[ hit shader (sphere) (PDF) ]|
-        // |                 |               |                     |                         |                       |                               |                             |
-        // | 0               | 1             | 2                   | 3                       | 3                     | 4                             | 5                           |
+        // |[ raygen shaders (path, volpath, ao, direct, bdpt, sppm, mlt) ]|[ miss shader ]|[ miss shader (PDF) ]|[ hit shader (triangle) ]|[ hit shader (sphere) ]|[ hit shader (triangle) (PDF) ]|[ hit shader (sphere) (PDF) ]|
+        // |                                                               |               |                     |                         |                       |                               |                             |
+        // | 0 - 6                                                         | 7             | 8                   | 9                       | 10                    | 11                            | 12                          |
 
         let sbt_address =
             unsafe { get_buffer_device_address(&device, shader_binding_table_buffer.buffer) };
@@ -1179,19 +1505,54 @@ fn main() {
             .stride(handle_size_aligned)
             .build();
 
+        let sbt_raygen_ao_region = vk::StridedDeviceAddressRegionKHR::builder()
+            .device_address(sbt_address + 2 * handle_size_aligned)
+            .size(handle_size_aligned)
+            .stride(handle_size_aligned)
+            .build();
+
+        let sbt_raygen_direct_region = vk::StridedDeviceAddressRegionKHR::builder()
+            .device_address(sbt_address + 3 * handle_size_aligned)
+            .size(handle_size_aligned)
+            .stride(handle_size_aligned)
+            .build();
+
+        let sbt_raygen_bdpt_region = vk::StridedDeviceAddressRegionKHR::builder()
+            .device_address(sbt_address + 4 * handle_size_aligned)
+            .size(handle_size_aligned)
+            .stride(handle_size_aligned)
+            .build();
+
+        let sbt_raygen_sppm_region = vk::StridedDeviceAddressRegionKHR::builder()
+            .device_address(sbt_address + 5 * handle_size_aligned)
+            .size(handle_size_aligned)
+            .stride(handle_size_aligned)
+            .build();
+
+        let sbt_raygen_mlt_region = vk::StridedDeviceAddressRegionKHR::builder()
+            .device_address(sbt_address + 6 * handle_size_aligned)
+            .size(handle_size_aligned)
+            .stride(handle_size_aligned)
+            .build();
+
         let sbt_raygen_region = match scene.integrator {
-            scene::intermediate_scene::Integrator::Path => sbt_raygen_path_region,
-            scene::intermediate_scene::Integrator::VolPath => sbt_raygen_volpath_region,
+            scene::intermediate_scene::Integrator::Path(_) => sbt_raygen_path_region,
+            scene::intermediate_scene::Integrator::VolPath(_) => sbt_raygen_volpath_region,
+            scene::intermediate_scene::Integrator::AmbientOcclusion(_) => sbt_raygen_ao_region,
+            scene::intermediate_scene::Integrator::DirectLighting(_) => sbt_raygen_direct_region,
+            scene::intermediate_scene::Integrator::Bdpt(_) => sbt_raygen_bdpt_region,
+            scene::intermediate_scene::Integrator::Sppm(_) => sbt_raygen_sppm_region,
+            scene::intermediate_scene::Integrator::Mlt(_) => sbt_raygen_mlt_region,
         };
 
         let sbt_miss_region = vk::StridedDeviceAddressRegionKHR::builder()
-            .device_address(sbt_address + 2 * handle_size_aligned)
+            .device_address(sbt_address + 7 * handle_size_aligned)
             .size(2 * handle_size_aligned)
             .stride(handle_size_aligned)
             .build();
 
         let sbt_hit_region = vk::StridedDeviceAddressRegionKHR::builder()
-            .device_address(sbt_address + 4 * handle_size_aligned)
+            .device_address(sbt_address + 9 * handle_size_aligned)
             .size(4 * handle_size_aligned)
             .stride(handle_size_aligned)
             .build();
@@ -1298,6 +1659,16 @@ fn main() {
             )
             .build();
 
+        // Photons are launched in layers of the film's size
+        let pixels = scene.film.xresolution * scene.film.yresolution;
+        let photon_layers = (scene.uniform.sppm_photons_per_iteration + pixels - 1) / pixels;
+
+        // The splatted light paths of BDPT and the SPPM pixels are written by every launch
+        let splat_barrier = vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE | vk::AccessFlags::SHADER_READ)
+            .dst_access_mask(vk::AccessFlags::SHADER_WRITE | vk::AccessFlags::SHADER_READ)
+            .build();
+
         let mut rng = StdRng::from_entropy();
         let mut sampled = 0;
 
@@ -1312,9 +1683,10 @@ fn main() {
                 .expect("Failed to allocate Command Buffers!")[0]
         };
 
-        while sampled < N_SAMPLES {
-            let samples = std::cmp::min(N_SAMPLES - sampled, N_SAMPLES_ITER);
-            sampled += samples;
+        // MLT starts its chains from bootstrap paths picked by their luminance,
+        // whose average also sets the brightness of the image
+        if let scene::intermediate_scene::Integrator::Mlt(_) = scene.integrator {
+            let bootstrap_seed = rng.next_u32();
 
             {
                 let command_buffer_begin_info = vk::CommandBufferBeginInfo::builder()
@@ -1325,6 +1697,16 @@ fn main() {
                     .expect("Failed to begin recording Command Buffer at beginning!");
             }
 
+            // Mutations must see the scene their chain's path was traced in,
+            // so an animated scene is frozen at one shutter time for the whole render
+            if scene_buffers.motion.is_some() {
+                log::warn!("MLT renders animated scenes without motion blur");
+                scene_buffers.stage_time(&scene, 0, rng.gen());
+                unsafe {
+                    scene_buffers.cmd_set_time(&device, &acceleration_structure, command_buffer, 0);
+                }
+            }
+
             unsafe {
                 device.cmd_bind_pipeline(
                     command_buffer,
@@ -1339,39 +1721,212 @@ fn main() {
                     &[descriptor_set],
                     &[],
                 );
+                device.cmd_push_constants(
+                    command_buffer,
+                    pipeline_layout,
+                    vk::ShaderStageFlags::RAYGEN_KHR,
+                    0,
+                    bytemuck::cast_slice(&[bootstrap_seed, mlt::PASS_BOOTSTRAP]),
+                );
+                rt_pipeline.cmd_trace_rays(
+                    command_buffer,
+                    &sbt_raygen_region,
+                    &sbt_miss_region,
+                    &sbt_hit_region,
+                    &sbt_call_region,
+                    mlt::CHAINS,
+                    1,
+                    1,
+                );
+
+                let host_barrier = vk::MemoryBarrier::builder()
+                    .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                    .dst_access_mask(vk::AccessFlags::HOST_READ)
+                    .build();
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                    vk::PipelineStageFlags::HOST,
+                    vk::DependencyFlags::empty(),
+                    &[host_barrier],
+                    &[],
+                    &[],
+                );
+
+                device.end_command_buffer(command_buffer).unwrap();
+
+                let command_buffers = [command_buffer];
+
+                let submit_infos = [vk::SubmitInfo::builder()
+                    .command_buffers(&command_buffers)
+                    .build()];
+
+                device
+                    .queue_submit(graphics_queue, &submit_infos, vk::Fence::null())
+                    .expect("Failed to execute queue submit.");
+
+                device.queue_wait_idle(graphics_queue).unwrap();
             }
-            for _ in 0..samples {
-                unsafe {
-                    device.cmd_pipeline_barrier(
-                        command_buffer,
-                        vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
-                        vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
-                        vk::DependencyFlags::empty(),
-                        &[],
-                        &[],
-                        &[image_barrier2],
-                    );
 
-                    device.cmd_push_constants(
-                        command_buffer,
-                        pipeline_layout,
-                        vk::ShaderStageFlags::RAYGEN_KHR,
-                        0,
-                        &rng.next_u32().to_le_bytes(),
-                    );
+            let mut bootstrap: Vec<u32> = scene_buffers.mlt_bootstrap.load();
+            let (header, rest) = bootstrap.split_at_mut(mlt::BOOTSTRAP_HEADER);
+            let (starts, weights) = rest.split_at_mut(mlt::CHAINS as usize);
 
-                    rt_pipeline.cmd_trace_rays(
-                        command_buffer,
-                        &sbt_raygen_region,
-                        &sbt_miss_region,
-                        &sbt_hit_region,
-                        &sbt_call_region,
-                        scene.film.xresolution,
-                        scene.film.yresolution,
-                        1,
+            let mut sum = 0.0;
+            let cdf: Vec<f64> = weights
+                .iter()
+                .map(|&weight| {
+                    let weight = f32::from_bits(weight) as f64;
+                    if weight.is_finite() && weight > 0.0 {
+                        sum += weight;
+                    }
+                    sum
+                })
+                .collect();
+            for start in starts.iter_mut() {
+                let u = rng.gen::<f64>() * sum;
+                *start = cdf.partition_point(|&c| c <= u).min(cdf.len() - 1) as u32;
+            }
+
+            // pbrt's `b`, the image's average luminance
+            let b = sum / cdf.len() as f64 * (scene.uniform.max_depth + 1) as f64;
+            header[0] = ((b * pixels as f64 / mlt::CHAINS as f64) as f32).to_bits();
+            header[1] = n_samples;
+            header[2] = bootstrap_seed;
+            scene_buffers.mlt_bootstrap.store(&bootstrap);
+        }
+
+        // Every sample of an animated scene is taken at its own time, staged per submission,
+        // MLT keeps the time its bootstrap was traced at
+        let moving = scene_buffers.motion.is_some()
+            && !matches!(
+                scene.integrator,
+                scene::intermediate_scene::Integrator::Mlt(_)
+            );
+        let samples_per_iter = if moving { MOTION_SLOTS } else { N_SAMPLES_ITER };
+
+        while sampled < n_samples {
+            let samples = std::cmp::min(n_samples - sampled, samples_per_iter);
+
+            if moving {
+                for i in 0..samples {
+                    // Stratify the shutter interval over the whole render
+                    scene_buffers.stage_time(
+                        &scene,
+                        i,
+                        ((sampled + i) as f32 + rng.gen::<f32>()) / n_samples as f32,
                     );
                 }
             }
+            sampled += samples;
+
+            {
+                let command_buffer_begin_info = vk::CommandBufferBeginInfo::builder()
+                    .flags(vk::CommandBufferUsageFlags::SIMULTANEOUS_USE)
+                    .build();
+
+                unsafe { device.begin_command_buffer(command_buffer, &command_buffer_begin_info) }
+                    .expect("Failed to begin recording Command Buffer at beginning!");
+            }
+
+            unsafe {
+                device.cmd_bind_pipeline(
+                    command_buffer,
+                    vk::PipelineBindPoint::RAY_TRACING_KHR,
+                    graphics_pipeline,
+                );
+                device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    vk::PipelineBindPoint::RAY_TRACING_KHR,
+                    pipeline_layout,
+                    0,
+                    &[descriptor_set],
+                    &[],
+                );
+            }
+            // An extra launch after the last sample of BDPT only moves the remaining splats,
+            // the one of SPPM adds the photons gathered over every iteration
+            // and the one of MLT scales the splats by the bootstrap's brightness
+            let finish = matches!(
+                scene.integrator,
+                scene::intermediate_scene::Integrator::Bdpt(_)
+                    | scene::intermediate_scene::Integrator::Sppm(_)
+                    | scene::intermediate_scene::Integrator::Mlt(_)
+            ) && sampled == n_samples;
+            let (width, height) = (scene.film.xresolution, scene.film.yresolution);
+            for i in 0..samples + finish as u32 {
+                // The pass and size of each launch taking the sample
+                let launches = match scene.integrator {
+                    scene::intermediate_scene::Integrator::Sppm(_) if i < samples => vec![
+                        (sppm::PASS_CAMERA, width, height, 1),
+                        (sppm::PASS_PHOTONS, width, height, photon_layers),
+                        (sppm::PASS_UPDATE, width, height, 1),
+                    ],
+                    scene::intermediate_scene::Integrator::Sppm(_) => {
+                        vec![(sppm::PASS_ESTIMATE, width, height, 1)]
+                    }
+                    // The chains start from their bootstrap paths before the first mutation
+                    scene::intermediate_scene::Integrator::Mlt(_) if sampled - samples + i == 0 => {
+                        vec![
+                            (mlt::PASS_INIT, mlt::CHAINS, 1, 1),
+                            (mlt::PASS_MUTATE, mlt::CHAINS, 1, 1),
+                        ]
+                    }
+                    scene::intermediate_scene::Integrator::Mlt(_) if i < samples => {
+                        vec![(mlt::PASS_MUTATE, mlt::CHAINS, 1, 1)]
+                    }
+                    scene::intermediate_scene::Integrator::Mlt(_) => {
+                        vec![(mlt::PASS_FLUSH, width, height, 1)]
+                    }
+                    _ => vec![((i == samples) as u32, width, height, 1)],
+                };
+
+                if moving && i < samples {
+                    unsafe {
+                        scene_buffers.cmd_set_time(
+                            &device,
+                            &acceleration_structure,
+                            command_buffer,
+                            i,
+                        );
+                    }
+                }
+
+                for (pass, width, height, depth) in launches {
+                    let push_constants = [rng.next_u32(), pass];
+
+                    unsafe {
+                        device.cmd_pipeline_barrier(
+                            command_buffer,
+                            vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                            vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                            vk::DependencyFlags::empty(),
+                            &[splat_barrier],
+                            &[],
+                            &[image_barrier2],
+                        );
+
+                        device.cmd_push_constants(
+                            command_buffer,
+                            pipeline_layout,
+                            vk::ShaderStageFlags::RAYGEN_KHR,
+                            0,
+                            bytemuck::cast_slice(&push_constants),
+                        );
+
+                        rt_pipeline.cmd_trace_rays(
+                            command_buffer,
+                            &sbt_raygen_region,
+                            &sbt_miss_region,
+                            &sbt_hit_region,
+                            &sbt_call_region,
+                            width,
+                            height,
+                            depth,
+                        );
+                    }
+                }
+            }
             let now = Instant::now();
             unsafe {
                 device.end_command_buffer(command_buffer).unwrap();
@@ -1391,7 +1946,7 @@ fn main() {
             eprint!(
                 "\rSamples: {} / {} ({} ms)",
                 sampled,
-                N_SAMPLES,
+                n_samples,
                 now.elapsed().as_millis()
             );
         }
@@ -1618,9 +2173,9 @@ fn main() {
     let mut data_normal_linear = f32_4_to_3(&data_normal_linear);
     let mut data_albedo_linear = f32_4_to_3(&data_albedo_linear);
 
-    average(&mut data_image_linear, N_SAMPLES);
-    average(&mut data_normal_linear, N_SAMPLES);
-    average(&mut data_albedo_linear, N_SAMPLES);
+    average(&mut data_image_linear, n_samples);
+    average(&mut data_normal_linear, n_samples);
+    average(&mut data_albedo_linear, n_samples);
 
     #[cfg(feature = "optix-denoiser")]
     if opts.denoiser == Denoiser::Optix {
@@ -2081,6 +2636,12 @@ impl BufferResource {
         }
     }
 
+    /// Read back a buffer in host visible memory.
+    fn load<T: bytemuck::Pod>(&self) -> Vec<T> {
+        let size = self.size as usize;
+        bytemuck::cast_slice(&self.allocation.mapped_slice().unwrap()[..size]).to_vec()
+    }
+
     fn to_gpu_only(
         self,
         allocator: &mut Allocator,
@@ -2114,46 +2675,14 @@ impl BufferResource {
                 .bind_buffer_memory(buffer, allocation.memory(), allocation.offset())
                 .unwrap();
 
-            // COPY
-
-            {
-                let command_buffer = {
-                    let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::builder()
-                        .command_buffer_count(1)
-                        .command_pool(command_pool)
-                        .level(vk::CommandBufferLevel::PRIMARY)
-                        .build();
-
-                    device
-                        .allocate_command_buffers(&command_buffer_allocate_info)
-                        .expect("Failed to allocate Command Buffers!")[0]
-                };
-
-                let command_buffer_begin_info = vk::CommandBufferBeginInfo::builder()
-                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT)
-                    .build();
-
-                device
-                    .begin_command_buffer(command_buffer, &command_buffer_begin_info)
-                    .expect("Failed to begin recording Command Buffer at beginning!");
-
-                let buffer_copy = [vk::BufferCopy::builder().size(size).build()];
-                device.cmd_copy_buffer(command_buffer, self.buffer, buffer, &buffer_copy);
-                device.end_command_buffer(command_buffer).unwrap();
-
-                let command_buffers = [command_buffer];
-
-                let submit_infos = [vk::SubmitInfo::builder()
-                    .command_buffers(&command_buffers)
-                    .build()];
-
-                device
-                    .queue_submit(graphics_queue, &submit_infos, vk::Fence::null())
-                    .expect("Failed to execute queue submit.");
-
-                device.queue_wait_idle(graphics_queue).unwrap();
-                device.free_command_buffers(command_pool, &[command_buffer]);
-            }
+            copy_buffer(
+                device,
+                command_pool,
+                graphics_queue,
+                self.buffer,
+                buffer,
+                size,
+            );
 
             self.destroy(allocator, device);
 
@@ -2166,10 +2695,91 @@ impl BufferResource {
         }
     }
 
-    unsafe fn destroy(self, allocator: &mut Allocator, device: &ash::Device) {
-        device.destroy_buffer(self.buffer, None);
-        allocator.free(self.allocation).unwrap();
-    }
+    /// Overwrite a buffer made by `to_gpu_only` through a staging buffer.
+    fn upload<T: Copy>(
+        &self,
+        data: &[T],
+        allocator: &mut Allocator,
+        device: &ash::Device,
+        command_pool: vk::CommandPool,
+        graphics_queue: vk::Queue,
+    ) {
+        let size = (std::mem::size_of::<T>() * data.len()) as vk::DeviceSize;
+        assert!(self.size >= size);
+
+        let mut staging_buffer = BufferResource::new(
+            allocator,
+            size,
+            MemoryLocation::CpuToGpu,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            None,
+            device,
+        );
+        staging_buffer.store(data);
+
+        unsafe {
+            copy_buffer(
+                device,
+                command_pool,
+                graphics_queue,
+                staging_buffer.buffer,
+                self.buffer,
+                size,
+            );
+            staging_buffer.destroy(allocator, device);
+        }
+    }
+
+    unsafe fn destroy(self, allocator: &mut Allocator, device: &ash::Device) {
+        device.destroy_buffer(self.buffer, None);
+        allocator.free(self.allocation).unwrap();
+    }
+}
+
+unsafe fn copy_buffer(
+    device: &ash::Device,
+    command_pool: vk::CommandPool,
+    graphics_queue: vk::Queue,
+    src: vk::Buffer,
+    dst: vk::Buffer,
+    size: vk::DeviceSize,
+) {
+    let command_buffer = {
+        let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::builder()
+            .command_buffer_count(1)
+            .command_pool(command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .build();
+
+        device
+            .allocate_command_buffers(&command_buffer_allocate_info)
+            .expect("Failed to allocate Command Buffers!")[0]
+    };
+
+    let command_buffer_begin_info = vk::CommandBufferBeginInfo::builder()
+        .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT)
+        .build();
+
+    device
+        .begin_command_buffer(command_buffer, &command_buffer_begin_info)
+        .expect("Failed to begin recording Command Buffer at beginning!");
+
+    let buffer_copy = [vk::BufferCopy::builder().size(size).build()];
+    device.cmd_copy_buffer(command_buffer, src, dst, &buffer_copy);
+    device.end_command_buffer(command_buffer).unwrap();
+
+    let command_buffers = [command_buffer];
+
+    let submit_infos = [vk::SubmitInfo::builder()
+        .command_buffers(&command_buffers)
+        .build()];
+
+    device
+        .queue_submit(graphics_queue, &submit_infos, vk::Fence::null())
+        .expect("Failed to execute queue submit.");
+
+    device.queue_wait_idle(graphics_queue).unwrap();
+    device.free_command_buffers(command_pool, &[command_buffer]);
 }
 
 fn aligned_size(value: u32, alignment: u32) -> u32 {
@@ -2414,6 +3024,197 @@ impl Image {
     }
 }
 
+#[derive(Clone, Copy)]
+struct BlasArg {
+    index_offset: u32,
+    primitive_count: u32,
+}
+
+fn transform_matrix(m: Affine3A) -> vk::TransformMatrixKHR {
+    vk::TransformMatrixKHR {
+        matrix: [
+            m.x_axis.x, m.y_axis.x, m.z_axis.x, m.w_axis.x, m.x_axis.y, m.y_axis.y, m.z_axis.y,
+            m.w_axis.y, m.x_axis.z, m.y_axis.z, m.z_axis.z, m.w_axis.z,
+        ],
+    }
+}
+
+fn emit_object(
+    instance: &TlasInstance,
+    matrix: Affine3A,
+    blas_args: &[BlasArg],
+    cdf_offset: u32,
+) -> EnumSurfaceSample {
+    match instance.shader_offset {
+        ShaderOffset::Sphere => {
+            EnumSurfaceSample::new_sphere(instance.area_light_index as u32, matrix)
+        }
+        ShaderOffset::Triangle => {
+            let blas = &blas_args[instance.blas_index.unwrap()];
+            EnumSurfaceSample::new_triangle(
+                blas.index_offset,
+                blas.primitive_count,
+                cdf_offset,
+                instance.area_light_index as u32,
+                matrix,
+            )
+        }
+    }
+}
+
+/// Samples of an animated scene taken by one submission, each at its own time.
+const MOTION_SLOTS: u32 = 100;
+
+/// Geometry of a TLAS over the instances at `address`.
+fn tlas_geometry(address: vk::DeviceAddress) -> vk::AccelerationStructureGeometryKHR {
+    let instances = vk::AccelerationStructureGeometryInstancesDataKHR::builder()
+        .array_of_pointers(false)
+        .data(vk::DeviceOrHostAddressConstKHR {
+            device_address: address,
+        })
+        .build();
+
+    vk::AccelerationStructureGeometryKHR::builder()
+        .geometry_type(vk::GeometryTypeKHR::INSTANCES)
+        .geometry(vk::AccelerationStructureGeometryDataKHR { instances })
+        .build()
+}
+
+/// Refit in place of a TLAS built with `ALLOW_UPDATE`, without its scratch.
+fn tlas_update_info(
+    geometries: &[vk::AccelerationStructureGeometryKHR],
+    tlas: vk::AccelerationStructureKHR,
+) -> vk::AccelerationStructureBuildGeometryInfoKHR {
+    vk::AccelerationStructureBuildGeometryInfoKHR::builder()
+        .flags(
+            vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
+                | vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE,
+        )
+        .geometries(geometries)
+        .mode(vk::BuildAccelerationStructureModeKHR::UPDATE)
+        .ty(vk::AccelerationStructureTypeKHR::TOP_LEVEL)
+        .src_acceleration_structure(tlas)
+        .dst_acceleration_structure(tlas)
+        .build()
+}
+
+/// State to move the instances and the camera of an animated scene between samples.
+/// What each sample of a submission sees is staged up front,
+/// so that the launches move the scene without waiting on the host.
+struct Motion {
+    tlas_instances: Vec<vk::AccelerationStructureInstanceKHR>,
+    emit_instance_indices: Vec<usize>,
+    blas_args: Vec<BlasArg>,
+    emit_cdf_offsets: Vec<u32>,
+    uniform: Uniform,
+    /// `MOTION_SLOTS` slots, each with the TLAS instances at its start,
+    /// then the emitters' TLAS instances, the emit objects and the uniform
+    staging: BufferResource,
+    slot_size: vk::DeviceSize,
+    emit_instances_offset: vk::DeviceSize,
+    emit_objects_offset: vk::DeviceSize,
+    uniform_offset: vk::DeviceSize,
+    /// Scratch of the refits of `tlas` and `tlas_emit_object`
+    scratch: BufferResource,
+    scratch_emit: BufferResource,
+}
+
+impl Motion {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        allocator: &mut Allocator,
+        device: &ash::Device,
+        acceleration_structure: &AccelerationStructure,
+        tlas_instances: Vec<vk::AccelerationStructureInstanceKHR>,
+        emit_instance_indices: Vec<usize>,
+        blas_args: Vec<BlasArg>,
+        emit_cdf_offsets: Vec<u32>,
+        uniform: Uniform,
+        emit_objects_len: usize,
+    ) -> Self {
+        let instance_size = std::mem::size_of::<vk::AccelerationStructureInstanceKHR>();
+        let emit_instances_offset = tlas_instances.len() * instance_size;
+        let emit_objects_offset =
+            emit_instances_offset + emit_instance_indices.len() * instance_size;
+        let uniform_offset = aligned_size(
+            (emit_objects_offset + emit_objects_len * std::mem::size_of::<EnumSurfaceSample>())
+                as u32,
+            16,
+        );
+        // Instances of every slot stay 16 bytes aligned as builds require
+        let slot_size = aligned_size(uniform_offset + std::mem::size_of::<Uniform>() as u32, 256)
+            as vk::DeviceSize;
+
+        let staging = BufferResource::new(
+            allocator,
+            slot_size * MOTION_SLOTS as vk::DeviceSize,
+            MemoryLocation::CpuToGpu,
+            vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+                | vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR
+                | vk::BufferUsageFlags::TRANSFER_SRC,
+            None,
+            device,
+        );
+
+        let mut scratch = |count: usize| {
+            let geometries = [tlas_geometry(0)];
+            let size_info = unsafe {
+                acceleration_structure.get_acceleration_structure_build_sizes(
+                    vk::AccelerationStructureBuildTypeKHR::DEVICE,
+                    &tlas_update_info(&geometries, vk::AccelerationStructureKHR::null()),
+                    &[count as u32],
+                )
+            };
+
+            BufferResource::new(
+                allocator,
+                size_info.update_scratch_size,
+                MemoryLocation::GpuOnly,
+                vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS | vk::BufferUsageFlags::STORAGE_BUFFER,
+                None,
+                device,
+            )
+        };
+        let scratch_emit = scratch(emit_instance_indices.len());
+        let scratch = scratch(tlas_instances.len());
+
+        Self {
+            tlas_instances,
+            emit_instance_indices,
+            blas_args,
+            emit_cdf_offsets,
+            uniform,
+            staging,
+            slot_size,
+            emit_instances_offset: emit_instances_offset as vk::DeviceSize,
+            emit_objects_offset: emit_objects_offset as vk::DeviceSize,
+            uniform_offset: uniform_offset as vk::DeviceSize,
+            scratch,
+            scratch_emit,
+        }
+    }
+
+    /// Write `data` at `offset` of the staging buffer.
+    fn store<T: Copy>(&mut self, offset: vk::DeviceSize, data: &[T]) {
+        let size = std::mem::size_of::<T>() * data.len();
+        assert!(offset + size as vk::DeviceSize <= self.staging.size);
+        unsafe {
+            let mapped_ptr = self.staging.allocation.mapped_ptr().unwrap().as_ptr() as *mut u8;
+            std::ptr::copy_nonoverlapping(
+                data.as_ptr() as *const u8,
+                mapped_ptr.add(offset as usize),
+                size,
+            );
+        }
+    }
+
+    unsafe fn destroy(self, allocator: &mut Allocator, device: &ash::Device) {
+        self.staging.destroy(allocator, device);
+        self.scratch.destroy(allocator, device);
+        self.scratch_emit.destroy(allocator, device);
+    }
+}
+
 struct SceneBuffers {
     tlas: vk::AccelerationStructureKHR,
     tlas_emit_object: vk::AccelerationStructureKHR,
@@ -2422,6 +3223,19 @@ struct SceneBuffers {
     uniform: BufferResource,
     materials: BufferResource,
     mediums: BufferResource,
+    background_distribution: BufferResource,
+    emit_distribution: BufferResource,
+    light_bvh: BufferResource,
+    /// Fixed point RGB of the light paths splatted to each pixel
+    splat: BufferResource,
+    /// Visible point and gathered photons of each pixel for SPPM
+    sppm_pixels: BufferResource,
+    /// First visible point in each bucket of the SPPM photon grid
+    sppm_grid: BufferResource,
+    /// Current path of each MLT chain
+    mlt_chains: BufferResource,
+    /// Bootstrap luminances and the chains' starting paths, see `mlt::BOOTSTRAP_HEADER`
+    mlt_bootstrap: BufferResource,
     buffers_alloc: Vec<BufferResource>,
     index_data: BufferResource,
     vertices: BufferResource,
@@ -2431,6 +3245,7 @@ struct SceneBuffers {
     area_lights: BufferResource,
     emit_objects: BufferResource,
     images: Vec<Image>,
+    motion: Option<Motion>,
 }
 
 impl SceneBuffers {
@@ -2739,6 +3554,7 @@ impl SceneBuffers {
     fn build_tlas(
         allocator: &mut Allocator,
         tlas_instances: &[vk::AccelerationStructureInstanceKHR],
+        flags: vk::BuildAccelerationStructureFlagsKHR,
         device: &ash::Device,
         acceleration_structure: &AccelerationStructure,
         command_pool: vk::CommandPool,
@@ -2829,7 +3645,7 @@ impl SceneBuffers {
         let geometries = [geometry];
 
         let mut build_info = vk::AccelerationStructureBuildGeometryInfoKHR::builder()
-            .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
+            .flags(flags)
             .geometries(&geometries)
             .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
             .ty(vk::AccelerationStructureTypeKHR::TOP_LEVEL)
@@ -2907,6 +3723,170 @@ impl SceneBuffers {
         (top_as, top_as_buffer, instance_buffer)
     }
 
+    /// Stage the instances, the emitters and the camera at the shutter position `u` in [0, 1]
+    /// into `slot` of the motion staging buffer, see `cmd_set_time`.
+    fn stage_time(&mut self, scene: &Scene, slot: u32, u: f32) {
+        let motion = if let Some(motion) = &mut self.motion {
+            motion
+        } else {
+            return;
+        };
+        let t = scene.transform_interpolant(u);
+
+        let tlas_instances: Vec<_> = motion
+            .tlas_instances
+            .iter()
+            .zip(scene.tlas.iter())
+            .map(
+                |(&vk_instance, instance)| vk::AccelerationStructureInstanceKHR {
+                    transform: transform_matrix(instance.matrix_at(t)),
+                    ..vk_instance
+                },
+            )
+            .collect();
+        let tlas_instances_emit: Vec<_> = motion
+            .emit_instance_indices
+            .iter()
+            .map(|&i| tlas_instances[i])
+            .collect();
+
+        let mut emit_objects: Vec<EnumSurfaceSample> = scene
+            .tlas
+            .iter()
+            .zip(&motion.emit_cdf_offsets)
+            .filter(|(instance, _)| !scene.area_lights[instance.area_light_index].is_null())
+            .map(|(instance, &cdf_offset)| {
+                emit_object(
+                    instance,
+                    instance.matrix_at(t),
+                    &motion.blas_args,
+                    cdf_offset,
+                )
+            })
+            .collect();
+        if emit_objects.is_empty() {
+            emit_objects.push(EnumSurfaceSample::new_sphere(0, Default::default()));
+        }
+
+        let mut uniform = motion.uniform;
+        uniform.camera_to_world = scene.camera_to_world.interpolate(t);
+
+        let base = slot as vk::DeviceSize * motion.slot_size;
+        motion.store(base, &tlas_instances);
+        motion.store(base + motion.emit_instances_offset, &tlas_instances_emit);
+        motion.store(base + motion.emit_objects_offset, &emit_objects);
+        motion.store(base + motion.uniform_offset, &[uniform]);
+    }
+
+    /// Record moving the scene to the time staged in `slot` before the next launch:
+    /// refit both TLASes and copy the emitters and the uniform into place.
+    unsafe fn cmd_set_time(
+        &self,
+        device: &ash::Device,
+        acceleration_structure: &AccelerationStructure,
+        command_buffer: vk::CommandBuffer,
+        slot: u32,
+    ) {
+        let motion = if let Some(motion) = &self.motion {
+            motion
+        } else {
+            return;
+        };
+        let base = slot as vk::DeviceSize * motion.slot_size;
+        let staging_address = get_buffer_device_address(device, motion.staging.buffer);
+
+        // Launches before read what is about to be overwritten
+        let before = vk::MemoryBarrier::builder()
+            .src_access_mask(
+                vk::AccessFlags::SHADER_READ
+                    | vk::AccessFlags::UNIFORM_READ
+                    | vk::AccessFlags::ACCELERATION_STRUCTURE_READ_KHR,
+            )
+            .dst_access_mask(
+                vk::AccessFlags::ACCELERATION_STRUCTURE_READ_KHR
+                    | vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR
+                    | vk::AccessFlags::TRANSFER_WRITE,
+            )
+            .build();
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+            vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR
+                | vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[before],
+            &[],
+            &[],
+        );
+
+        for (tlas, offset, count, scratch) in [
+            (self.tlas, 0, motion.tlas_instances.len(), &motion.scratch),
+            (
+                self.tlas_emit_object,
+                motion.emit_instances_offset,
+                motion.emit_instance_indices.len(),
+                &motion.scratch_emit,
+            ),
+        ] {
+            let geometries = [tlas_geometry(staging_address + base + offset)];
+            let mut build_info = tlas_update_info(&geometries, tlas);
+            build_info.scratch_data = vk::DeviceOrHostAddressKHR {
+                device_address: get_buffer_device_address(device, scratch.buffer),
+            };
+            let build_range_info = vk::AccelerationStructureBuildRangeInfoKHR::builder()
+                .primitive_count(count as u32)
+                .build();
+
+            let build_infos = [build_info];
+            let build_range_infos: &[&[_]] = &[&[build_range_info]];
+            acceleration_structure.cmd_build_acceleration_structures(
+                command_buffer,
+                &build_infos,
+                build_range_infos,
+            );
+        }
+
+        for (dst, offset, size) in [
+            (
+                self.emit_objects.buffer,
+                motion.emit_objects_offset,
+                self.emit_objects.size,
+            ),
+            (
+                self.uniform.buffer,
+                motion.uniform_offset,
+                std::mem::size_of::<Uniform>() as vk::DeviceSize,
+            ),
+        ] {
+            let region = vk::BufferCopy::builder()
+                .src_offset(base + offset)
+                .size(size)
+                .build();
+            device.cmd_copy_buffer(command_buffer, motion.staging.buffer, dst, &[region]);
+        }
+
+        let after = vk::MemoryBarrier::builder()
+            .src_access_mask(
+                vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR | vk::AccessFlags::TRANSFER_WRITE,
+            )
+            .dst_access_mask(
+                vk::AccessFlags::ACCELERATION_STRUCTURE_READ_KHR
+                    | vk::AccessFlags::SHADER_READ
+                    | vk::AccessFlags::UNIFORM_READ,
+            )
+            .build();
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR
+                | vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+            vk::DependencyFlags::empty(),
+            &[after],
+            &[],
+            &[],
+        );
+    }
+
     fn new(
         allocator: &mut Allocator,
         scene: &Scene,
@@ -2931,11 +3911,6 @@ impl SceneBuffers {
                 acceleration_structure.get_acceleration_structure_device_address(&as_addr_info)
             }
         };
-        struct BlasArg {
-            index_offset: u32,
-            primitive_count: u32,
-        }
-
         let mut buffers_alloc = Vec::new();
         let mut global_vertices: Vec<Vertex> = Vec::new();
         let mut global_indices: Vec<u32> = Vec::new();
@@ -3054,13 +4029,15 @@ impl SceneBuffers {
             material_buffer.to_gpu_only(allocator, device, command_pool, graphics_queue)
         };
 
+        // Areas are those at the shutter open in animated scenes
+        let emitters = scene.emitters();
+
         let mut index_data: Vec<IndexData> = Vec::new();
         let tlas_instances: Vec<vk::AccelerationStructureInstanceKHR> = scene
             .tlas
             .iter()
             .enumerate()
             .map(|(index, instance)| {
-                let m = instance.matrix;
                 index_data.push(IndexData {
                     material_index: instance.material_index as u32,
                     area_light_index: instance.area_light_index as u32,
@@ -3074,14 +4051,11 @@ impl SceneBuffers {
                         .unwrap_or(1),
                     interior_medium_index: instance.interior_medium_index as u32,
                     exterior_medium_index: instance.exterior_medium_index as u32,
+                    emit_bit_trail: emitters.bit_trail[index],
+                    emit_area: emitters.area[index],
                 });
                 vk::AccelerationStructureInstanceKHR {
-                    transform: vk::TransformMatrixKHR {
-                        matrix: [
-                            m.x_axis.x, m.y_axis.x, m.z_axis.x, m.w_axis.x, m.x_axis.y, m.y_axis.y,
-                            m.z_axis.y, m.w_axis.y, m.x_axis.z, m.y_axis.z, m.z_axis.z, m.w_axis.z,
-                        ],
-                    },
+                    transform: transform_matrix(instance.matrix),
                     instance_custom_index_and_mask: vk::Packed24_8::new(index as u32, 0xff),
                     instance_shader_binding_table_record_offset_and_flags: vk::Packed24_8::new(
                         instance.shader_offset as u32,
@@ -3106,22 +4080,38 @@ impl SceneBuffers {
             })
             .collect();
 
-        let mut tlas_instances_emit: Vec<vk::AccelerationStructureInstanceKHR> = tlas_instances
+        let mut emit_instance_indices: Vec<usize> = tlas_instances
             .iter()
-            .filter(|instance| {
+            .enumerate()
+            .filter(|(_, instance)| {
                 let index = instance.instance_custom_index_and_mask.low_24() as usize;
                 !scene.area_lights[index_data[index].area_light_index as usize].is_null()
             })
-            .cloned()
+            .map(|(i, _)| i)
             .collect();
 
-        if tlas_instances_emit.is_empty() {
-            tlas_instances_emit.push(tlas_instances[0]);
+        if emit_instance_indices.is_empty() {
+            emit_instance_indices.push(0);
         }
 
+        let tlas_instances_emit: Vec<vk::AccelerationStructureInstanceKHR> = emit_instance_indices
+            .iter()
+            .map(|&i| tlas_instances[i])
+            .collect();
+
+        // Animated scenes refit the TLAS for every sample
+        let animated = scene.is_animated();
+        let tlas_flags = if animated {
+            vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
+                | vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE
+        } else {
+            vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
+        };
+
         let (top_as, top_as_buffer, instance_buffer) = Self::build_tlas(
             allocator,
             &tlas_instances,
+            tlas_flags,
             device,
             acceleration_structure,
             command_pool,
@@ -3134,6 +4124,7 @@ impl SceneBuffers {
         let (top_as_emit, top_as_buffer, instance_buffer) = Self::build_tlas(
             allocator,
             &tlas_instances_emit,
+            tlas_flags,
             device,
             acceleration_structure,
             command_pool,
@@ -3143,18 +4134,9 @@ impl SceneBuffers {
         let mut emit_objects: Vec<EnumSurfaceSample> = scene
             .tlas
             .iter()
-            .filter(|t| !scene.area_lights[t.area_light_index].is_null())
-            .map(|t| match t.shader_offset {
-                ShaderOffset::Sphere => EnumSurfaceSample::new_sphere(t.matrix),
-                ShaderOffset::Triangle => {
-                    let blas = &blas_args[t.blas_index.unwrap() as usize];
-                    EnumSurfaceSample::new_triangle(
-                        blas.index_offset,
-                        blas.primitive_count,
-                        t.matrix,
-                    )
-                }
-            })
+            .zip(&emitters.cdf_offset)
+            .filter(|(t, _)| !scene.area_lights[t.area_light_index].is_null())
+            .map(|(t, &cdf_offset)| emit_object(t, t.matrix, &blas_args, cdf_offset))
             .collect();
 
         buffers_alloc.push(top_as_buffer);
@@ -3253,6 +4235,175 @@ impl SceneBuffers {
             mediums_buffer.to_gpu_only(allocator, device, command_pool, graphics_queue)
         };
 
+        let mut background_distribution = scene.background_distribution.clone();
+        if background_distribution.is_empty() {
+            background_distribution.push(0.0);
+        }
+
+        let background_distribution = {
+            let buffer_size =
+                (background_distribution.len() * std::mem::size_of::<f32>()) as vk::DeviceSize;
+
+            let mut background_distribution_buffer = BufferResource::new(
+                allocator,
+                buffer_size,
+                MemoryLocation::CpuToGpu,
+                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC,
+                None,
+                device,
+            );
+            background_distribution_buffer.store(&background_distribution);
+
+            background_distribution_buffer.to_gpu_only(
+                allocator,
+                device,
+                command_pool,
+                graphics_queue,
+            )
+        };
+
+        let mut emit_distribution = emitters.distribution;
+        if emit_distribution.is_empty() {
+            emit_distribution.push(0.0);
+        }
+
+        let emit_distribution = {
+            let buffer_size =
+                (emit_distribution.len() * std::mem::size_of::<f32>()) as vk::DeviceSize;
+
+            let mut emit_distribution_buffer = BufferResource::new(
+                allocator,
+                buffer_size,
+                MemoryLocation::CpuToGpu,
+                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC,
+                None,
+                device,
+            );
+            emit_distribution_buffer.store(&emit_distribution);
+
+            emit_distribution_buffer.to_gpu_only(allocator, device, command_pool, graphics_queue)
+        };
+
+        // The emitters' tree comes first, followed by the analytic lights' one
+        let light_bvh_root = emitters.light_bvh.len() as u32;
+        let mut light_bvh = emitters.light_bvh;
+        light_bvh.extend_from_slice(&scene.light_bvh);
+        if light_bvh.is_empty() {
+            light_bvh.push(LightBvhNode::default());
+        }
+
+        let light_bvh = {
+            let buffer_size =
+                (light_bvh.len() * std::mem::size_of::<LightBvhNode>()) as vk::DeviceSize;
+
+            let mut light_bvh_buffer = BufferResource::new(
+                allocator,
+                buffer_size,
+                MemoryLocation::CpuToGpu,
+                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC,
+                None,
+                device,
+            );
+            light_bvh_buffer.store(&light_bvh);
+
+            light_bvh_buffer.to_gpu_only(allocator, device, command_pool, graphics_queue)
+        };
+
+        let splat = {
+            let splat = vec![0u32; 3 * (scene.film.xresolution * scene.film.yresolution) as usize];
+            let buffer_size = (splat.len() * std::mem::size_of::<u32>()) as vk::DeviceSize;
+
+            let mut splat_buffer = BufferResource::new(
+                allocator,
+                buffer_size,
+                MemoryLocation::CpuToGpu,
+                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC,
+                None,
+                device,
+            );
+            splat_buffer.store(&splat);
+
+            splat_buffer.to_gpu_only(allocator, device, command_pool, graphics_queue)
+        };
+
+        let pixels = (scene.film.xresolution * scene.film.yresolution) as usize;
+
+        let sppm_pixels = {
+            let sppm_pixels = vec![SppmPixel::new(scene.uniform.sppm_radius); pixels];
+            let buffer_size =
+                (sppm_pixels.len() * std::mem::size_of::<SppmPixel>()) as vk::DeviceSize;
+
+            let mut sppm_pixels_buffer = BufferResource::new(
+                allocator,
+                buffer_size,
+                MemoryLocation::CpuToGpu,
+                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC,
+                None,
+                device,
+            );
+            sppm_pixels_buffer.store(&sppm_pixels);
+
+            sppm_pixels_buffer.to_gpu_only(allocator, device, command_pool, graphics_queue)
+        };
+
+        let sppm_grid = {
+            let sppm_grid = vec![sppm::NONE; pixels];
+            let buffer_size = (sppm_grid.len() * std::mem::size_of::<u32>()) as vk::DeviceSize;
+
+            let mut sppm_grid_buffer = BufferResource::new(
+                allocator,
+                buffer_size,
+                MemoryLocation::CpuToGpu,
+                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC,
+                None,
+                device,
+            );
+            sppm_grid_buffer.store(&sppm_grid);
+
+            sppm_grid_buffer.to_gpu_only(allocator, device, command_pool, graphics_queue)
+        };
+
+        // A single chain keeps the buffers small for the other integrators
+        let chains = match scene.integrator {
+            scene::intermediate_scene::Integrator::Mlt(_) => mlt::CHAINS as usize,
+            _ => 1,
+        };
+
+        let mlt_chains = {
+            let mlt_chains = vec![0u8; chains * std::mem::size_of::<MltChain>()];
+            let buffer_size = mlt_chains.len() as vk::DeviceSize;
+
+            let mut mlt_chains_buffer = BufferResource::new(
+                allocator,
+                buffer_size,
+                MemoryLocation::CpuToGpu,
+                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC,
+                None,
+                device,
+            );
+            mlt_chains_buffer.store(&mlt_chains);
+
+            mlt_chains_buffer.to_gpu_only(allocator, device, command_pool, graphics_queue)
+        };
+
+        // Stays host visible, the host picks the chains' bootstrap paths from it
+        let mlt_bootstrap = {
+            let words = mlt::BOOTSTRAP_HEADER + chains * (scene.uniform.max_depth as usize + 2);
+            let mlt_bootstrap = vec![0u32; words];
+            let buffer_size = (mlt_bootstrap.len() * std::mem::size_of::<u32>()) as vk::DeviceSize;
+
+            let mut mlt_bootstrap_buffer = BufferResource::new(
+                allocator,
+                buffer_size,
+                MemoryLocation::GpuToCpu,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+                None,
+                device,
+            );
+            mlt_bootstrap_buffer.store(&mlt_bootstrap);
+            mlt_bootstrap_buffer
+        };
+
         let mut images: Vec<Image> = scene
             .images
             .iter()
@@ -3274,11 +4425,12 @@ impl SceneBuffers {
             ))
         }
 
-        let uniform_buffer = {
-            let mut uniform = scene.uniform;
-            uniform.emit_object_len = emit_objects.len() as u32;
-            uniform.emit_primitives = emit_objects.iter().map(|s| s.primitive_count()).sum();
+        let mut uniform = scene.uniform;
+        uniform.emit_object_len = emit_objects.len() as u32;
+        uniform.light_bvh_root = light_bvh_root;
+        uniform.emit_primitives = emit_objects.iter().map(|s| s.primitive_count()).sum();
 
+        let uniform_buffer = {
             let buffer_size = std::mem::size_of::<Uniform>() as vk::DeviceSize;
 
             let mut uniform_buffer = BufferResource::new(
@@ -3295,8 +4447,9 @@ impl SceneBuffers {
         };
 
         if emit_objects.is_empty() {
-            emit_objects.push(EnumSurfaceSample::new_sphere(Default::default()));
+            emit_objects.push(EnumSurfaceSample::new_sphere(0, Default::default()));
         }
+        let emit_objects_len = emit_objects.len();
 
         let emit_objects = {
             let buffer_size =
@@ -3315,6 +4468,20 @@ impl SceneBuffers {
             emit_objects_buffer.to_gpu_only(allocator, device, command_pool, graphics_queue)
         };
 
+        let motion = animated.then(|| {
+            Motion::new(
+                allocator,
+                device,
+                acceleration_structure,
+                tlas_instances,
+                emit_instance_indices,
+                blas_args,
+                emitters.cdf_offset,
+                uniform,
+                emit_objects_len,
+            )
+        });
+
         Self {
             tlas: top_as,
             tlas_emit_object: top_as_emit,
@@ -3323,6 +4490,14 @@ impl SceneBuffers {
             uniform: uniform_buffer,
             materials: material_buffer,
             mediums,
+            background_distribution,
+            emit_distribution,
+            light_bvh,
+            splat,
+            sppm_pixels,
+            sppm_grid,
+            mlt_chains,
+            mlt_bootstrap,
             buffers_alloc,
             index_data,
             indices,
@@ -3332,6 +4507,7 @@ impl SceneBuffers {
             area_lights,
             emit_objects,
             images,
+            motion,
         }
     }
 
@@ -3349,6 +4525,14 @@ impl SceneBuffers {
         }
         self.materials.destroy(allocator, device);
         self.mediums.destroy(allocator, device);
+        self.background_distribution.destroy(allocator, device);
+        self.emit_distribution.destroy(allocator, device);
+        self.light_bvh.destroy(allocator, device);
+        self.splat.destroy(allocator, device);
+        self.sppm_pixels.destroy(allocator, device);
+        self.sppm_grid.destroy(allocator, device);
+        self.mlt_chains.destroy(allocator, device);
+        self.mlt_bootstrap.destroy(allocator, device);
         self.uniform.destroy(allocator, device);
         for buffer_alloc in self.buffers_alloc {
             buffer_alloc.destroy(allocator, device);
@@ -3360,6 +4544,9 @@ impl SceneBuffers {
         self.lights.destroy(allocator, device);
         self.area_lights.destroy(allocator, device);
         self.emit_objects.destroy(allocator, device);
+        if let Some(motion) = self.motion {
+            motion.destroy(allocator, device);
+        }
 
         for image in self.images {
             image.destroy(allocator, device);
@@ -1,21 +1,34 @@
 use std::{collections::HashMap, f32::consts::PI, path::Path};
 
-use glam::{vec3, vec3a, Affine3A, Mat4};
+use glam::{vec3, vec3a, vec4, Affine3A, Mat3, Mat4, Quat, Vec3, Vec3A};
+use pbrt_parser::ActiveTransform;
 use rene_shader::{
-    area_light::EnumAreaLight, light::EnumLight, material::EnumMaterial, medium::EnumMedium,
-    texture::EnumTexture, Uniform,
+    area_light::{AreaLight, EnumAreaLight},
+    bdpt::MAX_VERTICES,
+    camera::{EnumCamera, LensSystem, MAX_LENS_ELEMENTS},
+    distribution::{build_distribution_1d, build_distribution_2d},
+    light::EnumLight,
+    light_bvh::{build_light_bvh, cone_union, LightBounds, LightBvhNode},
+    material::EnumMaterial,
+    math::luminance,
+    medium::EnumMedium,
+    texture::EnumTexture,
+    Uniform,
 };
 use thiserror::Error;
 
 use crate::ShaderOffset;
 
 use self::intermediate_scene::{
-    AreaLightSource, Camera, Film, InnerTexture, Integrator, IntermediateScene, IntermediateWorld,
-    LightSource, Material, Medium, SceneObject, Shape, TextureOrColor, TriangleMesh, WorldObject,
+    AreaLightSource, Camera, Film, InnerCamera, InnerTexture, Integrator, IntermediateScene,
+    IntermediateWorld, LightSampleStrategy, LightSource, Material, Medium, SceneObject,
+    ScreenWindow, Shape, TextureOrColor, TriangleMesh, WorldObject,
 };
 
 pub mod image;
 pub mod intermediate_scene;
+mod lens_parser;
+pub mod lint;
 mod pfm_parser;
 mod spectrum;
 mod subdivision;
@@ -26,6 +39,8 @@ use crate::scene::image::Image;
 pub struct TlasInstance {
     pub shader_offset: ShaderOffset,
     pub matrix: Affine3A,
+    /// `matrix` at the end of `TransformTimes`, equal to it unless the instance moves
+    pub end_matrix: Affine3A,
     pub material_index: usize,
     pub interior_medium_index: usize,
     pub exterior_medium_index: usize,
@@ -33,19 +48,136 @@ pub struct TlasInstance {
     pub blas_index: Option<usize>,
 }
 
+impl TlasInstance {
+    pub fn is_animated(&self) -> bool {
+        self.matrix != self.end_matrix
+    }
+
+    pub fn matrix_at(&self, t: f32) -> Affine3A {
+        if self.is_animated() {
+            Affine3A::from_mat4(interpolate(
+                Mat4::from(self.matrix),
+                Mat4::from(self.end_matrix),
+                t,
+            ))
+        } else {
+            self.matrix
+        }
+    }
+}
+
+/// Transforms at the start and the end of `TransformTimes`.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct TransformSet {
+    pub start: Mat4,
+    pub end: Mat4,
+}
+
+impl TransformSet {
+    fn apply(&mut self, active: ActiveTransform, f: impl Fn(Mat4) -> Mat4) {
+        if active != ActiveTransform::EndTime {
+            self.start = f(self.start);
+        }
+        if active != ActiveTransform::StartTime {
+            self.end = f(self.end);
+        }
+    }
+
+    pub fn inverse(&self) -> Self {
+        Self {
+            start: self.start.inverse(),
+            end: self.end.inverse(),
+        }
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.start != self.end
+    }
+
+    pub fn interpolate(&self, t: f32) -> Mat4 {
+        if self.is_animated() {
+            interpolate(self.start, self.end, t)
+        } else {
+            self.start
+        }
+    }
+}
+
+/// Splits `m` into a translation, a rotation and a scale which may shear, `m = T R S`,
+/// by the polar decomposition of pbrt's `AnimatedTransform::Decompose`.
+fn decompose(m: Mat4) -> (Vec3, Quat, Mat3) {
+    let translation = m.w_axis.truncate();
+    let m = Mat3::from_mat4(m);
+
+    // Average with the inverse transpose until only the rotation is left
+    let mut r = m;
+    for _ in 0..100 {
+        let next = (r + r.transpose().inverse()) * 0.5;
+        let norm = (0..3)
+            .map(|i| (next.col(i) - r.col(i)).abs().max_element())
+            .fold(0.0, f32::max);
+        r = next;
+        if norm <= 0.0001 {
+            break;
+        }
+    }
+    // A mirroring transform keeps the reflection in the scale
+    if r.determinant() < 0.0 {
+        r = -r;
+    }
+
+    (translation, Quat::from_mat3(&r), r.inverse() * m)
+}
+
+/// Interpolate decomposed transforms like pbrt's `AnimatedTransform`,
+/// so rotations stay rigid in between.
+fn interpolate(start: Mat4, end: Mat4, t: f32) -> Mat4 {
+    let (start_translation, start_rotation, start_scale) = decompose(start);
+    let (end_translation, end_rotation, end_scale) = decompose(end);
+
+    Mat4::from_translation(start_translation.lerp(end_translation, t))
+        * Mat4::from_quat(start_rotation.slerp(end_rotation, t))
+        * Mat4::from_mat3(start_scale * (1.0 - t) + end_scale * t)
+}
+
+/// Sampling data of the instances with an area light, in the order of `Scene::tlas`.
+#[derive(Default, Debug)]
+pub struct Emitters {
+    /// Area CDFs of the triangle emitters
+    pub distribution: Vec<f32>,
+    /// Light BVH whose leaves index the emitters in order
+    pub light_bvh: Vec<LightBvhNode>,
+    /// Path from the root of `light_bvh` to each instance's leaf
+    pub bit_trail: Vec<u32>,
+    /// World space surface area of each instance
+    pub area: Vec<f32>,
+    /// Offset of each triangle emitter's area CDF in `distribution`
+    pub cdf_offset: Vec<u32>,
+}
+
 #[derive(Default, Debug)]
 pub struct Scene {
     pub integrator: Integrator,
     pub film: Film,
     pub uniform: Uniform,
+    pub camera_to_world: TransformSet,
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+    pub transform_start_time: f32,
+    pub transform_end_time: f32,
     pub tlas: Vec<TlasInstance>,
     pub materials: Vec<EnumMaterial>,
     pub mediums: Vec<EnumMedium>,
     pub area_lights: Vec<EnumAreaLight>,
     pub textures: Vec<EnumTexture>,
     pub blases: Vec<TriangleMesh>,
+    /// Lights without bounds come first
     pub lights: Vec<EnumLight>,
+    /// Light BVH over the lights with bounds
+    pub light_bvh: Vec<LightBvhNode>,
     pub images: Vec<Image>,
+    /// Importance sampling distribution of the infinite light's image map
+    pub background_distribution: Vec<f32>,
 }
 
 #[derive(Error, Debug)]
@@ -60,8 +192,52 @@ pub enum CreateSceneError {
     NotFoundTexture(String),
     #[error("Not Found Coord system: {0}")]
     NotFoundCoordSystem(String),
+    #[error("Invalid lens system: {0}")]
+    InvalidLens(String),
     #[error("Not Object: {0}")]
     UnknownObject(String),
+    #[error("{0} warnings in strict mode")]
+    Strict(usize),
+}
+
+/// Maps NDC to the screen window, the shorter axis spans [-1, 1] by default like pbrt.
+fn screen_window_matrix(screen_window: &ScreenWindow, film_aspect_ratio: f32) -> Mat4 {
+    let frame = screen_window
+        .frame_aspect_ratio
+        .unwrap_or(film_aspect_ratio);
+    let [x0, x1, y0, y1] = screen_window.screen_window.unwrap_or(if frame > 1.0 {
+        [-frame, frame, -1.0, 1.0]
+    } else {
+        [-1.0, 1.0, -1.0 / frame, 1.0 / frame]
+    });
+
+    Mat4::from_translation(vec3(0.5 * (x0 + x1), 0.5 * (y0 + y1), 0.0))
+        * Mat4::from_scale(vec3(0.5 * (x1 - x0), 0.5 * (y1 - y0), 1.0))
+}
+
+/// `max_depth` cut to what the shader's fixed size subpaths hold, reported when cut.
+fn limit_max_depth(integrator: &str, max_depth: u32, limit: u32) -> u32 {
+    if max_depth > limit {
+        log::warn!(
+            "Integrator \"{}\": maxdepth {} is limited to {}",
+            integrator,
+            max_depth,
+            limit
+        );
+    }
+    max_depth.min(limit)
+}
+
+/// Index of a named medium, `""` is vacuum.
+fn medium_index(mediums: &HashMap<String, u32>, name: String) -> Result<usize, CreateSceneError> {
+    if name.is_empty() {
+        Ok(0)
+    } else {
+        mediums
+            .get(&name)
+            .map(|&i| i as usize)
+            .ok_or(CreateSceneError::UnknownMedium(name))
+    }
 }
 
 #[derive(Default, Clone)]
@@ -69,12 +245,13 @@ struct WorldState {
     current_material_index: usize,
     current_medium_index: Option<(usize, usize)>,
     current_area_light_index: usize,
-    current_matrix: Mat4,
+    current_matrix: TransformSet,
+    active_transform: ActiveTransform,
     textures: HashMap<String, u32>,
     materials: HashMap<String, u32>,
     mediums: HashMap<String, u32>,
     objects: HashMap<String, Vec<TlasInstance>>,
-    coord_system: HashMap<String, Mat4>,
+    coord_system: HashMap<String, TransformSet>,
 }
 
 impl Scene {
@@ -97,14 +274,193 @@ impl Scene {
         }
     }
 
+    fn medium(&mut self, medium: Medium) -> u32 {
+        let Medium::Homogeneous {
+            sigma_a,
+            sigma_s,
+            g,
+        } = medium;
+        let medium_index = self.mediums.len();
+        self.mediums
+            .push(EnumMedium::new_homogeneous(sigma_a, sigma_s, g));
+        medium_index as u32
+    }
+
+    /// Areas of the primitives of `instance` in world space.
+    fn primitive_areas(&self, instance: &TlasInstance) -> Vec<f32> {
+        match instance.blas_index {
+            Some(blas_index) => {
+                let mesh = &self.blases[blas_index];
+                mesh.indices
+                    .chunks_exact(3)
+                    .map(|triangle| {
+                        let [p0, p1, p2] = [triangle[0], triangle[1], triangle[2]].map(|i| {
+                            instance
+                                .matrix
+                                .transform_point3a(mesh.vertices[i as usize].position)
+                        });
+                        0.5 * (p1 - p0).cross(p2 - p0).length()
+                    })
+                    .collect()
+            }
+            None => {
+                // Same radius as the shaders
+                let radius = instance.matrix.matrix3.x_axis.length();
+                vec![4.0 * PI * radius * radius]
+            }
+        }
+    }
+
+    /// Bounds of the emission of `instance` with the power `phi`.
+    fn emitter_bounds(&self, instance: &TlasInstance, phi: f32) -> LightBounds {
+        let points: Vec<Vec3A> = match instance.blas_index {
+            Some(blas_index) => self.blases[blas_index]
+                .vertices
+                .iter()
+                .map(|v| v.position)
+                .collect(),
+            None => (0..8)
+                .map(|i| {
+                    let corner = |bit: u32| if i & bit == 0 { -1.0 } else { 1.0 };
+                    vec3a(corner(1), corner(2), corner(4))
+                })
+                .collect(),
+        };
+        // Moving emitters are bounded over the whole shutter interval
+        let (p_min, p_max) = [instance.matrix, instance.end_matrix]
+            .iter()
+            .flat_map(|m| points.iter().map(move |&p| m.transform_point3a(p)))
+            .fold(
+                (Vec3A::splat(f32::INFINITY), Vec3A::splat(f32::NEG_INFINITY)),
+                |(min, max), p| (min.min(p), max.max(p)),
+            );
+
+        let mut bounds = LightBounds {
+            p_min,
+            p_max,
+            ..LightBounds::new_point(Vec3A::ZERO, phi)
+        };
+
+        // Cone of the normals deciding the emitting side, as the shaders compute them
+        if let (Some(blas_index), false) = (instance.blas_index, instance.is_animated()) {
+            let mesh = &self.blases[blas_index];
+            let normal_matrix = instance.matrix.matrix3.inverse().transpose();
+            let mut cone: Option<(Vec3A, f32)> = None;
+            for triangle in mesh.indices.chunks_exact(3) {
+                let [v0, v1, v2] =
+                    [triangle[0], triangle[1], triangle[2]].map(|i| &mesh.vertices[i as usize]);
+                let normals = if v0.normal == Vec3A::ZERO
+                    && v1.normal == Vec3A::ZERO
+                    && v2.normal == Vec3A::ZERO
+                {
+                    [(v1.position - v0.position).cross(v2.position - v0.position); 3]
+                } else {
+                    [v0.normal, v1.normal, v2.normal]
+                };
+
+                for n in normals {
+                    let n = (normal_matrix * n).normalize();
+                    if !n.is_finite() {
+                        continue;
+                    }
+                    cone = Some(match cone {
+                        Some((w, cos_theta)) => cone_union(w, cos_theta, n, 1.0),
+                        None => (n, 1.0),
+                    });
+                }
+            }
+
+            if let Some((w, cos_theta_o)) = cone {
+                bounds.w = w;
+                bounds.cos_theta_o = cos_theta_o;
+            }
+        }
+
+        bounds
+    }
+
+    /// Builds the light BVH over the emitters and the area distributions of their triangles.
+    pub fn emitters(&self) -> Emitters {
+        let mut emitters = Emitters {
+            bit_trail: vec![0; self.tlas.len()],
+            area: vec![0.0; self.tlas.len()],
+            cdf_offset: vec![0; self.tlas.len()],
+            ..Default::default()
+        };
+
+        let mut instance_indices = Vec::new();
+        let mut bounds = Vec::new();
+        for (i, instance) in self.tlas.iter().enumerate() {
+            let area_light = &self.area_lights[instance.area_light_index];
+            if area_light.is_null() {
+                continue;
+            }
+
+            let areas = self.primitive_areas(instance);
+            let area = areas.iter().sum();
+            let phi = luminance(area_light.emit(Vec3A::Z, Vec3A::Z)) * area;
+
+            emitters.area[i] = area;
+            bounds.push((
+                instance_indices.len() as u32,
+                self.emitter_bounds(instance, phi),
+            ));
+            instance_indices.push(i);
+            if instance.blas_index.is_some() {
+                emitters.cdf_offset[i] = emitters.distribution.len() as u32;
+                emitters.distribution.extend(build_distribution_1d(&areas));
+            }
+        }
+
+        let (light_bvh, bit_trails) = build_light_bvh(&bounds);
+        emitters.light_bvh = light_bvh;
+        for (i, bit_trail) in instance_indices.into_iter().zip(bit_trails) {
+            emitters.bit_trail[i] = bit_trail;
+        }
+
+        emitters
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.camera_to_world.is_animated() || self.tlas.iter().any(TlasInstance::is_animated)
+    }
+
+    /// Interpolation parameter between the start and end transforms
+    /// for the time at `u` in [0, 1] of the shutter interval.
+    pub fn transform_interpolant(&self, u: f32) -> f32 {
+        let time = self.shutter_open + (self.shutter_close - self.shutter_open) * u;
+        let span = self.transform_end_time - self.transform_start_time;
+        if span > 0.0 {
+            ((time - self.transform_start_time) / span).clamp(0.0, 1.0)
+        } else {
+            0.0
+        }
+    }
+
     pub fn create<P: AsRef<Path>>(
         scene_description: Vec<pbrt_parser::Scene>,
         base_dir: &P,
+        strict: bool,
     ) -> Result<Self, CreateSceneError> {
-        let mut scene = Self::default();
-        let mut wolrd_to_camera = Mat4::default();
+        let mut scene = Self {
+            shutter_close: 1.0,
+            transform_end_time: 1.0,
+            ..Default::default()
+        };
+        let mut wolrd_to_camera = TransformSet::default();
+        let mut active_transform = ActiveTransform::All;
+        // Named frames are global, pbrt-v4 predefines "world"
+        let mut coord_system = HashMap::new();
+        coord_system.insert("world".to_string(), TransformSet::default());
+        let mut mediums = HashMap::new();
+        let mut current_medium_index = None;
         // 90 degree
-        let mut fov = 0.5 * PI;
+        let mut camera = InnerCamera::Perspective {
+            fov: 0.5 * PI,
+            screen_window: ScreenWindow::default(),
+            lens_radius: 0.0,
+            focal_distance: 1e6,
+        };
 
         scene.materials.push(EnumMaterial::new_none());
         scene.area_lights.push(EnumAreaLight::new_null());
@@ -115,58 +471,242 @@ impl Scene {
             .textures
             .push(EnumTexture::new_solid(vec3a(1.0, 1.0, 1.0)));
 
+        let mut lints = Vec::new();
+
         for desc in scene_description {
-            match IntermediateScene::from_scene(desc, base_dir)? {
-                IntermediateScene::Sampler => {
-                    log::info!("Sampler is not yet implemented. Continue.");
-                }
+            match IntermediateScene::from_scene(desc, base_dir, &mut lints)? {
+                IntermediateScene::Sampler => {}
                 IntermediateScene::Integrator(integrator) => {
                     scene.integrator = integrator;
                 }
-                IntermediateScene::PixelFilter => {
-                    log::info!("PixelFilter is not yet implemented. Continue.");
-                }
+                IntermediateScene::PixelFilter => {}
                 IntermediateScene::Film(film) => {
                     scene.film = film;
                 }
                 IntermediateScene::Matrix(m) => {
-                    wolrd_to_camera *= m;
+                    wolrd_to_camera.apply(active_transform, |c| c * m);
                 }
                 IntermediateScene::Transform(m) => {
-                    wolrd_to_camera = m;
+                    wolrd_to_camera.apply(active_transform, |_| m);
+                }
+                IntermediateScene::ActiveTransform(active) => {
+                    active_transform = active;
+                }
+                IntermediateScene::MakeNamedMedium(name, medium) => {
+                    let medium_index = scene.medium(medium);
+                    mediums.insert(name, medium_index);
+                }
+                IntermediateScene::MediumInterface { interior, exterior } => {
+                    current_medium_index = Some((
+                        medium_index(&mediums, interior)?,
+                        medium_index(&mediums, exterior)?,
+                    ));
+                }
+                IntermediateScene::CoordinateSystem(name) => {
+                    coord_system.insert(name, wolrd_to_camera);
+                }
+                IntermediateScene::TransformTimes(start, end) => {
+                    scene.transform_start_time = start;
+                    scene.transform_end_time = end;
                 }
                 IntermediateScene::SceneObject(obj) => match obj {
-                    SceneObject::Camera(camera) => match camera {
-                        Camera::Perspective { fov: f } => {
-                            fov = f;
-                        }
-                    },
+                    SceneObject::Camera(Camera {
+                        shutter_open,
+                        shutter_close,
+                        inner,
+                    }) => {
+                        // Places things in the camera space by `CoordSysTransform "camera"`
+                        coord_system.insert("camera".to_string(), wolrd_to_camera.inverse());
+                        // Camera rays start in the exterior medium
+                        scene.uniform.camera_medium_index =
+                            current_medium_index.map(|t| t.1).unwrap_or(0) as u32;
+                        scene.shutter_open = shutter_open;
+                        scene.shutter_close = shutter_close;
+                        camera = inner;
+                    }
                 },
                 IntermediateScene::World(worlds) => {
-                    let mut state = WorldState::default();
-                    state
-                        .coord_system
-                        .insert("camera".to_string(), wolrd_to_camera);
+                    coord_system
+                        .entry("camera".to_string())
+                        .or_insert_with(|| wolrd_to_camera.inverse());
+                    let mut state = WorldState {
+                        coord_system,
+                        mediums: mediums.clone(),
+                        current_medium_index,
+                        ..Default::default()
+                    };
                     scene.append_world(&mut state, worlds)?;
+                    coord_system = state.coord_system;
                 }
             }
         }
 
+        for lint in &lints {
+            if strict {
+                log::error!("{}", lint);
+            } else {
+                log::warn!("{}", lint);
+            }
+        }
+
+        if strict && !lints.is_empty() {
+            return Err(CreateSceneError::Strict(lints.len()));
+        }
+
         let aspect_ratio = scene.film.xresolution as f32 / scene.film.yresolution as f32;
-        if scene.film.yresolution > scene.film.xresolution {
-            // TODO remove this ad-hoc
-            fov = ((fov * 0.5).tan() / scene.film.xresolution as f32
-                * scene.film.yresolution as f32)
-                .atan()
-                * 2.0;
+        scene.uniform.camera = match camera {
+            InnerCamera::Perspective {
+                fov,
+                screen_window,
+                lens_radius,
+                focal_distance,
+            } => {
+                // The screen window is on the z = 1 plane
+                let scale = (fov * 0.5).tan();
+                EnumCamera::new_perspective(
+                    Mat4::from_scale(vec3(scale, scale, 1.0))
+                        * screen_window_matrix(&screen_window, aspect_ratio),
+                    lens_radius,
+                    focal_distance,
+                )
+            }
+            InnerCamera::Orthographic {
+                screen_window,
+                lens_radius,
+                focal_distance,
+            } => EnumCamera::new_orthographic(
+                screen_window_matrix(&screen_window, aspect_ratio),
+                lens_radius,
+                focal_distance,
+            ),
+            InnerCamera::Environment => EnumCamera::new_environment(),
+            InnerCamera::Realistic {
+                lens,
+                aperture_diameter,
+                focus_distance,
+            } => {
+                if lens.is_empty() || lens.len() > MAX_LENS_ELEMENTS {
+                    return Err(CreateSceneError::InvalidLens(format!(
+                        "{} elements, expected 1 to {}",
+                        lens.len(),
+                        MAX_LENS_ELEMENTS
+                    )));
+                }
+                let elements = lens
+                    .into_iter()
+                    .map(|[radius, thickness, eta, mut diameter]| {
+                        if radius == 0.0 {
+                            if aperture_diameter > diameter {
+                                log::warn!(
+                                    "aperturediameter is greater than the maximum possible {}",
+                                    diameter
+                                );
+                            } else {
+                                diameter = aperture_diameter;
+                            }
+                        }
+                        // Millimeters to meters, and diameter to radius
+                        vec4(
+                            radius * 0.001,
+                            thickness * 0.001,
+                            eta,
+                            diameter * 0.001 / 2.0,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+
+                let diagonal = scene.film.diagonal * 0.001;
+                let mut lens_system = LensSystem::new(&elements, diagonal);
+                if !lens_system.focus(focus_distance) {
+                    return Err(CreateSceneError::InvalidLens(format!(
+                        "can't focus at {}",
+                        focus_distance
+                    )));
+                }
+                lens_system.compute_exit_pupil_bounds();
+
+                // The lens system flips the image, so the film is too
+                let aspect = scene.film.yresolution as f32 / scene.film.xresolution as f32;
+                let x = (diagonal * diagonal / (1.0 + aspect * aspect)).sqrt();
+                let y = aspect * x;
+                EnumCamera::new_realistic(
+                    Mat4::from_scale(vec3(-0.5 * x, -0.5 * y, 1.0)),
+                    lens_system,
+                )
+            }
+        };
+        scene.camera_to_world = wolrd_to_camera.inverse();
+        scene.uniform.camera_to_world = scene.camera_to_world.start;
+        scene.uniform.film_width = scene.film.xresolution;
+        scene.uniform.film_height = scene.film.yresolution;
+        match &scene.integrator {
+            Integrator::Path(parameters) | Integrator::VolPath(parameters) => {
+                scene.uniform.max_depth = parameters.max_depth;
+                scene.uniform.rr_threshold = parameters.rr_threshold;
+            }
+            Integrator::AmbientOcclusion(parameters) => {
+                scene.uniform.ao_max_distance = parameters.max_distance;
+                scene.uniform.ao_cos_sample = parameters.cos_sample as u32;
+            }
+            Integrator::DirectLighting(parameters) => {
+                scene.uniform.max_depth = parameters.max_depth;
+                scene.uniform.sample_all_lights =
+                    (parameters.strategy == LightSampleStrategy::All) as u32;
+            }
+            Integrator::Bdpt(parameters) => {
+                scene.uniform.max_depth =
+                    limit_max_depth("bdpt", parameters.max_depth, MAX_VERTICES as u32 - 2);
+            }
+            Integrator::Sppm(parameters) => {
+                // Only the photons are traced as subpaths
+                limit_max_depth("sppm", parameters.max_depth, MAX_VERTICES as u32 - 1);
+                scene.uniform.max_depth = parameters.max_depth;
+                scene.uniform.sppm_photons_per_iteration = if parameters.photons_per_iteration > 0 {
+                    parameters.photons_per_iteration as u32
+                } else {
+                    scene.film.xresolution * scene.film.yresolution
+                };
+                scene.uniform.sppm_radius = parameters.radius;
+            }
+            Integrator::Mlt(parameters) => {
+                // The host sizes the bootstrap by the depth, which the paths must reach
+                scene.uniform.max_depth =
+                    limit_max_depth("mlt", parameters.max_depth, MAX_VERTICES as u32 - 2);
+                scene.uniform.mlt_large_step_probability = parameters.large_step_probability;
+                scene.uniform.mlt_sigma = parameters.sigma;
+            }
         }
-        scene.uniform.camera.projection =
-            Mat4::perspective_lh(fov, aspect_ratio, 0.01, 1000.0).inverse();
-        scene.uniform.camera_to_world = wolrd_to_camera.inverse();
+        // Lights without bounds are evaluated at every vertex, one of the others is picked
+        scene.lights.sort_by_key(|light| light.bounds().is_some());
+        let bounds: Vec<(u32, LightBounds)> = scene
+            .lights
+            .iter()
+            .enumerate()
+            .filter_map(|(i, light)| light.bounds().map(|b| (i as u32, b)))
+            .collect();
         scene.uniform.lights_len = scene.lights.len() as u32;
+        scene.uniform.unbounded_lights_len = (scene.lights.len() - bounds.len()) as u32;
+        scene.light_bvh = build_light_bvh(&bounds).0;
         Ok(scene)
     }
 
+    /// Texture of a light's image, constant white without one.
+    fn light_texture(&mut self, image: Option<Image>) -> u32 {
+        let texture_index = self.textures.len() as u32;
+        match image {
+            Some(image) => {
+                let image_index = self.images.len();
+                self.images.push(image);
+                self.textures
+                    .push(EnumTexture::new_image_map(image_index as u32));
+            }
+            None => self
+                .textures
+                .push(EnumTexture::new_solid(vec3a(1.0, 1.0, 1.0))),
+        }
+        texture_index
+    }
+
     fn material(
         &mut self,
         state: &WorldState,
@@ -270,11 +810,14 @@ impl Scene {
                     let mut tmp_state = state.clone();
                     self.append_world(&mut tmp_state, worlds)?;
                     state.objects = tmp_state.objects;
+                    state.coord_system = tmp_state.coord_system;
                 }
                 IntermediateWorld::TransformBeginEnd(worlds) => {
                     let matrix = state.current_matrix;
+                    let active_transform = state.active_transform;
                     self.append_world(state, worlds)?;
                     state.current_matrix = matrix;
+                    state.active_transform = active_transform;
                 }
                 IntermediateWorld::ObjectBeginEnd(name, worlds) => {
                     let current_len = self.tlas.len();
@@ -293,15 +836,22 @@ impl Scene {
 
                     for tlas in objects.iter() {
                         let mut tlas = tlas.clone();
-                        tlas.matrix = tlas.matrix * Affine3A::from_mat4(state.current_matrix);
+                        tlas.matrix = tlas.matrix * Affine3A::from_mat4(state.current_matrix.start);
+                        tlas.end_matrix =
+                            tlas.end_matrix * Affine3A::from_mat4(state.current_matrix.end);
                         self.tlas.push(tlas);
                     }
                 }
                 IntermediateWorld::Matrix(m) => {
-                    state.current_matrix *= m;
+                    state
+                        .current_matrix
+                        .apply(state.active_transform, |c| c * m);
                 }
                 IntermediateWorld::Transform(m) => {
-                    state.current_matrix = m;
+                    state.current_matrix.apply(state.active_transform, |_| m);
+                }
+                IntermediateWorld::ActiveTransform(active) => {
+                    state.active_transform = active;
                 }
                 IntermediateWorld::NamedMaterial(name) => {
                     state.current_material_index = *state
@@ -310,6 +860,9 @@ impl Scene {
                         .ok_or(CreateSceneError::UnknownMaterial(name))?
                         as usize;
                 }
+                IntermediateWorld::CoordinateSystem(name) => {
+                    state.coord_system.insert(name, state.current_matrix);
+                }
                 IntermediateWorld::CoordSysTransform(name) => {
                     if let Some(mat) = state.coord_system.get(&name) {
                         state.current_matrix = *mat;
@@ -319,24 +872,8 @@ impl Scene {
                 }
                 IntermediateWorld::MediumInterface { interior, exterior } => {
                     state.current_medium_index = Some((
-                        if interior == "" {
-                            0
-                        } else {
-                            *state
-                                .mediums
-                                .get(&interior)
-                                .ok_or(CreateSceneError::UnknownMedium(interior))?
-                                as usize
-                        },
-                        if exterior == "" {
-                            0
-                        } else {
-                            *state
-                                .mediums
-                                .get(&exterior)
-                                .ok_or(CreateSceneError::UnknownMedium(exterior))?
-                                as usize
-                        },
+                        medium_index(&state.mediums, interior)?,
+                        medium_index(&state.mediums, exterior)?,
                     ));
                 }
                 IntermediateWorld::Texture(texture) => {
@@ -372,6 +909,25 @@ impl Scene {
                             self.uniform.background_color = color.extend(0.0);
 
                             if let Some(image) = image_map {
+                                // Luminance weighted by the solid angle of each row
+                                let (width, height) = (image.width as usize, image.height as usize);
+                                let func: Vec<f32> = image
+                                    .data
+                                    .chunks_exact(width)
+                                    .enumerate()
+                                    .flat_map(|(v, row)| {
+                                        let sin_theta =
+                                            (PI * (v as f32 + 0.5) / height as f32).sin();
+                                        row.iter().map(move |[r, g, b, _]| {
+                                            luminance(vec3a(*r, *g, *b)) * sin_theta
+                                        })
+                                    })
+                                    .collect();
+                                self.background_distribution =
+                                    build_distribution_2d(&func, width, height);
+                                self.uniform.background_distribution_width = image.width;
+                                self.uniform.background_distribution_height = image.height;
+
                                 let image_index = self.images.len();
                                 self.images.push(image);
 
@@ -379,13 +935,66 @@ impl Scene {
                                 self.textures
                                     .push(EnumTexture::new_image_map(image_index as u32));
 
-                                self.uniform.background_matrix = state.current_matrix.inverse();
+                                self.uniform.background_matrix =
+                                    state.current_matrix.start.inverse();
+                                self.uniform.background_to_world = state.current_matrix.start;
                                 self.uniform.background_texture = texture_index as u32;
                             }
                         }
                         LightSource::Distant { from, to, color } => {
                             self.lights.push(EnumLight::new_distant(from, to, color))
                         }
+                        LightSource::Point { from, intensity } => {
+                            let from = state.current_matrix.start.transform_point3a(from);
+                            self.lights.push(EnumLight::new_point(from, intensity))
+                        }
+                        LightSource::Spot {
+                            from,
+                            to,
+                            intensity,
+                            cone_angle,
+                            cone_delta_angle,
+                        } => {
+                            let matrix = state.current_matrix.start;
+                            self.lights.push(EnumLight::new_spot(
+                                matrix.transform_point3a(from),
+                                matrix.transform_point3a(to),
+                                intensity,
+                                cone_angle,
+                                cone_delta_angle,
+                            ))
+                        }
+                        LightSource::Goniometric {
+                            intensity,
+                            image,
+                            equal_area,
+                        } => {
+                            let texture = self.light_texture(image);
+                            self.lights.push(EnumLight::new_goniometric(
+                                state.current_matrix.start,
+                                intensity,
+                                texture,
+                                equal_area,
+                            ))
+                        }
+                        LightSource::Projection {
+                            intensity,
+                            fov,
+                            image,
+                        } => {
+                            let aspect_ratio = image
+                                .as_ref()
+                                .map(|image| image.width as f32 / image.height as f32)
+                                .unwrap_or(1.0);
+                            let texture = self.light_texture(image);
+                            self.lights.push(EnumLight::new_projection(
+                                state.current_matrix.start,
+                                intensity,
+                                texture,
+                                fov,
+                                aspect_ratio,
+                            ))
+                        }
                     },
                     WorldObject::AreaLightSource(AreaLightSource::Diffuse(l)) => {
                         state.current_area_light_index = self.area_lights.len();
@@ -402,23 +1011,19 @@ impl Scene {
                         state.current_material_index = self.materials.len();
                         self.materials.push(material);
                     }
-                    WorldObject::MakeNamedMedium(
-                        name,
-                        Medium::Homogeneous {
-                            sigma_a,
-                            sigma_s,
-                            g,
-                        },
-                    ) => {
-                        let medium = EnumMedium::new_homogeneous(sigma_a, sigma_s, g);
-                        state.mediums.insert(name, self.mediums.len() as u32);
-                        self.mediums.push(medium);
+                    WorldObject::MakeNamedMedium(name, medium) => {
+                        let medium_index = self.medium(medium);
+                        state.mediums.insert(name, medium_index);
                     }
                     WorldObject::Shape(shape) => match shape {
                         Shape::Sphere { radius } => self.tlas.push(TlasInstance {
                             shader_offset: ShaderOffset::Sphere,
                             matrix: Affine3A::from_mat4(
-                                state.current_matrix
+                                state.current_matrix.start
+                                    * Mat4::from_scale(vec3(radius, radius, radius)),
+                            ),
+                            end_matrix: Affine3A::from_mat4(
+                                state.current_matrix.end
                                     * Mat4::from_scale(vec3(radius, radius, radius)),
                             ),
                             material_index: state.current_material_index,
@@ -438,7 +1043,8 @@ impl Scene {
                             self.blases.push(trianglemesh);
                             self.tlas.push(TlasInstance {
                                 shader_offset: ShaderOffset::Triangle,
-                                matrix: Affine3A::from_mat4(state.current_matrix),
+                                matrix: Affine3A::from_mat4(state.current_matrix.start),
+                                end_matrix: Affine3A::from_mat4(state.current_matrix.end),
                                 material_index: state.current_material_index,
                                 area_light_index: state.current_area_light_index,
                                 interior_medium_index: state
@@ -459,3 +1065,98 @@ impl Scene {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use glam::Vec4Swizzles;
+
+    use super::*;
+
+    /// Screen window corners `[xmin, xmax, ymin, ymax]` of the NDC square.
+    fn corners(matrix: Mat4) -> [f32; 4] {
+        let min = matrix * vec4(-1.0, -1.0, 0.0, 1.0);
+        let max = matrix * vec4(1.0, 1.0, 0.0, 1.0);
+        [min.x, max.x, min.y, max.y]
+    }
+
+    fn assert_close(a: Mat4, b: Mat4) {
+        assert!(a.abs_diff_eq(b, 1e-4), "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_interpolate_sheared() {
+        let shear = Mat4::from_cols(
+            vec4(1.0, 0.0, 0.0, 0.0),
+            vec4(0.5, 2.0, 0.0, 0.0),
+            vec4(0.0, 0.3, 1.0, 0.0),
+            vec4(0.0, 0.0, 0.0, 1.0),
+        );
+        let start = Mat4::from_rotation_z(0.3) * shear;
+        let end = Mat4::from_translation(vec3(2.0, 0.0, 4.0)) * Mat4::from_rotation_z(1.3) * shear;
+
+        assert_close(interpolate(start, end, 0.0), start);
+        assert_close(interpolate(start, end, 1.0), end);
+        // The shear is shared, only the rotation and translation move
+        assert_close(
+            interpolate(start, end, 0.5),
+            Mat4::from_translation(vec3(1.0, 0.0, 2.0)) * Mat4::from_rotation_z(0.8) * shear,
+        );
+    }
+
+    #[test]
+    fn test_interpolate_mirrored() {
+        let start = Mat4::from_scale(vec3(-1.0, 2.0, 1.0));
+        let end = Mat4::from_rotation_y(1.0) * start;
+
+        assert_close(interpolate(start, end, 0.0), start);
+        assert_close(interpolate(start, end, 1.0), end);
+    }
+
+    #[test]
+    fn test_fov_on_shorter_axis() {
+        let fov = 60f32.to_radians();
+        let scale = (fov * 0.5).tan();
+
+        for (aspect_ratio, axis) in [(2.0, 1), (0.5, 0)] {
+            let matrix = Mat4::from_scale(vec3(scale, scale, 1.0))
+                * screen_window_matrix(&ScreenWindow::default(), aspect_ratio);
+            // Direction through the middle of the edge of the shorter axis
+            let mut ndc = vec4(0.0, 0.0, 1.0, 1.0);
+            ndc[axis] = 1.0;
+            let edge = (matrix * ndc).xyz().normalize();
+            assert!((edge.z.acos() - 0.5 * fov).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_default_screen_window() {
+        let wide = corners(screen_window_matrix(&ScreenWindow::default(), 2.0));
+        assert_eq!(wide, [-2.0, 2.0, -1.0, 1.0]);
+
+        let tall = corners(screen_window_matrix(&ScreenWindow::default(), 0.5));
+        assert_eq!(tall, [-1.0, 1.0, -2.0, 2.0]);
+
+        let square = corners(screen_window_matrix(&ScreenWindow::default(), 1.0));
+        assert_eq!(square, [-1.0, 1.0, -1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_frame_aspect_ratio_overrides_film() {
+        let screen_window = ScreenWindow {
+            screen_window: None,
+            frame_aspect_ratio: Some(0.5),
+        };
+        let window = corners(screen_window_matrix(&screen_window, 2.0));
+        assert_eq!(window, [-1.0, 1.0, -2.0, 2.0]);
+    }
+
+    #[test]
+    fn test_explicit_screen_window() {
+        let screen_window = ScreenWindow {
+            screen_window: Some([-0.5, 1.5, 0.0, 1.0]),
+            frame_aspect_ratio: Some(3.0),
+        };
+        let window = corners(screen_window_matrix(&screen_window, 2.0));
+        assert_eq!(window, [-0.5, 1.5, 0.0, 1.0]);
+    }
+}
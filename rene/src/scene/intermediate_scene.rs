@@ -1,8 +1,13 @@
-use std::{f32::consts::PI, ffi::OsStr, fs::File, io::Read, path::Path};
+use std::{f32::consts::PI, ffi::OsStr, io::Cursor, path::Path};
 
 use blackbody::temperature_to_rgb;
+use exr::prelude::{ReadChannels, ReadLayers};
 use glam::{vec2, vec3a, Mat4, Vec2, Vec3A};
 use image::GenericImageView;
+use pbrt_parser::{
+    include::{read_file, read_to_string},
+    ActiveTransform,
+};
 use ply::ply::{Ply, PropertyAccess};
 use ply_rs as ply;
 use rene_shader::Vertex;
@@ -10,7 +15,13 @@ use thiserror::Error;
 
 use crate::scene::pfm_parser::parse_pfm_rgb;
 
-use super::{image::Image, spectrum::parse_spd, subdivision::loop_subdivision};
+use super::{
+    image::Image,
+    lens_parser::parse_lens,
+    lint::{Lint, Tracked},
+    spectrum::parse_spd,
+    subdivision::loop_subdivision,
+};
 
 #[derive(PartialEq, Debug)]
 pub struct LookAt {
@@ -23,8 +34,41 @@ pub enum SceneObject {
     Camera(Camera),
 }
 
-pub enum Camera {
-    Perspective { fov: f32 },
+pub struct Camera {
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+    pub inner: InnerCamera,
+}
+
+pub enum InnerCamera {
+    Perspective {
+        /// Spans the shorter axis of the screen window
+        fov: f32,
+        screen_window: ScreenWindow,
+        lens_radius: f32,
+        focal_distance: f32,
+    },
+    Orthographic {
+        screen_window: ScreenWindow,
+        lens_radius: f32,
+        focal_distance: f32,
+    },
+    Environment,
+    Realistic {
+        /// Rows of curvature radius, thickness, index of refraction and aperture diameter in millimeters
+        lens: Vec<[f32; 4]>,
+        /// In millimeters
+        aperture_diameter: f32,
+        focus_distance: f32,
+    },
+}
+
+#[derive(Default)]
+pub struct ScreenWindow {
+    /// `[xmin, xmax, ymin, ymax]`, derived from the frame aspect ratio if not given
+    pub screen_window: Option<[f32; 4]>,
+    /// Defaults to the film aspect ratio
+    pub frame_aspect_ratio: Option<f32>,
 }
 
 pub enum IntermediateWorld {
@@ -37,7 +81,9 @@ pub enum IntermediateWorld {
     Transform(Mat4),
     Texture(Texture),
     NamedMaterial(String),
+    CoordinateSystem(String),
     CoordSysTransform(String),
+    ActiveTransform(ActiveTransform),
     MediumInterface { interior: String, exterior: String },
     ReverseOrientation,
 }
@@ -65,6 +111,31 @@ pub enum LightSource {
         to: Vec3A,
         color: Vec3A,
     },
+    Point {
+        from: Vec3A,
+        intensity: Vec3A,
+    },
+    Spot {
+        from: Vec3A,
+        to: Vec3A,
+        intensity: Vec3A,
+        /// In radians
+        cone_angle: f32,
+        /// In radians
+        cone_delta_angle: f32,
+    },
+    Goniometric {
+        intensity: Vec3A,
+        image: Option<Image>,
+        /// pbrt-v4's equal-area square image, given as "filename", instead of pbrt-v3's lat-long
+        equal_area: bool,
+    },
+    Projection {
+        intensity: Vec3A,
+        /// In radians
+        fov: f32,
+        image: Option<Image>,
+    },
 }
 
 #[derive(Clone)]
@@ -157,6 +228,8 @@ pub struct Film {
     pub filename: String,
     pub xresolution: u32,
     pub yresolution: u32,
+    /// In millimeters
+    pub diagonal: f32,
 }
 
 impl Default for Film {
@@ -165,6 +238,7 @@ impl Default for Film {
             filename: "out.png".to_string(),
             xresolution: 640,
             yresolution: 480,
+            diagonal: 35.0,
         }
     }
 }
@@ -172,6 +246,11 @@ impl Default for Film {
 pub enum IntermediateScene {
     Matrix(Mat4),
     Transform(Mat4),
+    ActiveTransform(ActiveTransform),
+    TransformTimes(f32, f32),
+    CoordinateSystem(String),
+    MakeNamedMedium(String, Medium),
+    MediumInterface { interior: String, exterior: String },
     SceneObject(SceneObject),
     World(Vec<IntermediateWorld>),
     // TODO implement it
@@ -185,16 +264,84 @@ pub enum IntermediateScene {
 
 #[derive(Debug)]
 pub enum Integrator {
-    Path,
-    VolPath,
+    Path(PathIntegrator),
+    VolPath(PathIntegrator),
+    AmbientOcclusion(AmbientOcclusionIntegrator),
+    DirectLighting(DirectLightingIntegrator),
+    Bdpt(BdptIntegrator),
+    Sppm(SppmIntegrator),
+    Mlt(MltIntegrator),
 }
 
 impl Default for Integrator {
     fn default() -> Self {
-        Self::Path
+        Self::Path(PathIntegrator::default())
     }
 }
 
+#[derive(Debug)]
+pub struct PathIntegrator {
+    pub max_depth: u32,
+    /// Russian roulette only starts once the throughput falls below it
+    pub rr_threshold: f32,
+}
+
+impl Default for PathIntegrator {
+    fn default() -> Self {
+        Self {
+            max_depth: 5,
+            rr_threshold: 1.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AmbientOcclusionIntegrator {
+    pub max_distance: f32,
+    pub cos_sample: bool,
+}
+
+#[derive(Debug)]
+pub struct DirectLightingIntegrator {
+    /// Bounces followed through specular surfaces
+    pub max_depth: u32,
+    pub strategy: LightSampleStrategy,
+}
+
+#[derive(Debug)]
+pub struct BdptIntegrator {
+    /// Bounces of the longest path made by connecting the two subpaths
+    pub max_depth: u32,
+}
+
+#[derive(Debug)]
+pub struct SppmIntegrator {
+    pub max_depth: u32,
+    pub iterations: u32,
+    /// Photons traced each iteration, the pixel count if not positive
+    pub photons_per_iteration: i32,
+    /// Initial radius of the photon search around each visible point
+    pub radius: f32,
+}
+
+#[derive(Debug)]
+pub struct MltIntegrator {
+    pub max_depth: u32,
+    /// Mutations per pixel on average, which sets the launch count
+    pub mutations_per_pixel: u32,
+    pub large_step_probability: f32,
+    /// Deviation of the small steps in primary sample space
+    pub sigma: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightSampleStrategy {
+    /// Estimate every light at each point
+    All,
+    /// Pick one light at each point
+    One,
+}
+
 #[derive(Error, Debug)]
 pub enum ArgumentError {
     #[error("unmatched value length")]
@@ -231,18 +378,21 @@ pub enum Error {
     Pfm,
     #[error("SPD decode error")]
     Spd,
+    #[error("Lens decode error")]
+    Lens,
     #[error("Ply error")]
     Ply,
     #[error("Exr Error")]
     Exr(#[from] exr::error::Error),
 }
 
-trait GetValue {
+pub(super) trait GetValue {
     fn get_bool(&self, name: &str) -> Result<Result<bool, ArgumentError>, Error>;
     fn get_float(&self, name: &str) -> Result<Result<f32, ArgumentError>, Error>;
     fn get_floats(&self, name: &str) -> Result<Result<&[f32], ArgumentError>, Error>;
     fn get_integer(&self, name: &str) -> Result<Result<i32, ArgumentError>, Error>;
     fn get_integers(&self, name: &str) -> Result<Result<&[i32], ArgumentError>, Error>;
+    fn get_point2s(&self, name: &str) -> Result<Result<&[Vec2], ArgumentError>, Error>;
     fn get_points(&self, name: &str) -> Result<Result<&[Vec3A], ArgumentError>, Error>;
     fn get_normals(&self, name: &str) -> Result<Result<&[Vec3A], ArgumentError>, Error>;
     fn get_str(&self, name: &str) -> Result<Result<&str, ArgumentError>, Error>;
@@ -257,35 +407,35 @@ trait GetValue {
         name: &str,
         base_path: &P,
     ) -> Result<Result<TextureOrColor, ArgumentError>, Error>;
-    fn get_material<P: AsRef<Path>>(&self, base_path: &P) -> Result<Material, Error>;
+    /// A light's "scale", a spectrum in pbrt-v3 and a float in pbrt-v4.
+    fn get_scale<P: AsRef<Path>>(&self, base_path: &P) -> Result<Vec3A, Error>;
+    fn get_material<P: AsRef<Path>>(&self, t: &str, base_path: &P) -> Result<Material, Error>;
 }
 
-impl<T> GetValue for pbrt_parser::Object<T> {
+impl<'a, T> GetValue for Tracked<'a, T> {
     fn get_rgb<P: AsRef<Path>>(
         &self,
         name: &str,
         base_path: &P,
     ) -> Result<Result<Vec3A, ArgumentError>, Error> {
-        self.get_value(name)
-            .map(|value| match value {
-                pbrt_parser::Value::Rgb(v) => Ok(*v),
-                pbrt_parser::Value::BlackBody(v) => {
-                    let mut color = Vec3A::ZERO;
-
-                    for v in v {
-                        color += v.y * Vec3A::from(temperature_to_rgb(v.x));
-                    }
-                    Ok(color)
-                }
-                pbrt_parser::Value::Spectrum(file) => {
-                    let mut path = base_path.as_ref().to_path_buf();
-                    path.push(file);
-                    // TODO Error handling
-                    Ok(load_spd(&path).unwrap())
+        self.get(name, |value| match value {
+            pbrt_parser::Value::Rgb(v) => Ok(*v),
+            pbrt_parser::Value::BlackBody(v) => {
+                let mut color = Vec3A::ZERO;
+
+                for v in v {
+                    color += v.y * Vec3A::from(temperature_to_rgb(v.x));
                 }
-                _ => Err(ArgumentError::UnmatchedType(name.to_string())),
-            })
-            .ok_or_else(|| Error::ArgumentNotFound(name.to_string()))
+                Ok(color)
+            }
+            pbrt_parser::Value::Spectrum(file) => {
+                let mut path = base_path.as_ref().to_path_buf();
+                path.push(file);
+                // TODO Error handling
+                Ok(load_spd(&path).unwrap())
+            }
+            _ => Err(ArgumentError::UnmatchedType(name.to_string())),
+        })
     }
 
     fn get_texture_or_color<P: AsRef<Path>>(
@@ -293,134 +443,131 @@ impl<T> GetValue for pbrt_parser::Object<T> {
         name: &str,
         base_path: &P,
     ) -> Result<Result<TextureOrColor, ArgumentError>, Error> {
-        self.get_value(name)
-            .map(|value| match value {
-                pbrt_parser::Value::Float(v) => {
-                    if v.len() != 1 {
-                        Err(ArgumentError::UnmatchedValueLength)
-                    } else {
-                        Ok(TextureOrColor::Color(vec3a(v[0], v[0], v[0])))
-                    }
+        self.get(name, |value| match value {
+            pbrt_parser::Value::Float(v) => {
+                if v.len() != 1 {
+                    Err(ArgumentError::UnmatchedValueLength)
+                } else {
+                    Ok(TextureOrColor::Color(vec3a(v[0], v[0], v[0])))
                 }
-                pbrt_parser::Value::Rgb(v) => Ok(TextureOrColor::Color(*v)),
-                pbrt_parser::Value::BlackBody(v) => {
-                    let mut color = Vec3A::ZERO;
+            }
+            pbrt_parser::Value::Rgb(v) => Ok(TextureOrColor::Color(*v)),
+            pbrt_parser::Value::BlackBody(v) => {
+                let mut color = Vec3A::ZERO;
 
-                    for v in v {
-                        color += v.y * Vec3A::from(temperature_to_rgb(v.x));
-                    }
-                    Ok(TextureOrColor::Color(color))
-                }
-                pbrt_parser::Value::Spectrum(file) => {
-                    let mut path = base_path.as_ref().to_path_buf();
-                    path.push(file);
-                    // TODO Error handling
-                    Ok(TextureOrColor::Color(load_spd(&path).unwrap()))
+                for v in v {
+                    color += v.y * Vec3A::from(temperature_to_rgb(v.x));
                 }
-                pbrt_parser::Value::Texture(s) => Ok(TextureOrColor::Texture(s[0].to_string())),
-                _ => Err(ArgumentError::UnmatchedType(name.to_string())),
-            })
-            .ok_or_else(|| Error::ArgumentNotFound(name.to_string()))
+                Ok(TextureOrColor::Color(color))
+            }
+            pbrt_parser::Value::Spectrum(file) => {
+                let mut path = base_path.as_ref().to_path_buf();
+                path.push(file);
+                // TODO Error handling
+                Ok(TextureOrColor::Color(load_spd(&path).unwrap()))
+            }
+            pbrt_parser::Value::Texture(s) => Ok(TextureOrColor::Texture(s[0].to_string())),
+            _ => Err(ArgumentError::UnmatchedType(name.to_string())),
+        })
     }
 
     fn get_float(&self, name: &str) -> Result<Result<f32, ArgumentError>, Error> {
-        self.get_value(name)
-            .map(|value| match value {
-                pbrt_parser::Value::Float(v) => {
-                    if v.len() == 1 {
-                        Ok(v[0])
-                    } else {
-                        Err(ArgumentError::UnmatchedValueLength)
-                    }
+        self.get(name, |value| match value {
+            pbrt_parser::Value::Float(v) => {
+                if v.len() == 1 {
+                    Ok(v[0])
+                } else {
+                    Err(ArgumentError::UnmatchedValueLength)
                 }
-                _ => Err(ArgumentError::UnmatchedType(name.to_string())),
-            })
-            .ok_or_else(|| Error::ArgumentNotFound(name.to_string()))
+            }
+            _ => Err(ArgumentError::UnmatchedType(name.to_string())),
+        })
     }
 
     fn get_floats(&self, name: &str) -> Result<Result<&[f32], ArgumentError>, Error> {
-        self.get_value(name)
-            .map(|value| match value {
-                pbrt_parser::Value::Float(v) => Ok(v.as_slice()),
-                _ => Err(ArgumentError::UnmatchedType(name.to_string())),
-            })
-            .ok_or_else(|| Error::ArgumentNotFound(name.to_string()))
+        self.get(name, |value| match value {
+            pbrt_parser::Value::Float(v) => Ok(v.as_slice()),
+            _ => Err(ArgumentError::UnmatchedType(name.to_string())),
+        })
     }
 
     fn get_integer(&self, name: &str) -> Result<Result<i32, ArgumentError>, Error> {
-        self.get_value(name)
-            .map(|value| match value {
-                pbrt_parser::Value::Integer(v) => {
-                    if v.len() == 1 {
-                        Ok(v[0])
-                    } else {
-                        Err(ArgumentError::UnmatchedValueLength)
-                    }
+        self.get(name, |value| match value {
+            pbrt_parser::Value::Integer(v) => {
+                if v.len() == 1 {
+                    Ok(v[0])
+                } else {
+                    Err(ArgumentError::UnmatchedValueLength)
                 }
-                _ => Err(ArgumentError::UnmatchedType(name.to_string())),
-            })
-            .ok_or_else(|| Error::ArgumentNotFound(name.to_string()))
+            }
+            _ => Err(ArgumentError::UnmatchedType(name.to_string())),
+        })
     }
 
     fn get_integers(&self, name: &str) -> Result<Result<&[i32], ArgumentError>, Error> {
-        self.get_value(name)
-            .map(|value| match value {
-                pbrt_parser::Value::Integer(v) => Ok(v.as_slice()),
-                _ => Err(ArgumentError::UnmatchedType(name.to_string())),
-            })
-            .ok_or_else(|| Error::ArgumentNotFound(name.to_string()))
+        self.get(name, |value| match value {
+            pbrt_parser::Value::Integer(v) => Ok(v.as_slice()),
+            _ => Err(ArgumentError::UnmatchedType(name.to_string())),
+        })
+    }
+
+    fn get_point2s(&self, name: &str) -> Result<Result<&[Vec2], ArgumentError>, Error> {
+        self.get(name, |value| match value {
+            pbrt_parser::Value::Point2(v) => Ok(v.as_slice()),
+            _ => Err(ArgumentError::UnmatchedType(name.to_string())),
+        })
     }
 
     fn get_points(&self, name: &str) -> Result<Result<&[Vec3A], ArgumentError>, Error> {
-        self.get_value(name)
-            .map(|value| match value {
-                pbrt_parser::Value::Point(v) => Ok(v.as_slice()),
-                _ => Err(ArgumentError::UnmatchedType(name.to_string())),
-            })
-            .ok_or_else(|| Error::ArgumentNotFound(name.to_string()))
+        self.get(name, |value| match value {
+            pbrt_parser::Value::Point(v) => Ok(v.as_slice()),
+            _ => Err(ArgumentError::UnmatchedType(name.to_string())),
+        })
     }
 
     fn get_normals(&self, name: &str) -> Result<Result<&[Vec3A], ArgumentError>, Error> {
-        self.get_value(name)
-            .map(|value| match value {
-                pbrt_parser::Value::Normal(v) => Ok(v.as_slice()),
-                _ => Err(ArgumentError::UnmatchedType(name.to_string())),
-            })
-            .ok_or_else(|| Error::ArgumentNotFound(name.to_string()))
+        self.get(name, |value| match value {
+            pbrt_parser::Value::Normal(v) => Ok(v.as_slice()),
+            _ => Err(ArgumentError::UnmatchedType(name.to_string())),
+        })
     }
 
     fn get_str(&self, name: &str) -> Result<Result<&str, ArgumentError>, Error> {
-        self.get_value(name)
-            .map(|value| match value {
-                pbrt_parser::Value::String(s) => {
-                    if s.len() == 1 {
-                        Ok(s[0].as_str())
-                    } else {
-                        Err(ArgumentError::UnmatchedValueLength)
-                    }
+        self.get(name, |value| match value {
+            pbrt_parser::Value::String(s) => {
+                if s.len() == 1 {
+                    Ok(s[0].as_str())
+                } else {
+                    Err(ArgumentError::UnmatchedValueLength)
                 }
-                _ => Err(ArgumentError::UnmatchedType(name.to_string())),
-            })
-            .ok_or_else(|| Error::ArgumentNotFound(name.to_string()))
+            }
+            _ => Err(ArgumentError::UnmatchedType(name.to_string())),
+        })
     }
 
     fn get_point(&self, name: &str) -> Result<Result<Vec3A, ArgumentError>, Error> {
-        self.get_value(name)
-            .map(|value| match value {
-                pbrt_parser::Value::Point(v) => {
-                    if v.len() == 1 {
-                        Ok(v[0])
-                    } else {
-                        Err(ArgumentError::UnmatchedValueLength)
-                    }
+        self.get(name, |value| match value {
+            pbrt_parser::Value::Point(v) => {
+                if v.len() == 1 {
+                    Ok(v[0])
+                } else {
+                    Err(ArgumentError::UnmatchedValueLength)
                 }
-                _ => Err(ArgumentError::UnmatchedType(name.to_string())),
-            })
-            .ok_or_else(|| Error::ArgumentNotFound(name.to_string()))
+            }
+            _ => Err(ArgumentError::UnmatchedType(name.to_string())),
+        })
+    }
+
+    fn get_scale<P: AsRef<Path>>(&self, base_path: &P) -> Result<Vec3A, Error> {
+        match self.get_float("scale") {
+            Ok(Ok(scale)) => Ok(vec3a(scale, scale, scale)),
+            Ok(Err(_)) => Ok(self.get_rgb("scale", base_path)??),
+            Err(_) => Ok(vec3a(1.0, 1.0, 1.0)),
+        }
     }
 
-    fn get_material<P: AsRef<Path>>(&self, base_path: &P) -> Result<Material, Error> {
-        match self.t.as_str() {
+    fn get_material<P: AsRef<Path>>(&self, t: &str, base_path: &P) -> Result<Material, Error> {
+        match t {
             "none" | "" => Ok(Material::None),
             "matte" => {
                 let albedo = self
@@ -594,18 +741,16 @@ impl<T> GetValue for pbrt_parser::Object<T> {
     }
 
     fn get_bool(&self, name: &str) -> Result<Result<bool, ArgumentError>, Error> {
-        self.get_value(name)
-            .map(|value| match value {
-                pbrt_parser::Value::Bool(v) => {
-                    if v.len() == 1 {
-                        Ok(v[0])
-                    } else {
-                        Err(ArgumentError::UnmatchedValueLength)
-                    }
+        self.get(name, |value| match value {
+            pbrt_parser::Value::Bool(v) => {
+                if v.len() == 1 {
+                    Ok(v[0])
+                } else {
+                    Err(ArgumentError::UnmatchedValueLength)
                 }
-                _ => Err(ArgumentError::UnmatchedType(name.to_string())),
-            })
-            .ok_or_else(|| Error::ArgumentNotFound(name.to_string()))
+            }
+            _ => Err(ArgumentError::UnmatchedType(name.to_string())),
+        })
     }
 }
 
@@ -622,43 +767,102 @@ fn inverse_gamma_correct(value: f32) -> f32 {
 }
 
 fn load_spd<P: AsRef<Path>>(path: &P) -> Result<Vec3A, Error> {
-    let mut content = String::new();
-    File::open(path)?.read_to_string(&mut content)?;
+    let content = read_to_string(path)?;
 
     Ok(parse_spd(&content).map_err(|_| Error::Spd)?.1)
 }
 
+fn load_lens<P: AsRef<Path>>(path: &P) -> Result<Vec<[f32; 4]>, Error> {
+    let content = read_to_string(path)?;
+
+    Ok(parse_lens(&content).map_err(|_| Error::Lens)?.1)
+}
+
+fn get_screen_window<T>(obj: &Tracked<T>) -> Result<ScreenWindow, Error> {
+    let screen_window = match obj.get_floats("screenwindow") {
+        Ok(window) => match window? {
+            &[x0, x1, y0, y1] => Some([x0, x1, y0, y1]),
+            _ => return Err(ArgumentError::UnmatchedValueLength.into()),
+        },
+        Err(_) => None,
+    };
+    let frame_aspect_ratio = match obj.get_float("frameaspectratio") {
+        Ok(ratio) => Some(ratio?),
+        Err(_) => None,
+    };
+
+    Ok(ScreenWindow {
+        screen_window,
+        frame_aspect_ratio,
+    })
+}
+
+fn load_medium<T, P: AsRef<Path>>(obj: &Tracked<T>, base_dir: &P) -> Result<Medium, Error> {
+    let sigma_a = obj
+        .get_rgb("sigma_a", base_dir)
+        .unwrap_or(Ok(vec3a(0.0011, 0.0024, 0.014)))?;
+
+    let sigma_s = obj
+        .get_rgb("sigma_s", base_dir)
+        .unwrap_or(Ok(vec3a(2.55, 3.21, 3.77)))?;
+
+    let g = obj.get_float("g").unwrap_or(Ok(0.0))?;
+
+    Ok(Medium::Homogeneous {
+        sigma_a,
+        sigma_s,
+        g,
+    })
+}
+
 fn load_image<P: AsRef<Path>>(path: P) -> Result<Image, Error> {
     let pfm = OsStr::new("pfm");
     let exr = OsStr::new("exr");
+    let gz = OsStr::new("gz");
 
-    match path.as_ref().extension() {
-        Some(ext) if ext == pfm => {
-            let mut content = Vec::new();
-            File::open(path)?.read_to_end(&mut content)?;
+    let content = read_file(&path)?;
 
-            Ok(parse_pfm_rgb(&content).map_err(|_| Error::Pfm)?.1)
-        }
+    // Look through ".gz" to find out the actual format
+    let path = path.as_ref();
+    let extension = match path.extension() {
+        Some(ext) if ext == gz => path.file_stem().map(Path::new).and_then(Path::extension),
+        ext => ext,
+    };
+
+    match extension {
+        Some(ext) if ext == pfm => Ok(parse_pfm_rgb(&content).map_err(|_| Error::Pfm)?.1),
         Some(ext) if ext == exr => {
-            let image = exr::prelude::read_first_rgba_layer_from_file(
-                path,
-                |resolution, _| {
-                    Image::new(
-                        resolution.width() as u32,
-                        resolution.height() as u32,
-                        vec![[0.0, 0.0, 0.0, 0.0]; resolution.width() * resolution.height()],
-                    )
-                },
-                |pixel_vector, position, (r, g, b, a): (f32, f32, f32, f32)| {
-                    pixel_vector.data[pixel_vector.width as usize * position.y() + position.x()] =
-                        [r, g, b, a];
-                },
-            )?;
+            let image = exr::prelude::read()
+                .no_deep_data()
+                .largest_resolution_level()
+                .rgba_channels(
+                    |resolution, _| {
+                        Image::new(
+                            resolution.width() as u32,
+                            resolution.height() as u32,
+                            vec![[0.0, 0.0, 0.0, 0.0]; resolution.width() * resolution.height()],
+                        )
+                    },
+                    |pixel_vector: &mut Image, position, (r, g, b, a): (f32, f32, f32, f32)| {
+                        pixel_vector.data
+                            [pixel_vector.width as usize * position.y() + position.x()] =
+                            [r, g, b, a];
+                    },
+                )
+                .first_valid_layer()
+                .all_attributes()
+                .from_buffered(Cursor::new(content))?;
 
             Ok(image.layer_data.channel_data.pixels)
         }
         _ => {
-            let image = image::io::Reader::open(path)?.decode()?;
+            // Formats without magic bytes like TGA are only known by their extension
+            let mut reader = image::io::Reader::new(Cursor::new(content));
+            match extension.and_then(image::ImageFormat::from_extension) {
+                Some(format) => reader.set_format(format),
+                None => reader = reader.with_guessed_format()?,
+            }
+            let image = reader.decode()?;
 
             let mut data = Vec::new();
 
@@ -752,7 +956,11 @@ fn load_ply<E: PropertyAccess>(ply: &Ply<E>) -> Result<TriangleMesh, Error> {
 }
 
 impl IntermediateWorld {
-    fn from_world<P: AsRef<Path>>(world: pbrt_parser::World, base_dir: &P) -> Result<Self, Error> {
+    fn from_world<P: AsRef<Path>>(
+        world: pbrt_parser::World,
+        base_dir: &P,
+        lints: &mut Vec<Lint>,
+    ) -> Result<Self, Error> {
         match world {
             pbrt_parser::World::ReverseOrientation => Ok(Self::ReverseOrientation),
             pbrt_parser::World::ObjectInstance(name) => Ok(Self::ObjectInstance(name.to_string())),
@@ -763,264 +971,361 @@ impl IntermediateWorld {
                 interior: interior.to_string(),
                 exterior: exterior.to_string(),
             }),
+            pbrt_parser::World::CoordinateSystem(name) => Ok(Self::CoordinateSystem(name)),
             pbrt_parser::World::CoordSysTransform(name) => {
                 Ok(Self::CoordSysTransform(name.to_string()))
             }
-            pbrt_parser::World::Texture(texture) => match texture.obj.t.as_str() {
-                "constant" => {
-                    let value = if let Ok(Ok(v)) = texture.obj.get_float("value") {
-                        vec3a(v, v, v)
-                    } else if let Ok(Ok(rgb)) = texture.obj.get_rgb("value", base_dir) {
-                        rgb
-                    } else {
-                        vec3a(1.0, 1.0, 1.0)
-                    };
-
-                    Ok(Self::Texture(Texture {
-                        name: texture.name.to_string(),
-                        inner: InnerTexture::Constant(value),
-                    }))
-                }
-                "scale" => {
-                    let tex1 = texture
-                        .obj
-                        .get_texture_or_color("tex1", base_dir)
-                        .unwrap_or_else(|_| Ok(TextureOrColor::Color(vec3a(1.0, 1.0, 1.0))))?;
-                    let tex2 = texture
-                        .obj
-                        .get_texture_or_color("tex2", base_dir)
-                        .unwrap_or_else(|_| Ok(TextureOrColor::Color(vec3a(1.0, 1.0, 1.0))))?;
-
-                    Ok(Self::Texture(Texture {
-                        name: texture.name.to_string(),
-                        inner: InnerTexture::Scale(tex1, tex2),
-                    }))
-                }
-                "checkerboard" => {
-                    let tex1 = texture
-                        .obj
-                        .get_texture_or_color("tex1", base_dir)
-                        .unwrap_or_else(|_| Ok(TextureOrColor::Color(vec3a(0.0, 0.0, 0.0))))?;
-                    let tex2 = texture
-                        .obj
-                        .get_texture_or_color("tex2", base_dir)
-                        .unwrap_or_else(|_| Ok(TextureOrColor::Color(vec3a(1.0, 1.0, 1.0))))?;
-
-                    let uscale = texture.obj.get_float("uscale").unwrap_or(Ok(2.0))?;
-                    let vscale = texture.obj.get_float("vscale").unwrap_or(Ok(2.0))?;
-
-                    Ok(Self::Texture(Texture {
-                        name: texture.name.to_string(),
-                        inner: InnerTexture::CheckerBoard {
-                            tex1,
-                            tex2,
-                            uscale,
-                            vscale,
-                        },
-                    }))
-                }
-                "imagemap" => {
-                    let filename = texture.obj.get_str("filename")??;
-                    let mut pathbuf = base_dir.as_ref().to_path_buf();
-                    pathbuf.push(filename);
-                    let image = load_image(pathbuf)?;
-                    Ok(Self::Texture(Texture {
-                        name: texture.name.to_string(),
-                        inner: InnerTexture::ImageMap(image),
-                    }))
-                }
-                t => Err(Error::InvalidTexture(t.to_string())),
-            },
-            pbrt_parser::World::WorldObject(obj) => match obj.object_type {
-                pbrt_parser::WorldObjectType::LightSource => match obj.t.as_str() {
-                    "infinite" => {
-                        let color = obj
-                            .get_rgb("L", base_dir)
-                            .unwrap_or_else(|_| Ok(vec3a(1.0, 1.0, 1.0)))?;
-
-                        let image_map = if let Ok(filename) = obj.get_str("mapname") {
-                            let filename = filename?;
-                            let mut pathbuf = base_dir.as_ref().to_path_buf();
-                            pathbuf.push(filename);
-                            Some(load_image(pathbuf)?)
+            pbrt_parser::World::ActiveTransform(active) => Ok(Self::ActiveTransform(active)),
+            pbrt_parser::World::Texture(texture) => {
+                let obj = Tracked::new("Texture", &texture.obj);
+                let world = match obj.t.as_str() {
+                    "constant" => {
+                        let value = if let Ok(Ok(v)) = obj.get_float("value") {
+                            vec3a(v, v, v)
+                        } else if let Ok(Ok(rgb)) = obj.get_rgb("value", base_dir) {
+                            rgb
                         } else {
-                            None
+                            vec3a(1.0, 1.0, 1.0)
                         };
 
-                        Ok(Self::WorldObject(WorldObject::LightSource(
-                            LightSource::Infinite { color, image_map },
-                        )))
+                        Ok(Self::Texture(Texture {
+                            name: texture.name.to_string(),
+                            inner: InnerTexture::Constant(value),
+                        }))
                     }
-                    "distant" => {
-                        let from = obj
-                            .get_point("from")
-                            .unwrap_or_else(|_| Ok(vec3a(0.0, 0.0, 0.0)))?;
-                        let to = obj
-                            .get_point("to")
-                            .unwrap_or_else(|_| Ok(vec3a(0.0, 0.0, 1.0)))?;
-                        let color = obj
-                            .get_rgb("L", base_dir)
-                            .unwrap_or_else(|_| Ok(vec3a(1.0, 1.0, 1.0)))?;
-                        Ok(Self::WorldObject(WorldObject::LightSource(
-                            LightSource::Distant { from, to, color },
-                        )))
+                    "scale" => {
+                        let tex1 = obj
+                            .get_texture_or_color("tex1", base_dir)
+                            .unwrap_or_else(|_| Ok(TextureOrColor::Color(vec3a(1.0, 1.0, 1.0))))?;
+                        let tex2 = obj
+                            .get_texture_or_color("tex2", base_dir)
+                            .unwrap_or_else(|_| Ok(TextureOrColor::Color(vec3a(1.0, 1.0, 1.0))))?;
+
+                        Ok(Self::Texture(Texture {
+                            name: texture.name.to_string(),
+                            inner: InnerTexture::Scale(tex1, tex2),
+                        }))
                     }
-                    t => Err(Error::InvalidLightSource(t.to_string())),
-                },
-                pbrt_parser::WorldObjectType::AreaLightSource => match obj.t.as_str() {
-                    "diffuse" | "area" => {
-                        let l = obj.get_rgb("L", base_dir)??;
-                        Ok(Self::WorldObject(WorldObject::AreaLightSource(
-                            AreaLightSource::Diffuse(l),
+                    "checkerboard" => {
+                        let tex1 = obj
+                            .get_texture_or_color("tex1", base_dir)
+                            .unwrap_or_else(|_| Ok(TextureOrColor::Color(vec3a(0.0, 0.0, 0.0))))?;
+                        let tex2 = obj
+                            .get_texture_or_color("tex2", base_dir)
+                            .unwrap_or_else(|_| Ok(TextureOrColor::Color(vec3a(1.0, 1.0, 1.0))))?;
+
+                        let uscale = obj.get_float("uscale").unwrap_or(Ok(2.0))?;
+                        let vscale = obj.get_float("vscale").unwrap_or(Ok(2.0))?;
+
+                        Ok(Self::Texture(Texture {
+                            name: texture.name.to_string(),
+                            inner: InnerTexture::CheckerBoard {
+                                tex1,
+                                tex2,
+                                uscale,
+                                vscale,
+                            },
+                        }))
+                    }
+                    "imagemap" => {
+                        let filename = obj.get_str("filename")??;
+                        let mut pathbuf = base_dir.as_ref().to_path_buf();
+                        pathbuf.push(filename);
+                        let image = load_image(pathbuf)?;
+                        Ok(Self::Texture(Texture {
+                            name: texture.name.to_string(),
+                            inner: InnerTexture::ImageMap(image),
+                        }))
+                    }
+                    t => Err(Error::InvalidTexture(t.to_string())),
+                }?;
+                obj.finish(lints);
+                Ok(world)
+            }
+            pbrt_parser::World::WorldObject(obj) => {
+                let directive = match obj.object_type {
+                    pbrt_parser::WorldObjectType::LightSource => "LightSource",
+                    pbrt_parser::WorldObjectType::AreaLightSource => "AreaLightSource",
+                    pbrt_parser::WorldObjectType::Material => "Material",
+                    pbrt_parser::WorldObjectType::MakeNamedMaterial => "MakeNamedMaterial",
+                    pbrt_parser::WorldObjectType::MakeNamedMedium => "MakeNamedMedium",
+                    pbrt_parser::WorldObjectType::Shape => "Shape",
+                };
+                let obj = Tracked::new(directive, &obj);
+                let world = match obj.object_type {
+                    pbrt_parser::WorldObjectType::LightSource => match obj.t.as_str() {
+                        "infinite" => {
+                            let color = obj
+                                .get_rgb("L", base_dir)
+                                .unwrap_or_else(|_| Ok(vec3a(1.0, 1.0, 1.0)))?
+                                * obj.get_scale(base_dir)?;
+
+                            // pbrt-v4 renamed "mapname" to "filename"
+                            let image_map = if let Ok(filename) =
+                                obj.get_str("filename").or_else(|_| obj.get_str("mapname"))
+                            {
+                                let filename = filename?;
+                                let mut pathbuf = base_dir.as_ref().to_path_buf();
+                                pathbuf.push(filename);
+                                Some(load_image(pathbuf)?)
+                            } else {
+                                None
+                            };
+
+                            Ok(Self::WorldObject(WorldObject::LightSource(
+                                LightSource::Infinite { color, image_map },
+                            )))
+                        }
+                        "distant" => {
+                            let from = obj
+                                .get_point("from")
+                                .unwrap_or_else(|_| Ok(vec3a(0.0, 0.0, 0.0)))?;
+                            let to = obj
+                                .get_point("to")
+                                .unwrap_or_else(|_| Ok(vec3a(0.0, 0.0, 1.0)))?;
+                            let color = obj
+                                .get_rgb("L", base_dir)
+                                .unwrap_or_else(|_| Ok(vec3a(1.0, 1.0, 1.0)))?
+                                * obj.get_scale(base_dir)?;
+                            Ok(Self::WorldObject(WorldObject::LightSource(
+                                LightSource::Distant { from, to, color },
+                            )))
+                        }
+                        "point" => {
+                            let from = obj
+                                .get_point("from")
+                                .unwrap_or_else(|_| Ok(vec3a(0.0, 0.0, 0.0)))?;
+                            let intensity = obj
+                                .get_rgb("I", base_dir)
+                                .unwrap_or_else(|_| Ok(vec3a(1.0, 1.0, 1.0)))?;
+                            let scale = obj.get_scale(base_dir)?;
+                            Ok(Self::WorldObject(WorldObject::LightSource(
+                                LightSource::Point {
+                                    from,
+                                    intensity: intensity * scale,
+                                },
+                            )))
+                        }
+                        "spot" => {
+                            let from = obj
+                                .get_point("from")
+                                .unwrap_or_else(|_| Ok(vec3a(0.0, 0.0, 0.0)))?;
+                            let to = obj
+                                .get_point("to")
+                                .unwrap_or_else(|_| Ok(vec3a(0.0, 0.0, 1.0)))?;
+                            let intensity = obj
+                                .get_rgb("I", base_dir)
+                                .unwrap_or_else(|_| Ok(vec3a(1.0, 1.0, 1.0)))?;
+                            let scale = obj.get_scale(base_dir)?;
+                            let cone_angle = obj.get_float("coneangle").unwrap_or(Ok(30.0))?;
+                            let cone_delta_angle =
+                                obj.get_float("conedeltaangle").unwrap_or(Ok(5.0))?;
+                            Ok(Self::WorldObject(WorldObject::LightSource(
+                                LightSource::Spot {
+                                    from,
+                                    to,
+                                    intensity: intensity * scale,
+                                    cone_angle: deg_to_radian(cone_angle),
+                                    cone_delta_angle: deg_to_radian(cone_delta_angle),
+                                },
+                            )))
+                        }
+                        "goniometric" => {
+                            let intensity = obj
+                                .get_rgb("I", base_dir)
+                                .unwrap_or_else(|_| Ok(vec3a(1.0, 1.0, 1.0)))?;
+                            let scale = obj.get_scale(base_dir)?;
+                            // pbrt-v4 renamed "mapname" to "filename" along with the mapping
+                            let (filename, equal_area) = match obj.get_str("filename") {
+                                Ok(filename) => (Some(filename), true),
+                                Err(_) => (obj.get_str("mapname").ok(), false),
+                            };
+                            let image = if let Some(filename) = filename {
+                                Some(load_image(base_dir.as_ref().join(filename?))?)
+                            } else {
+                                None
+                            };
+                            Ok(Self::WorldObject(WorldObject::LightSource(
+                                LightSource::Goniometric {
+                                    intensity: intensity * scale,
+                                    image,
+                                    equal_area,
+                                },
+                            )))
+                        }
+                        "projection" => {
+                            let intensity = obj
+                                .get_rgb("I", base_dir)
+                                .unwrap_or_else(|_| Ok(vec3a(1.0, 1.0, 1.0)))?;
+                            let scale = obj.get_scale(base_dir)?;
+                            let fov = obj.get_float("fov").unwrap_or(Ok(45.0))?;
+                            // pbrt-v4 renamed "mapname" to "filename"
+                            let image = if let Ok(filename) =
+                                obj.get_str("filename").or_else(|_| obj.get_str("mapname"))
+                            {
+                                Some(load_image(base_dir.as_ref().join(filename?))?)
+                            } else {
+                                None
+                            };
+                            Ok(Self::WorldObject(WorldObject::LightSource(
+                                LightSource::Projection {
+                                    intensity: intensity * scale,
+                                    fov: deg_to_radian(fov),
+                                    image,
+                                },
+                            )))
+                        }
+                        t => Err(Error::InvalidLightSource(t.to_string())),
+                    },
+                    pbrt_parser::WorldObjectType::AreaLightSource => match obj.t.as_str() {
+                        "diffuse" | "area" => {
+                            let l = obj.get_rgb("L", base_dir)??;
+                            Ok(Self::WorldObject(WorldObject::AreaLightSource(
+                                AreaLightSource::Diffuse(l),
+                            )))
+                        }
+                        t => Err(Error::InvalidAreaLightSource(t.to_string())),
+                    },
+                    pbrt_parser::WorldObjectType::Material => Ok(Self::WorldObject(
+                        WorldObject::Material(obj.get_material(&obj.t, base_dir)?),
+                    )),
+                    pbrt_parser::WorldObjectType::MakeNamedMaterial => {
+                        let t = obj.get_str("type")??;
+                        let name = obj.t.to_string();
+
+                        Ok(Self::WorldObject(WorldObject::MakeNamedMaterial(
+                            name,
+                            obj.get_material(t, base_dir)?,
                         )))
                     }
-                    t => Err(Error::InvalidAreaLightSource(t.to_string())),
-                },
-                pbrt_parser::WorldObjectType::Material => Ok(Self::WorldObject(
-                    WorldObject::Material(obj.get_material(base_dir)?),
-                )),
-                pbrt_parser::WorldObjectType::MakeNamedMaterial => {
-                    let t = obj.get_str("type")??;
-                    let name = obj.t.to_string();
-                    let mut obj = obj.clone();
-                    obj.t = t.to_string();
-
-                    Ok(Self::WorldObject(WorldObject::MakeNamedMaterial(
-                        name,
-                        obj.get_material(base_dir)?,
-                    )))
-                }
-                pbrt_parser::WorldObjectType::MakeNamedMedium => {
-                    let name = obj.t.to_string();
-
-                    let sigma_a = obj
-                        .get_rgb("sigma_a", base_dir)
-                        .unwrap_or(Ok(vec3a(0.0011, 0.0024, 0.014)))?;
-
-                    let sigma_s = obj
-                        .get_rgb("sigma_s", base_dir)
-                        .unwrap_or(Ok(vec3a(2.55, 3.21, 3.77)))?;
-
-                    let g = obj.get_float("g").unwrap_or(Ok(0.0))?;
-
-                    Ok(Self::WorldObject(WorldObject::MakeNamedMedium(
-                        name,
-                        Medium::Homogeneous {
-                            sigma_a,
-                            sigma_s,
-                            g,
-                        },
-                    )))
-                }
-                pbrt_parser::WorldObjectType::Shape => match obj.t.as_str() {
-                    "sphere" => {
-                        let radius = obj.get_float("radius").unwrap_or(Ok(1.0))?;
-                        Ok(Self::WorldObject(WorldObject::Shape(Shape::Sphere {
-                            radius,
-                        })))
+                    pbrt_parser::WorldObjectType::MakeNamedMedium => {
+                        Ok(Self::WorldObject(WorldObject::MakeNamedMedium(
+                            obj.t.to_string(),
+                            load_medium(&obj, base_dir)?,
+                        )))
                     }
-                    "trianglemesh" | "loopsubdiv" => {
-                        let indices = obj.get_integers("indices")??;
-                        let indices: Vec<u32> = indices.iter().map(|&i| i as u32).collect();
-                        let vertices = obj.get_points("P")??;
-
-                        let normal = obj
-                            .get_normals("N")
-                            .map(|r| r.map(Some))
-                            .unwrap_or(Ok(None))?;
-
-                        let uv = obj
-                            .get_floats("st")
-                            .or_else(|_| obj.get_floats("uv"))
-                            .map(|r| r.map(Some))
-                            .unwrap_or(Ok(None))?;
-
-                        // TODO st length check
-
-                        if indices.len() % 3 != 0 {
-                            return Err(Error::InvalidArgument(
-                                ArgumentError::UnmatchedValueLength,
-                            ));
+                    pbrt_parser::WorldObjectType::Shape => match obj.t.as_str() {
+                        "sphere" => {
+                            let radius = obj.get_float("radius").unwrap_or(Ok(1.0))?;
+                            Ok(Self::WorldObject(WorldObject::Shape(Shape::Sphere {
+                                radius,
+                            })))
                         }
+                        "trianglemesh" | "loopsubdiv" => {
+                            let indices = obj.get_integers("indices")??;
+                            let indices: Vec<u32> = indices.iter().map(|&i| i as u32).collect();
+                            let vertices = obj.get_points("P")??;
+
+                            let normal = obj
+                                .get_normals("N")
+                                .map(|r| r.map(Some))
+                                .unwrap_or(Ok(None))?;
+
+                            // pbrt-v4 uses "point2 uv", pbrt-v3 uses "float uv" or "float st"
+                            let uv: Option<Vec<Vec2>> = match obj.get_point2s("uv") {
+                                Ok(Ok(uv)) => Some(uv.to_vec()),
+                                _ => obj
+                                    .get_floats("st")
+                                    .or_else(|_| obj.get_floats("uv"))
+                                    .map(|r| {
+                                        r.map(|st| {
+                                            Some(
+                                                st.chunks_exact(2)
+                                                    .map(|st| vec2(st[0], st[1]))
+                                                    .collect(),
+                                            )
+                                        })
+                                    })
+                                    .unwrap_or(Ok(None))?,
+                            };
+
+                            // TODO st length check
 
-                        let mesh = if let Some(normal) = normal {
-                            if normal.len() != vertices.len() {
+                            if indices.len() % 3 != 0 {
                                 return Err(Error::InvalidArgument(
                                     ArgumentError::UnmatchedValueLength,
                                 ));
                             }
 
-                            TriangleMesh {
-                                indices,
-                                vertices: vertices
-                                    .iter()
-                                    .zip(normal.iter())
-                                    .enumerate()
-                                    .map(|(i, (position, normal))| Vertex {
-                                        position: *position,
-                                        normal: *normal,
-                                        uv: uv
-                                            .map(|uv| vec2(uv[2 * i], uv[2 * i + 1]))
-                                            .unwrap_or(Vec2::ZERO),
-                                    })
-                                    .collect(),
-                            }
-                        } else {
-                            TriangleMesh {
-                                indices,
-                                vertices: vertices
-                                    .iter()
-                                    .enumerate()
-                                    .map(|(i, position)| Vertex {
-                                        position: *position,
-                                        normal: Vec3A::ZERO,
-                                        uv: uv
-                                            .map(|st| vec2(st[2 * i], st[2 * i + 1]))
-                                            .unwrap_or(Vec2::ZERO),
-                                    })
-                                    .collect(),
+                            let mesh = if let Some(normal) = normal {
+                                if normal.len() != vertices.len() {
+                                    return Err(Error::InvalidArgument(
+                                        ArgumentError::UnmatchedValueLength,
+                                    ));
+                                }
+
+                                TriangleMesh {
+                                    indices,
+                                    vertices: vertices
+                                        .iter()
+                                        .zip(normal.iter())
+                                        .enumerate()
+                                        .map(|(i, (position, normal))| Vertex {
+                                            position: *position,
+                                            normal: *normal,
+                                            uv: uv.as_ref().map(|uv| uv[i]).unwrap_or(Vec2::ZERO),
+                                        })
+                                        .collect(),
+                                }
+                            } else {
+                                TriangleMesh {
+                                    indices,
+                                    vertices: vertices
+                                        .iter()
+                                        .enumerate()
+                                        .map(|(i, position)| Vertex {
+                                            position: *position,
+                                            normal: Vec3A::ZERO,
+                                            uv: uv.as_ref().map(|uv| uv[i]).unwrap_or(Vec2::ZERO),
+                                        })
+                                        .collect(),
+                                }
+                            };
+
+                            if obj.t == "loopsubdiv" {
+                                let nlevels = obj
+                                    .get_integer("levels")
+                                    .or_else(|_| obj.get_integer("nlevels"))??;
+
+                                Ok(Self::WorldObject(WorldObject::Shape(Shape::TriangleMesh(
+                                    loop_subdivision(mesh, nlevels as usize),
+                                ))))
+                            } else {
+                                Ok(Self::WorldObject(WorldObject::Shape(Shape::TriangleMesh(
+                                    mesh,
+                                ))))
                             }
-                        };
-
-                        if obj.t == "loopsubdiv" {
-                            let nlevels = obj.get_integer("nlevels")??;
-
-                            Ok(Self::WorldObject(WorldObject::Shape(Shape::TriangleMesh(
-                                loop_subdivision(mesh, nlevels as usize),
-                            ))))
-                        } else {
-                            Ok(Self::WorldObject(WorldObject::Shape(Shape::TriangleMesh(
-                                mesh,
-                            ))))
                         }
-                    }
-                    "plymesh" => {
-                        let filename = obj.get_str("filename")??;
-                        let mut pathbuf = base_dir.as_ref().to_path_buf();
-                        pathbuf.push(filename);
-                        let mut f = std::fs::File::open(pathbuf)?;
+                        "plymesh" => {
+                            let filename = obj.get_str("filename")??;
+                            let mut pathbuf = base_dir.as_ref().to_path_buf();
+                            pathbuf.push(filename);
+                            let content = read_file(pathbuf)?;
 
-                        let p = ply::parser::Parser::<ply::ply::DefaultElement>::new();
+                            let p = ply::parser::Parser::<ply::ply::DefaultElement>::new();
 
-                        let ply = p.read_ply(&mut f)?;
+                            let ply = p.read_ply(&mut content.as_slice())?;
 
-                        let triangle_mesh = load_ply(&ply)?;
+                            let triangle_mesh = load_ply(&ply)?;
 
-                        Ok(Self::WorldObject(WorldObject::Shape(Shape::TriangleMesh(
-                            triangle_mesh,
-                        ))))
-                    }
-                    t => Err(Error::InvalidShape(t.to_string())),
-                },
-            },
+                            Ok(Self::WorldObject(WorldObject::Shape(Shape::TriangleMesh(
+                                triangle_mesh,
+                            ))))
+                        }
+                        t => Err(Error::InvalidShape(t.to_string())),
+                    },
+                }?;
+                obj.finish(lints);
+                Ok(world)
+            }
             pbrt_parser::World::Attribute(worlds) => worlds
                 .into_iter()
-                .map(|w| Self::from_world(w, base_dir))
+                .map(|w| Self::from_world(w, base_dir, lints))
                 .collect::<Result<Vec<Self>, Error>>()
                 .map(IntermediateWorld::Attribute),
             pbrt_parser::World::TransformBeginEnd(worlds) => worlds
                 .into_iter()
-                .map(|w| Self::from_world(w, base_dir))
+                .map(|w| Self::from_world(w, base_dir, lints))
                 .collect::<Result<Vec<Self>, Error>>()
                 .map(IntermediateWorld::TransformBeginEnd),
             pbrt_parser::World::Translate(translation) => {
@@ -1028,7 +1333,7 @@ impl IntermediateWorld {
             }
             pbrt_parser::World::ObjectBeginEnd(name, worlds) => worlds
                 .into_iter()
-                .map(|w| Self::from_world(w, base_dir))
+                .map(|w| Self::from_world(w, base_dir, lints))
                 .collect::<Result<Vec<Self>, Error>>()
                 .map(|worlds| IntermediateWorld::ObjectBeginEnd(name.to_string(), worlds)),
             pbrt_parser::World::Scale(scale) => Ok(Self::Matrix(Mat4::from_scale(scale.into()))),
@@ -1044,6 +1349,7 @@ impl IntermediateScene {
     pub fn from_scene<P: AsRef<Path>>(
         scene: pbrt_parser::Scene,
         base_dir: &P,
+        lints: &mut Vec<Lint>,
     ) -> Result<Self, Error> {
         match scene {
             pbrt_parser::Scene::LookAt(look_at) => Ok(Self::Matrix(Mat4::look_at_lh(
@@ -1061,45 +1367,196 @@ impl IntermediateScene {
             pbrt_parser::Scene::Scale(scale) => Ok(Self::Matrix(Mat4::from_scale(scale.into()))),
             pbrt_parser::Scene::ConcatTransform(m) => Ok(Self::Matrix(m)),
             pbrt_parser::Scene::Transform(m) => Ok(Self::Transform(m)),
+            pbrt_parser::Scene::ActiveTransform(active) => Ok(Self::ActiveTransform(active)),
+            pbrt_parser::Scene::CoordinateSystem(name) => Ok(Self::CoordinateSystem(name)),
+            pbrt_parser::Scene::MakeNamedMedium(obj) => {
+                let obj = Tracked::new("MakeNamedMedium", &obj);
+                let scene = Self::MakeNamedMedium(obj.t.to_string(), load_medium(&obj, base_dir)?);
+                obj.finish(lints);
+                Ok(scene)
+            }
+            pbrt_parser::Scene::MediumInterface(interior, exterior) => {
+                Ok(Self::MediumInterface { interior, exterior })
+            }
+            pbrt_parser::Scene::TransformTimes(start, end) => Ok(Self::TransformTimes(start, end)),
             pbrt_parser::Scene::SceneObject(obj) => match obj.object_type {
-                pbrt_parser::SceneObjectType::Sampler => Ok(Self::Sampler),
+                pbrt_parser::SceneObjectType::Sampler => {
+                    // Rene draws its own random numbers and a fixed number of samples,
+                    // only the sampler's parameters are linted
+                    Tracked::new("Sampler", &obj).finish(lints);
+                    Ok(Self::Sampler)
+                }
                 pbrt_parser::SceneObjectType::Integrator => match obj.t.as_str() {
-                    "volpath" => Ok(Self::Integrator(Integrator::VolPath)),
-                    "path" => Ok(Self::Integrator(Integrator::Path)),
-                    i => {
-                        log::info!("{} integrator is not implemented. Use volpath.", i);
-                        Ok(Self::Integrator(Integrator::VolPath))
+                    "volpath" | "path" => {
+                        let obj = Tracked::new("Integrator", &obj);
+                        let parameters = PathIntegrator {
+                            max_depth: obj.get_integer("maxdepth").unwrap_or(Ok(5))?.max(0) as u32,
+                            rr_threshold: obj.get_float("rrthreshold").unwrap_or(Ok(1.0))?,
+                        };
+                        let integrator = if obj.t == "path" {
+                            Integrator::Path(parameters)
+                        } else {
+                            Integrator::VolPath(parameters)
+                        };
+                        obj.finish(lints);
+                        Ok(Self::Integrator(integrator))
                     }
-                },
-                pbrt_parser::SceneObjectType::PixelFilter => Ok(Self::PixelFilter),
-                pbrt_parser::SceneObjectType::Camera => match obj.t.as_str() {
-                    "perspective" => {
-                        let fov = obj.get_float("fov").unwrap_or(Ok(90.0))?;
-                        Ok(Self::SceneObject(SceneObject::Camera(
-                            Camera::Perspective {
-                                fov: deg_to_radian(fov),
-                            },
-                        )))
+                    "ambientocclusion" => {
+                        let obj = Tracked::new("Integrator", &obj);
+                        let integrator = AmbientOcclusionIntegrator {
+                            max_distance: obj
+                                .get_float("maxdistance")
+                                .unwrap_or(Ok(f32::INFINITY))?,
+                            cos_sample: obj.get_bool("cossample").unwrap_or(Ok(true))?,
+                        };
+                        obj.finish(lints);
+                        Ok(Self::Integrator(Integrator::AmbientOcclusion(integrator)))
                     }
-                    t => Err(Error::InvalidCamera(t.to_string())),
-                },
-                pbrt_parser::SceneObjectType::Film => match obj.t.as_str() {
-                    "image" => {
-                        let filename = obj.get_str("filename").unwrap_or(Ok("out.png"))?;
-                        let xresolution = obj.get_integer("xresolution").unwrap_or(Ok(640))? as u32;
-                        let yresolution = obj.get_integer("yresolution").unwrap_or(Ok(480))? as u32;
-                        Ok(Self::Film(Film {
-                            filename: filename.to_string(),
-                            xresolution,
-                            yresolution,
-                        }))
+                    "directlighting" => {
+                        let obj = Tracked::new("Integrator", &obj);
+                        // Like pbrt, an unknown strategy estimates every light
+                        let strategy = match obj.get_str("strategy").unwrap_or(Ok("all"))? {
+                            "one" => LightSampleStrategy::One,
+                            _ => LightSampleStrategy::All,
+                        };
+                        let integrator = DirectLightingIntegrator {
+                            max_depth: obj.get_integer("maxdepth").unwrap_or(Ok(5))?.max(0) as u32,
+                            strategy,
+                        };
+                        obj.finish(lints);
+                        Ok(Self::Integrator(Integrator::DirectLighting(integrator)))
+                    }
+                    "bdpt" => {
+                        let obj = Tracked::new("Integrator", &obj);
+                        let integrator = BdptIntegrator {
+                            max_depth: obj.get_integer("maxdepth").unwrap_or(Ok(5))?.max(0) as u32,
+                        };
+                        obj.finish(lints);
+                        Ok(Self::Integrator(Integrator::Bdpt(integrator)))
+                    }
+                    "sppm" => {
+                        let obj = Tracked::new("Integrator", &obj);
+                        let integrator = SppmIntegrator {
+                            max_depth: obj.get_integer("maxdepth").unwrap_or(Ok(5))?.max(0) as u32,
+                            iterations: obj
+                                .get_integer("iterations")
+                                .or_else(|_| obj.get_integer("numiterations"))
+                                .unwrap_or(Ok(64))?
+                                .max(1) as u32,
+                            photons_per_iteration: obj
+                                .get_integer("photonsperiteration")
+                                .unwrap_or(Ok(-1))?,
+                            radius: obj.get_float("radius").unwrap_or(Ok(1.0))?,
+                        };
+                        obj.finish(lints);
+                        Ok(Self::Integrator(Integrator::Sppm(integrator)))
+                    }
+                    "mlt" => {
+                        let obj = Tracked::new("Integrator", &obj);
+                        let integrator = MltIntegrator {
+                            max_depth: obj.get_integer("maxdepth").unwrap_or(Ok(5))?.max(0) as u32,
+                            mutations_per_pixel: obj
+                                .get_integer("mutationsperpixel")
+                                .unwrap_or(Ok(100))?
+                                .max(1) as u32,
+                            large_step_probability: obj
+                                .get_float("largestepprobability")
+                                .unwrap_or(Ok(0.3))?,
+                            sigma: obj.get_float("sigma").unwrap_or(Ok(0.01))?,
+                        };
+                        obj.finish(lints);
+                        Ok(Self::Integrator(Integrator::Mlt(integrator)))
+                    }
+                    _ => {
+                        // Fall back to volpath, it handles everything Rene supports
+                        lints.push(Lint::unsupported("Integrator", &obj));
+                        Ok(Self::Integrator(Integrator::VolPath(
+                            PathIntegrator::default(),
+                        )))
                     }
-                    t => Err(Error::InvalidFilm(t.to_string())),
                 },
+                pbrt_parser::SceneObjectType::PixelFilter => {
+                    // Rene reconstructs with a box filter, only the filter's parameters are linted
+                    Tracked::new("PixelFilter", &obj).finish(lints);
+                    Ok(Self::PixelFilter)
+                }
+                pbrt_parser::SceneObjectType::Camera => {
+                    let obj = Tracked::new("Camera", &obj);
+                    let inner = match obj.t.as_str() {
+                        "perspective" => {
+                            let lens_radius = obj.get_float("lensradius").unwrap_or(Ok(0.0))?;
+                            let focal_distance =
+                                obj.get_float("focaldistance").unwrap_or(Ok(1e6))?;
+                            let fov = obj.get_float("fov").unwrap_or(Ok(90.0))?;
+                            Ok(InnerCamera::Perspective {
+                                fov: deg_to_radian(fov),
+                                screen_window: get_screen_window(&obj)?,
+                                lens_radius,
+                                focal_distance,
+                            })
+                        }
+                        "orthographic" => {
+                            let lens_radius = obj.get_float("lensradius").unwrap_or(Ok(0.0))?;
+                            let focal_distance =
+                                obj.get_float("focaldistance").unwrap_or(Ok(1e6))?;
+                            Ok(InnerCamera::Orthographic {
+                                screen_window: get_screen_window(&obj)?,
+                                lens_radius,
+                                focal_distance,
+                            })
+                        }
+                        "environment" => Ok(InnerCamera::Environment),
+                        "realistic" => {
+                            let lensfile = obj.get_str("lensfile")??;
+                            let lens = load_lens(&base_dir.as_ref().join(lensfile))?;
+                            let aperture_diameter =
+                                obj.get_float("aperturediameter").unwrap_or(Ok(1.0))?;
+                            let focus_distance =
+                                obj.get_float("focusdistance").unwrap_or(Ok(10.0))?;
+                            Ok(InnerCamera::Realistic {
+                                lens,
+                                aperture_diameter,
+                                focus_distance,
+                            })
+                        }
+                        t => Err(Error::InvalidCamera(t.to_string())),
+                    }?;
+                    let shutter_open = obj.get_float("shutteropen").unwrap_or(Ok(0.0))?;
+                    let shutter_close = obj.get_float("shutterclose").unwrap_or(Ok(1.0))?;
+                    let scene = Self::SceneObject(SceneObject::Camera(Camera {
+                        shutter_open,
+                        shutter_close,
+                        inner,
+                    }));
+                    obj.finish(lints);
+                    Ok(scene)
+                }
+                pbrt_parser::SceneObjectType::Film => {
+                    let obj = Tracked::new("Film", &obj);
+                    let scene = match obj.t.as_str() {
+                        "image" => {
+                            let filename = obj.get_str("filename").unwrap_or(Ok("out.png"))?;
+                            let xresolution =
+                                obj.get_integer("xresolution").unwrap_or(Ok(640))? as u32;
+                            let yresolution =
+                                obj.get_integer("yresolution").unwrap_or(Ok(480))? as u32;
+                            let diagonal = obj.get_float("diagonal").unwrap_or(Ok(35.0))?;
+                            Ok(Self::Film(Film {
+                                filename: filename.to_string(),
+                                xresolution,
+                                yresolution,
+                                diagonal,
+                            }))
+                        }
+                        t => Err(Error::InvalidFilm(t.to_string())),
+                    }?;
+                    obj.finish(lints);
+                    Ok(scene)
+                }
             },
             pbrt_parser::Scene::World(worlds) => worlds
                 .into_iter()
-                .map(|w| IntermediateWorld::from_world(w, base_dir))
+                .map(|w| IntermediateWorld::from_world(w, base_dir, lints))
                 .collect::<Result<Vec<IntermediateWorld>, _>>()
                 .map(IntermediateScene::World),
         }
@@ -0,0 +1,68 @@
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_till},
+    character::complete::multispace1,
+    combinator::{all_consuming, value},
+    error::{Error, ErrorKind},
+    multi::{many0, many0_count},
+    number::complete::float,
+    sequence::{pair, preceded, terminated},
+    IResult,
+};
+
+fn comment(input: &str) -> IResult<&str, ()> {
+    value((), pair(tag("#"), take_till(|c| c == '\n')))(input)
+}
+
+fn skip(input: &str) -> IResult<&str, usize> {
+    many0_count(alt((value((), multispace1), comment)))(input)
+}
+
+/// Parses pbrt's lens description, one element per row of
+/// curvature radius, thickness, index of refraction and aperture diameter in millimeters.
+pub fn parse_lens(input: &str) -> IResult<&str, Vec<[f32; 4]>> {
+    let (rest, values) = all_consuming(terminated(many0(preceded(skip, float)), skip))(input)?;
+    if values.len() % 4 != 0 {
+        return Err(nom::Err::Failure(Error::new(input, ErrorKind::Verify)));
+    }
+
+    Ok((
+        rest,
+        values
+            .chunks_exact(4)
+            .map(|e| [e[0], e[1], e[2], e[3]])
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_lens() {
+        let input = "# D-GAUSS F/2 22deg HFOV
+# Scaled to 50 mm from 100 mm focal length
+#   radius	axpos	N	aperture
+29.475	3.76	1.67	25.2
+84.83	0.12	1	25.2
+0	4.5	0	17.1 # aperture stop
+-39.73	5	1	20
+";
+        let (_, lens) = parse_lens(input).unwrap();
+        assert_eq!(
+            lens,
+            vec![
+                [29.475, 3.76, 1.67, 25.2],
+                [84.83, 0.12, 1.0, 25.2],
+                [0.0, 4.5, 0.0, 17.1],
+                [-39.73, 5.0, 1.0, 20.0],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_incomplete_row() {
+        assert!(parse_lens("29.475 3.76 1.67 25.2\n84.83 0.12 1\n").is_err());
+    }
+}
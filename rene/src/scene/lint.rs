@@ -0,0 +1,292 @@
+use std::{cell::RefCell, ops::Deref};
+
+use pbrt_parser::{Object, Value};
+use thiserror::Error;
+
+use super::intermediate_scene::{ArgumentError, Error};
+
+/// Parameters of pbrt-v3 which are valid but Rene doesn't handle yet, as
+/// (directive, object type, parameter). An empty type matches any type,
+/// `MakeNamedMaterial` is looked up as `Material` with its "type" parameter.
+const UNSUPPORTED_PARAMETERS: &[(&str, &str, &str)] = &[
+    ("Film", "", "cropwindow"),
+    ("Film", "", "scale"),
+    ("Film", "", "maxsampleluminance"),
+    ("Integrator", "", "lightsamplestrategy"),
+    ("Integrator", "", "pixelbounds"),
+    ("Sampler", "", "pixelsamples"),
+    ("Sampler", "", "jitter"),
+    ("Sampler", "stratified", "xsamples"),
+    ("Sampler", "stratified", "ysamples"),
+    ("Sampler", "", "dimensions"),
+    ("Sampler", "halton", "samplepixelcenter"),
+    ("PixelFilter", "", "xwidth"),
+    ("PixelFilter", "", "ywidth"),
+    ("PixelFilter", "gaussian", "alpha"),
+    ("PixelFilter", "mitchell", "B"),
+    ("PixelFilter", "mitchell", "C"),
+    ("PixelFilter", "sinc", "tau"),
+    ("Material", "", "bumpmap"),
+    ("Material", "", "displacement"),
+    ("Material", "matte", "sigma"),
+    ("LightSource", "", "samples"),
+    ("LightSource", "", "nsamples"),
+    ("AreaLightSource", "", "samples"),
+    ("AreaLightSource", "", "nsamples"),
+    ("AreaLightSource", "", "twosided"),
+    ("AreaLightSource", "", "scale"),
+    ("Shape", "", "alpha"),
+    ("Shape", "", "shadowalpha"),
+    ("Shape", "sphere", "zmin"),
+    ("Shape", "sphere", "zmax"),
+    ("Shape", "sphere", "phimax"),
+    ("Shape", "trianglemesh", "S"),
+    ("Shape", "trianglemesh", "faceIndices"),
+    ("Shape", "plymesh", "displacement"),
+    ("Texture", "", "mapping"),
+    ("Texture", "", "udelta"),
+    ("Texture", "", "vdelta"),
+    ("Texture", "imagemap", "filter"),
+    ("Texture", "imagemap", "maxanisotropy"),
+    ("Texture", "imagemap", "wrap"),
+    ("Texture", "imagemap", "trilinear"),
+    ("Texture", "imagemap", "gamma"),
+    ("Texture", "imagemap", "invert"),
+];
+
+fn is_unsupported(directive: &str, t: &str, name: &str) -> bool {
+    UNSUPPORTED_PARAMETERS
+        .iter()
+        .any(|&(d, ty, n)| d == directive && (ty.is_empty() || ty == t) && n == name)
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum LintKind {
+    #[error("unknown parameter \"{0}\"")]
+    UnknownParameter(String),
+    #[error("unmatched type on parameter \"{0}\"")]
+    UnmatchedType(String),
+    #[error("parameter \"{0}\" is not supported yet and ignored")]
+    UnsupportedParameter(String),
+    #[error("not supported yet and ignored")]
+    Unsupported,
+}
+
+#[derive(Error, Debug, Clone)]
+#[error("{directive}: {kind}")]
+pub struct Lint {
+    pub directive: String,
+    pub kind: LintKind,
+}
+
+impl Lint {
+    pub fn unsupported<T>(directive: &str, obj: &Object<T>) -> Self {
+        Self {
+            directive: format!("{} \"{}\"", directive, obj.t),
+            kind: LintKind::Unsupported,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Usage {
+    Unused,
+    Mismatched,
+    Used,
+}
+
+/// `Object` which remembers which of its arguments were looked up.
+pub struct Tracked<'a, T> {
+    obj: &'a Object<T>,
+    directive: &'static str,
+    usage: RefCell<Vec<Usage>>,
+}
+
+impl<'a, T> Tracked<'a, T> {
+    pub fn new(directive: &'static str, obj: &'a Object<T>) -> Self {
+        Self {
+            obj,
+            directive,
+            usage: RefCell::new(vec![Usage::Unused; obj.arguments.len()]),
+        }
+    }
+
+    pub fn get<R>(
+        &self,
+        name: &str,
+        f: impl FnOnce(&'a Value) -> Result<R, ArgumentError>,
+    ) -> Result<Result<R, ArgumentError>, Error> {
+        let index = self
+            .obj
+            .arguments
+            .iter()
+            .position(|a| a.name == name)
+            .ok_or_else(|| Error::ArgumentNotFound(name.to_string()))?;
+
+        let result = f(&self.obj.arguments[index].value);
+
+        // An argument may be tried with several types, any success counts
+        let mut usage = self.usage.borrow_mut();
+        usage[index] = match (usage[index], &result) {
+            (Usage::Used, _) => Usage::Used,
+            (_, Err(ArgumentError::UnmatchedType(_))) => Usage::Mismatched,
+            _ => Usage::Used,
+        };
+
+        Ok(result)
+    }
+
+    pub fn finish(self, lints: &mut Vec<Lint>) {
+        let directive = format!("{} \"{}\"", self.directive, self.obj.t);
+        let (key, t) = if self.directive == "MakeNamedMaterial" {
+            let t = self.obj.arguments.iter().find_map(|a| match &a.value {
+                Value::String(s) if a.name == "type" => s.first().map(String::as_str),
+                _ => None,
+            });
+            ("Material", t.unwrap_or_default())
+        } else {
+            (self.directive, self.obj.t.as_str())
+        };
+
+        for (argument, usage) in self.obj.arguments.iter().zip(self.usage.into_inner()) {
+            let kind = match usage {
+                Usage::Used => continue,
+                Usage::Mismatched => LintKind::UnmatchedType(argument.name.clone()),
+                Usage::Unused if is_unsupported(key, t, &argument.name) => {
+                    LintKind::UnsupportedParameter(argument.name.clone())
+                }
+                Usage::Unused => LintKind::UnknownParameter(argument.name.clone()),
+            };
+
+            lints.push(Lint {
+                directive: directive.clone(),
+                kind,
+            });
+        }
+    }
+}
+
+impl<'a, T> Deref for Tracked<'a, T> {
+    type Target = Object<T>;
+
+    fn deref(&self) -> &Self::Target {
+        self.obj
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pbrt_parser::{Argument, SceneObjectType, WorldObjectType};
+
+    use super::*;
+    use crate::scene::intermediate_scene::GetValue;
+
+    fn argument(name: &str, value: Value) -> Argument {
+        Argument {
+            name: name.to_string(),
+            value,
+        }
+    }
+
+    fn kinds(lints: &[Lint]) -> Vec<LintKind> {
+        lints.iter().map(|l| l.kind.clone()).collect()
+    }
+
+    #[test]
+    fn test_unused_and_mismatched() {
+        let obj = Object {
+            object_type: SceneObjectType::Integrator,
+            t: "path".to_string(),
+            arguments: vec![
+                argument("maxdepth", Value::Integer(vec![3])),
+                argument("rrthreshold", Value::String(vec!["high".to_string()])),
+                argument("foo", Value::Float(vec![1.0])),
+            ],
+        };
+        let obj = Tracked::new("Integrator", &obj);
+        assert_eq!(obj.get_integer("maxdepth").unwrap().unwrap(), 3);
+        assert!(obj.get_float("rrthreshold").unwrap().is_err());
+
+        let mut lints = Vec::new();
+        obj.finish(&mut lints);
+        assert_eq!(
+            kinds(&lints),
+            vec![
+                LintKind::UnmatchedType("rrthreshold".to_string()),
+                LintKind::UnknownParameter("foo".to_string()),
+            ]
+        );
+        assert_eq!(lints[0].directive, "Integrator \"path\"");
+    }
+
+    #[test]
+    fn test_any_matching_type_counts() {
+        let obj = Object {
+            object_type: SceneObjectType::Film,
+            t: "image".to_string(),
+            arguments: vec![argument("xresolution", Value::Integer(vec![640]))],
+        };
+        let obj = Tracked::new("Film", &obj);
+        assert!(obj.get_float("xresolution").unwrap().is_err());
+        assert!(obj.get_integer("xresolution").unwrap().is_ok());
+
+        let mut lints = Vec::new();
+        obj.finish(&mut lints);
+        assert!(lints.is_empty());
+    }
+
+    #[test]
+    fn test_unsupported_by_directive_and_type() {
+        let obj = Object {
+            object_type: SceneObjectType::Film,
+            t: "image".to_string(),
+            arguments: vec![
+                argument("scale", Value::Float(vec![2.0])),
+                argument("zmin", Value::Float(vec![0.0])),
+            ],
+        };
+        let mut lints = Vec::new();
+        Tracked::new("Film", &obj).finish(&mut lints);
+        assert_eq!(
+            kinds(&lints),
+            vec![
+                LintKind::UnsupportedParameter("scale".to_string()),
+                LintKind::UnknownParameter("zmin".to_string()),
+            ]
+        );
+
+        // "zmin" is only a parameter of spheres
+        let sphere = Object {
+            object_type: WorldObjectType::Shape,
+            t: "sphere".to_string(),
+            arguments: vec![argument("zmin", Value::Float(vec![0.0]))],
+        };
+        let mut lints = Vec::new();
+        Tracked::new("Shape", &sphere).finish(&mut lints);
+        assert_eq!(
+            kinds(&lints),
+            vec![LintKind::UnsupportedParameter("zmin".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_named_material_type() {
+        let obj = Object {
+            object_type: WorldObjectType::MakeNamedMaterial,
+            t: "wall".to_string(),
+            arguments: vec![
+                argument("type", Value::String(vec!["matte".to_string()])),
+                argument("sigma", Value::Float(vec![20.0])),
+            ],
+        };
+        let obj = Tracked::new("MakeNamedMaterial", &obj);
+        assert_eq!(obj.get_str("type").unwrap().unwrap(), "matte");
+
+        let mut lints = Vec::new();
+        obj.finish(&mut lints);
+        assert_eq!(
+            kinds(&lints),
+            vec![LintKind::UnsupportedParameter("sigma".to_string())]
+        );
+    }
+}
@@ -0,0 +1,659 @@
+use glam::Vec3A;
+use pbrt_parser::{
+    visit::{self, Fold},
+    Argument, Scene, SceneObject, SceneObjectType, Texture, Value, WorldObject, WorldObjectType,
+};
+
+/// Rewrite a pbrt-v3 scene into pbrt-v4 form.
+/// Conversions which can't preserve the appearance are reported in `warnings`.
+#[derive(Default)]
+pub struct Upgrade {
+    pub warnings: Vec<String>,
+}
+
+pub fn upgrade(scenes: Vec<Scene>) -> (Vec<Scene>, Vec<String>) {
+    let mut upgrade = Upgrade::default();
+    let scenes = upgrade.fold_scenes(scenes);
+    (scenes, upgrade.warnings)
+}
+
+fn rename(arguments: &mut [Argument], from: &str, to: &str) {
+    for argument in arguments.iter_mut() {
+        if argument.name == from {
+            argument.name = to.to_string();
+        }
+    }
+}
+
+fn take(arguments: &mut Vec<Argument>, name: &str) -> Option<Argument> {
+    let i = arguments.iter().position(|a| a.name == name)?;
+    Some(arguments.remove(i))
+}
+
+/// Whether `value` is the constant `c`, textures never are.
+fn is_constant(value: &Value, c: f32) -> bool {
+    match value {
+        Value::Float(v) => v.iter().all(|&v| v == c),
+        Value::Rgb(v) => *v == Vec3A::splat(c),
+        _ => false,
+    }
+}
+
+/// Color of a constant spectrum, textures have none.
+fn constant_color(value: &Value) -> Option<Vec3A> {
+    match value {
+        Value::Float(v) if v.len() == 1 => Some(Vec3A::splat(v[0])),
+        Value::Rgb(v) => Some(*v),
+        _ => None,
+    }
+}
+
+/// Whether the spectrum `name` is black, an absent one is.
+fn is_zero(arguments: &[Argument], name: &str) -> bool {
+    arguments
+        .iter()
+        .filter(|a| a.name == name)
+        .all(|a| is_constant(&a.value, 0.0))
+}
+
+impl Upgrade {
+    fn drop_argument(&mut self, context: &str, arguments: &mut Vec<Argument>, name: &str) {
+        if take(arguments, name).is_some() {
+            self.warnings.push(format!(
+                "{}: parameter \"{}\" is not supported in pbrt-v4 and was removed",
+                context, name
+            ));
+        }
+    }
+
+    fn material(&mut self, t: &str, arguments: &mut Vec<Argument>) -> String {
+        let context = format!("Material \"{}\"", t);
+        match t {
+            "matte" => {
+                rename(arguments, "Kd", "reflectance");
+                self.drop_argument(&context, arguments, "sigma");
+                "diffuse"
+            }
+            "plastic" | "substrate" => {
+                rename(arguments, "Kd", "reflectance");
+                self.drop_argument(&context, arguments, "Ks");
+                // pbrt-v3's plastic defaults to a rough coat, pbrt-v4's to a smooth one
+                if t == "plastic" && !arguments.iter().any(|a| a.name == "roughness") {
+                    arguments.push(Argument {
+                        name: "roughness".to_string(),
+                        value: Value::Float(vec![0.1]),
+                    });
+                }
+                "coateddiffuse"
+            }
+            "metal" => "conductor",
+            "mirror" => {
+                rename(arguments, "Kr", "reflectance");
+                "conductor"
+            }
+            "glass" => {
+                rename(arguments, "index", "eta");
+                self.drop_argument(&context, arguments, "Kr");
+                self.drop_argument(&context, arguments, "Kt");
+                "dielectric"
+            }
+            "uber" => {
+                // pbrt-v4 has no layered uber, keep the lobe which dominates its look
+                if let Some(opacity) = take(arguments, "opacity") {
+                    if !is_constant(&opacity.value, 1.0) {
+                        self.warnings.push(format!(
+                            "{}: \"opacity\" was removed, set \"alpha\" on its shapes instead",
+                            context
+                        ));
+                    }
+                }
+                rename(arguments, "index", "eta");
+                if !is_zero(arguments, "Kt") {
+                    // Specular transmission, a glass
+                    for name in ["Kd", "Ks", "Kr", "Kt"] {
+                        self.drop_argument(&context, arguments, name);
+                    }
+                    "dielectric"
+                } else if !is_zero(arguments, "Kr")
+                    && ["Kd", "Ks"].iter().all(|&name| {
+                        // Both default to 0.25 in uber
+                        arguments.iter().any(|a| a.name == name) && is_zero(arguments, name)
+                    })
+                {
+                    // Specular reflection only, a mirror
+                    take(arguments, "Kd");
+                    take(arguments, "Ks");
+                    take(arguments, "eta");
+                    rename(arguments, "Kr", "reflectance");
+                    "conductor"
+                } else {
+                    // Diffuse under a glossy coat
+                    take(arguments, "Kt");
+                    rename(arguments, "Kd", "reflectance");
+                    for name in ["Ks", "Kr"] {
+                        self.drop_argument(&context, arguments, name);
+                    }
+                    "coateddiffuse"
+                }
+            }
+            "none" | "" => "interface",
+            "translucent" => {
+                // pbrt-v3 scales both lobes by Kd, fold it into the constant ones
+                let kd = match take(arguments, "Kd") {
+                    Some(kd) => constant_color(&kd.value).unwrap_or_else(|| {
+                        self.warnings.push(format!(
+                            "{}: textured \"Kd\" can't be folded into pbrt-v4's lobes and was removed",
+                            context
+                        ));
+                        Vec3A::ONE
+                    }),
+                    None => Vec3A::splat(0.25),
+                };
+                for (from, to) in [("reflect", "reflectance"), ("transmit", "transmittance")] {
+                    let value = take(arguments, from).map_or(Value::Float(vec![0.5]), |a| a.value);
+                    let value = match constant_color(&value) {
+                        Some(color) => Value::Rgb(kd * color),
+                        None => {
+                            if kd != Vec3A::ONE {
+                                self.warnings.push(format!(
+                                    "{}: \"Kd\" can't scale the textured \"{}\" and was ignored",
+                                    context, from
+                                ));
+                            }
+                            value
+                        }
+                    };
+                    arguments.push(Argument {
+                        name: to.to_string(),
+                        value,
+                    });
+                }
+                for name in ["Ks", "roughness", "remaproughness"] {
+                    self.drop_argument(&context, arguments, name);
+                }
+                "diffusetransmission"
+            }
+            "kdsubsurface" => {
+                rename(arguments, "Kd", "reflectance");
+                for name in ["Kr", "Kt"] {
+                    self.drop_argument(&context, arguments, name);
+                }
+                "subsurface"
+            }
+            "hair" => {
+                rename(arguments, "color", "reflectance");
+                "hair"
+            }
+            "disney" | "fourier" => {
+                // pbrt-v4 has no disney, and its measured material can't read fourier's data
+                self.warnings.push(format!(
+                    "{}: material is not supported in pbrt-v4, converted to \"diffuse\"",
+                    context
+                ));
+                rename(arguments, "color", "reflectance");
+                arguments.retain(|a| a.name == "reflectance" || a.name == "displacement");
+                "diffuse"
+            }
+            t => t,
+        }
+        .to_string()
+    }
+
+    fn light(&mut self, directive: &str, t: &str, arguments: &mut Vec<Argument>) {
+        let context = format!("{} \"{}\"", directive, t);
+        if matches!(t, "infinite" | "goniometric" | "projection") {
+            if t == "goniometric" && arguments.iter().any(|a| a.name == "mapname") {
+                self.warnings.push(format!(
+                    "{}: pbrt-v4 reads \"filename\" as an equal-area image, the lat-long image needs converting",
+                    context
+                ));
+            }
+            rename(arguments, "mapname", "filename");
+        }
+        self.drop_argument(&context, arguments, "nsamples");
+        self.drop_argument(&context, arguments, "samples");
+
+        // pbrt-v4's scale is a float, a colored pbrt-v3 scale goes into an RGB emission
+        let mut color = Vec3A::ONE;
+        let mut scale = match take(arguments, "scale") {
+            Some(Argument {
+                value: Value::Float(v),
+                ..
+            }) => v.first().copied().unwrap_or(1.0),
+            Some(Argument {
+                value: Value::Rgb(v),
+                ..
+            }) => {
+                color = v;
+                1.0
+            }
+            Some(_) => {
+                self.warnings.push(format!(
+                    "{}: spectral \"scale\" is not supported in pbrt-v4 and was removed",
+                    context
+                ));
+                1.0
+            }
+            None => 1.0,
+        };
+
+        for argument in arguments.iter_mut() {
+            if argument.name != "L" && argument.name != "I" {
+                continue;
+            }
+            match &mut argument.value {
+                Value::Rgb(v) => {
+                    *v *= color;
+                    color = Vec3A::ONE;
+                }
+                Value::BlackBody(v) => {
+                    if let [blackbody] = v.as_mut_slice() {
+                        scale *= blackbody.y;
+                        blackbody.y = 1.0;
+                    } else {
+                        self.warnings.push(format!(
+                            "{}: pbrt-v4 takes a single blackbody, \"{}\" was written in pbrt-v3 form",
+                            context, argument.name
+                        ));
+                    }
+                    self.warnings.push(format!(
+                        "{}: pbrt-v4 normalizes blackbody \"{}\" to unit luminance, \"scale\" may need adjusting",
+                        context, argument.name
+                    ));
+                }
+                Value::Spectrum(_) => self.warnings.push(format!(
+                    "{}: pbrt-v4 normalizes spectrum \"{}\" to unit luminance, \"scale\" may need adjusting",
+                    context, argument.name
+                )),
+                _ => {}
+            }
+        }
+
+        if color != Vec3A::ONE {
+            // No RGB emission to take the color, keep its brightness
+            if color.min_element() != color.max_element() {
+                self.warnings.push(format!(
+                    "{}: colored \"scale\" was replaced by its average",
+                    context
+                ));
+            }
+            scale *= (color.x + color.y + color.z) / 3.0;
+        }
+        if scale != 1.0 {
+            arguments.push(Argument {
+                name: "scale".to_string(),
+                value: Value::Float(vec![scale]),
+            });
+        }
+    }
+
+    fn shape(&mut self, t: &str, arguments: &mut Vec<Argument>) {
+        let context = format!("Shape \"{}\"", t);
+        match t {
+            "trianglemesh" => match take(arguments, "uv").or_else(|| take(arguments, "st")) {
+                Some(Argument {
+                    value: Value::Float(uv),
+                    ..
+                }) => arguments.push(Argument {
+                    name: "uv".to_string(),
+                    value: Value::Point2(
+                        uv.chunks_exact(2)
+                            .map(|uv| glam::vec2(uv[0], uv[1]))
+                            .collect(),
+                    ),
+                }),
+                Some(uv) => arguments.push(Argument {
+                    name: "uv".to_string(),
+                    ..uv
+                }),
+                None => {}
+            },
+            "loopsubdiv" => rename(arguments, "nlevels", "levels"),
+            "cone" | "paraboloid" | "hyperboloid" | "heightfield" | "nurbs" => {
+                self.warnings
+                    .push(format!("{}: shape was removed in pbrt-v4", context));
+            }
+            _ => {}
+        }
+        self.drop_argument(&context, arguments, "shadowalpha");
+    }
+}
+
+impl Fold for Upgrade {
+    fn fold_scene_object(&mut self, mut obj: SceneObject) -> SceneObject {
+        match (obj.object_type, obj.t.as_str()) {
+            (SceneObjectType::Film, "image") => obj.t = "rgb".to_string(),
+            (SceneObjectType::Sampler, "lowdiscrepancy" | "02sequence") => {
+                obj.t = "paddedsobol".to_string()
+            }
+            (SceneObjectType::Sampler, "maxmindist") => obj.t = "pmj02bn".to_string(),
+            (SceneObjectType::Integrator, "directlighting") => {
+                self.warnings.push(
+                    "Integrator \"directlighting\": converted to \"path\" with maxdepth 1"
+                        .to_string(),
+                );
+                obj.t = "path".to_string();
+                take(&mut obj.arguments, "strategy");
+                take(&mut obj.arguments, "maxdepth");
+                obj.arguments.push(Argument {
+                    name: "maxdepth".to_string(),
+                    value: Value::Integer(vec![1]),
+                });
+            }
+            (SceneObjectType::PixelFilter, t) => {
+                rename(&mut obj.arguments, "xwidth", "xradius");
+                rename(&mut obj.arguments, "ywidth", "yradius");
+                if t == "gaussian" && obj.arguments.iter().any(|a| a.name == "alpha") {
+                    self.warnings.push(
+                        "PixelFilter \"gaussian\": \"alpha\" was replaced by \"sigma\"".to_string(),
+                    );
+                    take(&mut obj.arguments, "alpha");
+                }
+            }
+            _ => {}
+        }
+
+        visit::fold_scene_object(self, obj)
+    }
+
+    fn fold_world_object(&mut self, mut obj: WorldObject) -> WorldObject {
+        match obj.object_type {
+            WorldObjectType::Material => obj.t = self.material(&obj.t, &mut obj.arguments),
+            WorldObjectType::MakeNamedMaterial => {
+                if let Some(Argument {
+                    value: Value::String(t),
+                    ..
+                }) = take(&mut obj.arguments, "type")
+                {
+                    let t = self.material(&t.concat(), &mut obj.arguments);
+                    obj.arguments.insert(
+                        0,
+                        Argument {
+                            name: "type".to_string(),
+                            value: Value::String(vec![t]),
+                        },
+                    );
+                }
+            }
+            WorldObjectType::LightSource => self.light("LightSource", &obj.t, &mut obj.arguments),
+            WorldObjectType::AreaLightSource => {
+                self.light("AreaLightSource", &obj.t, &mut obj.arguments)
+            }
+            WorldObjectType::Shape => self.shape(&obj.t, &mut obj.arguments),
+            WorldObjectType::MakeNamedMedium => {}
+        }
+
+        visit::fold_world_object(self, obj)
+    }
+
+    fn fold_texture(&mut self, mut texture: Texture) -> Texture {
+        if texture.value_type == "color" {
+            texture.value_type = "spectrum".to_string();
+        }
+
+        let arguments = &mut texture.obj.arguments;
+        match texture.obj.t.as_str() {
+            "imagemap" => {
+                if let Some(Argument {
+                    value: Value::Bool(gamma),
+                    ..
+                }) = take(arguments, "gamma")
+                {
+                    let encoding = if gamma.first().copied().unwrap_or(false) {
+                        "sRGB"
+                    } else {
+                        "linear"
+                    };
+                    arguments.push(Argument {
+                        name: "encoding".to_string(),
+                        value: Value::String(vec![encoding.to_string()]),
+                    });
+                }
+            }
+            "scale" => {
+                rename(arguments, "tex1", "tex");
+                rename(arguments, "tex2", "scale");
+            }
+            _ => {}
+        }
+
+        visit::fold_texture(self, texture)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use glam::{vec2, vec3a};
+    use pbrt_parser::{
+        write::{to_pbrt_string, Version},
+        World,
+    };
+
+    use super::*;
+
+    fn argument(name: &str, value: Value) -> Argument {
+        Argument {
+            name: name.to_string(),
+            value,
+        }
+    }
+
+    fn world_object(object_type: WorldObjectType, t: &str, arguments: Vec<Argument>) -> Scene {
+        Scene::World(vec![World::WorldObject(WorldObject {
+            object_type,
+            t: t.to_string(),
+            arguments,
+        })])
+    }
+
+    /// Upgraded pbrt-v4 text and the warnings
+    fn upgrade_one(scene: Scene) -> (String, Vec<String>) {
+        let (scenes, warnings) = upgrade(vec![scene]);
+        (to_pbrt_string(&scenes, Version::V4), warnings)
+    }
+
+    #[test]
+    fn test_matte() {
+        let (text, warnings) = upgrade_one(world_object(
+            WorldObjectType::Material,
+            "matte",
+            vec![
+                argument("Kd", Value::Rgb(vec3a(0.5, 0.5, 0.5))),
+                argument("sigma", Value::Float(vec![20.0])),
+            ],
+        ));
+
+        assert!(text.contains("Material \"diffuse\""));
+        assert!(text.contains("\"rgb reflectance\" [ 0.5 0.5 0.5 ]"));
+        assert!(!text.contains("sigma"));
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_plastic() {
+        let (text, warnings) = upgrade_one(world_object(
+            WorldObjectType::Material,
+            "plastic",
+            vec![
+                argument("Kd", Value::Rgb(vec3a(0.5, 0.5, 0.5))),
+                argument("Ks", Value::Rgb(vec3a(0.2, 0.2, 0.2))),
+            ],
+        ));
+
+        assert!(text.contains("Material \"coateddiffuse\""));
+        assert!(text.contains("\"rgb reflectance\" [ 0.5 0.5 0.5 ]"));
+        assert!(text.contains("\"float roughness\" [ 0.1 ]"));
+        assert!(!text.contains("Ks"));
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_named_glass() {
+        let (text, warnings) = upgrade_one(world_object(
+            WorldObjectType::MakeNamedMaterial,
+            "glass",
+            vec![
+                argument("type", Value::String(vec!["glass".to_string()])),
+                argument("index", Value::Float(vec![1.33])),
+            ],
+        ));
+
+        assert!(text.contains("MakeNamedMaterial \"glass\""));
+        assert!(text.contains("\"string type\" [ \"dielectric\" ]"));
+        assert!(text.contains("\"float eta\" [ 1.33 ]"));
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_uber() {
+        // Transmission makes a glass
+        let (text, _) = upgrade_one(world_object(
+            WorldObjectType::Material,
+            "uber",
+            vec![
+                argument("Kt", Value::Rgb(vec3a(1.0, 1.0, 1.0))),
+                argument("index", Value::Float(vec![1.5])),
+            ],
+        ));
+        assert!(text.contains("Material \"dielectric\""));
+        assert!(text.contains("\"float eta\" [ 1.5 ]"));
+        assert!(!text.contains("Kt"));
+
+        // Reflection alone makes a mirror
+        let (text, warnings) = upgrade_one(world_object(
+            WorldObjectType::Material,
+            "uber",
+            vec![
+                argument("Kd", Value::Float(vec![0.0])),
+                argument("Ks", Value::Float(vec![0.0])),
+                argument("Kr", Value::Rgb(vec3a(0.9, 0.9, 0.9))),
+            ],
+        ));
+        assert!(text.contains("Material \"conductor\""));
+        assert!(text.contains("\"rgb reflectance\" [ 0.9 0.9 0.9 ]"));
+        assert!(warnings.is_empty());
+
+        // Otherwise a coated diffuse, what can't be kept is reported
+        let (text, warnings) = upgrade_one(world_object(
+            WorldObjectType::Material,
+            "uber",
+            vec![
+                argument("Kd", Value::Rgb(vec3a(0.5, 0.5, 0.5))),
+                argument("Kr", Value::Float(vec![0.1])),
+                argument("opacity", Value::Float(vec![0.5])),
+            ],
+        ));
+        assert!(text.contains("Material \"coateddiffuse\""));
+        assert!(text.contains("\"rgb reflectance\" [ 0.5 0.5 0.5 ]"));
+        assert!(!text.contains("Kr") && !text.contains("opacity"));
+        assert_eq!(warnings.len(), 2);
+    }
+
+    #[test]
+    fn test_translucent() {
+        let (text, warnings) = upgrade_one(world_object(
+            WorldObjectType::Material,
+            "translucent",
+            vec![
+                argument("Kd", Value::Rgb(vec3a(0.5, 0.5, 0.5))),
+                argument("transmit", Value::Float(vec![1.0])),
+            ],
+        ));
+
+        assert!(text.contains("Material \"diffusetransmission\""));
+        assert!(text.contains("\"rgb reflectance\" [ 0.25 0.25 0.25 ]"));
+        assert!(text.contains("\"rgb transmittance\" [ 0.5 0.5 0.5 ]"));
+        assert!(!text.contains("Kd"));
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_kept_materials() {
+        let (text, warnings) = upgrade_one(world_object(
+            WorldObjectType::Material,
+            "kdsubsurface",
+            vec![
+                argument("Kd", Value::Rgb(vec3a(0.5, 0.5, 0.5))),
+                argument("mfp", Value::Float(vec![0.1])),
+            ],
+        ));
+        assert!(text.contains("Material \"subsurface\""));
+        assert!(text.contains("\"rgb reflectance\" [ 0.5 0.5 0.5 ]"));
+        assert!(text.contains("\"float mfp\" [ 0.1 ]"));
+        assert!(warnings.is_empty());
+
+        let (text, warnings) = upgrade_one(world_object(
+            WorldObjectType::Material,
+            "hair",
+            vec![argument("eumelanin", Value::Float(vec![1.3]))],
+        ));
+        assert!(text.contains("Material \"hair\""));
+        assert!(text.contains("\"float eumelanin\" [ 1.3 ]"));
+        assert!(warnings.is_empty());
+
+        let (text, warnings) = upgrade_one(world_object(
+            WorldObjectType::Material,
+            "disney",
+            vec![
+                argument("color", Value::Rgb(vec3a(0.5, 0.5, 0.5))),
+                argument("metallic", Value::Float(vec![0.5])),
+            ],
+        ));
+        assert!(text.contains("Material \"diffuse\""));
+        assert!(text.contains("\"rgb reflectance\" [ 0.5 0.5 0.5 ]"));
+        assert!(!text.contains("metallic"));
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_lights() {
+        let (text, warnings) = upgrade_one(world_object(
+            WorldObjectType::LightSource,
+            "infinite",
+            vec![
+                argument("mapname", Value::String(vec!["sky.exr".to_string()])),
+                argument("nsamples", Value::Integer(vec![8])),
+            ],
+        ));
+        assert!(text.contains("\"string filename\" [ \"sky.exr\" ]"));
+        assert!(!text.contains("nsamples"));
+        assert_eq!(warnings.len(), 1);
+
+        // The blackbody's scale goes into the light's, a gray scale loses nothing
+        let (text, warnings) = upgrade_one(world_object(
+            WorldObjectType::LightSource,
+            "point",
+            vec![
+                argument("I", Value::BlackBody(vec![vec2(6500.0, 2.0)])),
+                argument("scale", Value::Rgb(vec3a(3.0, 3.0, 3.0))),
+            ],
+        ));
+        assert!(text.contains("\"blackbody I\" [ 6500 ]"));
+        assert!(text.contains("\"float scale\" [ 6 ]"));
+        assert!(!warnings.iter().any(|w| w.contains("average")));
+
+        // A colored scale goes into an RGB emission
+        let (text, warnings) = upgrade_one(world_object(
+            WorldObjectType::LightSource,
+            "spot",
+            vec![
+                argument("I", Value::Rgb(vec3a(1.0, 2.0, 3.0))),
+                argument("scale", Value::Rgb(vec3a(2.0, 1.0, 1.0))),
+            ],
+        ));
+        assert!(text.contains("\"rgb I\" [ 2 2 3 ]"));
+        assert!(!text.contains("scale"));
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_world_end() {
+        let (text, _) = upgrade_one(world_object(
+            WorldObjectType::Shape,
+            "sphere",
+            vec![argument("radius", Value::Float(vec![1.0]))],
+        ));
+        assert!(text.contains("WorldBegin"));
+        assert!(!text.contains("WorldEnd"));
+    }
+}